@@ -0,0 +1,103 @@
+//! Example: Blocking client over a `std::net::TcpStream`
+//!
+//! This example runs `BlockingMqttClient` on the host without an async
+//! executor, the same way it runs in a bare superloop on a device.
+//!
+//! # Key Concepts
+//!
+//! - Implement `BlockingTransport` with a short read timeout, returning
+//!   `Ok(0)` when no data arrived
+//! - Provide an `embassy-time` driver, here backed by `std::time::Instant`
+//! - Call `poll()` from the main loop between other work
+//!
+//! # Running
+//!
+//! Start a broker on `localhost:1883` (e.g. `mosquitto`), then run
+//! `cargo run --example blocking_tcp --features blocking`.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::OnceLock;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use myrtio_mqtt::blocking::{BlockingMqttClient, BlockingTransport};
+use myrtio_mqtt::{MqttEvent, MqttOptions, QoS};
+
+/// `embassy-time` driver counting microseconds since the first call.
+struct StdDriver;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+impl embassy_time_driver::Driver for StdDriver {
+    fn now(&self) -> u64 {
+        START.get_or_init(Instant::now).elapsed().as_micros() as u64
+    }
+
+    fn schedule_wake(&self, _at: u64, waker: &Waker) {
+        // The blocking client polls in a loop, so waking right away is enough
+        waker.wake_by_ref();
+    }
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: StdDriver = StdDriver);
+
+/// Blocking transport over a TCP stream with a short read timeout.
+struct TcpBlockingTransport {
+    stream: TcpStream,
+}
+
+impl TcpBlockingTransport {
+    fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        Ok(Self { stream })
+    }
+}
+
+impl BlockingTransport for TcpBlockingTransport {
+    type Error = io::Error;
+
+    fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.stream.write_all(buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self.stream.read(buf) {
+            // The broker closed the connection
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => Ok(n),
+            // Read timeout: no data yet
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let transport = TcpBlockingTransport::connect("localhost:1883")?;
+    let options = MqttOptions::new("blocking-example");
+    let mut client = BlockingMqttClient::<_, 4, 512>::new(transport, options);
+
+    client
+        .connect()
+        .map_err(|e| format!("connect failed: {e:?}"))?;
+    client
+        .subscribe("example/cmd", QoS::AtLeastOnce)
+        .map_err(|e| format!("subscribe failed: {e:?}"))?;
+    client
+        .publish("example/state", b"online", QoS::AtLeastOnce)
+        .map_err(|e| format!("publish failed: {e:?}"))?;
+
+    loop {
+        match client.poll() {
+            Ok(Some(MqttEvent::Publish(msg))) => {
+                println!("{}: {}", msg.topic, String::from_utf8_lossy(msg.payload));
+            }
+            Ok(_) => {
+                // Other superloop work goes here
+            }
+            Err(e) => return Err(format!("connection lost: {e:?}").into()),
+        }
+    }
+}
@@ -8,23 +8,23 @@
 //!
 //! - Define topics as `const` or `static` strings
 //! - Implement `register()` to add topics to the registry
-//! - Implement `on_message()` to handle incoming messages
+//! - Implement `on_message()` to handle incoming messages and queue responses
 //! - Use `on_tick()` for periodic state publishing
 //!
 //! # Note
 //!
-//! This example is for illustration purposes and won't compile as a
-//! standalone binary without a proper transport implementation.
+//! This example is for illustration purposes and won't run as a standalone
+//! binary without a proper transport implementation.
 
-#![no_std]
-#![no_main]
+#![cfg_attr(any(target_arch = "xtensa", target_arch = "riscv32"), no_std, no_main)]
 
 use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use embassy_time::Duration;
 use myrtio_mqtt::{
+    QoS,
     packet::Publish,
-    runtime::{Context, MqttModule, TopicRegistry},
-    transport::{MqttTransport, TransportError},
+    runtime::{MqttModule, PublishOutbox, TopicCollector},
+    util::JsonWriter,
 };
 
 // Define topics as constants - these have 'static lifetime
@@ -42,123 +42,93 @@ static BRIGHTNESS: AtomicU8 = AtomicU8::new(255);
 /// - On/Off commands via `device/light/cmd`
 /// - Brightness commands via `device/light/brightness/cmd`
 /// - State publishing via `device/light/state`
-pub struct LightModule {
-    /// Flag indicating state changed and needs publishing
-    needs_publish: bool,
-}
+pub struct LightModule;
 
 impl LightModule {
     /// Create a new light module
     pub const fn new() -> Self {
-        Self {
-            needs_publish: false,
-        }
+        Self
     }
 
-    /// Get current light state as JSON bytes
-    fn get_state_payload(&self, buf: &mut [u8]) -> usize {
+    /// Queue the current light state as JSON
+    fn publish_state(&self, outbox: &mut dyn PublishOutbox) {
         let on = LIGHT_ON.load(Ordering::Relaxed);
         let brightness = BRIGHTNESS.load(Ordering::Relaxed);
 
         // Simple JSON formatting without serde
         let state = if on { "ON" } else { "OFF" };
-        let len = format_state(state, brightness, buf);
-        len
+        let mut buf = [0u8; 64];
+        let len = format_state(state, brightness, &mut buf);
+        outbox.publish(STATE_TOPIC, &buf[..len], QoS::AtMostOnce);
     }
 }
 
-impl<'a, T, const MAX_TOPICS: usize, const BUF_SIZE: usize> MqttModule<'a, T, MAX_TOPICS, BUF_SIZE>
-    for LightModule
-where
-    T: MqttTransport,
-{
+impl Default for LightModule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MqttModule for LightModule {
     /// Register topics for subscription.
     ///
-    /// The `'reg` lifetime is tied to `&self`, but since our topics are
-    /// `&'static str` constants, they satisfy any lifetime requirement.
-    fn register<'reg>(&'reg self, registry: &mut TopicRegistry<'reg, MAX_TOPICS>) {
-        // Register command topics - these are 'static so always valid
-        let _ = registry.add(CMD_TOPIC);
-        let _ = registry.add(BRIGHTNESS_CMD_TOPIC);
+    /// The collector copies the topics, so any string works; constants are
+    /// the simplest choice.
+    fn register(&self, collector: &mut dyn TopicCollector) {
+        collector.add(CMD_TOPIC);
+        collector.add(BRIGHTNESS_CMD_TOPIC);
     }
 
-    /// Handle incoming messages.
-    fn on_message<'m>(&mut self, msg: &Publish<'m>) {
+    /// Handle incoming messages and answer with the new state.
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
         match msg.topic {
             CMD_TOPIC => {
                 // Handle on/off command
                 if msg.payload == b"ON" {
                     LIGHT_ON.store(true, Ordering::Relaxed);
-                    self.needs_publish = true;
                 } else if msg.payload == b"OFF" {
                     LIGHT_ON.store(false, Ordering::Relaxed);
-                    self.needs_publish = true;
+                } else {
+                    return;
                 }
             }
             BRIGHTNESS_CMD_TOPIC => {
                 // Handle brightness command (expects decimal string)
-                if let Ok(s) = core::str::from_utf8(msg.payload) {
-                    if let Ok(val) = s.trim().parse::<u8>() {
-                        BRIGHTNESS.store(val, Ordering::Relaxed);
-                        self.needs_publish = true;
-                    }
-                }
+                let Some(val) = msg
+                    .payload_str()
+                    .ok()
+                    .and_then(|s| s.trim().parse::<u8>().ok())
+                else {
+                    return;
+                };
+                BRIGHTNESS.store(val, Ordering::Relaxed);
             }
-            _ => {}
+            _ => return,
         }
+
+        // Published by the runtime right after this method returns
+        self.publish_state(outbox);
     }
 
     /// Periodic task: publish current state
-    async fn on_tick(&mut self, ctx: &mut Context<'a, '_, T, MAX_TOPICS, BUF_SIZE>) -> Duration
-    where
-        T::Error: TransportError,
-    {
-        self.needs_publish = false;
-
-        // Publish state
-        let mut buf = [0u8; 64];
-        let len = self.get_state_payload(&mut buf);
-        let _ = ctx
-            .publish(STATE_TOPIC, &buf[..len], myrtio_mqtt::QoS::AtMostOnce)
-            .await;
+    fn on_tick(&mut self, outbox: &mut dyn PublishOutbox) -> Duration {
+        self.publish_state(outbox);
 
         // Re-publish state every 30 seconds
         Duration::from_secs(30)
     }
-
-    fn needs_immediate_publish(&self) -> bool {
-        self.needs_publish
-    }
 }
 
 /// Format state as simple JSON: {"state":"ON","brightness":255}
 fn format_state(state: &str, brightness: u8, buf: &mut [u8]) -> usize {
-    use core::fmt::Write;
-
-    struct BufWriter<'a> {
-        buf: &'a mut [u8],
-        pos: usize,
-    }
-
-    impl Write for BufWriter<'_> {
-        fn write_str(&mut self, s: &str) -> core::fmt::Result {
-            let bytes = s.as_bytes();
-            if self.pos + bytes.len() > self.buf.len() {
-                return Err(core::fmt::Error);
-            }
-            self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
-            self.pos += bytes.len();
-            Ok(())
-        }
-    }
-
-    let mut w = BufWriter { buf, pos: 0 };
-    let _ = write!(
-        w,
-        "{{\"state\":\"{}\",\"brightness\":{}}}",
-        state, brightness
-    );
-    w.pos
+    let mut json = JsonWriter::new(buf);
+    let written = json
+        .begin_object()
+        .and_then(|json| json.field_str("state", state))
+        .and_then(|json| json.field_i32("brightness", i32::from(brightness)))
+        .and_then(|json| json.end_object());
+    // Nothing is published if the state doesn't fit
+    written.unwrap_or(0)
 }
 
 // Placeholder main - actual implementation would use embassy executor
@@ -173,3 +143,67 @@ fn main() {
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
+
+/// Drives `LightModule` through a `MqttRuntime` connected to a
+/// `LoopbackBroker`. Run with `cargo test --features test-util --example
+/// const_topics_module`.
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::sync::OnceLock;
+    use std::task::Waker;
+    use std::time::Instant;
+
+    use embassy_futures::select::{Either, select};
+    use embassy_time::Timer;
+    use myrtio_mqtt::runtime::{MqttRuntime, PublishRequestChannel};
+    use myrtio_mqtt::test_util::LoopbackBroker;
+    use myrtio_mqtt::{MqttClient, MqttOptions, MqttVersion};
+
+    use super::*;
+
+    /// `embassy-time` driver counting microseconds since the first call.
+    struct StdDriver;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    impl embassy_time_driver::Driver for StdDriver {
+        fn now(&self) -> u64 {
+            START.get_or_init(Instant::now).elapsed().as_micros() as u64
+        }
+
+        fn schedule_wake(&self, _at: u64, waker: &Waker) {
+            // `block_on` keeps polling after a wake
+            waker.wake_by_ref();
+        }
+    }
+
+    embassy_time_driver::time_driver_impl!(static DRIVER: StdDriver = StdDriver);
+
+    #[test]
+    fn light_answers_a_command_with_its_state() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let broker = LoopbackBroker::<1024>::new(MqttVersion::V3);
+        let client = MqttClient::<_, 4, 512>::new(broker.transport(), MqttOptions::new("light"));
+        let mut runtime =
+            MqttRuntime::<_, _, 4, 512, 4>::new(client, LightModule::new(), CHANNEL.receiver());
+
+        let script = async {
+            // Let the runtime connect, subscribe and publish the initial state
+            Timer::after_millis(10).await;
+            assert!(broker.is_subscribed(CMD_TOPIC));
+            let initial = broker.take_published();
+            assert_eq!(initial[0].payload, br#"{"state":"OFF","brightness":255}"#);
+
+            assert!(broker.publish(CMD_TOPIC, b"ON"));
+            Timer::after_millis(10).await;
+            let published = broker.take_published();
+            assert_eq!(published.len(), 1);
+            assert_eq!(published[0].topic, STATE_TOPIC);
+            assert_eq!(published[0].payload, br#"{"state":"ON","brightness":255}"#);
+        };
+        match embassy_futures::block_on(select(runtime.run(), script)) {
+            Either::First(result) => panic!("runtime stopped: {result:?}"),
+            Either::Second(()) => {}
+        }
+    }
+}
@@ -7,8 +7,7 @@
 //! # Key Concepts
 //!
 //! - Store topics in `heapless::String` fields
-//! - The `register<'reg>(&'reg self, ...)` lifetime allows borrowing from `self`
-//! - Topics are valid as long as the module lives
+//! - `register` passes them to the collector, which copies them
 //!
 //! # When to Use Dynamic Topics
 //!
@@ -18,18 +17,17 @@
 //!
 //! # Note
 //!
-//! This example is for illustration purposes and won't compile as a
-//! standalone binary without a proper transport implementation.
+//! This example is for illustration purposes and won't run as a standalone
+//! binary without a proper transport implementation.
 
-#![no_std]
-#![no_main]
+#![cfg_attr(any(target_arch = "xtensa", target_arch = "riscv32"), no_std, no_main)]
 
 use embassy_time::Duration;
 use heapless::String;
 use myrtio_mqtt::{
+    QoS,
     packet::Publish,
-    runtime::{Context, MqttModule, TopicRegistry},
-    transport::{MqttTransport, TransportError},
+    runtime::{MqttModule, PublishOutbox, TopicCollector},
 };
 
 /// Maximum length for topic strings
@@ -38,8 +36,6 @@ const MAX_TOPIC_LEN: usize = 64;
 /// A sensor module with configurable device ID in topics.
 ///
 /// Topics are constructed at creation time and stored in the struct.
-/// The new `register<'reg>` lifetime model allows these to be registered
-/// because they live as long as the module itself.
 pub struct SensorModule {
     /// Command topic: `{device_id}/sensor/cmd`
     cmd_topic: String<MAX_TOPIC_LEN>,
@@ -47,8 +43,6 @@ pub struct SensorModule {
     state_topic: String<MAX_TOPIC_LEN>,
     /// Current sensor value
     value: i32,
-    /// Flag for state change
-    needs_publish: bool,
 }
 
 impl SensorModule {
@@ -70,78 +64,58 @@ impl SensorModule {
             cmd_topic,
             state_topic,
             value: 0,
-            needs_publish: false,
         }
     }
 
     /// Update the sensor value (called from hardware driver)
     pub fn set_value(&mut self, value: i32) {
-        if self.value != value {
-            self.value = value;
-            self.needs_publish = true;
-        }
+        self.value = value;
+    }
+
+    /// Queue the current value on the state topic
+    fn publish_state(&self, outbox: &mut dyn PublishOutbox) {
+        let mut buf = [0u8; 16];
+        let len = format_i32(self.value, &mut buf);
+        outbox.publish(self.state_topic.as_str(), &buf[..len], QoS::AtMostOnce);
     }
 }
 
-impl<'a, T, const MAX_TOPICS: usize, const BUF_SIZE: usize> MqttModule<'a, T, MAX_TOPICS, BUF_SIZE>
-    for SensorModule
-where
-    T: MqttTransport,
-{
+impl MqttModule for SensorModule {
     /// Register topics for subscription.
     ///
-    /// The `'reg` lifetime is tied to `&'reg self`, which means we can
-    /// borrow from our fields. The topics in `self.cmd_topic` and
-    /// `self.state_topic` live as long as `self`, so they can be
-    /// registered in the registry.
-    fn register<'reg>(&'reg self, registry: &mut TopicRegistry<'reg, MAX_TOPICS>) {
-        // Register our command topic - borrowed from self
-        let _ = registry.add(self.cmd_topic.as_str());
+    /// The collector copies the topic, so borrowing it from `self` only
+    /// needs to last for this call.
+    fn register(&self, collector: &mut dyn TopicCollector) {
+        collector.add(self.cmd_topic.as_str());
     }
 
     /// Handle incoming messages.
-    fn on_message<'m>(&mut self, msg: &Publish<'m>) {
-        // Check if this message is for our command topic
-        if msg.topic == self.cmd_topic.as_str() {
-            // Parse command (e.g., "SET:123" to set calibration offset)
-            if let Ok(s) = core::str::from_utf8(msg.payload) {
-                if let Some(val_str) = s.strip_prefix("SET:") {
-                    if let Ok(val) = val_str.trim().parse::<i32>() {
-                        self.value = val;
-                        self.needs_publish = true;
-                    }
-                }
-            }
-        }
+    ///
+    /// Only called for topics matching `cmd_topic`, since that is the only
+    /// filter this module registers.
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        // Parse command (e.g., "SET:123" to set calibration offset)
+        let Some(val) = msg
+            .payload_str()
+            .ok()
+            .and_then(|s| s.strip_prefix("SET:"))
+            .and_then(|s| s.trim().parse::<i32>().ok())
+        else {
+            return;
+        };
+        self.value = val;
+
+        // Confirm the new value right away
+        self.publish_state(outbox);
     }
 
     /// Periodic task: publish current state
-    async fn on_tick(&mut self, ctx: &mut Context<'a, '_, T, MAX_TOPICS, BUF_SIZE>) -> Duration
-    where
-        T::Error: TransportError,
-    {
-        self.needs_publish = false;
-
-        // Format value as string
-        let mut buf = [0u8; 16];
-        let len = format_i32(self.value, &mut buf);
-
-        // Publish to our state topic
-        let _ = ctx
-            .publish(
-                self.state_topic.as_str(),
-                &buf[..len],
-                myrtio_mqtt::QoS::AtMostOnce,
-            )
-            .await;
+    fn on_tick(&mut self, outbox: &mut dyn PublishOutbox) -> Duration {
+        self.publish_state(outbox);
 
         // Re-publish every 60 seconds
         Duration::from_secs(60)
     }
-
-    fn needs_immediate_publish(&self) -> bool {
-        self.needs_publish
-    }
 }
 
 /// Format i32 to bytes
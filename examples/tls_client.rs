@@ -0,0 +1,119 @@
+//! Example: Connecting to a TLS broker with `TlsTransport`
+//!
+//! This example shows how to reach a broker that requires TLS, usually on
+//! port 8883, over an `embassy-net` TCP socket.
+//!
+//! # Key Concepts
+//!
+//! - Open the TCP socket first, then hand it to `TlsTransport::connect`,
+//!   which performs the handshake
+//! - Describe the server in an `embedded-tls` `TlsConfig`: the server name
+//!   sent for SNI, and the CA certificate or pre-shared key
+//! - Provide the handshake randomness from the hardware RNG
+//! - Use the transport like any other: `MqttClient::new(transport, options)`
+//!
+//! # Note
+//!
+//! This example is for illustration purposes and won't run as a standalone
+//! binary without a network stack. Build it with
+//! `cargo build --example tls_client --features tls`.
+
+#![cfg_attr(any(target_arch = "xtensa", target_arch = "riscv32"), no_std, no_main)]
+
+use embassy_net::tcp::{ConnectError, TcpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use embassy_time::Duration;
+use embedded_tls::{Aes128GcmSha256, Certificate, NoVerify, TlsConfig};
+use myrtio_mqtt::error::MqttError;
+use myrtio_mqtt::transport::{StreamError, TlsError, TlsTransport};
+use myrtio_mqtt::{MqttClient, MqttEvent, MqttOptions, QoS};
+use rand_core::{CryptoRng, RngCore};
+
+const BROKER_NAME: &str = "broker.example.com";
+const BROKER_ADDR: Ipv4Address = Ipv4Address::new(192, 0, 2, 10);
+const BROKER_PORT: u16 = 8883;
+
+/// DER-encoded certificate of the CA that signed the broker's certificate.
+const CA_DER: &[u8] = &[];
+
+/// Largest TLS record a broker sends without a negotiated Max Fragment Length.
+const TLS_RECORD_LEN: usize = 16640;
+
+/// Why `run` stopped, for the firmware to log.
+#[derive(Debug)]
+#[allow(dead_code)]
+enum Error {
+    /// The TCP connection couldn't be opened.
+    Connect(ConnectError),
+    /// The TLS handshake failed.
+    Tls(StreamError<TlsError>),
+    /// The MQTT session failed.
+    Mqtt(MqttError<StreamError<TlsError>>),
+}
+
+/// Connects to the broker over TLS and echoes commands back as state.
+#[allow(dead_code)]
+async fn run(stack: Stack<'_>, rng: &mut (impl CryptoRng + RngCore)) -> Result<(), Error> {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket
+        .connect(IpEndpoint::new(BROKER_ADDR.into(), BROKER_PORT))
+        .await
+        .map_err(Error::Connect)?;
+
+    let mut record_read_buf = [0; TLS_RECORD_LEN];
+    let mut record_write_buf = [0; 2048];
+    let config = TlsConfig::new()
+        .with_server_name(BROKER_NAME)
+        .with_ca(Certificate::X509(CA_DER));
+    // `NoVerify` skips checking the broker's certificate chain, which
+    // `embedded-tls` only supports on `std`; use a PSK for mutual trust
+    let transport = TlsTransport::<_, Aes128GcmSha256>::connect::<NoVerify, _>(
+        socket,
+        &mut record_read_buf,
+        &mut record_write_buf,
+        &config,
+        rng,
+        Duration::from_secs(30),
+    )
+    .await
+    .map_err(Error::Tls)?;
+
+    let options = MqttOptions::new("tls-device").with_keep_alive(Duration::from_secs(30));
+    let mut client = MqttClient::<_, 4, 1024>::new(transport, options);
+    client.connect().await.map_err(Error::Mqtt)?;
+    client
+        .subscribe("device/cmd", QoS::AtLeastOnce)
+        .await
+        .map_err(Error::Mqtt)?;
+
+    let mut state = [0u8; 16];
+    loop {
+        let len = match client.next_event().await.map_err(Error::Mqtt)? {
+            MqttEvent::Publish(publish) => {
+                let len = publish.payload.len().min(state.len());
+                state[..len].copy_from_slice(&publish.payload[..len]);
+                len
+            }
+            MqttEvent::Disconnected(_) => return Ok(()),
+        };
+        client
+            .publish("device/state", &state[..len], QoS::AtLeastOnce)
+            .await
+            .map_err(Error::Mqtt)?;
+    }
+}
+
+// Placeholder main - actual implementation would use embassy executor
+#[cfg(not(any(target_arch = "xtensa", target_arch = "riscv32")))]
+fn main() {
+    // This example is for documentation purposes: `run` is spawned on the
+    // firmware's executor with its network stack and hardware RNG.
+}
+
+#[cfg(any(target_arch = "xtensa", target_arch = "riscv32"))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,306 @@
+//! # Blocking Client
+//!
+//! `BlockingMqttClient` wraps `MqttClient` for firmware without an async
+//! executor, e.g. a bare superloop. Each method drives the async one to
+//! completion by polling it with a no-op waker, so the packet handling is the
+//! same as for the async client.
+//!
+//! The transport is a `BlockingTransport`. Its `recv` should block for a short
+//! read timeout at most and return `Ok(0)` when nothing arrived; the client
+//! then checks its keep-alive deadline and reads again. A `recv` that blocks
+//! indefinitely still works, but keep-alive pings are only sent once it returns.
+//!
+//! Timeouts use `embassy-time`, so the target still needs an `embassy-time`
+//! driver. See `examples/blocking_tcp.rs` for a `std::net::TcpStream` adapter.
+
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use crate::client::{MqttClient, MqttEvent, MqttOptions};
+use crate::error::MqttError;
+use crate::packet::{Publish, QoS};
+use crate::transport::{MqttTransport, StreamError, TransportError};
+
+/// A transport whose operations block the caller.
+pub trait BlockingTransport {
+    /// The error type returned by the transport.
+    type Error: core::fmt::Debug;
+
+    /// Sends a buffer of data, blocking until it is written.
+    fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
+
+    /// Receives data into a buffer and returns the number of bytes read.
+    ///
+    /// Returns `Ok(0)` if no data arrived within the transport's read timeout.
+    /// A connection closed by the peer must be reported as an error.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// Adapts a `BlockingTransport` to `MqttTransport`.
+///
+/// A `recv` that returns no data leaves the read pending, so the client can
+/// race it against its keep-alive timer.
+pub struct BlockingAdapter<T> {
+    transport: T,
+}
+
+impl<T: BlockingTransport> BlockingAdapter<T> {
+    /// Wraps a blocking transport.
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    /// Returns the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: BlockingTransport> MqttTransport for BlockingAdapter<T> {
+    type Error = StreamError<T::Error>;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.transport.send(buf).map_err(StreamError::Io)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::poll_fn(|cx| match self.transport.recv(buf) {
+            Ok(0) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(e) => Poll::Ready(Err(StreamError::Io(e))),
+        })
+        .await
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.transport.recv(buf).map_err(StreamError::Io)
+    }
+}
+
+/// Result type of `BlockingMqttClient` methods.
+///
+/// Errors of the blocking transport are `MqttError::Transport(StreamError::Io)`,
+/// the other variants come from the client.
+pub type BlockingResult<R, T> = Result<R, MqttError<StreamError<<T as BlockingTransport>::Error>>>;
+
+/// A synchronous MQTT client driving `MqttClient` over a `BlockingTransport`.
+///
+/// ```ignore
+/// let mut client = BlockingMqttClient::<_, 4, 256>::new(transport, options);
+/// client.connect()?;
+/// client.subscribe("device/cmd", QoS::AtLeastOnce)?;
+/// loop {
+///     if let Some(MqttEvent::Publish(msg)) = client.poll()? {
+///         handle(msg.topic, msg.payload);
+///     }
+///     // Other superloop work
+/// }
+/// ```
+pub struct BlockingMqttClient<
+    'a,
+    T,
+    const MAX_TOPICS: usize,
+    const TX_BUF: usize,
+    const RX_BUF: usize = TX_BUF,
+> where
+    T: BlockingTransport,
+{
+    client: MqttClient<'a, BlockingAdapter<T>, MAX_TOPICS, TX_BUF, RX_BUF>,
+}
+
+impl<'a, T, const MAX_TOPICS: usize, const TX_BUF: usize, const RX_BUF: usize>
+    BlockingMqttClient<'a, T, MAX_TOPICS, TX_BUF, RX_BUF>
+where
+    T: BlockingTransport,
+    StreamError<T::Error>: TransportError,
+{
+    /// Creates a client over `transport`, which must not be connected to
+    /// the broker at the MQTT level yet. Call `connect` next.
+    pub fn new(transport: T, options: MqttOptions<'a>) -> Self {
+        Self {
+            client: MqttClient::new(BlockingAdapter::new(transport), options),
+        }
+    }
+
+    /// Returns the wrapped async client, e.g. for its getters.
+    pub fn client(&self) -> &MqttClient<'a, BlockingAdapter<T>, MAX_TOPICS, TX_BUF, RX_BUF> {
+        &self.client
+    }
+
+    /// Returns the wrapped async client mutably. Its async methods can be run
+    /// with `block_on`.
+    pub fn client_mut(
+        &mut self,
+    ) -> &mut MqttClient<'a, BlockingAdapter<T>, MAX_TOPICS, TX_BUF, RX_BUF> {
+        &mut self.client
+    }
+
+    /// Returns a reference to the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.client.transport().transport
+    }
+
+    /// Returns a mutable reference to the underlying transport.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.client.transport_mut().transport
+    }
+
+    /// Connects to the broker. See `MqttClient::connect`.
+    pub fn connect(&mut self) -> BlockingResult<(), T> {
+        block_on(self.client.connect())
+    }
+
+    /// Publishes a message. See `MqttClient::publish`.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], qos: QoS) -> BlockingResult<(), T> {
+        block_on(self.client.publish(topic, payload, qos))
+    }
+
+    /// Publishes a message with the retain flag. See
+    /// `MqttClient::publish_with_retain`.
+    pub fn publish_with_retain(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> BlockingResult<(), T> {
+        block_on(self.client.publish_with_retain(topic, payload, qos, retain))
+    }
+
+    /// Subscribes to a topic. See `MqttClient::subscribe`.
+    pub fn subscribe(&mut self, topic: &str, qos: QoS) -> BlockingResult<(), T> {
+        block_on(self.client.subscribe(topic, qos))
+    }
+
+    /// Subscribes to several topics in one SUBSCRIBE. See
+    /// `MqttClient::subscribe_many`.
+    pub fn subscribe_many(&mut self, topics: &[(&str, QoS)]) -> BlockingResult<(), T> {
+        block_on(self.client.subscribe_many(topics))
+    }
+
+    /// Reads and handles at most one packet. See `MqttClient::poll`.
+    ///
+    /// Unlike the async version, this doesn't wait for a packet: it returns
+    /// `Ok(None)` when the transport reported no data, so the superloop can do
+    /// other work between polls.
+    pub fn poll(&mut self) -> BlockingResult<Option<MqttEvent<'_>>, T> {
+        // The adapter's send and recv never suspend, so the only pending point
+        // is the wait for data, where dropping the future loses nothing
+        let future = pin!(self.client.poll());
+        match future.poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Ok(None),
+        }
+    }
+
+    /// Handles every packet the transport already has and returns the number
+    /// handled. See `MqttClient::run_until_idle`.
+    pub fn run_until_idle(
+        &mut self,
+        on_publish: impl FnMut(&Publish<'_>),
+    ) -> BlockingResult<usize, T> {
+        block_on(self.client.run_until_idle(on_publish))
+    }
+
+    /// Waits for the next message. See `MqttClient::next_event`.
+    pub fn next_event(&mut self) -> BlockingResult<MqttEvent<'_>, T> {
+        block_on(self.client.next_event())
+    }
+
+    /// Sends a PINGREQ and waits for the PINGRESP. See `MqttClient::ping`.
+    pub fn ping(&mut self) -> BlockingResult<(), T> {
+        block_on(self.client.ping())
+    }
+
+    /// Disconnects from the broker. See `MqttClient::disconnect`.
+    pub fn disconnect(&mut self) -> BlockingResult<(), T> {
+        block_on(self.client.disconnect())
+    }
+}
+
+/// Runs a future to completion on the current thread.
+///
+/// The future is polled in a loop with a no-op waker, so this spins while it
+/// is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MqttVersion;
+    use crate::test_util::{MockError, MockTransport};
+
+    /// A `MockTransport` behind the blocking interface, whose next `idle`
+    /// reads report no data.
+    struct Blocking {
+        inner: MockTransport<256>,
+        idle: usize,
+    }
+
+    impl BlockingTransport for Blocking {
+        type Error = MockError;
+
+        fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            block_on(self.inner.send(buf))
+        }
+
+        fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.idle > 0 {
+                self.idle -= 1;
+                return Ok(0);
+            }
+            block_on(self.inner.recv(buf))
+        }
+    }
+
+    #[test]
+    fn blocking_client_round_trip_with_flat_errors() {
+        let version = if cfg!(feature = "v5") {
+            MqttVersion::V5
+        } else {
+            MqttVersion::V3
+        };
+        let mut inner = MockTransport::new(version);
+        assert!(inner.push_connack(false, 0));
+        assert!(inner.push_suback(1, &[1]));
+        assert!(inner.push_puback(2));
+        let options = MqttOptions::new("cid");
+        #[cfg(feature = "v5")]
+        let options = options.with_version(MqttVersion::V5);
+        let transport = Blocking { inner, idle: 0 };
+        let mut client = BlockingMqttClient::<_, 4, 256>::new(transport, options);
+
+        client.connect().unwrap();
+        client.subscribe("t", QoS::AtLeastOnce).unwrap();
+        client.publish("x", b"1", QoS::AtLeastOnce).unwrap();
+        let transport = client.transport_mut();
+        assert!(
+            transport
+                .inner
+                .push_publish("t", b"hi", QoS::AtMostOnce, None)
+        );
+        transport.idle = 1;
+        assert!(client.poll().unwrap().is_none());
+        match client.poll().unwrap() {
+            Some(MqttEvent::Publish(publish)) => assert_eq!(publish.payload, b"hi"),
+            other => panic!("{other:?}"),
+        }
+        // The blocking transport's error is passed on as is
+        assert!(matches!(
+            client.poll(),
+            Err(MqttError::Transport(StreamError::Io(MockError::Exhausted)))
+        ));
+    }
+}
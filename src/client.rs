@@ -3,13 +3,32 @@
 //! This module contains the primary `MqttClient` struct, which manages the state,
 //! connection, and communication with an MQTT broker.
 
-use crate::error::{MqttError, ProtocolError};
-use crate::packet::{self, Connect, EncodePacket, MqttPacket, PingReq, Publish, QoS, Subscribe};
+#[cfg(feature = "v5")]
+use crate::error::{ConnectReasonCode, V5ConnectReasonCode};
+use crate::error::{MqttError, OptionsError, ProtocolError};
+use crate::observer::{MqttObserver, NoopObserver};
+use crate::packet::{
+    self, Connect, Disconnect, EncodePacket, MqttPacket, PingReq, PubAck, PubComp, PubRec, Publish,
+    QoS, Subscribe, SubscribeOptions, Unsubscribe,
+};
+#[cfg(feature = "v5")]
+use crate::packet::{PropertiesExt, ServerCapabilities};
+use crate::reader::PacketReader;
+#[cfg(feature = "stats")]
+use crate::stats::MqttStats;
+use crate::stats::StatsRecorder;
 use crate::transport::{self, MqttTransport};
+use crate::util;
 use embassy_time::{Duration, Instant, Timer};
 use heapless::{String, Vec};
 
 /// Represents the MQTT protocol version used by the client.
+///
+/// The `v5` feature compiles in the v5 packet fields and APIs; the version
+/// itself is chosen at runtime. A v5 build connecting with `V3` uses plain
+/// v3.1.1 framing, so one binary can talk to either kind of broker, e.g. by
+/// falling back with `MqttOptions::with_version_fallback` or
+/// `MqttClient::set_version` when a broker rejects v5.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MqttVersion {
@@ -36,34 +55,188 @@ pub struct MqttOptions<'a> {
     client_id: &'a str,
     version: MqttVersion,
     keep_alive: Duration,
-    username: Option<String<32>>,
-    password: Option<String<64>>,
+    connect_timeout: Duration,
+    ack_timeout: Duration,
+    clean_session: bool,
+    username: Option<String<MAX_USERNAME_LEN>>,
+    password: Option<String<MAX_PASSWORD_LEN>>,
     will: Option<LastWill<'a>>,
+    max_inflight: u16,
+    #[cfg(feature = "v5")]
+    will_delay: Option<u32>,
+    #[cfg(feature = "v5")]
+    topic_aliases: bool,
+    #[cfg(feature = "v5")]
+    inbound_topic_aliases: bool,
+    #[cfg(feature = "v5")]
+    version_fallback: bool,
 }
 
+/// Default time `MqttClient::connect` waits for the CONNACK, in seconds.
+const DEFAULT_CONNECT_TIMEOUT_SECS: u16 = 10;
+
+/// Default time a QoS 1 publish waits for its PUBACK, in seconds.
+const DEFAULT_ACK_TIMEOUT_SECS: u16 = 10;
+
+/// Maximum username length stored by `MqttOptions`.
+pub const MAX_USERNAME_LEN: usize = 32;
+/// Maximum password length stored by `MqttOptions`.
+pub const MAX_PASSWORD_LEN: usize = 64;
+
 impl<'a> MqttOptions<'a> {
+    /// Creates options with the given client id and a clean session.
+    ///
+    /// An empty id lets the broker assign one; an MQTT v5 broker reports it in
+    /// the CONNACK, see `MqttClient::client_id`.
     pub fn new(client_id: &'a str) -> Self {
         Self {
             client_id,
             version: MqttVersion::V3,
             keep_alive: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(u64::from(DEFAULT_CONNECT_TIMEOUT_SECS)),
+            ack_timeout: Duration::from_secs(u64::from(DEFAULT_ACK_TIMEOUT_SECS)),
+            clean_session: true,
             username: None,
             password: None,
             will: None,
+            max_inflight: MAX_INFLIGHT as u16,
+            #[cfg(feature = "v5")]
+            will_delay: None,
+            #[cfg(feature = "v5")]
+            topic_aliases: false,
+            #[cfg(feature = "v5")]
+            inbound_topic_aliases: false,
+            #[cfg(feature = "v5")]
+            version_fallback: false,
+        }
+    }
+
+    /// Builds the CONNECT for these options, with `runtime_will` replacing
+    /// the configured last will.
+    ///
+    /// `will_delay` is scratch space for the v5 Will Delay Interval.
+    #[cfg_attr(not(feature = "v5"), allow(unused_variables))]
+    fn connect_packet<'p>(
+        &'p self,
+        runtime_will: Option<&'p OwnedLastWill>,
+        will_delay: &'p mut [u8; 4],
+    ) -> Result<Connect<'p>, MqttError<transport::ErrorPlaceHolder>> {
+        let will = if let Some(will) = runtime_will {
+            Some(LastWill {
+                topic: will.topic.as_str(),
+                payload: will.payload.as_slice(),
+                qos: will.qos,
+                retain: will.retain,
+            })
+        } else {
+            self.will
+        };
+        #[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+        let mut connect_packet = Connect::with_credentials(
+            self.client_id,
+            self.keep_alive.as_secs() as u16,
+            self.clean_session,
+            self.username.as_deref(),
+            self.password.as_ref().map(|s| s.as_bytes()),
+            will,
+        );
+        #[cfg(feature = "v5")]
+        if self.inbound_topic_aliases {
+            connect_packet
+                .properties
+                .push(packet::Property {
+                    id: packet::Property::TOPIC_ALIAS_MAXIMUM,
+                    data: &INBOUND_TOPIC_ALIAS_MAXIMUM,
+                })
+                .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))?;
+        }
+        #[cfg(feature = "v5")]
+        if let Some(seconds) = self.will_delay.filter(|_| will.is_some()) {
+            connect_packet.set_will_delay(will_delay, seconds)?;
+        }
+        Ok(connect_packet)
+    }
+
+    /// Returns the size of the encoded CONNECT for these options.
+    fn connect_len(&self, runtime_will: Option<&OwnedLastWill>) -> usize {
+        let mut will_delay = [0u8; 4];
+        // An invalid will delay fails `connect` before anything is encoded
+        self.connect_packet(runtime_will, &mut will_delay)
+            .map_or(0, |connect| connect.encoded_len(self.version))
+    }
+
+    /// Returns a builder that validates the options as they are assembled.
+    ///
+    /// Defaults match `MqttOptions::new`: an empty client id, MQTT v3.1.1,
+    /// a 60 second keep-alive, 10 second connect and acknowledgment timeouts
+    /// and a clean session.
+    pub fn builder() -> MqttOptionsBuilder<'a> {
+        MqttOptionsBuilder {
+            client_id: "",
+            version: MqttVersion::V3,
+            keep_alive: 60,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT_SECS,
+            ack_timeout: DEFAULT_ACK_TIMEOUT_SECS,
+            clean_session: true,
+            credentials: None,
+            will: None,
+            max_inflight: MAX_INFLIGHT as u16,
+            #[cfg(feature = "v5")]
+            will_delay: None,
+            #[cfg(feature = "v5")]
+            topic_aliases: false,
+            #[cfg(feature = "v5")]
+            inbound_topic_aliases: false,
+            #[cfg(feature = "v5")]
+            version_fallback: false,
         }
     }
+    /// Selects the protocol version (default MQTT v3.1.1).
     #[cfg(feature = "v5")]
     pub fn with_version(mut self, version: MqttVersion) -> Self {
         self.version = version;
         self
     }
+    /// Lets `MqttClient::connect` retry with MQTT v3.1.1 when a broker
+    /// refuses the v5 CONNECT for its protocol version (default off).
+    ///
+    /// See `MqttOptionsBuilder::version_fallback`.
+    #[cfg(feature = "v5")]
+    pub fn with_version_fallback(mut self, enabled: bool) -> Self {
+        self.version_fallback = enabled;
+        self
+    }
+    /// Sets the keep-alive interval (default 60 seconds).
+    ///
+    /// The client sends a PINGREQ when nothing else was sent for this long, and
+    /// treats the connection as lost if the PINGRESP doesn't follow within
+    /// another interval.
     pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
         self.keep_alive = keep_alive;
         self
     }
+    /// Sets how long `MqttClient::connect` waits for the CONNACK (default 10
+    /// seconds) before failing with `MqttError::Timeout`.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+    /// Sets how long a QoS 1 publish waits for its PUBACK (default 10
+    /// seconds) before failing with `MqttError::Timeout`.
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+    /// Sets how many QoS 1 publishes may await their PUBACK at once (default
+    /// and maximum `MAX_INFLIGHT`). See `MqttClient::max_inflight`.
+    pub fn with_max_inflight(mut self, count: u16) -> Self {
+        self.max_inflight = count;
+        self
+    }
     /// Sets the username and password for MQTT broker authentication.
     ///
-    /// Username is limited to 32 bytes, password to 64 bytes.
+    /// Username is limited to `MAX_USERNAME_LEN` bytes, password to
+    /// `MAX_PASSWORD_LEN` bytes; longer values are ignored.
     pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
         self.username = String::try_from(username).ok();
         self.password = String::try_from(password).ok();
@@ -75,11 +248,352 @@ impl<'a> MqttOptions<'a> {
         self.will = Some(will);
         self
     }
+
+    /// Sets the MQTT v5 Will Delay Interval: after an unexpected disconnect
+    /// the broker waits this many seconds before publishing the Last Will,
+    /// and drops it if the client reconnects in time.
+    ///
+    /// Ignored without a Last Will or when connecting with v3.1.1.
+    #[cfg(feature = "v5")]
+    pub fn with_will_delay(mut self, seconds: u32) -> Self {
+        self.will_delay = Some(seconds);
+        self
+    }
+
+    /// Enables MQTT v5 topic aliases for outgoing publishes (default off).
+    ///
+    /// See `MqttOptionsBuilder::topic_aliases`.
+    #[cfg(feature = "v5")]
+    pub fn with_topic_aliases(mut self, enabled: bool) -> Self {
+        self.topic_aliases = enabled;
+        self
+    }
+
+    /// Lets the broker use MQTT v5 topic aliases for incoming publishes
+    /// (default off).
+    ///
+    /// See `MqttOptionsBuilder::inbound_topic_aliases`.
+    #[cfg(feature = "v5")]
+    pub fn with_inbound_topic_aliases(mut self, enabled: bool) -> Self {
+        self.inbound_topic_aliases = enabled;
+        self
+    }
+}
+
+/// Builder for `MqttOptions`, created with `MqttOptions::builder`.
+///
+/// Unlike the `with_*` methods, which silently drop credentials that don't
+/// fit, `build` reports invalid settings as an `OptionsError`.
+///
+/// ```ignore
+/// let options = MqttOptions::builder()
+///     .client_id("living-room-light")
+///     .keep_alive(30)
+///     .credentials("user", "secret")
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MqttOptionsBuilder<'a> {
+    client_id: &'a str,
+    version: MqttVersion,
+    keep_alive: u16,
+    connect_timeout: u16,
+    ack_timeout: u16,
+    clean_session: bool,
+    credentials: Option<(&'a str, &'a str)>,
+    will: Option<LastWill<'a>>,
+    max_inflight: u16,
+    #[cfg(feature = "v5")]
+    will_delay: Option<u32>,
+    #[cfg(feature = "v5")]
+    topic_aliases: bool,
+    #[cfg(feature = "v5")]
+    inbound_topic_aliases: bool,
+    #[cfg(feature = "v5")]
+    version_fallback: bool,
+}
+
+impl<'a> MqttOptionsBuilder<'a> {
+    /// Sets the client identifier.
+    pub fn client_id(mut self, client_id: &'a str) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    /// Selects the protocol version (default MQTT v3.1.1).
+    #[cfg(feature = "v5")]
+    pub fn version(mut self, version: MqttVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Lets `MqttClient::connect` retry with MQTT v3.1.1 when a broker
+    /// refuses the v5 CONNECT for its protocol version (default off).
+    ///
+    /// The client then stays on v3.1.1 for later connections; check
+    /// `MqttClient::version` to see which one is in use.
+    #[cfg(feature = "v5")]
+    pub fn version_fallback(mut self, enabled: bool) -> Self {
+        self.version_fallback = enabled;
+        self
+    }
+
+    /// Sets the keep-alive interval in seconds (default 60).
+    pub fn keep_alive(mut self, secs: u16) -> Self {
+        self.keep_alive = secs;
+        self
+    }
+
+    /// Sets how long `connect` waits for the CONNACK in seconds (default 10).
+    pub fn connect_timeout(mut self, secs: u16) -> Self {
+        self.connect_timeout = secs;
+        self
+    }
+
+    /// Sets how long a QoS 1 publish waits for its PUBACK in seconds
+    /// (default 10).
+    pub fn ack_timeout(mut self, secs: u16) -> Self {
+        self.ack_timeout = secs;
+        self
+    }
+
+    /// Sets how many QoS 1 publishes may await their PUBACK at once (default
+    /// and maximum `MAX_INFLIGHT`).
+    pub fn max_inflight(mut self, count: u16) -> Self {
+        self.max_inflight = count;
+        self
+    }
+
+    /// Sets whether the broker should discard any previous session (default `true`).
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// Sets the username and password for broker authentication.
+    pub fn credentials(mut self, username: &'a str, password: &'a str) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    /// Sets the MQTT Last Will and Testament message.
+    pub fn will(mut self, will: LastWill<'a>) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Sets the MQTT v5 Will Delay Interval in seconds, so a brief network
+    /// blip doesn't publish the Last Will. Ignored without a will.
+    #[cfg(feature = "v5")]
+    pub fn will_delay(mut self, seconds: u32) -> Self {
+        self.will_delay = Some(seconds);
+        self
+    }
+
+    /// Enables MQTT v5 topic aliases for outgoing publishes (default off).
+    ///
+    /// The first publish to a topic carries the full topic and assigns it an
+    /// alias; later publishes to it send only the alias. Up to
+    /// `MAX_TOPIC_ALIASES` topics get an alias, limited further by the Topic
+    /// Alias Maximum from the broker's CONNACK. Other topics are sent in full.
+    #[cfg(feature = "v5")]
+    pub fn topic_aliases(mut self, enabled: bool) -> Self {
+        self.topic_aliases = enabled;
+        self
+    }
+
+    /// Lets the broker use MQTT v5 topic aliases for incoming publishes
+    /// (default off).
+    ///
+    /// The CONNECT then announces a Topic Alias Maximum of
+    /// `MAX_INBOUND_TOPIC_ALIASES`, and the client maps the aliases back, so
+    /// received messages always carry their full topic. Topics longer than
+    /// 128 bytes can't be mapped; a later message using their alias fails
+    /// with `ProtocolError::InvalidTopicAlias`.
+    #[cfg(feature = "v5")]
+    pub fn inbound_topic_aliases(mut self, enabled: bool) -> Self {
+        self.inbound_topic_aliases = enabled;
+        self
+    }
+
+    /// Validates the settings and builds the options.
+    ///
+    /// Use `build_for` to also check them against the client's TX buffer.
+    pub fn build(self) -> Result<MqttOptions<'a>, OptionsError> {
+        if self.client_id.len() > usize::from(u16::MAX) {
+            return Err(OptionsError::ClientIdTooLong);
+        }
+        if self.client_id.is_empty() && !self.clean_session {
+            return Err(OptionsError::EmptyClientId);
+        }
+
+        let (username, password) = match self.credentials {
+            Some((username, password)) => (
+                Some(String::try_from(username).map_err(|_| OptionsError::UsernameTooLong)?),
+                Some(String::try_from(password).map_err(|_| OptionsError::PasswordTooLong)?),
+            ),
+            None => (None, None),
+        };
+
+        Ok(MqttOptions {
+            client_id: self.client_id,
+            version: self.version,
+            keep_alive: Duration::from_secs(u64::from(self.keep_alive)),
+            connect_timeout: Duration::from_secs(u64::from(self.connect_timeout)),
+            ack_timeout: Duration::from_secs(u64::from(self.ack_timeout)),
+            clean_session: self.clean_session,
+            username,
+            password,
+            will: self.will,
+            max_inflight: self.max_inflight,
+            #[cfg(feature = "v5")]
+            will_delay: self.will_delay,
+            #[cfg(feature = "v5")]
+            topic_aliases: self.topic_aliases,
+            #[cfg(feature = "v5")]
+            inbound_topic_aliases: self.inbound_topic_aliases,
+            #[cfg(feature = "v5")]
+            version_fallback: self.version_fallback,
+        })
+    }
+
+    /// Validates the settings like `build`, and that the CONNECT they make
+    /// fits a client with a `TX_BUF`-byte TX buffer.
+    ///
+    /// A client id that leaves no room for the CONNECT fails with
+    /// `ClientIdTooLong` here, instead of `connect` failing with
+    /// `BufferTooSmall` later:
+    ///
+    /// ```
+    /// use myrtio_mqtt::MqttOptions;
+    /// use myrtio_mqtt::error::OptionsError;
+    ///
+    /// let options = MqttOptions::builder().client_id("a-rather-long-client-id");
+    /// assert!(options.build_for::<64>().is_ok());
+    /// assert_eq!(options.build_for::<32>().err(), Some(OptionsError::ClientIdTooLong));
+    /// ```
+    pub fn build_for<const TX_BUF: usize>(self) -> Result<MqttOptions<'a>, OptionsError> {
+        let options = self.build()?;
+        if options.connect_len(None) > TX_BUF {
+            return Err(OptionsError::ClientIdTooLong);
+        }
+        Ok(options)
+    }
 }
 
+/// Maximum number of messages `MqttClient::publish_many` sends before it
+/// waits for their PUBACKs.
+pub const MAX_PUBLISH_BURST: usize = 8;
+
 /// Maximum number of receive attempts when waiting for PUBACK/SUBACK.
 /// Skips interleaved packets (PingResp, Publish). Prevents infinite loop on broken connection.
 const MAX_RECV_ATTEMPTS: usize = 16;
+/// Maximum length of a client identifier assigned by an MQTT v5 broker.
+///
+/// Longer identifiers are not stored, and `MqttClient::client_id` keeps
+/// returning the configured one.
+#[cfg(feature = "v5")]
+pub const MAX_ASSIGNED_CLIENT_ID_LEN: usize = 64;
+
+/// Maximum number of topics the client assigns an MQTT v5 topic alias to.
+#[cfg(feature = "v5")]
+pub const MAX_TOPIC_ALIASES: usize = 8;
+/// Maximum length of a topic that can be given a topic alias.
+#[cfg(feature = "v5")]
+const MAX_ALIASED_TOPIC_LEN: usize = 128;
+
+/// The topic aliases assigned on the current connection.
+///
+/// Alias `n` stands for `topics[n - 1]`. Aliases are only valid for one
+/// connection, so the table is reset on every CONNACK.
+#[cfg(feature = "v5")]
+#[derive(Default)]
+struct TopicAliases {
+    topics: Vec<String<MAX_ALIASED_TOPIC_LEN>, MAX_TOPIC_ALIASES>,
+    /// Topic Alias Maximum announced by the broker.
+    maximum: u16,
+}
+
+#[cfg(feature = "v5")]
+impl TopicAliases {
+    fn reset(&mut self, maximum: u16) {
+        self.topics.clear();
+        self.maximum = maximum;
+    }
+
+    /// Returns the alias for `topic`, and whether it was assigned before.
+    ///
+    /// Assigns the next free alias to a new topic. Returns `None` once the
+    /// aliases are exhausted or if the topic is too long to store.
+    fn resolve(&mut self, topic: &str) -> Option<(u16, bool)> {
+        if let Some(index) = self.topics.iter().position(|known| known == topic) {
+            return Some((index as u16 + 1, true));
+        }
+        if self.topics.len() >= usize::from(self.maximum) {
+            return None;
+        }
+        self.topics.push(String::try_from(topic).ok()?).ok()?;
+        Some((self.topics.len() as u16, false))
+    }
+}
+
+/// Topic Alias Maximum the client announces with `inbound_topic_aliases`.
+#[cfg(feature = "v5")]
+pub const MAX_INBOUND_TOPIC_ALIASES: usize = 8;
+
+/// `MAX_INBOUND_TOPIC_ALIASES` encoded as the Topic Alias Maximum property.
+#[cfg(feature = "v5")]
+const INBOUND_TOPIC_ALIAS_MAXIMUM: [u8; 2] = (MAX_INBOUND_TOPIC_ALIASES as u16).to_be_bytes();
+
+/// The topic aliases the broker assigned on the current connection.
+///
+/// Alias `n` stands for `topics[n - 1]`. Like `TopicAliases`, the table is
+/// reset on every CONNACK.
+#[cfg(feature = "v5")]
+#[derive(Default)]
+struct InboundTopicAliases {
+    topics: [Option<String<MAX_ALIASED_TOPIC_LEN>>; MAX_INBOUND_TOPIC_ALIASES],
+    enabled: bool,
+}
+
+#[cfg(feature = "v5")]
+impl InboundTopicAliases {
+    fn reset(&mut self, enabled: bool) {
+        self.topics = Default::default();
+        self.enabled = enabled;
+    }
+
+    /// Records the alias a received publish assigns to its topic.
+    ///
+    /// Fails with `InvalidTopicAlias` for an alias outside the announced
+    /// maximum, or one that stands in for an empty topic but was never
+    /// assigned.
+    fn learn(&mut self, publish: &Publish<'_>) -> Result<(), ProtocolError> {
+        let Some(alias) = publish.topic_alias().filter(|_| self.enabled) else {
+            return Ok(());
+        };
+        let slot = usize::from(alias)
+            .checked_sub(1)
+            .and_then(|index| self.topics.get_mut(index))
+            .ok_or(ProtocolError::InvalidTopicAlias(alias))?;
+        if publish.topic.is_empty() {
+            return match slot {
+                Some(_) => Ok(()),
+                None => Err(ProtocolError::InvalidTopicAlias(alias)),
+            };
+        }
+        *slot = String::try_from(publish.topic).ok();
+        Ok(())
+    }
+
+    /// Returns the topic a publish with an empty topic refers to.
+    fn resolve(&self, publish: &Publish<'_>) -> Option<&str> {
+        let alias = publish.topic_alias().filter(|_| self.enabled)?;
+        let index = usize::from(alias).checked_sub(1)?;
+        self.topics.get(index)?.as_deref()
+    }
+}
+
 /// Maximum topic length for runtime-provided Last Will messages.
 const MAX_WILL_TOPIC_LEN: usize = 128;
 /// Maximum payload length for runtime-provided Last Will messages.
@@ -93,60 +607,491 @@ struct OwnedLastWill {
     retain: bool,
 }
 
-/// Represents the current connection state of the client.
+/// Maximum number of unacknowledged QoS 1 publishes kept for retransmission,
+/// which also caps the window set with `MqttOptions::with_max_inflight`.
+pub const MAX_INFLIGHT: usize = 4;
+/// Maximum topic length of a QoS 1 publish, which is kept for retransmission.
+pub const MAX_INFLIGHT_TOPIC_LEN: usize = 128;
+/// Maximum payload length of a QoS 1 publish, which is kept for retransmission.
+pub const MAX_INFLIGHT_PAYLOAD_LEN: usize = 256;
+
+/// Maximum number of received QoS 2 publishes whose PUBREL is outstanding.
+///
+/// Redeliveries of these are acknowledged but not passed on again. Beyond
+/// this, messages are still delivered, but a redelivery may be seen twice.
+const MAX_AWAITING_RELEASE: usize = 8;
+
+/// PUBACK or PUBREC for a received publish, recorded until it is sent.
+#[derive(Clone, Copy)]
+struct PendingAck {
+    qos: QoS,
+    packet_id: u16,
+    /// Whether the publish is delivered once acknowledged; `false` for a
+    /// QoS 2 redelivery.
+    deliver: bool,
+}
+
+/// Owned copy of a QoS 1 publish that is waiting for its PUBACK.
+struct InflightPublish {
+    packet_id: u16,
+    topic: String<MAX_INFLIGHT_TOPIC_LEN>,
+    payload: Vec<u8, MAX_INFLIGHT_PAYLOAD_LEN>,
+    qos: QoS,
+    retain: bool,
+}
+
+/// Fixed-size store of unacknowledged publishes, keyed by packet id.
+#[derive(Default)]
+struct InflightStore {
+    entries: Vec<InflightPublish, MAX_INFLIGHT>,
+}
+
+impl InflightStore {
+    /// Stores a copy of the publish.
+    ///
+    /// Returns `false` if the store is full or the message exceeds the inline storage.
+    fn insert(
+        &mut self,
+        packet_id: u16,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> bool {
+        let mut owned_topic = String::new();
+        if owned_topic.push_str(topic).is_err() {
+            return false;
+        }
+        let mut owned_payload = Vec::new();
+        if owned_payload.extend_from_slice(payload).is_err() {
+            return false;
+        }
+        self.entries
+            .push(InflightPublish {
+                packet_id,
+                topic: owned_topic,
+                payload: owned_payload,
+                qos,
+                retain,
+            })
+            .is_ok()
+    }
+
+    /// Returns whether a publish with the given packet id is awaiting its PUBACK.
+    fn contains(&self, packet_id: u16) -> bool {
+        self.entries.iter().any(|e| e.packet_id == packet_id)
+    }
+
+    /// Removes the entry with the given packet id, returning whether it was present.
+    fn remove(&mut self, packet_id: u16) -> bool {
+        match self.entries.iter().position(|e| e.packet_id == packet_id) {
+            Some(index) => {
+                self.entries.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Maximum length of a topic filter tracked as an active subscription.
+pub const MAX_FILTER_LEN: usize = 128;
+
+/// The active subscriptions, kept in sync with SUBACK and UNSUBACK.
+#[derive(Default)]
+struct SubscriptionList<const N: usize> {
+    filters: Vec<(String<MAX_FILTER_LEN>, QoS), N>,
+}
+
+impl<const N: usize> SubscriptionList<N> {
+    fn contains(&self, filter: &str) -> bool {
+        self.filters.iter().any(|(f, _)| f == filter)
+    }
+
+    /// Returns whether every filter in `topics` can be tracked once the
+    /// broker accepts them.
+    fn has_room_for(&self, topics: &[(&str, SubscribeOptions)]) -> bool {
+        let new = topics
+            .iter()
+            .filter(|(filter, _)| !self.contains(filter))
+            .count();
+        self.filters.len() + new <= N
+            && topics
+                .iter()
+                .all(|(filter, _)| filter.len() <= MAX_FILTER_LEN)
+    }
+
+    /// Records `filter` as subscribed, replacing the QoS of an existing entry.
+    fn insert(&mut self, filter: &str, qos: QoS) {
+        if let Some(entry) = self.filters.iter_mut().find(|(f, _)| f == filter) {
+            entry.1 = qos;
+        } else if let Ok(filter) = String::try_from(filter) {
+            // `has_room_for` was checked before subscribing
+            let _ = self.filters.push((filter, qos));
+        }
+    }
+
+    fn remove(&mut self, filter: &str) {
+        self.filters.retain(|(f, _)| f != filter);
+    }
+}
+
+/// Hands out MQTT packet identifiers in the range `1..=65535`.
+///
+/// Identifiers increase monotonically and wrap from 65535 back to 1; 0 is never
+/// returned, as the spec reserves it. Identifiers reported as in use (e.g. by
+/// unacknowledged publishes) are skipped.
+pub(crate) struct PacketIdAllocator {
+    last: u16,
+}
+
+impl PacketIdAllocator {
+    /// Creates an allocator whose first identifier is 1.
+    pub(crate) const fn new() -> Self {
+        Self { last: 0 }
+    }
+
+    /// Returns the next identifier for which `in_use` returns `false`.
+    ///
+    /// If every identifier is in use, the next one in sequence is returned.
+    pub(crate) fn allocate(&mut self, mut in_use: impl FnMut(u16) -> bool) -> u16 {
+        for _ in 0..u16::MAX {
+            self.last = self.last.checked_add(1).unwrap_or(1);
+            if !in_use(self.last) {
+                return self.last;
+            }
+        }
+        self.last = self.last.checked_add(1).unwrap_or(1);
+        self.last
+    }
+}
+
+/// The connection lifecycle of an `MqttClient`, returned by
+/// `MqttClient::state`.
+///
+/// Publishing, subscribing and polling require `Connected` and fail with
+/// `MqttError::NotConnected` in every other state. `connect` may be called
+/// in any state to (re-)establish the connection.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-enum ConnectionState {
+pub enum ConnectionState {
+    /// No connection; the initial state, and the one after a lost
+    /// connection or a completed `disconnect`.
     Disconnected,
+    /// A CONNECT was sent and the CONNACK is pending.
     Connecting,
+    /// The broker accepted the connection.
     Connected,
+    /// A DISCONNECT is being sent.
+    Disconnecting,
 }
 
 /// The asynchronous MQTT client.
-pub struct MqttClient<'a, T, const MAX_TOPICS: usize, const BUF_SIZE: usize>
-where
+///
+/// `TX_BUF` bounds the largest packet the client can send and `RX_BUF` the
+/// largest it can receive. `RX_BUF` defaults to `TX_BUF`, so
+/// `MqttClient<'a, T, MAX_TOPICS, SIZE>` keeps a single size for both; pass it
+/// explicitly to size them independently, e.g. for a device that receives
+/// large commands but only publishes small state updates:
+///
+/// ```
+/// use myrtio_mqtt::transport::MqttTransport;
+/// use myrtio_mqtt::{MqttClient, MqttOptions};
+///
+/// // 128 bytes for outgoing packets, 2 KiB for incoming ones.
+/// fn client<T: MqttTransport>(transport: T) -> MqttClient<'static, T, 4, 128, 2048> {
+///     MqttClient::new(transport, MqttOptions::new("thermostat"))
+/// }
+/// ```
+pub struct MqttClient<
+    'a,
+    T,
+    const MAX_TOPICS: usize,
+    const TX_BUF: usize,
+    const RX_BUF: usize = TX_BUF,
+> where
     T: MqttTransport,
 {
     transport: T,
     options: MqttOptions<'a>,
-    tx_buffer: [u8; BUF_SIZE],
-    rx_buffer: [u8; BUF_SIZE],
+    tx_buffer: [u8; TX_BUF],
+    rx: PacketReader<RX_BUF>,
     state: ConnectionState,
     last_tx_time: Instant,
-    next_packet_id: u16,
+    /// Keep-alive interval of the current connection, which a v5 broker may
+    /// override in its CONNACK.
+    keep_alive: Duration,
+    /// When the outstanding PINGREQ was sent, if its PINGRESP hasn't arrived yet.
+    ping_sent_at: Option<Instant>,
+    /// When the broker accepted the last CONNECT.
+    connected_at: Option<Instant>,
+    /// Whether the broker resumed a stored session in the last CONNACK.
+    session_present: bool,
+    packet_ids: PacketIdAllocator,
     runtime_will: Option<OwnedLastWill>,
+    inflight: InflightStore,
+    /// Packet ids of received QoS 2 publishes that await their PUBREL.
+    awaiting_release: Vec<u16, MAX_AWAITING_RELEASE>,
+    /// Acknowledgment of the publish in `rx` that wasn't sent completely,
+    /// because the future sending it was dropped.
+    pending_ack: Option<PendingAck>,
+    subscriptions: SubscriptionList<MAX_TOPICS>,
+    observer: &'a dyn MqttObserver,
+    stats: StatsRecorder,
+    /// Client identifier assigned by the broker in the last v5 CONNACK.
+    #[cfg(feature = "v5")]
+    assigned_client_id: Option<String<MAX_ASSIGNED_CLIENT_ID_LEN>>,
+    #[cfg(feature = "v5")]
+    topic_aliases: TopicAliases,
+    #[cfg(feature = "v5")]
+    inbound_aliases: InboundTopicAliases,
+    /// Session Expiry Interval granted by the broker in the last v5 CONNACK.
+    #[cfg(feature = "v5")]
+    session_expiry: Option<u32>,
+    /// Maximum Packet Size announced by the broker in the last v5 CONNACK.
+    #[cfg(feature = "v5")]
+    maximum_packet_size: Option<u32>,
+    /// Receive Maximum announced by the broker in the last v5 CONNACK.
+    #[cfg(feature = "v5")]
+    receive_maximum: u16,
+    /// Optional features announced by the broker in the last v5 CONNACK.
+    #[cfg(feature = "v5")]
+    capabilities: ServerCapabilities,
 }
 
-impl<'a, T, const MAX_TOPICS: usize, const BUF_SIZE: usize> MqttClient<'a, T, MAX_TOPICS, BUF_SIZE>
+impl<'a, T, const MAX_TOPICS: usize, const TX_BUF: usize, const RX_BUF: usize>
+    MqttClient<'a, T, MAX_TOPICS, TX_BUF, RX_BUF>
 where
     T: MqttTransport,
 {
     pub fn new(transport: T, options: MqttOptions<'a>) -> Self {
         Self {
             transport,
+            keep_alive: options.keep_alive,
             options,
-            tx_buffer: [0; BUF_SIZE],
-            rx_buffer: [0; BUF_SIZE],
+            tx_buffer: [0; TX_BUF],
+            rx: PacketReader::new(),
             state: ConnectionState::Disconnected,
             last_tx_time: Instant::now(),
-            next_packet_id: 1,
+            ping_sent_at: None,
+            connected_at: None,
+            session_present: false,
+            packet_ids: PacketIdAllocator::new(),
             runtime_will: None,
+            inflight: InflightStore::default(),
+            awaiting_release: Vec::new(),
+            pending_ack: None,
+            subscriptions: SubscriptionList::default(),
+            observer: &NoopObserver,
+            stats: StatsRecorder::default(),
+            #[cfg(feature = "v5")]
+            assigned_client_id: None,
+            #[cfg(feature = "v5")]
+            topic_aliases: TopicAliases::default(),
+            #[cfg(feature = "v5")]
+            inbound_aliases: InboundTopicAliases::default(),
+            #[cfg(feature = "v5")]
+            session_expiry: None,
+            #[cfg(feature = "v5")]
+            maximum_packet_size: None,
+            #[cfg(feature = "v5")]
+            receive_maximum: u16::MAX,
+            #[cfg(feature = "v5")]
+            capabilities: ServerCapabilities::default(),
         }
     }
 
-    /// Sets/overrides the Last Will and Testament for the next connections.
-    ///
-    /// Returns `false` when topic or payload exceed internal fixed buffers.
-    pub fn set_last_will(&mut self, will: LastWill<'_>) -> bool {
-        let mut topic: String<MAX_WILL_TOPIC_LEN> = String::new();
-        if topic.push_str(will.topic).is_err() {
-            return false;
-        }
+    /// Attaches an observer that is notified of sent and received packets
+    /// and of failed operations.
+    pub fn set_observer(&mut self, observer: &'a dyn MqttObserver) {
+        self.observer = observer;
+    }
 
-        let mut payload: Vec<u8, MAX_WILL_PAYLOAD_LEN> = Vec::new();
-        if payload.extend_from_slice(will.payload).is_err() {
-            return false;
-        }
+    /// Returns a snapshot of the connection counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> MqttStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns the attached observer.
+    pub(crate) fn observer(&self) -> &'a dyn MqttObserver {
+        self.observer
+    }
+
+    /// Fails with `PacketTooLarge` if a packet of `len` bytes exceeds the
+    /// Maximum Packet Size announced by the broker.
+    #[cfg_attr(not(feature = "v5"), allow(unused_variables))]
+    fn check_packet_size(&self, len: usize) -> Result<(), MqttError<T::Error>> {
+        #[cfg(feature = "v5")]
+        if let Some(maximum) = self.maximum_packet_size
+            && len > maximum as usize
+        {
+            return Err(MqttError::PacketTooLarge { size: len, maximum });
+        }
+        Ok(())
+    }
+
+    /// Reports a PUBACK for an untracked packet id, e.g. a late duplicate, to
+    /// the observer. The ack is otherwise ignored rather than failing the
+    /// operation in progress.
+    fn report_unknown_ack(&self, packet_id: u16) {
+        self.observer
+            .on_error(&MqttError::Protocol(ProtocolError::UnknownPacketId(
+                packet_id,
+            )));
+    }
+
+    /// Reports a failed operation to the observer and passes the result on.
+    fn observe<R>(&self, result: Result<R, MqttError<T::Error>>) -> Result<R, MqttError<T::Error>> {
+        if let Err(e) = &result {
+            self.observer.on_error(&e.as_dyn());
+        }
+        result
+    }
+
+    /// Returns the client identifier of the current session.
+    ///
+    /// This is the identifier assigned by the broker if it sent one in the
+    /// CONNACK (MQTT v5, after connecting with an empty client id), and the
+    /// configured one otherwise.
+    pub fn client_id(&self) -> &str {
+        #[cfg(feature = "v5")]
+        if let Some(id) = &self.assigned_client_id {
+            return id.as_str();
+        }
+        self.options.client_id
+    }
+
+    /// Returns the keep-alive interval in effect: the one granted by the
+    /// broker (MQTT v5 Server Keep Alive) if it sent one, and the configured
+    /// one otherwise.
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    /// Returns the Session Expiry Interval in seconds granted by the broker,
+    /// if its last CONNACK carried one.
+    #[cfg(feature = "v5")]
+    pub fn session_expiry(&self) -> Option<u32> {
+        self.session_expiry
+    }
+
+    /// Returns the largest packet in bytes the broker accepts, if its last
+    /// CONNACK set a limit. Larger publishes fail with `PacketTooLarge`
+    /// without being sent.
+    #[cfg(feature = "v5")]
+    pub fn maximum_packet_size(&self) -> Option<u32> {
+        self.maximum_packet_size
+    }
+
+    /// Returns the optional features the broker announced in its last
+    /// CONNACK. Retained publishes fail with `RetainNotSupported` without being
+    /// sent if the broker doesn't support them.
+    #[cfg(feature = "v5")]
+    pub fn server_capabilities(&self) -> ServerCapabilities {
+        self.capabilities
+    }
+
+    /// Returns the current connection state.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Returns when the broker accepted the current connection, or `None`
+    /// while disconnected.
+    pub fn connected_since(&self) -> Option<Instant> {
+        self.connected_at
+            .filter(|_| self.state == ConnectionState::Connected)
+    }
+
+    /// Returns how long the current connection has been up, e.g. for a
+    /// telemetry module reporting device health. `None` while disconnected.
+    pub fn session_uptime(&self) -> Option<Duration> {
+        self.connected_since().map(|since| since.elapsed())
+    }
+
+    /// Returns whether the broker resumed a stored session in the last CONNACK.
+    ///
+    /// Only possible with `clean_session` disabled. The session's
+    /// subscriptions are still active then, so they don't need to be
+    /// subscribed again.
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Returns how many QoS 1 publishes may await their PUBACK at once.
+    ///
+    /// This is the count set with `MqttOptions::with_max_inflight`, capped at
+    /// `MAX_INFLIGHT` and by the Receive Maximum from a v5 broker's CONNACK.
+    /// A QoS 1 publish beyond it first waits for an earlier PUBACK, and fails
+    /// with `MqttError::Timeout` if none arrives within the acknowledgment
+    /// timeout.
+    pub fn max_inflight(&self) -> usize {
+        let count = usize::from(self.options.max_inflight).min(MAX_INFLIGHT);
+        #[cfg(feature = "v5")]
+        let count = count.min(usize::from(self.receive_maximum));
+        count.max(1)
+    }
+
+    /// Starts a write batch on the transport. Packets sent until `flush` may
+    /// be buffered and pushed out together, e.g. several QoS 0 publishes.
+    ///
+    /// Operations that wait for a reply, such as `subscribe` or a QoS 1
+    /// `publish`, still work inside a batch: the transport flushes before it
+    /// reads.
+    pub fn begin_batch(&mut self) {
+        self.transport.begin_batch();
+    }
+
+    /// Ends the write batch started with `begin_batch` and sends everything
+    /// buffered since.
+    pub async fn flush(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.transport.flush().await.map_err(MqttError::from);
+        self.observe(result)
+    }
+
+    /// Returns a reference to the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns a mutable reference to the underlying transport.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Returns the protocol version used for the next connections.
+    pub fn version(&self) -> MqttVersion {
+        self.options.version
+    }
+
+    /// Changes the protocol version used for the next connections.
+    ///
+    /// A broker that only speaks v3.1.1 refuses a v5 CONNECT with
+    /// `ConnectReasonCode::UnacceptableProtocolVersion` and closes the
+    /// connection; reconnect the transport, downgrade to `MqttVersion::V3`
+    /// and call `connect` again.
+    #[cfg(feature = "v5")]
+    pub fn set_version(&mut self, version: MqttVersion) {
+        self.options.version = version;
+    }
+
+    /// Sets/overrides the Last Will and Testament for the next connections.
+    ///
+    /// Returns `false` when topic or payload exceed internal fixed buffers.
+    pub fn set_last_will(&mut self, will: LastWill<'_>) -> bool {
+        let mut topic: String<MAX_WILL_TOPIC_LEN> = String::new();
+        if topic.push_str(will.topic).is_err() {
+            return false;
+        }
+
+        let mut payload: Vec<u8, MAX_WILL_PAYLOAD_LEN> = Vec::new();
+        if payload.extend_from_slice(will.payload).is_err() {
+            return false;
+        }
 
         self.runtime_will = Some(OwnedLastWill {
             topic,
@@ -157,85 +1102,211 @@ where
         true
     }
 
-    /// Attempts to connect to the MQTT broker.
-    pub async fn connect(&mut self) -> Result<(), MqttError<T::Error>>
+    /// Returns the largest packet the client may need to send, given the
+    /// longest topic (or filter) and payload the application uses.
+    ///
+    /// This covers the CONNECT built from the options, client id, credentials
+    /// and last will, as well as PUBLISH and SUBSCRIBE for the given sizes.
+    /// Checking it against `TX_BUF` at startup turns an undersized buffer into
+    /// a clear failure instead of `BufferTooSmall` deep inside an encode:
+    ///
+    /// ```ignore
+    /// let client = MqttClient::<_, 4, 512>::new(transport, options);
+    /// assert!(client.max_outgoing_len(64, 256) <= 512);
+    /// ```
+    ///
+    /// `packet::publish_len` and `packet::subscribe_len` compute the other
+    /// terms in a `const` context. An application that uses
+    /// `publish_with_response` checks `max_outgoing_len_with_response`
+    /// instead.
+    pub fn max_outgoing_len(&self, max_topic_len: usize, max_payload_len: usize) -> usize
     where
         T::Error: transport::TransportError,
     {
-        #[cfg(feature = "esp32-log")]
-        esp_println::println!("MQTT: Starting connect...");
-
-        self.state = ConnectionState::Connecting;
-        let will = if let Some(will) = self.runtime_will.as_ref() {
-            Some(LastWill {
-                topic: will.topic.as_str(),
-                payload: will.payload.as_slice(),
-                qos: will.qos,
-                retain: will.retain,
-            })
-        } else {
-            self.options.will
-        };
-        let connect_packet = Connect::with_credentials(
-            self.options.client_id,
-            self.options.keep_alive.as_secs() as u16,
-            true,
-            self.options.username.as_deref(),
-            self.options.password.as_ref().map(|s| s.as_bytes()),
-            will,
-        );
-        let len = connect_packet
-            .encode(&mut self.tx_buffer, self.options.version)
-            .map_err(MqttError::cast_transport_error)?;
-
-        #[cfg(feature = "esp32-log")]
-        esp_println::println!("MQTT TX ({} bytes): {:02X?}", len, &self.tx_buffer[..len]);
-
-        self.transport.send(&self.tx_buffer[..len]).await?;
-
-        #[cfg(feature = "esp32-log")]
-        esp_println::println!("MQTT: Waiting for CONNACK...");
+        self.max_outgoing_len_for(max_topic_len, max_payload_len, None)
+    }
 
-        let n = self.transport.recv(&mut self.rx_buffer).await?;
+    /// Returns the largest packet the client may need to send, like
+    /// `max_outgoing_len`, for an application that also publishes requests
+    /// with `publish_with_response`: a Response Topic of up to
+    /// `max_topic_len` bytes and Correlation Data of up to
+    /// `max_correlation_len` bytes.
+    #[cfg(feature = "v5")]
+    pub fn max_outgoing_len_with_response(
+        &self,
+        max_topic_len: usize,
+        max_payload_len: usize,
+        max_correlation_len: usize,
+    ) -> usize
+    where
+        T::Error: transport::TransportError,
+    {
+        self.max_outgoing_len_for(max_topic_len, max_payload_len, Some(max_correlation_len))
+    }
 
-        #[cfg(feature = "esp32-log")]
-        esp_println::println!("MQTT RX ({} bytes): {:02X?}", n, &self.rx_buffer[..n]);
+    fn max_outgoing_len_for(
+        &self,
+        max_topic_len: usize,
+        max_payload_len: usize,
+        max_correlation_len: Option<usize>,
+    ) -> usize
+    where
+        T::Error: transport::TransportError,
+    {
+        let version = self.options.version;
+        self.options
+            .connect_len(self.runtime_will.as_ref())
+            .max(packet::publish_with_response_len(
+                max_topic_len,
+                max_payload_len,
+                max_correlation_len.map(|_| max_topic_len),
+                max_correlation_len,
+                QoS::ExactlyOnce,
+                version,
+            ))
+            .max(packet::subscribe_len(max_topic_len, version))
+    }
 
-        let packet = packet::decode::<T::Error>(&self.rx_buffer[..n], self.options.version);
+    /// Attempts to connect to the MQTT broker.
+    ///
+    /// With `MqttOptions::with_version_fallback`, a v5 CONNECT refused with
+    /// v3.1.1 return code 0x01 or v5 reason code 0x84 is sent again as
+    /// v3.1.1 on the same transport. A broker that closed the connection
+    /// after refusing makes the retry fail with a transport error; the
+    /// client keeps the downgrade, so the next `connect` over a new transport
+    /// uses v3.1.1.
+    pub async fn connect(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        // Bytes left over from a previous connection belong to a different stream
+        self.rx.clear();
+        self.pending_ack = None;
+        #[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+        let mut result = self.connect_inner().await;
+        #[cfg(feature = "v5")]
+        if self.options.version_fallback
+            && self.options.version == MqttVersion::V5
+            && let Err(MqttError::ConnectionRefused(
+                ConnectReasonCode::UnacceptableProtocolVersion
+                | ConnectReasonCode::V5(V5ConnectReasonCode::UnsupportedProtocolVersion),
+            )) = result
+        {
+            log_warn!("mqtt: broker refused MQTT v5, retrying with v3.1.1");
+            self.options.version = MqttVersion::V3;
+            result = self.connect_inner().await;
+        }
+        if result.is_err() {
+            self.state = ConnectionState::Disconnected;
+        }
+        self.observe(result)
+    }
 
-        #[cfg(feature = "esp32-log")]
-        if let Err(ref e) = packet {
-            esp_println::println!("MQTT decode error: {:?}", e);
+    async fn connect_inner(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.state = ConnectionState::Connecting;
+        {
+            let mut will_delay = [0u8; 4];
+            let connect_packet = self
+                .options
+                .connect_packet(self.runtime_will.as_ref(), &mut will_delay)
+                .map_err(MqttError::cast_transport_error)?;
+            let len = connect_packet
+                .encode(&mut self.tx_buffer, self.options.version)
+                .map_err(MqttError::cast_transport_error)?;
+            self.transport.send(&self.tx_buffer[..len]).await?;
+            self.stats.bytes_sent(len);
+            self.observer
+                .on_packet_sent(&MqttPacket::Connect(connect_packet));
         }
 
-        let packet = packet?.ok_or(MqttError::Protocol(ProtocolError::InvalidResponse))?;
+        // A broker that accepted the TCP connection but never answers would
+        // otherwise block here forever
+        let timer_fut = Timer::after(self.options.connect_timeout);
+        let connack_fut = self.recv_packet();
+        let timed_out =
+            match futures::future::select(core::pin::pin!(connack_fut), core::pin::pin!(timer_fut))
+                .await
+            {
+                futures::future::Either::Left((result, _)) => result.map(|()| false),
+                futures::future::Either::Right(((), _)) => Ok(true),
+            }?;
+        if timed_out {
+            self.state = ConnectionState::Disconnected;
+            return Err(MqttError::Timeout);
+        }
+        let packet = packet::decode::<T::Error>(self.rx.packet(), self.options.version)?
+            .ok_or(MqttError::Protocol(ProtocolError::InvalidResponse))?;
+        self.observer.on_packet_received(&packet);
+        self.stats.packet_received(&packet);
 
         if let MqttPacket::ConnAck(connack) = packet {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!(
-                "MQTT CONNACK: reason_code={}, session_present={}",
-                connack.reason_code,
-                connack.session_present
-            );
-
             if connack.reason_code == 0 {
+                // A broker must not resume a session after a clean start
+                self.session_present = connack.session_present && !self.options.clean_session;
+                // Without a stored session the broker forgot every subscription
+                if !self.session_present {
+                    self.subscriptions.filters.clear();
+                    self.awaiting_release.clear();
+                }
+                self.keep_alive = self.options.keep_alive;
+                #[cfg(feature = "v5")]
+                {
+                    // A Server Keep Alive of 0 disables pings, which the client
+                    // doesn't support; keep the requested interval instead
+                    if let Some(secs) = connack.server_keep_alive().filter(|&secs| secs > 0) {
+                        self.keep_alive = Duration::from_secs(u64::from(secs));
+                    }
+                    self.session_expiry = connack.session_expiry_interval();
+                    self.maximum_packet_size = connack.maximum_packet_size();
+                    self.receive_maximum = connack.receive_maximum();
+                    self.capabilities = connack.capabilities();
+                    self.assigned_client_id = connack
+                        .assigned_client_id()
+                        .and_then(|id| String::try_from(id).ok());
+                    self.topic_aliases.reset(connack.topic_alias_maximum());
+                    self.inbound_aliases.reset(
+                        self.options.version == MqttVersion::V5
+                            && self.options.inbound_topic_aliases,
+                    );
+                }
                 self.state = ConnectionState::Connected;
+                self.stats.connected();
                 self.last_tx_time = Instant::now();
+                self.ping_sent_at = None;
+                self.connected_at = Some(self.last_tx_time);
                 Ok(())
             } else {
                 self.state = ConnectionState::Disconnected;
+                // 0x01 isn't a v5 reason code: it's a v3.1.1-only broker
+                // refusing the protocol version with its own return code table
+                #[cfg(feature = "v5")]
+                if self.options.version == MqttVersion::V5 && connack.reason_code != 0x01 {
+                    return Err(MqttError::ConnectionRefused(ConnectReasonCode::from_v5(
+                        connack.reason_code,
+                    )));
+                }
                 Err(MqttError::ConnectionRefused(connack.reason_code.into()))
             }
         } else {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!("MQTT: Expected CONNACK, got different packet!");
-
             self.state = ConnectionState::Disconnected;
             Err(MqttError::Protocol(ProtocolError::InvalidResponse))
         }
     }
 
     /// Publishes a message to a topic.
+    ///
+    /// A QoS 1 publish waits for its PUBACK and fails with
+    /// `MqttError::Timeout` if it doesn't arrive within the acknowledgment
+    /// timeout from `MqttOptions`.
+    ///
+    /// A QoS 1 publish is kept until its PUBACK so it can be resent after a
+    /// reconnect, which bounds its payload to `MAX_INFLIGHT_PAYLOAD_LEN` and
+    /// its topic to `MAX_INFLIGHT_TOPIC_LEN` bytes. Larger ones fail with
+    /// `ProtocolError::PayloadTooLarge` or `MqttError::BufferTooSmall`
+    /// without being sent; QoS 0 messages have no such limit.
     pub async fn publish(
         &mut self,
         topic: &str,
@@ -259,190 +1330,2739 @@ where
     where
         T::Error: transport::TransportError,
     {
-        if self.state != ConnectionState::Connected {
-            return Err(MqttError::NotConnected);
-        }
-
-        let packet_id = if qos != QoS::AtMostOnce {
-            Some(self.get_next_packet_id())
-        } else {
-            None
-        };
-
-        let publish = Publish {
-            topic,
-            qos,
-            retain,
-            payload,
-            packet_id,
-            #[cfg(feature = "v5")]
-            properties: heapless::Vec::new(),
-        };
-
-        let len = publish
-            .encode(&mut self.tx_buffer, self.options.version)
-            .map_err(MqttError::cast_transport_error)?;
-        self.transport.send(&self.tx_buffer[..len]).await?;
-        self.last_tx_time = Instant::now();
+        let publish = Publish::new(topic, payload, qos).retain(retain);
+        let result = self.send_publish(publish).await;
+        self.observe(result)
+    }
 
-        // Wait for PUBACK if QoS > 0; skip interleaved PingResp/Publish to avoid race with keep-alive
-        if qos != QoS::AtMostOnce {
-            for _ in 0..MAX_RECV_ATTEMPTS {
-                let n = self.transport.recv(&mut self.rx_buffer).await?;
-                let packet =
-                    packet::decode::<T::Error>(&self.rx_buffer[..n], self.options.version)?
-                        .ok_or(MqttError::Protocol(ProtocolError::InvalidResponse))?;
+    /// Publishes a message, waiting at most `ack_timeout` for the PUBACK of a
+    /// QoS 1 message instead of the `MqttOptions` default.
+    ///
+    /// Without a PUBACK in time this fails with `MqttError::Timeout`. The
+    /// connection stays up and the message stays in the inflight store, so
+    /// `resend_inflight` retransmits it; the caller can also just retry.
+    pub async fn publish_with_ack_timeout(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        ack_timeout: Duration,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let publish = Publish::new(topic, payload, qos);
+        let result = self
+            .send_publish_with_timeout(publish, &[], ack_timeout)
+            .await;
+        self.observe(result)
+    }
 
-                match packet {
-                    MqttPacket::PubAck(_) => return Ok(()),
-                    MqttPacket::PingResp => continue,
-                    MqttPacket::Publish(_) => continue,
-                    _ => return Err(MqttError::Protocol(ProtocolError::InvalidResponse)),
-                }
-            }
-            return Err(MqttError::Protocol(ProtocolError::InvalidResponse));
+    /// Publishes a message that the broker discards if it can't be delivered
+    /// within `message_expiry` seconds, e.g. sensor readings that are worthless
+    /// when delayed. `None` publishes without an expiry, like `publish`.
+    ///
+    /// The expiry is dropped if the message has to be resent after a reconnect.
+    #[cfg(feature = "v5")]
+    pub async fn publish_with_expiry(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        message_expiry: Option<u32>,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let mut scratch = [0u8; 4];
+        let mut publish = Publish::new(topic, payload, qos);
+        if let Some(seconds) = message_expiry {
+            publish
+                .set_message_expiry(&mut scratch, seconds)
+                .map_err(MqttError::cast_transport_error)?;
         }
-
-        Ok(())
+        let result = self.send_publish(publish).await;
+        self.observe(result)
     }
 
-    /// Subscribes to a topic with specified QoS.
-    pub async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), MqttError<T::Error>>
+    /// Publishes a request of a request/response exchange: the receiver
+    /// replies on `response_topic` and echoes `correlation_data`, so the reply
+    /// can be matched to this request. `None` leaves the property out.
+    ///
+    /// Both properties are encoded straight into the TX buffer, so they only
+    /// need to fit the packet. Returns `EncodeBufferTooSmall` if they don't,
+    /// and `PayloadTooLarge` for values over 65535 bytes.
+    /// `packet::publish_with_response_len` computes the buffer size this
+    /// needs.
+    #[cfg(feature = "v5")]
+    pub async fn publish_with_response(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        response_topic: Option<&str>,
+        correlation_data: Option<&[u8]>,
+    ) -> Result<(), MqttError<T::Error>>
     where
         T::Error: transport::TransportError,
     {
-        if self.state != ConnectionState::Connected {
-            return Err(MqttError::NotConnected);
+        let mut prefixed: Vec<packet::PrefixedProperty<'_>, 2> = Vec::new();
+        if let Some(response_topic) = response_topic {
+            // Can't fail, the vector has room for both properties
+            let _ = prefixed.push(packet::PrefixedProperty {
+                id: packet::Property::RESPONSE_TOPIC,
+                data: response_topic.as_bytes(),
+            });
         }
+        if let Some(data) = correlation_data {
+            let _ = prefixed.push(packet::PrefixedProperty {
+                id: packet::Property::CORRELATION_DATA,
+                data,
+            });
+        }
+        let result = self
+            .send_publish_with_timeout(
+                Publish::new(topic, payload, qos),
+                &prefixed,
+                self.options.ack_timeout,
+            )
+            .await;
+        self.observe(result)
+    }
 
-        let packet_id = self.get_next_packet_id();
-        let subscribe = Subscribe::new(packet_id, topic, qos);
-
-        let len = subscribe
-            .encode(&mut self.tx_buffer, self.options.version)
-            .map_err(MqttError::cast_transport_error)?;
-        self.transport.send(&self.tx_buffer[..len]).await?;
-        self.last_tx_time = Instant::now();
+    /// Deletes the retained message on `topic` by publishing an empty
+    /// retained message, e.g. to remove a Home Assistant discovery config.
+    ///
+    /// Sent with QoS 1, so this returns once the broker acknowledged it.
+    pub async fn clear_retained(&mut self, topic: &str) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.publish_with_retain(topic, &[], QoS::AtLeastOnce, true)
+            .await
+    }
 
-        // Wait for SUBACK; skip interleaved PingResp/Publish to avoid race with keep-alive
-        for _ in 0..MAX_RECV_ATTEMPTS {
-            let n = self.transport.recv(&mut self.rx_buffer).await?;
-            let packet = packet::decode::<T::Error>(&self.rx_buffer[..n], self.options.version)?
-                .ok_or(MqttError::Protocol(ProtocolError::InvalidResponse))?;
+    /// Publishes several messages back to back, e.g. a burst of related state
+    /// topics.
+    ///
+    /// Messages are sent in groups of up to `MAX_PUBLISH_BURST` within one
+    /// transport batch, so a group is flushed once instead of per message.
+    /// The PUBACKs of a group's QoS 1 messages are awaited together before the
+    /// next group is sent. Messages are not retained.
+    pub async fn publish_many(
+        &mut self,
+        msgs: &[(&str, &[u8], QoS)],
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.publish_many_inner(msgs).await;
+        self.observe(result)
+    }
 
-            match packet {
-                MqttPacket::SubAck(suback) => {
-                    if suback.packet_id != packet_id {
-                        return Err(MqttError::Protocol(ProtocolError::InvalidResponse));
+    async fn publish_many_inner(
+        &mut self,
+        msgs: &[(&str, &[u8], QoS)],
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        for burst in msgs.chunks(MAX_PUBLISH_BURST) {
+            self.transport.begin_batch();
+            let mut pending = Vec::new();
+            let mut written = Ok(());
+            for &(topic, payload, qos) in burst {
+                if qos != QoS::AtMostOnce
+                    && let Err(e) = self
+                        .wait_for_inflight_slot(&mut pending, self.options.ack_timeout)
+                        .await
+                {
+                    written = Err(e);
+                    break;
+                }
+                let publish = Publish::new(topic, payload, qos);
+                match self.write_publish(publish, &[]).await {
+                    // Can't fail, a burst has at most `MAX_PUBLISH_BURST` messages
+                    Ok(Some(packet_id)) => {
+                        let _ = pending.push(packet_id);
                     }
-                    if suback
-                        .reason_codes
-                        .first()
-                        .map(|&c| c >= 0x80)
-                        .unwrap_or(true)
-                    {
-                        return Err(MqttError::Protocol(ProtocolError::InvalidResponse));
+                    Ok(None) => {}
+                    Err(e) => {
+                        written = Err(e);
+                        break;
                     }
-                    return Ok(());
                 }
-                MqttPacket::PingResp => continue,
-                MqttPacket::Publish(_) => continue,
-                _ => return Err(MqttError::Protocol(ProtocolError::InvalidResponse)),
             }
+            // End the batch even after a failure, so the earlier messages go out
+            self.transport.flush().await?;
+            written?;
+            self.wait_for_pubacks(&mut pending, self.options.ack_timeout)
+                .await?;
         }
-        Err(MqttError::Protocol(ProtocolError::InvalidResponse))
+        Ok(())
     }
 
-    /// Sends a pre-constructed packet over the transport.
-    async fn _send_packet<P>(&mut self, packet: P) -> Result<(), MqttError<T::Error>>
+    /// Publishes a prepared MQTT v5 PUBLISH packet, e.g. one carrying
+    /// properties such as a Response Topic and Correlation Data.
+    ///
+    /// The client assigns the packet identifier, so `packet_id` and `dup` are
+    /// ignored. Like `publish`, this waits for the PUBACK of a QoS 1 message.
+    /// If the message has to be resent after a reconnect, it is resent
+    /// without its properties.
+    #[cfg(feature = "v5")]
+    pub async fn publish_packet(&mut self, publish: Publish<'_>) -> Result<(), MqttError<T::Error>>
     where
-        P: EncodePacket,
         T::Error: transport::TransportError,
     {
-        if self.state != ConnectionState::Connected {
-            return Err(MqttError::NotConnected);
+        let result = self.send_publish(publish).await;
+        self.observe(result)
+    }
+
+    async fn send_publish(&mut self, publish: Publish<'_>) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.send_publish_with_timeout(publish, &[], self.options.ack_timeout)
+            .await
+    }
+
+    async fn send_publish_with_timeout(
+        &mut self,
+        publish: Publish<'_>,
+        prefixed: &[packet::PrefixedProperty<'_>],
+        ack_timeout: Duration,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let mut pending = Vec::new();
+        if publish.qos != QoS::AtMostOnce {
+            self.wait_for_inflight_slot(&mut pending, ack_timeout)
+                .await?;
         }
-        let len = packet
-            .encode(&mut self.tx_buffer, self.options.version)
-            .map_err(MqttError::cast_transport_error)?;
-        self.transport.send(&self.tx_buffer[..len]).await?;
-        self.last_tx_time = Instant::now();
-        Ok(())
+        if let Some(packet_id) = self.write_publish(publish, prefixed).await? {
+            // Can't fail, the vector is empty
+            let _ = pending.push(packet_id);
+        }
+        self.wait_for_pubacks(&mut pending, ack_timeout).await
     }
 
-    /// Polls the connection for incoming packets and handles keep-alives.
+    /// Assigns a packet identifier to `publish` and sends it without waiting
+    /// for the PUBACK. Returns the identifier of a QoS 1 publish.
     ///
-    /// The returned `MqttEvent` contains references to the client's internal receive
-    /// buffer. These references are only valid until the next call to `poll`.
-    pub async fn poll<'p>(&'p mut self) -> Result<Option<MqttEvent<'p>>, MqttError<T::Error>>
+    /// `prefixed` properties are encoded after the packet's own, straight into
+    /// the TX buffer. Observers and the inflight copy don't see them.
+    async fn write_publish(
+        &mut self,
+        publish: Publish<'_>,
+        prefixed: &[packet::PrefixedProperty<'_>],
+    ) -> Result<Option<u16>, MqttError<T::Error>>
     where
         T::Error: transport::TransportError,
     {
         if self.state != ConnectionState::Connected {
             return Err(MqttError::NotConnected);
         }
-
-        let elapsed = self.last_tx_time.elapsed();
-        let remaining = if elapsed >= self.options.keep_alive {
-            Duration::from_millis(0)
-        } else {
-            self.options.keep_alive - elapsed
+        // A v5 publish with its own topic alias may leave the topic empty
+        #[cfg(feature = "v5")]
+        let aliased = publish
+            .properties
+            .find_property(packet::Property::TOPIC_ALIAS)
+            .is_some();
+        #[cfg(not(feature = "v5"))]
+        let aliased = false;
+        if !aliased {
+            util::validate_publish_topic(publish.topic).map_err(MqttError::Protocol)?;
+        }
+        // The broker would close the connection over it
+        #[cfg(feature = "v5")]
+        if publish.retain && !self.capabilities.retain_available {
+            return Err(MqttError::Protocol(ProtocolError::RetainNotSupported));
+        }
+        // A publish that can't be kept couldn't be resent after a reconnect
+        if publish.qos != QoS::AtMostOnce {
+            if publish.payload.len() > MAX_INFLIGHT_PAYLOAD_LEN {
+                return Err(MqttError::Protocol(ProtocolError::PayloadTooLarge));
+            }
+            if publish.topic.len() > MAX_INFLIGHT_TOPIC_LEN || self.inflight.entries.is_full() {
+                return Err(MqttError::BufferTooSmall);
+            }
+        }
+
+        #[cfg(feature = "v5")]
+        let alias;
+        #[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+        let mut publish = Publish {
+            dup: false,
+            packet_id: if publish.qos != QoS::AtMostOnce {
+                Some(self.get_next_packet_id())
+            } else {
+                None
+            },
+            ..publish
+        };
+        let topic = publish.topic;
+        let packet_id = publish.packet_id;
+
+        // With a topic alias, the full topic is only sent on first use. A
+        // caller's own alias is sent as is.
+        #[cfg(feature = "v5")]
+        if !aliased && let Some((value, known)) = self.resolve_topic_alias(topic) {
+            alias = value.to_be_bytes();
+            publish
+                .properties
+                .push(packet::Property {
+                    id: packet::Property::TOPIC_ALIAS,
+                    data: &alias,
+                })
+                .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))?;
+            if known {
+                publish.topic = "";
+            }
+        }
+
+        let len = publish
+            .encode_with(&mut self.tx_buffer, self.options.version, prefixed)
+            .map_err(MqttError::cast_transport_error)?;
+        self.check_packet_size(len)?;
+
+        // Keep a copy until the PUBACK arrives so it can be resent after a reconnect
+        if let Some(id) = packet_id
+            && !self
+                .inflight
+                .insert(id, topic, publish.payload, publish.qos, publish.retain)
+        {
+            return Err(MqttError::BufferTooSmall);
+        }
+
+        self.transport.send(&self.tx_buffer[..len]).await?;
+        self.stats.bytes_sent(len);
+        self.last_tx_time = Instant::now();
+        self.stats.publish_sent();
+        self.observer.on_packet_sent(&MqttPacket::Publish(publish));
+        Ok(packet_id)
+    }
+
+    /// Reads packets until a PUBACK arrived for every identifier in `pending`.
+    ///
+    /// Skips interleaved PingResp/Publish to avoid a race with keep-alive, but
+    /// gives up after `MAX_RECV_ATTEMPTS` packets without progress, or with
+    /// `MqttError::Timeout` once `ack_timeout` has passed. Unacknowledged
+    /// publishes stay in the inflight store either way.
+    async fn wait_for_pubacks(
+        &mut self,
+        pending: &mut Vec<u16, MAX_PUBLISH_BURST>,
+        ack_timeout: Duration,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.read_pubacks(pending, ack_timeout, |_, pending| !pending.is_empty())
+            .await
+    }
+
+    /// Reads packets until the inflight window has room for another QoS 1
+    /// publish, like `wait_for_pubacks`. Acknowledged identifiers are removed
+    /// from `pending`.
+    async fn wait_for_inflight_slot(
+        &mut self,
+        pending: &mut Vec<u16, MAX_PUBLISH_BURST>,
+        ack_timeout: Duration,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.inflight.entries.len() >= self.max_inflight()
+            && self.state != ConnectionState::Connected
+        {
+            return Err(MqttError::NotConnected);
+        }
+        self.read_pubacks(pending, ack_timeout, |client, _| {
+            client.inflight.entries.len() >= client.max_inflight()
+        })
+        .await
+    }
+
+    /// Reads packets and handles their PUBACKs while `waiting` returns `true`.
+    async fn read_pubacks(
+        &mut self,
+        pending: &mut Vec<u16, MAX_PUBLISH_BURST>,
+        ack_timeout: Duration,
+        waiting: impl Fn(&Self, &[u16]) -> bool,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let deadline = Instant::now() + ack_timeout;
+        while waiting(self, pending) {
+            // Acks may arrive in any order, including ones for resent
+            // publishes before ours. Each one that frees a slot or completes
+            // a pending publish restarts the attempts.
+            let packet_id = self
+                .read_reply(Some(deadline), |packet, client| match packet {
+                    MqttPacket::PubAck(puback)
+                        if pending.contains(&puback.packet_id)
+                            || client.inflight.contains(puback.packet_id) =>
+                    {
+                        Some(Ok(puback.packet_id))
+                    }
+                    _ => None,
+                })
+                .await?;
+            if let Some(index) = pending.iter().position(|&id| id == packet_id) {
+                pending.swap_remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads packets until `reply` accepts one and returns its result.
+    ///
+    /// Whatever else the broker sends meanwhile is handled: PUBACKs complete
+    /// inflight publishes, a PINGRESP clears the keep-alive deadline, and
+    /// incoming messages are acknowledged and kept for the next `poll`. A
+    /// DISCONNECT, any other packet, `MAX_RECV_ATTEMPTS` packets without a
+    /// reply or passing `deadline` end the wait with an error.
+    async fn read_reply<R>(
+        &mut self,
+        deadline: Option<Instant>,
+        reply: impl Fn(&MqttPacket<'_>, &Self) -> Option<Result<R, MqttError<T::Error>>>,
+    ) -> Result<R, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.hold_pending_ack().await?;
+        for _ in 0..MAX_RECV_ATTEMPTS {
+            match deadline {
+                Some(deadline) => {
+                    let recv_fut = self.recv_packet();
+                    let timer_fut = Timer::at(deadline);
+                    match futures::future::select(
+                        core::pin::pin!(recv_fut),
+                        core::pin::pin!(timer_fut),
+                    )
+                    .await
+                    {
+                        futures::future::Either::Left((result, _)) => result?,
+                        futures::future::Either::Right(((), _)) => {
+                            return Err(MqttError::Timeout);
+                        }
+                    }
+                }
+                None => self.recv_packet().await?,
+            }
+            // Replies are sent once the packet no longer borrows `rx`
+            let mut received = None;
+            let mut released = None;
+            let accepted;
+            {
+                let packet = packet::decode::<T::Error>(self.rx.packet(), self.options.version)?
+                    .ok_or(MqttError::Protocol(ProtocolError::InvalidResponse))?;
+                self.observer.on_packet_received(&packet);
+                self.stats.packet_received(&packet);
+                accepted = reply(&packet, self);
+
+                match packet {
+                    MqttPacket::PubAck(puback) => {
+                        if !self.inflight.remove(puback.packet_id) && accepted.is_none() {
+                            self.report_unknown_ack(puback.packet_id);
+                        }
+                    }
+                    MqttPacket::PingResp => self.ping_sent_at = None,
+                    MqttPacket::Publish(publish) => {
+                        #[cfg(feature = "v5")]
+                        self.inbound_aliases
+                            .learn(&publish)
+                            .map_err(MqttError::Protocol)?;
+                        received = Some((publish.qos, publish.packet_id));
+                    }
+                    MqttPacket::PubRel(pubrel) => released = Some(pubrel.packet_id),
+                    MqttPacket::Disconnect(disconnect) => {
+                        self.state = ConnectionState::Disconnected;
+                        return Err(Self::disconnect_error(&disconnect, self.options.version));
+                    }
+                    _ if accepted.is_some() => {}
+                    _ => return Err(MqttError::Protocol(ProtocolError::InvalidResponse)),
+                }
+            }
+            if let Some((qos, packet_id)) = received {
+                self.hold_publish(qos, packet_id).await?;
+            }
+            if let Some(packet_id) = released {
+                self.release(packet_id).await?;
+            }
+            if let Some(result) = accepted {
+                return result;
+            }
+        }
+        Err(MqttError::Protocol(ProtocolError::InvalidResponse))
+    }
+
+    /// Returns the topic alias to publish `topic` with, if aliases are enabled,
+    /// and whether the broker already knows it.
+    #[cfg(feature = "v5")]
+    fn resolve_topic_alias(&mut self, topic: &str) -> Option<(u16, bool)> {
+        if self.options.version != MqttVersion::V5 || !self.options.topic_aliases {
+            return None;
+        }
+        self.topic_aliases.resolve(topic)
+    }
+
+    /// Subscribes to a topic with specified QoS.
+    pub async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.subscribe_many(&[(topic, qos)]).await
+    }
+
+    /// Subscribes to several topics with a single SUBSCRIBE packet.
+    ///
+    /// Waits for one SUBACK and checks that it carries a successful reason code
+    /// for every requested topic. A rejected topic is reported as
+    /// `MqttError::SubscriptionRefused` with its index in `topics`.
+    ///
+    /// At most `MAX_SUBSCRIBE_TOPICS` topics fit in one packet; larger sets
+    /// return `BufferTooSmall`. So does a set that would exceed the `MAX_TOPICS`
+    /// active subscriptions the client tracks, or a filter longer than
+    /// `MAX_FILTER_LEN`.
+    pub async fn subscribe_many(
+        &mut self,
+        topics: &[(&str, QoS)],
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.subscribe_many_inner(topics).await;
+        self.observe(result)
+    }
+
+    async fn subscribe_many_inner(
+        &mut self,
+        topics: &[(&str, QoS)],
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let subscribe = Subscribe::with_topics(0, topics).ok_or(MqttError::BufferTooSmall)?;
+        self.send_subscribe(subscribe).await
+    }
+
+    /// Subscribes to a topic with an MQTT v5 Subscription Identifier.
+    ///
+    /// The broker tags every message delivered through this subscription
+    /// with `subscription_id`, which `Publish::subscription_id` returns, so
+    /// handlers can route by number instead of comparing topic strings.
+    #[cfg(feature = "v5")]
+    pub async fn subscribe_with_id(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        subscription_id: usize,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let mut scratch = [0u8; 4];
+        let mut subscribe = Subscribe::new(0, topic, qos);
+        let result = match subscribe.set_subscription_id(&mut scratch, subscription_id) {
+            Ok(()) => self.send_subscribe(subscribe).await,
+            Err(e) => Err(MqttError::cast_transport_error(e)),
+        };
+        self.observe(result)
+    }
+
+    /// Subscribes to a topic with MQTT v5 subscription options, e.g. No Local
+    /// for a device that shouldn't receive its own messages on a shared topic.
+    #[cfg(feature = "v5")]
+    pub async fn subscribe_with_options(
+        &mut self,
+        topic: &str,
+        options: SubscribeOptions,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = match Subscribe::with_options(0, &[(topic, options)]) {
+            Some(subscribe) => self.send_subscribe(subscribe).await,
+            None => Err(MqttError::BufferTooSmall),
+        };
+        self.observe(result)
+    }
+
+    /// Assigns a packet identifier to `subscribe`, sends it and waits for a
+    /// SUBACK accepting every topic.
+    async fn send_subscribe(&mut self, subscribe: Subscribe<'_>) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+
+        for (filter, _) in &subscribe.topics {
+            util::validate_subscribe_filter(filter).map_err(MqttError::Protocol)?;
+        }
+        if !self.subscriptions.has_room_for(&subscribe.topics) {
+            return Err(MqttError::BufferTooSmall);
+        }
+
+        let packet_id = self.get_next_packet_id();
+        let subscribe = Subscribe {
+            packet_id,
+            ..subscribe
+        };
+        let topics = subscribe.topics.clone();
+
+        let len = subscribe
+            .encode(&mut self.tx_buffer, self.options.version)
+            .map_err(MqttError::cast_transport_error)?;
+        self.check_packet_size(len)?;
+        self.transport.send(&self.tx_buffer[..len]).await?;
+        self.stats.bytes_sent(len);
+        self.last_tx_time = Instant::now();
+        self.observer
+            .on_packet_sent(&MqttPacket::Subscribe(subscribe));
+
+        // Wait for SUBACK; keep interleaved publishes for `poll`
+        self.read_reply(None, |packet, _| match packet {
+            MqttPacket::SubAck(suback) => Some(
+                if suback.packet_id != packet_id || suback.reason_codes.len() != topics.len() {
+                    Err(MqttError::Protocol(ProtocolError::InvalidResponse))
+                } else if let Some((index, &reason_code)) = suback
+                    .reason_codes
+                    .iter()
+                    .enumerate()
+                    .find(|&(_, &c)| c >= 0x80)
+                {
+                    Err(MqttError::SubscriptionRefused { index, reason_code })
+                } else {
+                    Ok(())
+                },
+            ),
+            _ => None,
+        })
+        .await?;
+        for &(filter, options) in &topics {
+            self.subscriptions.insert(filter, options.qos);
+        }
+        Ok(())
+    }
+
+    /// Unsubscribes from a topic filter and waits for the UNSUBACK.
+    ///
+    /// `topic` must match an active subscription exactly, wildcards included:
+    /// unsubscribing `home/+/temp` doesn't affect `home/#`. Other filters fail
+    /// with `MqttError::NotSubscribed` without contacting the broker.
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.unsubscribe_inner(topic).await;
+        self.observe(result)
+    }
+
+    async fn unsubscribe_inner(&mut self, topic: &str) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+        if !self.subscriptions.contains(topic) {
+            return Err(MqttError::NotSubscribed);
+        }
+
+        let packet_id = self.get_next_packet_id();
+        let unsubscribe = Unsubscribe::new(packet_id, topic);
+        let len = unsubscribe
+            .encode(&mut self.tx_buffer, self.options.version)
+            .map_err(MqttError::cast_transport_error)?;
+        self.check_packet_size(len)?;
+        self.transport.send(&self.tx_buffer[..len]).await?;
+        self.stats.bytes_sent(len);
+        self.last_tx_time = Instant::now();
+        self.observer
+            .on_packet_sent(&MqttPacket::Unsubscribe(unsubscribe));
+
+        // Wait for UNSUBACK; keep interleaved publishes for `poll`
+        self.read_reply(None, |packet, _| match packet {
+            MqttPacket::UnsubAck(unsuback) => Some(if unsuback.packet_id != packet_id {
+                Err(MqttError::Protocol(ProtocolError::InvalidResponse))
+            } else if let Some(&reason_code) = unsuback.reason_codes.first()
+                && reason_code >= 0x80
+            {
+                // v3.1.1 UNSUBACKs carry no reason codes
+                Err(MqttError::SubscriptionRefused {
+                    index: 0,
+                    reason_code,
+                })
+            } else {
+                Ok(())
+            }),
+            _ => None,
+        })
+        .await?;
+        self.subscriptions.remove(topic);
+        Ok(())
+    }
+
+    /// Returns the active subscriptions with their requested QoS.
+    ///
+    /// Filters are added when the broker accepts them in a SUBACK and removed
+    /// by `unsubscribe`. A CONNACK without a stored session clears the list,
+    /// since the broker forgot them too.
+    pub fn subscriptions(&self) -> impl Iterator<Item = (&str, QoS)> + '_ {
+        self.subscriptions
+            .filters
+            .iter()
+            .map(|(filter, qos)| (filter.as_str(), *qos))
+    }
+
+    /// Resends every QoS 1 publish that hasn't been acknowledged yet, with the DUP flag set.
+    ///
+    /// Call this after reconnecting so messages that were in flight when the
+    /// connection dropped are delivered. Their PUBACKs are processed by later
+    /// calls to `poll`, `publish` or `subscribe`.
+    pub async fn resend_inflight(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.resend_inflight_inner().await;
+        self.observe(result)
+    }
+
+    async fn resend_inflight_inner(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+
+        for entry in self.inflight.entries.iter() {
+            let publish = Publish {
+                topic: entry.topic.as_str(),
+                qos: entry.qos,
+                dup: true,
+                retain: entry.retain,
+                payload: entry.payload.as_slice(),
+                packet_id: Some(entry.packet_id),
+                #[cfg(feature = "v5")]
+                properties: heapless::Vec::new(),
+            };
+            let len = publish
+                .encode(&mut self.tx_buffer, self.options.version)
+                .map_err(MqttError::cast_transport_error)?;
+            self.check_packet_size(len)?;
+            self.transport.send(&self.tx_buffer[..len]).await?;
+            self.stats.bytes_sent(len);
+            self.last_tx_time = Instant::now();
+            self.stats.publish_sent();
+            self.observer.on_packet_sent(&MqttPacket::Publish(publish));
+        }
+        Ok(())
+    }
+
+    /// Sends a PINGREQ and waits for the PINGRESP, e.g. as a liveness probe
+    /// independent of the keep-alive interval.
+    ///
+    /// Fails with `MqttError::Timeout` if the PINGRESP doesn't arrive within
+    /// the acknowledgment timeout from `MqttOptions`, and marks the client
+    /// disconnected, as for a missed keep-alive PINGRESP. PUBACKs received
+    /// meanwhile are handled, and incoming messages are acknowledged and kept
+    /// for the next `poll`, as while waiting for a PUBACK.
+    pub async fn ping(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.ping_inner().await;
+        self.observe(result)
+    }
+
+    async fn ping_inner(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self._send_packet(PingReq).await?;
+        self.ping_sent_at = Some(self.last_tx_time);
+        self.stats.ping_sent();
+        self.observer.on_packet_sent(&MqttPacket::PingReq);
+
+        let deadline = Instant::now() + self.options.ack_timeout;
+        let result = self
+            .read_reply(Some(deadline), |packet, _| match packet {
+                MqttPacket::PingResp => Some(Ok(())),
+                _ => None,
+            })
+            .await;
+        if let Err(MqttError::Timeout) = result {
+            self.state = ConnectionState::Disconnected;
+        }
+        result
+    }
+
+    /// Gracefully closes the session by sending a DISCONNECT packet.
+    ///
+    /// The client is marked disconnected even if sending fails, so subsequent
+    /// publish/subscribe calls return `MqttError::NotConnected` until the next
+    /// successful `connect`. The broker discards the Last Will on a clean disconnect.
+    pub async fn disconnect(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.send_disconnect(&Disconnect::new()).await;
+        self.observe(result)
+    }
+
+    /// Gracefully closes the session with an MQTT v5 DISCONNECT packet.
+    ///
+    /// Use this to send a reason code or properties such as the Session
+    /// Expiry Interval alongside the disconnect.
+    #[cfg(feature = "v5")]
+    pub async fn disconnect_with(
+        &mut self,
+        packet: &Disconnect<'_>,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.send_disconnect(packet).await;
+        self.observe(result)
+    }
+
+    async fn send_disconnect(&mut self, packet: &Disconnect<'_>) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+        self.state = ConnectionState::Disconnecting;
+        let result = self.write_disconnect(packet).await;
+        self.state = ConnectionState::Disconnected;
+        result
+    }
+
+    async fn write_disconnect(&mut self, packet: &Disconnect<'_>) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let len = packet
+            .encode(&mut self.tx_buffer, self.options.version)
+            .map_err(MqttError::cast_transport_error)?;
+        self.transport.send(&self.tx_buffer[..len]).await?;
+        self.stats.bytes_sent(len);
+        self.last_tx_time = Instant::now();
+        self.observer
+            .on_packet_sent(&MqttPacket::Disconnect(packet.clone()));
+        Ok(())
+    }
+
+    /// Sends a pre-constructed packet over the transport.
+    async fn _send_packet<P>(&mut self, packet: P) -> Result<(), MqttError<T::Error>>
+    where
+        P: EncodePacket,
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+        let len = packet
+            .encode(&mut self.tx_buffer, self.options.version)
+            .map_err(MqttError::cast_transport_error)?;
+        self.check_packet_size(len)?;
+        self.transport.send(&self.tx_buffer[..len]).await?;
+        self.stats.bytes_sent(len);
+        self.last_tx_time = Instant::now();
+        Ok(())
+    }
+
+    /// Polls the connection for incoming packets and handles keep-alives.
+    ///
+    /// A PINGREQ is sent once the keep-alive interval elapses without any other
+    /// outgoing packet. The broker then has one more keep-alive interval to answer
+    /// with a PINGRESP; if it doesn't, the client is marked disconnected and
+    /// `MqttError::Timeout` is returned.
+    ///
+    /// Several packets may arrive in one read, e.g. a retained PUBLISH right
+    /// behind a SUBACK. The rest stays buffered after the reply that was
+    /// waited for, and each `poll` returns the next buffered packet before
+    /// reading from the transport again. Messages that arrive while `publish`,
+    /// `subscribe`, `unsubscribe` or `ping` wait for their reply are kept the
+    /// same way and returned first.
+    ///
+    /// A received QoS 1 message is acknowledged with a PUBACK before it is
+    /// returned. QoS 2 messages get a PUBREC, and the broker's PUBREL is
+    /// answered with a PUBCOMP when it arrives.
+    ///
+    /// The returned `MqttEvent` contains references to the client's internal receive
+    /// buffer. These references are only valid until the next call to `poll`.
+    ///
+    /// Dropping the future, e.g. when racing it against a timer, loses no
+    /// message: an acknowledgment that was being sent is sent again by the
+    /// next call, which then returns its message. A transport that was
+    /// interrupted halfway through writing it may still have sent part of it,
+    /// so prefer racing only the wait for data, as `MqttRuntime` does.
+    pub async fn poll<'p>(&'p mut self) -> Result<Option<MqttEvent<'p>>, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.poll_until(core::future::pending()).await
+    }
+
+    /// Like `poll`, but returns `Ok(None)` once `wake` completes while
+    /// waiting for data. Sends are never interrupted by `wake`.
+    pub(crate) async fn poll_until<'p>(
+        &'p mut self,
+        wake: impl Future<Output = ()>,
+    ) -> Result<Option<MqttEvent<'p>>, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.poll_packet(wake).await;
+        if self.observe(result)? {
+            self.buffered_publish().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Waits for the next event from the broker.
+    ///
+    /// Keep-alives, PINGRESPs and PUBACKs are handled internally, as in `poll`,
+    /// but instead of returning `None` for them this keeps reading until a
+    /// message arrives. Losing the connection (a transport error, the broker
+    /// closing it, or a keep-alive timeout) marks the client disconnected and is
+    /// reported as `MqttEvent::Disconnected`, with the reason code if an MQTT v5
+    /// broker sent a DISCONNECT; call `connect` to re-establish it.
+    /// A transport read timeout, as reported by `TransportError::is_timeout`,
+    /// only means nothing arrived, so this keeps waiting.
+    ///
+    /// ```ignore
+    /// loop {
+    ///     match client.next_event().await? {
+    ///         MqttEvent::Publish(msg) => handle(msg.topic, msg.payload),
+    ///         MqttEvent::Disconnected(_) => client.connect().await?,
+    ///     }
+    /// }
+    /// ```
+    pub async fn next_event(&mut self) -> Result<MqttEvent<'_>, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        loop {
+            let result = self.poll_packet(core::future::pending()).await;
+            match self.observe(result) {
+                Ok(true) => return self.buffered_publish(),
+                Ok(false) => {}
+                Err(
+                    MqttError::Transport(_)
+                    | MqttError::Timeout
+                    | MqttError::Protocol(ProtocolError::ConnectionClosed),
+                ) => {
+                    self.state = ConnectionState::Disconnected;
+                    return Ok(MqttEvent::Disconnected(None));
+                }
+                // Already marked disconnected
+                #[cfg(feature = "v5")]
+                Err(MqttError::ServerDisconnect { reason_code }) => {
+                    return Ok(MqttEvent::Disconnected(Some(reason_code)));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the PUBLISH that `poll_packet` left in the receive buffer.
+    fn buffered_publish(&self) -> Result<MqttEvent<'_>, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        match packet::decode::<T::Error>(self.rx.packet(), self.options.version)? {
+            #[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+            Some(MqttPacket::Publish(mut publish)) => {
+                // An aliased topic is only sent with the first message using it
+                #[cfg(feature = "v5")]
+                if publish.topic.is_empty()
+                    && let Some(topic) = self.inbound_aliases.resolve(&publish)
+                {
+                    publish.topic = topic;
+                }
+                Ok(MqttEvent::Publish(publish))
+            }
+            _ => Err(MqttError::Protocol(ProtocolError::InvalidResponse)),
+        }
+    }
+
+    /// Reads and handles at most one packet, sending a PINGREQ when the
+    /// keep-alive interval elapses, or returns once `wake` completes while
+    /// waiting for data.
+    ///
+    /// Returns `true` if the packet is a PUBLISH, which is left in the receive
+    /// buffer for the caller; every other packet is handled here.
+    async fn poll_packet(
+        &mut self,
+        wake: impl Future<Output = ()>,
+    ) -> Result<bool, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+        // A message whose acknowledgment was interrupted is still in `rx`
+        if self.pending_ack.is_some() {
+            return self.send_pending_ack().await;
+        }
+        // Messages kept while waiting for a reply come first
+        self.rx.release_held();
+
+        // While a PINGRESP is outstanding, the deadline is measured from the PINGREQ
+        let elapsed = self.ping_sent_at.unwrap_or(self.last_tx_time).elapsed();
+        let remaining = if elapsed >= self.keep_alive {
+            Duration::from_millis(0)
+        } else {
+            self.keep_alive - elapsed
+        };
+
+        enum PollDecision {
+            Received,
+            KeepAlive,
+            Woken,
+        }
+
+        // A previous read may already have buffered a complete packet
+        let ready = self
+            .rx
+            .next_packet()
+            .map_err(MqttError::cast_transport_error)?
+            .is_some();
+
+        let decision = if ready {
+            PollDecision::Received
+        } else {
+            let recv_fut = self.rx.fill(&mut self.transport);
+            let timer_fut = core::pin::pin!(Timer::after(remaining));
+            let wake_fut = core::pin::pin!(wake);
+            let wait_fut = futures::future::select(timer_fut, wake_fut);
+            match futures::future::select(core::pin::pin!(recv_fut), wait_fut).await {
+                futures::future::Either::Left((Ok(n), _)) => {
+                    self.stats.bytes_received(n);
+                    PollDecision::Received
+                }
+                // A transport read timeout shorter than the keep-alive on an idle link
+                futures::future::Either::Left((Err(e), _))
+                    if transport::TransportError::is_timeout(&e) =>
+                {
+                    return Ok(false);
+                }
+                futures::future::Either::Left((Err(e), _)) => return Err(e.into()),
+                futures::future::Either::Right((
+                    futures::future::Either::Left(_),
+                    _pending_recv,
+                )) => PollDecision::KeepAlive,
+                futures::future::Either::Right((
+                    futures::future::Either::Right(_),
+                    _pending_recv,
+                )) => PollDecision::Woken,
+            }
+        };
+
+        match decision {
+            PollDecision::Received => {
+                // Partial packets are completed by subsequent polls
+                if !ready
+                    && self
+                        .rx
+                        .next_packet()
+                        .map_err(MqttError::cast_transport_error)?
+                        .is_none()
+                {
+                    return Ok(false);
+                }
+                // A held message was acknowledged when it arrived
+                if self.rx.is_replayed() {
+                    return Ok(true);
+                }
+
+                self.handle_buffered_packet().await
+            }
+            PollDecision::KeepAlive => self.send_keep_alive().await.map(|()| false),
+            PollDecision::Woken => Ok(false),
+        }
+    }
+
+    /// Handles every packet that is already available, then returns without
+    /// waiting for more data.
+    ///
+    /// This lets a task that multiplexes MQTT with other work service the
+    /// connection whenever it gets the chance, instead of dedicating a task to
+    /// `poll`. Incoming messages are passed to `on_publish`; PUBACKs, PINGRESPs
+    /// and keep-alives are handled as in `poll`. Reads use
+    /// `MqttTransport::try_recv`, so this only returns promptly on transports
+    /// that implement it without waiting.
+    ///
+    /// Returns the number of packets handled.
+    pub async fn run_until_idle(
+        &mut self,
+        mut on_publish: impl FnMut(&Publish<'_>),
+    ) -> Result<usize, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let result = self.run_until_idle_inner(&mut on_publish).await;
+        self.observe(result)
+    }
+
+    async fn run_until_idle_inner(
+        &mut self,
+        on_publish: &mut impl FnMut(&Publish<'_>),
+    ) -> Result<usize, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+
+        let mut handled = 0;
+        // A message whose acknowledgment was interrupted is still in `rx`
+        if self.pending_ack.is_some() {
+            handled += 1;
+            if self.send_pending_ack().await?
+                && let MqttEvent::Publish(publish) = self.buffered_publish()?
+            {
+                on_publish(&publish);
+            }
+        }
+        self.rx.release_held();
+        loop {
+            if self
+                .rx
+                .next_packet()
+                .map_err(MqttError::cast_transport_error)?
+                .is_none()
+            {
+                let n = self.rx.try_fill(&mut self.transport).await?;
+                self.stats.bytes_received(n);
+                if n == 0 {
+                    break;
+                }
+                // A partial packet may be completed by data that is still buffered
+                continue;
+            }
+
+            handled += 1;
+            if (self.rx.is_replayed() || self.handle_buffered_packet().await?)
+                && let MqttEvent::Publish(publish) = self.buffered_publish()?
+            {
+                on_publish(&publish);
+            }
+        }
+
+        let elapsed = self.ping_sent_at.unwrap_or(self.last_tx_time).elapsed();
+        if elapsed >= self.keep_alive {
+            self.send_keep_alive().await?;
+        }
+        Ok(handled)
+    }
+
+    /// Decodes and handles the complete packet buffered in `rx`.
+    ///
+    /// Returns `true` if the packet is a PUBLISH to deliver, which is left in
+    /// the receive buffer for the caller. It has been acknowledged already.
+    async fn handle_buffered_packet(&mut self) -> Result<bool, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        // Replies are sent once the packet no longer borrows `rx`
+        let mut received = None;
+        let mut released = None;
+        {
+            let packet = packet::decode::<T::Error>(self.rx.packet(), self.options.version)?;
+            if let Some(packet) = &packet {
+                self.observer.on_packet_received(packet);
+                self.stats.packet_received(packet);
+            }
+            match packet {
+                Some(MqttPacket::Publish(publish)) => {
+                    #[cfg(feature = "v5")]
+                    self.inbound_aliases
+                        .learn(&publish)
+                        .map_err(MqttError::Protocol)?;
+                    received = Some((publish.qos, publish.packet_id))
+                }
+                Some(MqttPacket::PubRel(pubrel)) => released = Some(pubrel.packet_id),
+                // Removes the acknowledged publish; only unknown ids enter the arm
+                Some(MqttPacket::PubAck(puback)) if !self.inflight.remove(puback.packet_id) => {
+                    self.report_unknown_ack(puback.packet_id);
+                }
+                Some(MqttPacket::PingResp) => self.ping_sent_at = None,
+                Some(MqttPacket::Disconnect(disconnect)) => {
+                    self.state = ConnectionState::Disconnected;
+                    return Err(Self::disconnect_error(&disconnect, self.options.version));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(packet_id) = released {
+            self.release(packet_id).await?;
+        }
+        match received {
+            Some((qos, packet_id)) => self.acknowledge(qos, packet_id).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Acknowledges a publish that arrived while waiting for a reply and
+    /// keeps it in `rx`, so the next `poll` delivers it.
+    ///
+    /// A message held before is only kept again. One that doesn't fit is
+    /// dropped without an acknowledgment, so the broker sends a QoS 1 or 2
+    /// message again when the session resumes.
+    async fn hold_publish(
+        &mut self,
+        qos: QoS,
+        packet_id: Option<u16>,
+    ) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if !self.rx.is_replayed() {
+            if !self.rx.can_hold() {
+                log_warn!(
+                    "mqtt: no room to keep a message received while waiting, dropping it! len={}",
+                    self.rx.packet().len()
+                );
+                return Ok(());
+            }
+            // A QoS 2 redelivery was delivered already
+            if !self.acknowledge(qos, packet_id).await? {
+                return Ok(());
+            }
+        }
+        self.rx.hold();
+        Ok(())
+    }
+
+    /// Acknowledges a received publish, returning whether it should be
+    /// delivered.
+    ///
+    /// QoS 1 is answered with a PUBACK, otherwise the broker keeps resending
+    /// the message. QoS 2 is answered with a PUBREC and the packet id is kept
+    /// until its PUBREL, so a redelivery in between is not delivered twice.
+    async fn acknowledge(
+        &mut self,
+        qos: QoS,
+        packet_id: Option<u16>,
+    ) -> Result<bool, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let Some(packet_id) = packet_id else {
+            return Ok(true);
+        };
+        let deliver = match qos {
+            QoS::AtMostOnce => return Ok(true),
+            QoS::AtLeastOnce => true,
+            QoS::ExactlyOnce => {
+                let first = !self.awaiting_release.contains(&packet_id);
+                if first {
+                    // When full, the id can't be tracked; deliver anyway
+                    let _ = self.awaiting_release.push(packet_id);
+                }
+                first
+            }
+        };
+        self.pending_ack = Some(PendingAck {
+            qos,
+            packet_id,
+            deliver,
+        });
+        self.send_pending_ack().await
+    }
+
+    /// Sends the acknowledgment recorded in `pending_ack`, returning whether
+    /// its publish should be delivered.
+    ///
+    /// It stays recorded until the send completes, so if the future is
+    /// dropped, e.g. when the runtime races `poll` against a timer, the next
+    /// read sends it again before the publish in `rx` is discarded.
+    async fn send_pending_ack(&mut self) -> Result<bool, MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let Some(ack) = self.pending_ack else {
+            return Ok(false);
+        };
+        if ack.qos == QoS::ExactlyOnce {
+            let pubrec = PubRec::new(ack.packet_id);
+            self._send_packet(pubrec.clone()).await?;
+            self.observer.on_packet_sent(&MqttPacket::PubRec(pubrec));
+        } else {
+            let puback = PubAck::new(ack.packet_id);
+            self._send_packet(puback.clone()).await?;
+            self.observer.on_packet_sent(&MqttPacket::PubAck(puback));
+        }
+        self.pending_ack = None;
+        Ok(ack.deliver)
+    }
+
+    /// Finishes an interrupted acknowledgment before waiting for a reply,
+    /// keeping its message for the next `poll` as `hold_publish` does.
+    async fn hold_pending_ack(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        let Some(ack) = self.pending_ack else {
+            return Ok(());
+        };
+        if ack.deliver && !self.rx.can_hold() {
+            log_warn!(
+                "mqtt: no room to keep a message received while waiting, dropping it! len={}",
+                self.rx.packet().len()
+            );
+            // Unacknowledged, so the broker sends it again when the session resumes
+            self.pending_ack = None;
+            self.awaiting_release.retain(|&id| id != ack.packet_id);
+            return Ok(());
+        }
+        if self.send_pending_ack().await? {
+            self.rx.hold();
+        }
+        Ok(())
+    }
+
+    /// Answers a PUBREL with a PUBCOMP, completing a received QoS 2 publish.
+    async fn release(&mut self, packet_id: u16) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        self.awaiting_release.retain(|&id| id != packet_id);
+        let pubcomp = PubComp::new(packet_id);
+        self._send_packet(pubcomp.clone()).await?;
+        self.observer.on_packet_sent(&MqttPacket::PubComp(pubcomp));
+        Ok(())
+    }
+
+    /// Sends a PINGREQ once the keep-alive interval elapsed, or fails with
+    /// `Timeout` if the previous one is still unanswered.
+    async fn send_keep_alive(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.ping_sent_at.is_some() {
+            self.state = ConnectionState::Disconnected;
+            return Err(MqttError::Timeout);
+        }
+
+        self._send_packet(PingReq).await?;
+        self.ping_sent_at = Some(self.last_tx_time);
+        self.stats.ping_sent();
+        self.observer.on_packet_sent(&MqttPacket::PingReq);
+        Ok(())
+    }
+
+    /// Sends a PINGREQ without reading its PINGRESP, which a later `poll`
+    /// handles. Lets the runtime keep a paused connection alive without
+    /// consuming incoming messages.
+    pub(crate) async fn send_ping(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        if self.state != ConnectionState::Connected {
+            return Err(MqttError::NotConnected);
+        }
+        self._send_packet(PingReq).await?;
+        // The keep-alive deadline restarts from the newest PINGREQ; the
+        // PINGRESP to any of them clears it
+        self.ping_sent_at = Some(self.last_tx_time);
+        self.stats.ping_sent();
+        self.observer.on_packet_sent(&MqttPacket::PingReq);
+        Ok(())
+    }
+
+    /// Returns the error for a DISCONNECT sent by the broker.
+    ///
+    /// Only v5 brokers may send one; from a v3.1.1 broker it is treated as the
+    /// connection being closed.
+    fn disconnect_error(
+        _disconnect: &Disconnect<'_>,
+        _version: MqttVersion,
+    ) -> MqttError<T::Error> {
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            return MqttError::ServerDisconnect {
+                reason_code: _disconnect.reason_code,
+            };
+        }
+        MqttError::Protocol(ProtocolError::ConnectionClosed)
+    }
+
+    /// Reads from the transport until a complete packet is buffered in `rx`.
+    async fn recv_packet(&mut self) -> Result<(), MqttError<T::Error>>
+    where
+        T::Error: transport::TransportError,
+    {
+        while self
+            .rx
+            .next_packet()
+            .map_err(MqttError::cast_transport_error)?
+            .is_none()
+        {
+            let n = self.rx.fill(&mut self.transport).await?;
+            self.stats.bytes_received(n);
+        }
+        Ok(())
+    }
+
+    fn get_next_packet_id(&mut self) -> u16 {
+        let inflight = &self.inflight;
+        self.packet_ids.allocate(|id| inflight.contains(id))
+    }
+}
+
+/// Represents an event received from the MQTT broker.
+/// The lifetime `'p` indicates that the event borrows data from the client's
+/// buffer and is only valid for the duration of the `poll` call.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+// Events are returned by value and never stored, so the size of `Publish`
+// costs nothing, and there is no allocator to box it with.
+#[allow(clippy::large_enum_variant)]
+pub enum MqttEvent<'p> {
+    /// A message was published to one of the client's subscriptions.
+    Publish(Publish<'p>),
+    /// The connection was lost. Only returned by `MqttClient::next_event`.
+    ///
+    /// Carries the reason code of the DISCONNECT an MQTT v5 broker closed the
+    /// connection with, e.g. `0x8D` for a keep-alive timeout, and `None` if
+    /// the connection dropped otherwise.
+    Disconnected(Option<u8>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MockClock, MockError, MockTransport};
+
+    type TestClient = MqttClient<'static, MockTransport<1024>, 4, 256>;
+
+    fn version() -> MqttVersion {
+        if cfg!(feature = "v5") {
+            MqttVersion::V5
+        } else {
+            MqttVersion::V3
+        }
+    }
+
+    fn options() -> MqttOptions<'static> {
+        let options = MqttOptions::new("cid");
+        #[cfg(feature = "v5")]
+        let options = options.with_version(MqttVersion::V5);
+        options
+    }
+
+    /// Returns a connected client whose transport then replays `script`, with
+    /// the CONNECT cleared from the capture.
+    fn connected(script: impl FnOnce(&mut MockTransport<1024>)) -> TestClient {
+        let mut transport = MockTransport::new(version());
+        assert!(transport.push_connack(false, 0));
+        script(&mut transport);
+        let mut client = MqttClient::new(transport, options());
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().clear_sent();
+        client
+    }
+
+    fn publish_packet(
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        packet_id: Option<u16>,
+    ) -> Vec<u8, 128> {
+        let publish = Publish {
+            packet_id,
+            ..Publish::new(topic, payload, qos)
         };
+        let mut buf = [0; 128];
+        let len = publish.encode(&mut buf, version()).unwrap();
+        Vec::from_slice(&buf[..len]).unwrap()
+    }
+
+    fn expect_publish(event: Option<MqttEvent<'_>>, topic: &str) {
+        match event {
+            Some(MqttEvent::Publish(publish)) => assert_eq!(publish.topic, topic),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn poll_returns_scripted_publish() {
+        let mut client = connected(|t| {
+            assert!(t.push_pingresp());
+            assert!(t.push_rx(&publish_packet("t", b"hi", QoS::AtMostOnce, None)));
+        });
+        embassy_futures::block_on(async {
+            // The PINGRESP is handled internally
+            assert!(client.poll().await.unwrap().is_none());
+            match client.poll().await.unwrap() {
+                Some(MqttEvent::Publish(publish)) => {
+                    assert_eq!(publish.topic, "t");
+                    assert_eq!(publish.payload, b"hi");
+                    assert_eq!(publish.qos, QoS::AtMostOnce);
+                }
+                other => panic!("{other:?}"),
+            }
+            // The script is exhausted, as if the connection dropped
+            assert!(matches!(
+                client.next_event().await.unwrap(),
+                MqttEvent::Disconnected(None)
+            ));
+        });
+    }
+
+    /// Fails reads with `MqttError::Timeout` while `timeouts` is non-zero, like
+    /// a `StreamTransport` whose read timeout is shorter than the keep-alive.
+    struct IdleTimeouts {
+        inner: MockTransport<1024>,
+        timeouts: usize,
+    }
+
+    impl MqttTransport for IdleTimeouts {
+        type Error = MqttError<MockError>;
+
+        async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            self.inner.send(buf).await.map_err(MqttError::Transport)
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.timeouts > 0 {
+                self.timeouts -= 1;
+                return Err(MqttError::Timeout);
+            }
+            self.inner.recv(buf).await.map_err(MqttError::Transport)
+        }
+    }
+
+    #[test]
+    fn next_event_waits_through_transport_read_timeouts() {
+        let mut inner = MockTransport::new(version());
+        assert!(inner.push_connack(false, 0));
+        let transport = IdleTimeouts { inner, timeouts: 0 };
+        let mut client = MqttClient::<_, 4, 256>::new(transport, options());
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            client.transport_mut().timeouts = 3;
+            let message = publish_packet("t", b"1", QoS::AtMostOnce, None);
+            assert!(client.transport_mut().inner.push_rx(&message));
+            match client.next_event().await.unwrap() {
+                MqttEvent::Publish(publish) => assert_eq!(publish.topic, "t"),
+                other => panic!("{other:?}"),
+            }
+            assert_eq!(client.transport().timeouts, 0);
+            assert_eq!(client.state(), ConnectionState::Connected);
+        });
+    }
 
-        enum PollDecision {
-            Received(usize),
-            KeepAlive,
+    /// Leaves the next `stalls` sends pending forever, like a socket whose
+    /// send buffer is full, without writing anything.
+    struct StalledSends {
+        inner: MockTransport<1024>,
+        stalls: usize,
+    }
+
+    impl MqttTransport for StalledSends {
+        type Error = MockError;
+
+        async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            if self.stalls > 0 {
+                self.stalls -= 1;
+                core::future::pending::<()>().await;
+            }
+            self.inner.send(buf).await
         }
 
-        let decision = {
-            let recv_fut = self.transport.recv(&mut self.rx_buffer);
-            let timer_fut = Timer::after(remaining);
-            match futures::future::select(core::pin::pin!(recv_fut), core::pin::pin!(timer_fut))
-                .await
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.inner.recv(buf).await
+        }
+    }
+
+    #[test]
+    fn poll_dropped_while_acknowledging_delivers_the_message_once() {
+        use core::task::{Context, Waker};
+
+        for (qos, ack) in [(QoS::AtLeastOnce, 0x40), (QoS::ExactlyOnce, 0x50)] {
+            let mut inner = MockTransport::new(version());
+            assert!(inner.push_connack(false, 0));
+            assert!(inner.push_rx(&publish_packet("t", b"1", qos, Some(7))));
+            let transport = StalledSends { inner, stalls: 0 };
+            let mut client = MqttClient::<_, 4, 256>::new(transport, options());
+            embassy_futures::block_on(client.connect()).unwrap();
+            client.transport_mut().inner.clear_sent();
+            client.transport_mut().stalls = 1;
+
+            // Reads the message, then is dropped while its ack is pending
             {
-                futures::future::Either::Left((result, _)) => result.map(PollDecision::Received),
-                futures::future::Either::Right(((), _pending_recv)) => Ok(PollDecision::KeepAlive),
+                let mut poll = core::pin::pin!(client.poll());
+                let mut cx = Context::from_waker(Waker::noop());
+                assert!(poll.as_mut().poll(&mut cx).is_pending());
             }
-        }?;
+            assert!(client.transport().inner.sent().is_empty());
 
-        match decision {
-            PollDecision::Received(n) => {
-                if n == 0 {
-                    return Ok(None);
+            // The next poll sends the ack, then returns the message
+            expect_publish(embassy_futures::block_on(client.poll()).unwrap(), "t");
+            assert_eq!(client.transport().inner.sent(), [ack, 2, 0, 7]);
+
+            // Neither is repeated
+            let exhausted = matches!(
+                embassy_futures::block_on(client.poll()),
+                Err(MqttError::Transport(MockError::Exhausted))
+            );
+            assert!(exhausted);
+            assert_eq!(client.transport().inner.sent(), [ack, 2, 0, 7]);
+        }
+    }
+
+    #[test]
+    fn reply_wait_after_a_dropped_poll_keeps_the_message_for_the_next_poll() {
+        use core::task::{Context, Waker};
+
+        let mut inner = MockTransport::new(version());
+        assert!(inner.push_connack(false, 0));
+        assert!(inner.push_rx(&publish_packet("t", b"1", QoS::AtLeastOnce, Some(7))));
+        let transport = StalledSends { inner, stalls: 0 };
+        let mut client = MqttClient::<_, 4, 256>::new(transport, options());
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().inner.clear_sent();
+        client.transport_mut().stalls = 1;
+        {
+            let mut poll = core::pin::pin!(client.poll());
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(poll.as_mut().poll(&mut cx).is_pending());
+        }
+
+        // A ping in between acknowledges the message without losing it
+        assert!(client.transport_mut().inner.push_pingresp());
+        embassy_futures::block_on(client.ping()).unwrap();
+        assert_eq!(client.transport().inner.sent(), [0xC0, 0, 0x40, 2, 0, 7]);
+        expect_publish(embassy_futures::block_on(client.poll()).unwrap(), "t");
+        assert_eq!(client.transport().inner.sent(), [0xC0, 0, 0x40, 2, 0, 7]);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn broker_disconnect_reason_reaches_poll_and_next_event() {
+        // Keep Alive Timeout
+        let disconnect = [0xE0, 1, 0x8D];
+        let mut client = connected(|t| assert!(t.push_rx(&disconnect)));
+        match embassy_futures::block_on(client.poll()) {
+            Err(MqttError::ServerDisconnect { reason_code: 0x8D }) => {}
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+
+        let mut client = connected(|t| assert!(t.push_rx(&disconnect)));
+        match embassy_futures::block_on(client.next_event()) {
+            Ok(MqttEvent::Disconnected(Some(0x8D))) => {}
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn run_until_idle_handles_buffered_messages_then_returns() {
+        let mut client = connected(|t| {
+            assert!(t.push_rx(&publish_packet("a", b"1", QoS::AtMostOnce, None)));
+            assert!(t.push_rx(&publish_packet("b", b"2", QoS::AtLeastOnce, Some(5))));
+        });
+        let mut topics: Vec<String<8>, 4> = Vec::new();
+        // Nothing is left to read afterwards; a blocking read would fail with
+        // `Exhausted` instead
+        let handled = embassy_futures::block_on(client.run_until_idle(|publish| {
+            topics
+                .push(String::try_from(publish.topic).unwrap())
+                .unwrap();
+        }))
+        .unwrap();
+        assert_eq!(handled, 2);
+        assert_eq!(topics, ["a", "b"]);
+        assert_eq!(client.transport().sent(), [0x40, 2, 0, 5]);
+
+        // Idle: returns at once with nothing handled
+        let handled = embassy_futures::block_on(client.run_until_idle(|_| unreachable!())).unwrap();
+        assert_eq!(handled, 0);
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn poll_acknowledges_qos1_publish() {
+        let mut client = connected(|t| {
+            assert!(t.push_rx(&publish_packet("t", b"1", QoS::AtLeastOnce, Some(7))));
+        });
+        embassy_futures::block_on(async {
+            expect_publish(client.poll().await.unwrap(), "t");
+        });
+        assert_eq!(client.transport().sent(), &[0x40, 2, 0, 7]);
+    }
+
+    #[test]
+    fn publish_received_while_waiting_for_puback_is_acked_and_kept() {
+        let mut client = connected(|t| {
+            assert!(t.push_rx(&publish_packet("in/1", b"a", QoS::AtLeastOnce, Some(9))));
+            assert!(t.push_rx(&publish_packet("in/2", b"b", QoS::ExactlyOnce, Some(10))));
+            assert!(t.push_puback(1));
+        });
+        embassy_futures::block_on(async {
+            client.publish("out", b"x", QoS::AtLeastOnce).await.unwrap();
+            // Acknowledged on arrival, PUBACK and PUBREC
+            let sent = client.transport().sent();
+            assert!(sent.ends_with(&[0x40, 2, 0, 9, 0x50, 2, 0, 10]), "{sent:?}");
+            let sent_len = sent.len();
+
+            expect_publish(client.poll().await.unwrap(), "in/1");
+            expect_publish(client.poll().await.unwrap(), "in/2");
+            // Not acknowledged a second time
+            assert_eq!(client.transport().sent().len(), sent_len);
+        });
+    }
+
+    #[test]
+    fn publish_received_while_waiting_for_suback_is_delivered_in_order() {
+        let mut client = connected(|t| {
+            assert!(t.push_rx(&publish_packet("a/1", b"1", QoS::AtMostOnce, None)));
+            assert!(t.push_suback(1, &[0]));
+            assert!(t.push_rx(&publish_packet("a/2", b"2", QoS::AtLeastOnce, Some(4))));
+        });
+        embassy_futures::block_on(async {
+            client.subscribe("a/#", QoS::AtLeastOnce).await.unwrap();
+            expect_publish(client.poll().await.unwrap(), "a/1");
+            expect_publish(client.poll().await.unwrap(), "a/2");
+        });
+        assert!(client.transport().sent().ends_with(&[0x40, 2, 0, 4]));
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn publish_with_response_encodes_both_properties() {
+        let mut client = connected(|_| {});
+        embassy_futures::block_on(client.publish_with_response(
+            "t",
+            b"p",
+            QoS::AtMostOnce,
+            Some("r/1"),
+            Some(&[1, 2, 3]),
+        ))
+        .unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0x30, 17, 0, 1, b't',
+            // Property length, Response Topic and Correlation Data
+            12,
+            0x08, 0, 3, b'r', b'/', b'1',
+            0x09, 0, 3, 1, 2, 3,
+            b'p',
+        ];
+        assert_eq!(client.transport().sent(), expected);
+        // Only the Topic Alias and Message Expiry room is left unused
+        let max = packet::publish_with_response_len(
+            1,
+            1,
+            Some(3),
+            Some(3),
+            QoS::AtMostOnce,
+            MqttVersion::V5,
+        );
+        assert_eq!(max, expected.len() + 3 + 5);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn publish_with_response_checks_the_tx_buffer() {
+        let mut client = connected(|_| {});
+        let data = [0u8; 300];
+        let result = embassy_futures::block_on(client.publish_with_response(
+            "t",
+            b"p",
+            QoS::AtMostOnce,
+            None,
+            Some(&data),
+        ));
+        assert!(matches!(
+            result,
+            Err(MqttError::EncodeBufferTooSmall {
+                needed: 312,
+                available: 256
+            })
+        ));
+        assert!(client.transport().sent().is_empty());
+    }
+
+    #[test]
+    fn unacknowledged_qos1_publish_is_resent_after_reconnect() {
+        // No PUBACK before the connection drops
+        let mut client = connected(|_| {});
+        let result = embassy_futures::block_on(client.publish("t", b"1", QoS::AtLeastOnce));
+        assert!(result.is_err());
+        let sent = publish_packet("t", b"1", QoS::AtLeastOnce, Some(1));
+        assert_eq!(client.transport().sent(), sent);
+
+        let transport = client.transport_mut();
+        assert!(transport.push_connack(true, 0));
+        transport.clear_sent();
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            client.transport_mut().clear_sent();
+            client.resend_inflight().await.unwrap();
+        });
+        // Same packet, with the DUP flag
+        assert_eq!(client.transport().sent()[0], 0x3A);
+        assert_eq!(client.transport().sent()[1..], sent[1..]);
+    }
+
+    #[test]
+    fn qos1_publish_too_large_to_keep_is_refused_before_sending() {
+        let mut transport = MockTransport::<1024>::new(version());
+        assert!(transport.push_connack(false, 0));
+        let mut client: MqttClient<'static, _, 4, 512> = MqttClient::new(transport, options());
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().clear_sent();
+
+        let payload = [0x55; MAX_INFLIGHT_PAYLOAD_LEN + 1];
+        let topic = "t".repeat(MAX_INFLIGHT_TOPIC_LEN + 1);
+        embassy_futures::block_on(async {
+            let result = client.publish("t", &payload, QoS::AtLeastOnce).await;
+            assert!(
+                matches!(
+                    result,
+                    Err(MqttError::Protocol(ProtocolError::PayloadTooLarge))
+                ),
+                "{result:?}"
+            );
+            let result = client.publish(&topic, b"1", QoS::AtLeastOnce).await;
+            assert!(
+                matches!(result, Err(MqttError::BufferTooSmall)),
+                "{result:?}"
+            );
+        });
+        assert!(client.transport().sent().is_empty());
+        assert!(client.inflight.entries.is_empty());
+
+        // QoS 0 isn't kept, so it isn't limited either
+        embassy_futures::block_on(client.publish("t", &payload, QoS::AtMostOnce)).unwrap();
+        // The largest publish that can be kept, left without a PUBACK
+        let result = embassy_futures::block_on(client.publish(
+            "t",
+            &payload[..MAX_INFLIGHT_PAYLOAD_LEN],
+            QoS::AtLeastOnce,
+        ));
+        assert!(matches!(
+            result,
+            Err(MqttError::Transport(MockError::Exhausted))
+        ));
+
+        let transport = client.transport_mut();
+        assert!(transport.push_connack(true, 0));
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            client.transport_mut().clear_sent();
+            client.resend_inflight().await.unwrap();
+        });
+        // Only the publish that was kept is resent, with the DUP flag
+        let sent = client.transport().sent();
+        assert_eq!(sent[0], 0x3A);
+        let remaining_len = usize::from(sent[1] & 0x7F) | usize::from(sent[2]) << 7;
+        assert_eq!(sent.len(), 3 + remaining_len);
+        assert!(sent.ends_with(&payload[..MAX_INFLIGHT_PAYLOAD_LEN]));
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn publish_with_own_topic_alias_is_not_aliased_again() {
+        let mut transport = MockTransport::new(MqttVersion::V5);
+        // Topic Alias Maximum 4
+        assert!(transport.push_rx(&[0x20, 6, 0, 0, 3, 0x22, 0, 4]));
+        let mut client = TestClient::new(transport, options().with_topic_aliases(true));
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().clear_sent();
+
+        let alias = 3u16.to_be_bytes();
+        let mut publish = Publish::new("a/b", b"1", QoS::AtMostOnce);
+        publish
+            .properties
+            .push(packet::Property {
+                id: packet::Property::TOPIC_ALIAS,
+                data: &alias,
+            })
+            .unwrap();
+        embassy_futures::block_on(client.publish_packet(publish)).unwrap();
+        // A single Topic Alias property, the caller's
+        assert_eq!(
+            client.transport().sent(),
+            [0x30, 10, 0, 3, b'a', b'/', b'b', 3, 0x23, 0, 3, b'1']
+        );
+    }
+
+    #[test]
+    fn packet_ids_wrap_past_65535_to_1() {
+        let mut ids = PacketIdAllocator { last: 65533 };
+        let allocated: Vec<u16, 4> = (0..4).map(|_| ids.allocate(|_| false)).collect();
+        assert_eq!(allocated, [65534, 65535, 1, 2]);
+
+        // Identifiers in use are skipped, across the wrap too
+        let mut ids = PacketIdAllocator { last: 65534 };
+        assert_eq!(ids.allocate(|id| id == 65535 || id == 1), 2);
+
+        // With every identifier in use, the sequence goes on without 0
+        let mut ids = PacketIdAllocator { last: 65535 };
+        assert_eq!(ids.allocate(|_| true), 1);
+
+        // A full cycle hands out every identifier once, then starts over
+        let mut ids = PacketIdAllocator::new();
+        for expected in 1..=u16::MAX {
+            assert_eq!(ids.allocate(|_| false), expected);
+        }
+        assert_eq!(ids.allocate(|_| false), 1);
+    }
+
+    /// Waits forever once the script is exhausted, like a broker that stays
+    /// connected but never answers.
+    struct Silent(MockTransport<1024>);
+
+    impl MqttTransport for Silent {
+        type Error = MockError;
+
+        async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            self.0.send(buf).await
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            if self.0.pending_rx() == 0 {
+                core::future::pending::<()>().await;
+            }
+            self.0.recv(buf).await
+        }
+    }
+
+    fn silent(options: MqttOptions<'static>) -> MqttClient<'static, Silent, 4, 256> {
+        let mut transport = MockTransport::new(version());
+        assert!(transport.push_connack(false, 0));
+        let mut client = MqttClient::new(Silent(transport), options);
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().0.clear_sent();
+        client
+    }
+
+    #[test]
+    fn qos1_publish_without_puback_times_out() {
+        let mut client = silent(options());
+        let result = embassy_futures::block_on(client.publish_with_ack_timeout(
+            "t",
+            b"1",
+            QoS::AtLeastOnce,
+            Duration::from_millis(5),
+        ));
+        assert!(matches!(result, Err(MqttError::Timeout)), "{result:?}");
+        // Still connected, with the message kept for a retransmission
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(client.inflight.entries.len(), 1);
+        let sent = publish_packet("t", b"1", QoS::AtLeastOnce, Some(1));
+        assert_eq!(client.transport().0.sent(), sent);
+    }
+
+    #[test]
+    fn ping_waits_for_the_pingresp() {
+        let mut client = connected(|t| {
+            assert!(t.push_rx(&publish_packet("in", b"1", QoS::AtLeastOnce, Some(3))));
+            assert!(t.push_pingresp());
+        });
+        embassy_futures::block_on(async {
+            client.ping().await.unwrap();
+            // The PINGREQ, then the PUBACK for the message that came first
+            assert_eq!(client.transport().sent(), [0xC0, 0, 0x40, 2, 0, 3]);
+            assert_eq!(client.transport().pending_rx(), 0);
+            assert!(client.ping_sent_at.is_none());
+            expect_publish(client.poll().await.unwrap(), "in");
+        });
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn ping_without_a_pingresp_times_out_and_disconnects() {
+        let mut client = silent(options().with_ack_timeout(Duration::from_millis(5)));
+        let result = embassy_futures::block_on(client.ping());
+        assert!(matches!(result, Err(MqttError::Timeout)), "{result:?}");
+        assert_eq!(client.transport().0.sent(), [0xC0, 0]);
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn pubacks_are_matched_by_packet_id() {
+        extern crate std;
+
+        let mut client = connected(|t| {
+            // Out of order, with an ack for nothing in flight in between
+            assert!(t.push_puback(2));
+            assert!(t.push_puback(9));
+            assert!(t.push_puback(1));
+        });
+        let observer = std::boxed::Box::leak(std::boxed::Box::new(
+            crate::test_util::CountingObserver::new(),
+        ));
+        client.set_observer(observer);
+        embassy_futures::block_on(
+            client.publish_many(&[("a", b"1", QoS::AtLeastOnce), ("b", b"2", QoS::AtLeastOnce)]),
+        )
+        .unwrap();
+        assert!(client.inflight.entries.is_empty());
+        // Only the unknown identifier is reported
+        assert_eq!(observer.errors(), 1);
+    }
+
+    #[test]
+    fn second_qos1_publish_waits_for_a_free_inflight_slot() {
+        let options = options()
+            .with_max_inflight(1)
+            .with_ack_timeout(Duration::from_millis(5));
+        let mut client = silent(options);
+        let first = publish_packet("a", b"1", QoS::AtLeastOnce, Some(1));
+        let result = embassy_futures::block_on(
+            client.publish_many(&[("a", b"1", QoS::AtLeastOnce), ("b", b"2", QoS::AtLeastOnce)]),
+        );
+        assert!(matches!(result, Err(MqttError::Timeout)), "{result:?}");
+        // The second publish was held back for the first one's PUBACK
+        assert_eq!(client.transport().0.sent(), first);
+
+        let transport = &mut client.transport_mut().0;
+        assert!(transport.push_puback(1));
+        assert!(transport.push_puback(2));
+        embassy_futures::block_on(client.publish("b", b"2", QoS::AtLeastOnce)).unwrap();
+        let second = publish_packet("b", b"2", QoS::AtLeastOnce, Some(2));
+        assert_eq!(client.transport().0.sent()[first.len()..], second);
+        assert!(client.inflight.entries.is_empty());
+    }
+
+    #[test]
+    fn suback_and_retained_publish_in_one_read_are_both_handled() {
+        let publish = Publish::new("r/1", b"on", QoS::AtMostOnce).retain(true);
+        let mut buf = [0; 32];
+        let len = publish.encode(&mut buf, version()).unwrap();
+        // The mock returns every queued byte from a single recv
+        let mut client = connected(|t| {
+            assert!(t.push_suback(1, &[0]));
+            assert!(t.push_rx(&buf[..len]));
+        });
+        embassy_futures::block_on(async {
+            client.subscribe("r/#", QoS::AtMostOnce).await.unwrap();
+            assert_eq!(client.transport().pending_rx(), 0);
+            match client.poll().await.unwrap() {
+                Some(MqttEvent::Publish(publish)) => {
+                    assert_eq!(publish.topic, "r/1");
+                    assert_eq!(publish.payload, b"on");
+                    assert!(publish.retain);
                 }
+                other => panic!("{other:?}"),
+            }
+        });
+    }
+
+    #[cfg(feature = "v5")]
+    fn aliased_publish(topic: &str, alias: u16, payload: &[u8]) -> Vec<u8, 128> {
+        let alias = alias.to_be_bytes();
+        let mut publish = Publish::new(topic, payload, QoS::AtMostOnce);
+        publish
+            .properties
+            .push(packet::Property {
+                id: packet::Property::TOPIC_ALIAS,
+                data: &alias,
+            })
+            .unwrap();
+        let mut buf = [0; 128];
+        let len = publish.encode(&mut buf, MqttVersion::V5).unwrap();
+        Vec::from_slice(&buf[..len]).unwrap()
+    }
 
-                let packet =
-                    packet::decode::<T::Error>(&self.rx_buffer[..n], self.options.version)?;
-                if let Some(MqttPacket::Publish(packet)) = packet {
-                    return Ok(Some(MqttEvent::Publish(packet)));
+    #[cfg(feature = "v5")]
+    #[test]
+    fn empty_topic_with_a_known_alias_is_resolved() {
+        let mut transport = MockTransport::new(MqttVersion::V5);
+        assert!(transport.push_connack(false, 0));
+        assert!(transport.push_rx(&aliased_publish("a/b", 2, b"1")));
+        assert!(transport.push_rx(&aliased_publish("", 2, b"2")));
+        // Past the announced Topic Alias Maximum
+        let alias = MAX_INBOUND_TOPIC_ALIASES as u16 + 1;
+        assert!(transport.push_rx(&aliased_publish("", alias, b"3")));
+        let options = options().with_inbound_topic_aliases(true);
+        let mut client = TestClient::new(transport, options);
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            for payload in [b"1", b"2"] {
+                match client.poll().await.unwrap() {
+                    Some(MqttEvent::Publish(publish)) => {
+                        assert_eq!(publish.topic, "a/b");
+                        assert_eq!(publish.payload, payload);
+                    }
+                    other => panic!("{other:?}"),
                 }
+            }
+            let result = client.poll().await;
+            assert!(
+                matches!(
+                    result,
+                    Err(MqttError::Protocol(ProtocolError::InvalidTopicAlias(a))) if a == alias
+                ),
+                "{result:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn refused_subscription_is_reported() {
+        let mut client = connected(|t| assert!(t.push_suback(1, &[0x80])));
+        let result = embassy_futures::block_on(client.subscribe("a/#", QoS::AtLeastOnce));
+        assert!(
+            matches!(
+                result,
+                Err(MqttError::SubscriptionRefused {
+                    index: 0,
+                    reason_code: 0x80
+                })
+            ),
+            "{result:?}"
+        );
+        // The connection itself is fine
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn disconnected_client_rejects_further_operations() {
+        let mut client = connected(|_| {});
+        embassy_futures::block_on(client.disconnect()).unwrap();
+        assert_eq!(client.transport().sent(), [0xE0, 0]);
+
+        embassy_futures::block_on(async {
+            let result = client.publish("t", b"1", QoS::AtMostOnce).await;
+            assert!(matches!(result, Err(MqttError::NotConnected)), "{result:?}");
+            let result = client.subscribe("t", QoS::AtMostOnce).await;
+            assert!(matches!(result, Err(MqttError::NotConnected)), "{result:?}");
+            let result = client.disconnect().await;
+            assert!(matches!(result, Err(MqttError::NotConnected)), "{result:?}");
+        });
+        // Nothing else was sent
+        assert_eq!(client.transport().sent(), [0xE0, 0]);
+    }
+
+    #[test]
+    fn state_follows_connect_and_disconnect() {
+        let mut transport = MockTransport::<1024>::new(version());
+        assert!(transport.push_connack(false, 0));
+        let mut client = TestClient::new(transport, options());
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            assert_eq!(client.state(), ConnectionState::Connected);
+            client.disconnect().await.unwrap();
+            assert_eq!(client.state(), ConnectionState::Disconnected);
+            assert!(client.transport_mut().push_connack(false, 0));
+            client.connect().await.unwrap();
+            assert_eq!(client.state(), ConnectionState::Connected);
+            client.disconnect().await.unwrap();
+        });
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn session_uptime_restarts_with_each_connection() {
+        let clock = MockClock::start();
+        let mut transport = MockTransport::<1024>::new(version());
+        assert!(transport.push_connack(false, 0));
+        let mut client = TestClient::new(transport, options());
+        assert_eq!(client.session_uptime(), None);
+
+        embassy_futures::block_on(client.connect()).unwrap();
+        assert_eq!(client.session_uptime(), Some(Duration::from_secs(0)));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(client.session_uptime(), Some(Duration::from_secs(5)));
 
-                Ok(None)
+        embassy_futures::block_on(client.disconnect()).unwrap();
+        assert_eq!(client.session_uptime(), None);
+        clock.advance(Duration::from_secs(1));
+
+        assert!(client.transport_mut().push_connack(false, 0));
+        embassy_futures::block_on(client.connect()).unwrap();
+        assert_eq!(client.session_uptime(), Some(Duration::from_secs(0)));
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(client.session_uptime(), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn publish_requires_connected() {
+        let mut client = connected(|_| {});
+        for state in [
+            ConnectionState::Disconnected,
+            ConnectionState::Connecting,
+            ConnectionState::Disconnecting,
+        ] {
+            client.state = state;
+            let result = embassy_futures::block_on(client.publish("t", b"1", QoS::AtLeastOnce));
+            assert!(matches!(result, Err(MqttError::NotConnected)), "{result:?}");
+            assert_eq!(client.state(), state);
+        }
+        assert!(client.transport().sent().is_empty());
+        assert!(client.inflight.entries.is_empty());
+    }
+
+    #[test]
+    fn max_outgoing_len_matches_the_encoded_connect() {
+        let will = LastWill {
+            topic: "dev/status",
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        };
+        for version in [MqttVersion::V3, MqttVersion::V5] {
+            if version == MqttVersion::V5 && !cfg!(feature = "v5") {
+                continue;
             }
-            PollDecision::KeepAlive => {
-                #[cfg(feature = "esp32-log")]
-                esp_println::println!("MQTT: Sending PINGREQ");
-                self._send_packet(PingReq).await?;
-                #[cfg(feature = "esp32-log")]
-                esp_println::println!("MQTT: PINGREQ sent");
-                Ok(None)
+            let mut transport = MockTransport::new(version);
+            assert!(transport.push_connack(false, 0));
+            let mut options = MqttOptions::new("sensor-1")
+                .with_credentials("user", "secret")
+                .with_last_will(will);
+            #[cfg(feature = "v5")]
+            {
+                options = options.with_will_delay(30);
             }
+            options.version = version;
+            let mut client = TestClient::new(transport, options);
+
+            // Tiny messages leave the CONNECT as the largest packet
+            let max = client.max_outgoing_len(1, 1);
+            embassy_futures::block_on(client.connect()).unwrap();
+            assert_eq!(max, client.transport().sent().len(), "{version:?}");
         }
     }
 
-    fn get_next_packet_id(&mut self) -> u16 {
-        self.next_packet_id = self.next_packet_id.wrapping_add(1);
-        if self.next_packet_id == 0 {
-            self.next_packet_id = 1;
+    #[test]
+    fn options_builder_defaults_match_new() {
+        let built = MqttOptions::builder().client_id("cid").build().unwrap();
+        let new = MqttOptions::new("cid");
+        for options in [&built, &new] {
+            assert_eq!(options.client_id, "cid");
+            assert_eq!(options.version, MqttVersion::V3);
+            assert_eq!(options.keep_alive, Duration::from_secs(60));
+            assert_eq!(options.connect_timeout, Duration::from_secs(10));
+            assert_eq!(options.ack_timeout, Duration::from_secs(10));
+            assert!(options.clean_session);
+            assert!(options.username.is_none() && options.password.is_none());
+            assert!(options.will.is_none());
+            assert_eq!(usize::from(options.max_inflight), MAX_INFLIGHT);
         }
-        self.next_packet_id
     }
-}
 
-/// Represents an event received from the MQTT broker.
-/// The lifetime `'p` indicates that the event borrows data from the client's
-/// buffer and is only valid for the duration of the `poll` call.
-#[derive(Debug)]
-pub enum MqttEvent<'p> {
-    Publish(Publish<'p>),
+    #[test]
+    fn options_builder_sets_every_option() {
+        let will = LastWill {
+            topic: "dev/status",
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        };
+        let builder = MqttOptions::builder()
+            .client_id("light")
+            .keep_alive(30)
+            .connect_timeout(5)
+            .ack_timeout(3)
+            .max_inflight(2)
+            .clean_session(false)
+            .credentials("user", "secret")
+            .will(will);
+        #[cfg(feature = "v5")]
+        let builder = builder
+            .version(MqttVersion::V5)
+            .version_fallback(true)
+            .will_delay(15)
+            .topic_aliases(true)
+            .inbound_topic_aliases(true);
+        let options = builder.build().unwrap();
+
+        assert_eq!(options.client_id, "light");
+        assert_eq!(options.keep_alive, Duration::from_secs(30));
+        assert_eq!(options.connect_timeout, Duration::from_secs(5));
+        assert_eq!(options.ack_timeout, Duration::from_secs(3));
+        assert_eq!(options.max_inflight, 2);
+        assert!(!options.clean_session);
+        assert_eq!(options.username.as_deref(), Some("user"));
+        assert_eq!(options.password.as_deref(), Some("secret"));
+        let will = options.will.unwrap();
+        assert_eq!((will.topic, will.payload), ("dev/status", &b"offline"[..]));
+        assert_eq!((will.qos, will.retain), (QoS::AtLeastOnce, true));
+        #[cfg(feature = "v5")]
+        {
+            assert_eq!(options.version, MqttVersion::V5);
+            assert!(options.version_fallback);
+            assert_eq!(options.will_delay, Some(15));
+            assert!(options.topic_aliases && options.inbound_topic_aliases);
+        }
+    }
+
+    #[test]
+    fn options_builder_rejects_invalid_settings() {
+        let long = "x".repeat(MAX_PASSWORD_LEN + 1);
+        let build = |builder: MqttOptionsBuilder<'_>| builder.build().err();
+        assert_eq!(
+            build(MqttOptions::builder().clean_session(false)),
+            Some(OptionsError::EmptyClientId)
+        );
+        assert_eq!(
+            build(MqttOptions::builder().credentials(&long, "secret")),
+            Some(OptionsError::UsernameTooLong)
+        );
+        assert_eq!(
+            build(MqttOptions::builder().credentials("user", &long)),
+            Some(OptionsError::PasswordTooLong)
+        );
+    }
+
+    #[test]
+    fn options_built_for_a_small_tx_buffer_reject_a_client_id_that_does_not_fit() {
+        #[cfg_attr(not(feature = "v5"), allow(unused_mut))]
+        let mut builder = MqttOptions::builder().client_id("kitchen-sensor-0123456789");
+        #[cfg(feature = "v5")]
+        {
+            builder = builder.version(version());
+        }
+        let fits = builder.build_for::<64>().unwrap();
+        let len = fits.connect_len(None);
+        assert_eq!(
+            builder.build_for::<32>().err(),
+            Some(OptionsError::ClientIdTooLong)
+        );
+
+        // The length checked is the CONNECT that `connect` sends
+        let mut transport = MockTransport::<1024>::new(version());
+        assert!(transport.push_connack(false, 0));
+        let options = builder.build().unwrap();
+        let mut client = MqttClient::<_, 4, 256>::new(transport, options);
+        assert!(embassy_futures::block_on(client.connect()).is_ok());
+        assert_eq!(len, client.transport.sent().len());
+    }
+
+    /// Connects a client with a 32 byte receive buffer, then receives a
+    /// publish that is `len` bytes long.
+    fn receive_publish_of_len(len: usize) -> Result<usize, MqttError<MockError>> {
+        let overhead = publish_packet("t", b"", QoS::AtMostOnce, None).len();
+        let payload = [b'x'; 32];
+        let mut transport = MockTransport::<1024>::new(version());
+        assert!(transport.push_connack(false, 0));
+        assert!(transport.push_rx(&publish_packet(
+            "t",
+            &payload[..len - overhead],
+            QoS::AtMostOnce,
+            None
+        )));
+        let mut client = MqttClient::<_, 4, 256, 32>::new(transport, options());
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            match client.next_event().await? {
+                MqttEvent::Publish(msg) => Ok(msg.payload_len()),
+                MqttEvent::Disconnected(_) => panic!("disconnected"),
+            }
+        })
+    }
+
+    #[test]
+    fn publish_filling_the_receive_buffer_is_delivered() {
+        let overhead = publish_packet("t", b"", QoS::AtMostOnce, None).len();
+        assert_eq!(receive_publish_of_len(32).unwrap(), 32 - overhead);
+    }
+
+    #[test]
+    fn publish_larger_than_the_receive_buffer_is_rejected() {
+        let result = receive_publish_of_len(33);
+        assert!(
+            matches!(result, Err(MqttError::BufferTooSmall)),
+            "{result:?}"
+        );
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats_count_sent_publishes() {
+        let mut client = connected(|t| assert!(t.push_puback(1)));
+        assert_eq!(client.stats().publishes_sent, 0);
+        let connected_bytes = client.stats().bytes_sent;
+
+        embassy_futures::block_on(async {
+            client.publish("a", b"1", QoS::AtMostOnce).await.unwrap();
+            client.publish("a", b"2", QoS::AtLeastOnce).await.unwrap();
+        });
+        let stats = client.stats();
+        assert_eq!(stats.publishes_sent, 2);
+        assert_eq!(
+            stats.bytes_sent - connected_bytes,
+            client.transport().sent().len() as u64
+        );
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn v5_connack_refusal_is_mapped_with_the_v5_table() {
+        let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+        assert!(transport.push_connack(false, 0x8C));
+        let mut client: TestClient = MqttClient::new(transport, options());
+        let result = embassy_futures::block_on(client.connect());
+        assert!(
+            matches!(
+                result,
+                Err(MqttError::ConnectionRefused(ConnectReasonCode::V5(
+                    V5ConnectReasonCode::BadAuthenticationMethod
+                )))
+            ),
+            "{result:?}"
+        );
+    }
+
+    /// Returns a successful v5 CONNACK carrying the encoded `properties`.
+    #[cfg(feature = "v5")]
+    fn connack_with_properties(properties: &[u8]) -> Vec<u8, 64> {
+        let mut packet = Vec::new();
+        let remaining_len = 3 + properties.len() as u8;
+        packet
+            .extend_from_slice(&[0x20, remaining_len, 0, 0, properties.len() as u8])
+            .unwrap();
+        packet.extend_from_slice(properties).unwrap();
+        packet
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn client_id_assigned_by_the_broker_is_adopted() {
+        let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+        let property = [
+            crate::packet::Property::ASSIGNED_CLIENT_IDENTIFIER,
+            0,
+            5,
+            b'a',
+            b'u',
+            b't',
+            b'o',
+            b'1',
+        ];
+        assert!(transport.push_rx(&connack_with_properties(&property)));
+        let options = MqttOptions::new("").with_version(MqttVersion::V5);
+        let mut client: TestClient = MqttClient::new(transport, options);
+        assert_eq!(client.client_id(), "");
+
+        embassy_futures::block_on(client.connect()).unwrap();
+        assert_eq!(client.client_id(), "auto1");
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn repeated_publish_sends_only_the_topic_alias() {
+        let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+        // Topic Alias Maximum 1
+        assert!(transport.push_rx(&connack_with_properties(&[0x22, 0, 1])));
+        let mut client = TestClient::new(transport, options().with_topic_aliases(true));
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            client.transport_mut().clear_sent();
+            client.publish("a/b", b"1", QoS::AtMostOnce).await.unwrap();
+            client.publish("a/b", b"2", QoS::AtMostOnce).await.unwrap();
+            client.publish("c/d", b"3", QoS::AtMostOnce).await.unwrap();
+        });
+
+        let sent = client.transport().sent();
+        // The full topic assigns alias 1
+        let (first, rest) = sent.split_at(12);
+        assert_eq!(
+            first,
+            [0x30, 10, 0, 3, b'a', b'/', b'b', 3, 0x23, 0, 1, b'1']
+        );
+        // An empty topic with the alias
+        let (second, third) = rest.split_at(9);
+        assert_eq!(second, [0x30, 7, 0, 0, 3, 0x23, 0, 1, b'2']);
+        // No alias left for another topic
+        assert_eq!(third, [0x30, 7, 0, 3, b'c', b'/', b'd', 0, b'3']);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn server_keep_alive_replaces_the_requested_interval() {
+        let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+        // Server Keep Alive of 1 second
+        assert!(transport.push_rx(&connack_with_properties(&[0x13, 0, 1])));
+        let mut client = MqttClient::<_, 4, 256>::new(Silent(transport), options());
+        embassy_futures::block_on(client.connect()).unwrap();
+        assert_eq!(client.keep_alive(), Duration::from_secs(1));
+        client.transport_mut().0.clear_sent();
+
+        // The PINGREQ is due after the granted second, not the requested minute
+        let wait = embassy_time::Timer::after_millis(1500);
+        let timed_out = matches!(
+            embassy_futures::block_on(embassy_futures::select::select(client.next_event(), wait)),
+            embassy_futures::select::Either::Second(())
+        );
+        assert!(timed_out);
+        assert_eq!(client.transport().0.sent(), [0xC0, 0]);
+    }
+
+    #[test]
+    fn publish_many_flushes_each_burst_once() {
+        let mut client = connected(|t| {
+            assert!(t.push_puback(1));
+            assert!(t.push_puback(2));
+        });
+        let msgs: [(&str, &[u8], QoS); 3] = [
+            ("a", b"1", QoS::AtMostOnce),
+            ("b", b"2", QoS::AtLeastOnce),
+            ("c", b"3", QoS::AtLeastOnce),
+        ];
+        embassy_futures::block_on(client.publish_many(&msgs)).unwrap();
+        assert_eq!(client.transport().flushes(), 1);
+        assert!(client.inflight.entries.is_empty());
+    }
+
+    #[test]
+    fn connect_times_out_without_a_connack() {
+        let options = options().with_connect_timeout(Duration::from_millis(20));
+        let mut client =
+            MqttClient::<_, 4, 256>::new(Silent(MockTransport::new(version())), options);
+        let result = embassy_futures::block_on(client.connect());
+        assert!(matches!(result, Err(MqttError::Timeout)), "{result:?}");
+        // The CONNECT went out, and the client isn't left half connected
+        assert_eq!(client.transport().0.sent()[0], 0x10);
+        assert_eq!(client.state, ConnectionState::Disconnected);
+    }
+
+    /// A message passed to `publish_many`.
+    type Message = (&'static str, &'static [u8], QoS);
+
+    /// The messages `publish_many` tests send, with the bytes they encode to
+    /// when the QoS 1 publishes get identifiers 1 and 2.
+    fn burst() -> ([Message; 3], Vec<u8, 128>) {
+        let msgs: [Message; 3] = [
+            ("a", b"1", QoS::AtLeastOnce),
+            ("b", b"2", QoS::AtMostOnce),
+            ("c", b"3", QoS::AtLeastOnce),
+        ];
+        let mut sent = publish_packet("a", b"1", QoS::AtLeastOnce, Some(1));
+        sent.extend_from_slice(&publish_packet("b", b"2", QoS::AtMostOnce, None))
+            .unwrap();
+        sent.extend_from_slice(&publish_packet("c", b"3", QoS::AtLeastOnce, Some(2)))
+            .unwrap();
+        (msgs, sent)
+    }
+
+    #[test]
+    fn publish_many_sends_every_message_then_awaits_the_pubacks() {
+        let mut client = connected(|t| {
+            assert!(t.push_puback(1));
+            assert!(t.push_puback(2));
+        });
+        let (msgs, sent) = burst();
+        embassy_futures::block_on(client.publish_many(&msgs)).unwrap();
+        assert_eq!(client.transport().sent(), sent);
+        // Both PUBACKs were read
+        assert_eq!(client.transport().pending_rx(), 0);
+        assert!(client.inflight.entries.is_empty());
+    }
+
+    #[test]
+    fn publish_many_fails_when_a_puback_is_missing() {
+        let mut client = silent(options().with_ack_timeout(Duration::from_millis(5)));
+        assert!(client.transport_mut().0.push_puback(1));
+        let (msgs, sent) = burst();
+        let result = embassy_futures::block_on(client.publish_many(&msgs));
+        assert!(matches!(result, Err(MqttError::Timeout)), "{result:?}");
+        assert_eq!(client.transport().0.sent(), sent);
+        // Only the unacknowledged message is kept for a retransmission
+        assert_eq!(client.inflight.entries.len(), 1);
+        assert_eq!(client.inflight.entries[0].packet_id, 2);
+    }
+
+    #[test]
+    fn clear_retained_publishes_an_empty_retained_message() {
+        let mut client = connected(|t| assert!(t.push_puback(1)));
+        embassy_futures::block_on(client.clear_retained("a/b")).unwrap();
+        let mut expected = publish_packet("a/b", b"", QoS::AtLeastOnce, Some(1));
+        // The retain flag
+        expected[0] |= 0x01;
+        assert_eq!(client.transport().sent(), expected);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn publish_over_the_maximum_packet_size_is_never_written() {
+        let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+        // Maximum Packet Size of 16 bytes
+        assert!(transport.push_rx(&connack_with_properties(&[0x27, 0, 0, 0, 16])));
+        let mut client = TestClient::new(transport, options());
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().clear_sent();
+
+        let result = embassy_futures::block_on(client.publish("a/b", &[0; 20], QoS::AtLeastOnce));
+        assert!(
+            matches!(
+                result,
+                Err(MqttError::PacketTooLarge {
+                    size: 30,
+                    maximum: 16
+                })
+            ),
+            "{result:?}"
+        );
+        assert!(client.transport().sent().is_empty());
+        assert!(client.inflight.entries.is_empty());
+
+        // A packet of exactly the maximum goes out
+        embassy_futures::block_on(client.publish("a/b", &[0; 8], QoS::AtMostOnce)).unwrap();
+        assert_eq!(client.transport().sent().len(), 16);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn retained_publish_is_rejected_without_retain_available() {
+        let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+        // Retain Available 0
+        assert!(transport.push_rx(&connack_with_properties(&[0x25, 0])));
+        let mut client = TestClient::new(transport, options());
+        embassy_futures::block_on(client.connect()).unwrap();
+        assert!(!client.server_capabilities().retain_available);
+        client.transport_mut().clear_sent();
+
+        let result = embassy_futures::block_on(client.publish_with_retain(
+            "a/b",
+            b"1",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        assert!(
+            matches!(
+                result,
+                Err(MqttError::Protocol(ProtocolError::RetainNotSupported))
+            ),
+            "{result:?}"
+        );
+        assert!(client.transport().sent().is_empty());
+        assert!(client.inflight.entries.is_empty());
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn refused_v5_connect_falls_back_to_v3() {
+        // A v5 reason code 0x84, and a v3.1.1-only broker's return code 0x01
+        for refusal in [&[0x20, 3, 0, 0x84, 0][..], &[0x20, 2, 0, 1]] {
+            let mut transport = MockTransport::<1024>::new(MqttVersion::V5);
+            assert!(transport.push_rx(refusal));
+            assert!(transport.push_rx(&[0x20, 2, 0, 0]));
+            let options = MqttOptions::new("cid")
+                .with_version(MqttVersion::V5)
+                .with_version_fallback(true);
+            let mut client = TestClient::new(transport, options);
+
+            embassy_futures::block_on(client.connect()).unwrap();
+            assert_eq!(client.state(), ConnectionState::Connected);
+            assert_eq!(client.version(), MqttVersion::V3);
+            assert_eq!(client.options.version, MqttVersion::V3);
+
+            // Two CONNECTs, at protocol level 5 and then 4
+            let sent = client.transport().sent();
+            let first_len = 2 + usize::from(sent[1]);
+            let (first, second) = sent.split_at(first_len);
+            assert_eq!((first[0], first[8]), (0x10, 5));
+            assert_eq!((second[0], second[8]), (0x10, 4));
+            assert_eq!(second.len(), 2 + usize::from(second[1]));
+        }
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn v3_mode_sends_no_properties() {
+        let mut transport = MockTransport::new(MqttVersion::V3);
+        assert!(transport.push_connack(false, 0));
+        let options = MqttOptions::new("cid")
+            .with_will_delay(30)
+            .with_topic_aliases(true);
+        let mut client = TestClient::new(transport, options);
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            // Protocol level 4, without the v5 property length after the
+            // keep-alive or the will delay
+            assert_eq!(
+                client.transport_mut().sent(),
+                [
+                    0x10, 15, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 3, b'c', b'i', b'd'
+                ]
+            );
+            client.transport_mut().clear_sent();
+
+            // Neither the message expiry nor a topic alias is framed
+            client
+                .publish_with_expiry("t", b"x", QoS::AtMostOnce, Some(3600))
+                .await
+                .unwrap();
+            client.publish("t", b"y", QoS::AtMostOnce).await.unwrap();
+            assert_eq!(
+                client.transport_mut().sent(),
+                [0x30, 4, 0, 1, b't', b'x', 0x30, 4, 0, 1, b't', b'y']
+            );
+        });
+        assert_eq!(client.version(), MqttVersion::V3);
+    }
+
+    #[test]
+    fn subscription_list_follows_subscribe_and_unsubscribe() {
+        let mut client = connected(|t| {
+            assert!(t.push_suback(1, &[1]));
+            assert!(t.push_suback(2, &[0]));
+            assert!(t.push_suback(3, &[0]));
+            assert!(t.push_unsuback(4, &[0]));
+        });
+        embassy_futures::block_on(async {
+            client.subscribe("a/#", QoS::AtLeastOnce).await.unwrap();
+            client.subscribe("b", QoS::AtMostOnce).await.unwrap();
+            // Subscribing again replaces the granted QoS instead of adding an entry
+            client.subscribe("a/#", QoS::AtMostOnce).await.unwrap();
+            assert!(
+                client
+                    .subscriptions()
+                    .eq([("a/#", QoS::AtMostOnce), ("b", QoS::AtMostOnce)])
+            );
+
+            client.unsubscribe("a/#").await.unwrap();
+            assert!(client.subscriptions().eq([("b", QoS::AtMostOnce)]));
+
+            // A filter that is no longer tracked is refused without a packet
+            client.transport_mut().clear_sent();
+            assert!(matches!(
+                client.unsubscribe("a/#").await,
+                Err(MqttError::NotSubscribed)
+            ));
+            assert!(client.transport_mut().sent().is_empty());
+        });
+    }
+
+    #[test]
+    fn subscription_list_counts_only_new_filters_against_its_capacity() {
+        let mut list = SubscriptionList::<2>::default();
+        let options = SubscribeOptions::new(QoS::AtMostOnce);
+        list.insert("a", QoS::AtMostOnce);
+        list.insert("b", QoS::AtMostOnce);
+        assert!(list.has_room_for(&[("a", options), ("b", options)]));
+        assert!(!list.has_room_for(&[("a", options), ("c", options)]));
+
+        list.remove("a");
+        assert!(!list.contains("a"));
+        assert!(list.has_room_for(&[("c", options)]));
+
+        let long = [b'x'; MAX_FILTER_LEN + 1];
+        let long = core::str::from_utf8(&long).unwrap();
+        assert!(!list.has_room_for(&[(long, options)]));
+    }
 }
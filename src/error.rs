@@ -4,7 +4,7 @@
 //! providing detailed information about potential failures, from transport issues
 //! to protocol violations.
 
-use crate::transport;
+use crate::transport::{self, StreamError, TcpError};
 
 /// A placeholder error type used in generic contexts where the specific transport
 /// error is not yet known. This is a common pattern for implementing `encode` methods
@@ -29,12 +29,46 @@ pub enum MqttError<T> {
     Protocol(ProtocolError),
     /// The connection was refused by the broker. The enclosed code provides the reason.
     ConnectionRefused(ConnectReasonCode),
+    /// The broker rejected a subscription in a SUBACK, or an unsubscribe in
+    /// an MQTT v5 UNSUBACK.
+    SubscriptionRefused {
+        /// Index of the rejected topic within the request.
+        index: usize,
+        /// The failure reason code returned by the broker (`>= 0x80`).
+        reason_code: u8,
+    },
     /// The client is not currently connected to the broker.
     NotConnected,
+    /// The topic filter to unsubscribe from isn't an active subscription.
+    NotSubscribed,
     /// The buffer provided for an operation was too small.
     BufferTooSmall,
+    /// The buffer is too small for the PUBLISH or SUBSCRIBE packet being
+    /// encoded. Nothing was written.
+    EncodeBufferTooSmall {
+        /// Size of the encoded packet in bytes.
+        needed: usize,
+        /// Size of the buffer in bytes.
+        available: usize,
+    },
     /// An operation timed out.
     Timeout,
+    /// The packet exceeds the Maximum Packet Size announced by the MQTT v5
+    /// broker, which would close the connection instead of accepting it.
+    #[cfg(feature = "v5")]
+    PacketTooLarge {
+        /// Size of the encoded packet in bytes.
+        size: usize,
+        /// The broker's limit in bytes.
+        maximum: u32,
+    },
+    /// The MQTT v5 broker closed the connection with a DISCONNECT packet.
+    #[cfg(feature = "v5")]
+    ServerDisconnect {
+        /// The reason code sent by the broker, e.g. `0x8D` for a keep-alive
+        /// timeout or `0x93` for an exceeded Receive Maximum.
+        reason_code: u8,
+    },
 }
 
 /// Implements the `From` trait to allow for automatic conversion of any transport
@@ -46,27 +80,129 @@ impl<T: transport::TransportError> From<T> for MqttError<T> {
     }
 }
 
-impl<T: transport::TransportError> MqttError<T> {
+/// Returned by `MqttError::try_cast_transport_error` when the error being cast
+/// wraps a transport error, whose concrete type can't be converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CastError;
+
+impl<T: core::fmt::Debug> MqttError<T> {
     /// A helper method to convert an `MqttError` with a placeholder transport error
     /// into an `MqttError` with a specific transport error type `T`.
     ///
     /// This is used to bridge the gap between generic packet encoding functions
     /// and the specific error type required by the client's `Result`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `other` is `MqttError::Transport`. Packet logic never produces
+    /// transport errors, so this path is unreachable for the errors this method
+    /// is used with; use `try_cast_transport_error` when that can't be guaranteed.
     pub fn cast_transport_error<E: transport::TransportError>(other: MqttError<E>) -> MqttError<T> {
+        match Self::try_cast_transport_error(other) {
+            Ok(err) => err,
+            Err(CastError) => unreachable!("Cannot cast a transport error"),
+        }
+    }
+
+    /// Converts an `MqttError` over transport error `E` into one over `T`.
+    ///
+    /// Returns `CastError` for the `Transport` variant, as a value of the
+    /// concrete type `E` can't be turned into a `T`.
+    pub fn try_cast_transport_error<E: transport::TransportError>(
+        other: MqttError<E>,
+    ) -> Result<MqttError<T>, CastError> {
         match other {
-            MqttError::Protocol(p) => MqttError::Protocol(p),
-            MqttError::ConnectionRefused(c) => MqttError::ConnectionRefused(c),
+            MqttError::Protocol(p) => Ok(MqttError::Protocol(p)),
+            MqttError::ConnectionRefused(c) => Ok(MqttError::ConnectionRefused(c)),
+            MqttError::SubscriptionRefused { index, reason_code } => {
+                Ok(MqttError::SubscriptionRefused { index, reason_code })
+            }
+            MqttError::NotConnected => Ok(MqttError::NotConnected),
+            MqttError::NotSubscribed => Ok(MqttError::NotSubscribed),
+            MqttError::BufferTooSmall => Ok(MqttError::BufferTooSmall),
+            MqttError::EncodeBufferTooSmall { needed, available } => {
+                Ok(MqttError::EncodeBufferTooSmall { needed, available })
+            }
+            MqttError::Timeout => Ok(MqttError::Timeout),
+            #[cfg(feature = "v5")]
+            MqttError::PacketTooLarge { size, maximum } => {
+                Ok(MqttError::PacketTooLarge { size, maximum })
+            }
+            #[cfg(feature = "v5")]
+            MqttError::ServerDisconnect { reason_code } => {
+                Ok(MqttError::ServerDisconnect { reason_code })
+            }
+            MqttError::Transport(_) => Err(CastError),
+        }
+    }
+}
+
+impl MqttError<StreamError<TcpError>> {
+    /// Returns the TCP socket error of a client over `TcpTransport`, if this
+    /// is one.
+    pub fn as_tcp_error(&self) -> Option<&TcpError> {
+        match self {
+            MqttError::Transport(StreamError::Io(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> MqttError<T> {
+    /// Borrows the error with its transport error as `&dyn Debug`, for code
+    /// that isn't generic over the transport, such as an `MqttObserver`.
+    pub fn as_dyn(&self) -> MqttError<&dyn core::fmt::Debug> {
+        match self {
+            MqttError::Transport(e) => MqttError::Transport(e),
+            MqttError::Protocol(p) => MqttError::Protocol(*p),
+            MqttError::ConnectionRefused(c) => MqttError::ConnectionRefused(*c),
+            MqttError::SubscriptionRefused { index, reason_code } => {
+                MqttError::SubscriptionRefused {
+                    index: *index,
+                    reason_code: *reason_code,
+                }
+            }
             MqttError::NotConnected => MqttError::NotConnected,
+            MqttError::NotSubscribed => MqttError::NotSubscribed,
             MqttError::BufferTooSmall => MqttError::BufferTooSmall,
+            MqttError::EncodeBufferTooSmall { needed, available } => {
+                MqttError::EncodeBufferTooSmall {
+                    needed: *needed,
+                    available: *available,
+                }
+            }
             MqttError::Timeout => MqttError::Timeout,
-            // The transport variant can't be cast, as we don't know the concrete type `E`.
-            // This method is designed for errors originating from packet logic, which
-            // should not produce transport errors directly.
-            MqttError::Transport(_) => panic!("Cannot cast a transport error"),
+            #[cfg(feature = "v5")]
+            MqttError::PacketTooLarge { size, maximum } => MqttError::PacketTooLarge {
+                size: *size,
+                maximum: *maximum,
+            },
+            #[cfg(feature = "v5")]
+            MqttError::ServerDisconnect { reason_code } => MqttError::ServerDisconnect {
+                reason_code: *reason_code,
+            },
         }
     }
 }
 
+/// Returned by `MqttOptionsBuilder::build` when the options can't be used
+/// for a CONNECT packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OptionsError {
+    /// The client identifier is longer than an MQTT string can encode, or
+    /// too long for the CONNECT to fit the TX buffer given to
+    /// `MqttOptionsBuilder::build_for`.
+    ClientIdTooLong,
+    /// An empty client identifier is only allowed with a clean session.
+    EmptyClientId,
+    /// The username exceeds the options' inline username storage.
+    UsernameTooLong,
+    /// The password exceeds the options' inline password storage.
+    PasswordTooLong,
+}
+
 /// Represents the reason codes for a connection refusal (`CONNACK`).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -84,10 +220,108 @@ pub enum ConnectReasonCode {
     BadUserNameOrPassword = 4,
     /// The client is not authorized to connect.
     NotAuthorized = 5,
+    /// A reason code from an MQTT v5 CONNACK, which uses its own table.
+    #[cfg(feature = "v5")]
+    V5(V5ConnectReasonCode),
     /// An unknown or unspecified error occurred.
     Other(u8),
 }
 
+impl ConnectReasonCode {
+    /// Interprets the reason code of an MQTT v5 CONNACK.
+    ///
+    /// `From<u8>` applies the v3.1.1 return code table, in which the same
+    /// numbers mean different things.
+    #[cfg(feature = "v5")]
+    pub fn from_v5(val: u8) -> Self {
+        match val {
+            0 => Self::Success,
+            _ => Self::V5(V5ConnectReasonCode::from(val)),
+        }
+    }
+}
+
+/// The reason codes of an MQTT v5 CONNACK (MQTT 5.0, section 3.2.2.2).
+#[cfg(feature = "v5")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum V5ConnectReasonCode {
+    /// 0x80: The broker doesn't want to reveal the reason.
+    UnspecifiedError,
+    /// 0x81: The CONNECT packet could not be parsed.
+    MalformedPacket,
+    /// 0x82: The CONNECT packet violates the specification.
+    ProtocolError,
+    /// 0x83: The CONNECT is valid but not accepted by this broker.
+    ImplementationSpecificError,
+    /// 0x84: The broker does not support the requested protocol version.
+    UnsupportedProtocolVersion,
+    /// 0x85: The client identifier is not allowed.
+    ClientIdentifierNotValid,
+    /// 0x86: The username or password was not accepted.
+    BadUserNameOrPassword,
+    /// 0x87: The client is not authorized to connect.
+    NotAuthorized,
+    /// 0x88: The broker is unavailable.
+    ServerUnavailable,
+    /// 0x89: The broker is busy; try again later.
+    ServerBusy,
+    /// 0x8A: The client has been banned.
+    Banned,
+    /// 0x8C: The authentication method is not supported.
+    BadAuthenticationMethod,
+    /// 0x90: The Will Topic is malformed.
+    TopicNameInvalid,
+    /// 0x95: The CONNECT packet exceeds the broker's maximum packet size.
+    PacketTooLarge,
+    /// 0x97: An implementation or administrative limit was exceeded.
+    QuotaExceeded,
+    /// 0x99: The Will Payload doesn't match its Payload Format Indicator.
+    PayloadFormatInvalid,
+    /// 0x9A: The broker does not support retained messages.
+    RetainNotSupported,
+    /// 0x9B: The broker does not support the requested Will QoS.
+    QosNotSupported,
+    /// 0x9C: The client should temporarily use another broker.
+    UseAnotherServer,
+    /// 0x9D: The client should permanently use another broker.
+    ServerMoved,
+    /// 0x9F: The connection rate limit was exceeded.
+    ConnectionRateExceeded,
+    /// A reason code not defined by the specification.
+    Other(u8),
+}
+
+#[cfg(feature = "v5")]
+impl From<u8> for V5ConnectReasonCode {
+    fn from(val: u8) -> Self {
+        match val {
+            0x80 => Self::UnspecifiedError,
+            0x81 => Self::MalformedPacket,
+            0x82 => Self::ProtocolError,
+            0x83 => Self::ImplementationSpecificError,
+            0x84 => Self::UnsupportedProtocolVersion,
+            0x85 => Self::ClientIdentifierNotValid,
+            0x86 => Self::BadUserNameOrPassword,
+            0x87 => Self::NotAuthorized,
+            0x88 => Self::ServerUnavailable,
+            0x89 => Self::ServerBusy,
+            0x8A => Self::Banned,
+            0x8C => Self::BadAuthenticationMethod,
+            0x90 => Self::TopicNameInvalid,
+            0x95 => Self::PacketTooLarge,
+            0x97 => Self::QuotaExceeded,
+            0x99 => Self::PayloadFormatInvalid,
+            0x9A => Self::RetainNotSupported,
+            0x9B => Self::QosNotSupported,
+            0x9C => Self::UseAnotherServer,
+            0x9D => Self::ServerMoved,
+            0x9F => Self::ConnectionRateExceeded,
+            _ => Self::Other(val),
+        }
+    }
+}
+
 impl From<u8> for ConnectReasonCode {
     fn from(val: u8) -> Self {
         match val {
@@ -118,7 +352,315 @@ pub enum ProtocolError {
     PayloadTooLarge,
     /// A string was not valid UTF-8.
     InvalidUtf8String,
+    /// An acknowledgment for a packet identifier the client isn't waiting on.
+    UnknownPacketId(u16),
+    /// Hex or base64 text that could not be decoded.
+    InvalidEncoding,
+    /// A topic name with wildcards, or a malformed subscription filter.
+    InvalidTopic,
+    /// An empty client id without a clean session; the broker can only assign
+    /// an id to a new session.
+    EmptyClientId,
+    /// A QoS 1 or QoS 2 publish to encode without a packet identifier.
+    MissingPacketId,
     /// An MQTT v5 packet contained too many properties.
     #[cfg(feature = "v5")]
     TooManyProperties,
+    /// A v5 Subscription Identifier outside `1..=268_435_455`.
+    #[cfg(feature = "v5")]
+    InvalidSubscriptionId,
+    /// A retained publish to a v5 broker that doesn't support retained
+    /// messages.
+    #[cfg(feature = "v5")]
+    RetainNotSupported,
+    /// A received v5 Topic Alias that is out of range, or that replaces the
+    /// topic but was never assigned.
+    #[cfg(feature = "v5")]
+    InvalidTopicAlias(u16),
+}
+
+impl<T: core::fmt::Debug> core::fmt::Display for MqttError<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MqttError::Transport(e) => write!(f, "transport error: {:?}", e),
+            MqttError::Protocol(e) => write!(f, "protocol error: {}", e),
+            MqttError::ConnectionRefused(code) => write!(f, "connection refused: {}", code),
+            MqttError::SubscriptionRefused { index, reason_code } => write!(
+                f,
+                "subscription {} refused with reason code 0x{:02X}",
+                index, reason_code
+            ),
+            MqttError::NotConnected => f.write_str("not connected"),
+            MqttError::NotSubscribed => f.write_str("not subscribed"),
+            MqttError::BufferTooSmall => f.write_str("buffer too small"),
+            MqttError::EncodeBufferTooSmall { needed, available } => write!(
+                f,
+                "packet of {} bytes doesn't fit a buffer of {} bytes",
+                needed, available
+            ),
+            MqttError::Timeout => f.write_str("operation timed out"),
+            #[cfg(feature = "v5")]
+            MqttError::PacketTooLarge { size, maximum } => write!(
+                f,
+                "packet of {} bytes exceeds the broker maximum of {} bytes",
+                size, maximum
+            ),
+            #[cfg(feature = "v5")]
+            MqttError::ServerDisconnect { reason_code } => write!(
+                f,
+                "disconnected by the broker with reason code 0x{:02X}",
+                reason_code
+            ),
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> core::error::Error for MqttError<T> {}
+
+impl core::fmt::Display for ConnectReasonCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Success => f.write_str("success"),
+            Self::UnacceptableProtocolVersion => f.write_str("unacceptable protocol version"),
+            Self::IdentifierRejected => f.write_str("identifier rejected"),
+            Self::ServerUnavailable => f.write_str("server unavailable"),
+            Self::BadUserNameOrPassword => f.write_str("bad user name or password"),
+            Self::NotAuthorized => f.write_str("not authorized"),
+            #[cfg(feature = "v5")]
+            Self::V5(code) => write!(f, "{}", code),
+            Self::Other(code) => write!(f, "reason code 0x{:02X}", code),
+        }
+    }
+}
+
+#[cfg(feature = "v5")]
+impl core::fmt::Display for V5ConnectReasonCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnspecifiedError => f.write_str("unspecified error"),
+            Self::MalformedPacket => f.write_str("malformed packet"),
+            Self::ProtocolError => f.write_str("protocol error"),
+            Self::ImplementationSpecificError => f.write_str("implementation specific error"),
+            Self::UnsupportedProtocolVersion => f.write_str("unsupported protocol version"),
+            Self::ClientIdentifierNotValid => f.write_str("client identifier not valid"),
+            Self::BadUserNameOrPassword => f.write_str("bad user name or password"),
+            Self::NotAuthorized => f.write_str("not authorized"),
+            Self::ServerUnavailable => f.write_str("server unavailable"),
+            Self::ServerBusy => f.write_str("server busy"),
+            Self::Banned => f.write_str("banned"),
+            Self::BadAuthenticationMethod => f.write_str("bad authentication method"),
+            Self::TopicNameInvalid => f.write_str("topic name invalid"),
+            Self::PacketTooLarge => f.write_str("packet too large"),
+            Self::QuotaExceeded => f.write_str("quota exceeded"),
+            Self::PayloadFormatInvalid => f.write_str("payload format invalid"),
+            Self::RetainNotSupported => f.write_str("retain not supported"),
+            Self::QosNotSupported => f.write_str("QoS not supported"),
+            Self::UseAnotherServer => f.write_str("use another server"),
+            Self::ServerMoved => f.write_str("server moved"),
+            Self::ConnectionRateExceeded => f.write_str("connection rate exceeded"),
+            Self::Other(code) => write!(f, "reason code 0x{:02X}", code),
+        }
+    }
+}
+
+impl core::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidPacketType(t) => write!(f, "invalid packet type {}", t),
+            Self::InvalidResponse => f.write_str("invalid response"),
+            Self::ConnectionClosed => f.write_str("connection closed"),
+            Self::MalformedPacket => f.write_str("malformed packet"),
+            Self::PayloadTooLarge => f.write_str("payload too large"),
+            Self::InvalidUtf8String => f.write_str("invalid UTF-8 string"),
+            Self::UnknownPacketId(id) => write!(f, "acknowledgment for unknown packet id {}", id),
+            Self::InvalidEncoding => f.write_str("invalid hex or base64 encoding"),
+            Self::InvalidTopic => f.write_str("invalid topic name or filter"),
+            Self::EmptyClientId => f.write_str("empty client id requires a clean session"),
+            Self::MissingPacketId => f.write_str("QoS 1 or 2 publish without a packet id"),
+            #[cfg(feature = "v5")]
+            Self::TooManyProperties => f.write_str("too many properties"),
+            #[cfg(feature = "v5")]
+            Self::InvalidSubscriptionId => f.write_str("invalid subscription identifier"),
+            #[cfg(feature = "v5")]
+            Self::RetainNotSupported => {
+                f.write_str("retained messages not supported by the broker")
+            }
+            #[cfg(feature = "v5")]
+            Self::InvalidTopicAlias(alias) => write!(f, "invalid topic alias {}", alias),
+        }
+    }
+}
+
+impl core::fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::ClientIdTooLong => f.write_str("client id too long"),
+            Self::EmptyClientId => f.write_str("empty client id requires a clean session"),
+            Self::UsernameTooLong => f.write_str("username too long"),
+            Self::PasswordTooLong => f.write_str("password too long"),
+        }
+    }
+}
+
+impl core::error::Error for OptionsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockError;
+
+    #[test]
+    fn non_transport_errors_cast_to_another_transport() {
+        let cast: MqttError<MockError> = MqttError::try_cast_transport_error(
+            MqttError::<ErrorPlaceHolder>::EncodeBufferTooSmall {
+                needed: 12,
+                available: 4,
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            cast,
+            MqttError::EncodeBufferTooSmall {
+                needed: 12,
+                available: 4
+            }
+        ));
+        let cast: MqttError<MockError> =
+            MqttError::cast_transport_error(MqttError::<ErrorPlaceHolder>::Protocol(
+                ProtocolError::MalformedPacket,
+            ));
+        assert!(matches!(
+            cast,
+            MqttError::Protocol(ProtocolError::MalformedPacket)
+        ));
+    }
+
+    #[test]
+    fn transport_errors_do_not_cast() {
+        let result = MqttError::<ErrorPlaceHolder>::try_cast_transport_error(MqttError::Transport(
+            MockError::Exhausted,
+        ));
+        assert_eq!(result.unwrap_err(), CastError);
+    }
+
+    /// A TCP socket whose connection was reset by the peer.
+    struct ResetSocket;
+
+    impl embedded_io_async::ErrorType for ResetSocket {
+        type Error = TcpError;
+    }
+
+    impl embedded_io_async::Read for ResetSocket {
+        async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+            Err(TcpError::ConnectionReset)
+        }
+    }
+
+    impl embedded_io_async::Write for ResetSocket {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn tcp_reset_is_identified_through_the_client_error() {
+        use crate::{MqttClient, MqttOptions, StreamTransport};
+
+        // The same error type as a client over `TcpTransport`
+        let transport = StreamTransport::new(ResetSocket, None);
+        let mut client = MqttClient::<_, 4, 64>::new(transport, MqttOptions::new("cid"));
+        let err = embassy_futures::block_on(client.connect()).unwrap_err();
+        assert_eq!(err.as_tcp_error(), Some(&TcpError::ConnectionReset));
+        assert!(matches!(
+            err,
+            MqttError::Transport(StreamError::Io(TcpError::ConnectionReset))
+        ));
+
+        // Timeouts of the client or the transport aren't socket errors
+        let timeout = MqttError::<StreamError<TcpError>>::Timeout;
+        assert_eq!(timeout.as_tcp_error(), None);
+        let timeout = MqttError::Transport(StreamError::<TcpError>::Timeout);
+        assert_eq!(timeout.as_tcp_error(), None);
+    }
+
+    /// Formats `value` with `Display`.
+    fn display(value: impl core::fmt::Display) -> heapless::String<96> {
+        use core::fmt::Write;
+
+        let mut text = heapless::String::new();
+        write!(text, "{value}").unwrap();
+        text
+    }
+
+    #[test]
+    fn errors_display_a_readable_message() {
+        assert_eq!(
+            display(MqttError::Transport(MockError::Exhausted)),
+            "transport error: Exhausted"
+        );
+        assert_eq!(
+            display(MqttError::<MockError>::Protocol(
+                ProtocolError::UnknownPacketId(7)
+            )),
+            "protocol error: acknowledgment for unknown packet id 7"
+        );
+        assert_eq!(
+            display(MqttError::<MockError>::ConnectionRefused(
+                ConnectReasonCode::NotAuthorized
+            )),
+            "connection refused: not authorized"
+        );
+        assert_eq!(
+            display(MqttError::<MockError>::SubscriptionRefused {
+                index: 1,
+                reason_code: 0x80
+            }),
+            "subscription 1 refused with reason code 0x80"
+        );
+        assert_eq!(
+            display(MqttError::<MockError>::Timeout),
+            "operation timed out"
+        );
+        assert_eq!(display(ConnectReasonCode::Other(0x2A)), "reason code 0x2A");
+        assert_eq!(
+            display(OptionsError::EmptyClientId),
+            "empty client id requires a clean session"
+        );
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn v5_connack_codes_use_the_v5_table() {
+        let cases = [
+            (0x00, ConnectReasonCode::Success),
+            (
+                0x84,
+                ConnectReasonCode::V5(V5ConnectReasonCode::UnsupportedProtocolVersion),
+            ),
+            (
+                0x87,
+                ConnectReasonCode::V5(V5ConnectReasonCode::NotAuthorized),
+            ),
+            (
+                0x8C,
+                ConnectReasonCode::V5(V5ConnectReasonCode::BadAuthenticationMethod),
+            ),
+            (
+                0x9F,
+                ConnectReasonCode::V5(V5ConnectReasonCode::ConnectionRateExceeded),
+            ),
+            (
+                0x05,
+                ConnectReasonCode::V5(V5ConnectReasonCode::Other(0x05)),
+            ),
+        ];
+        for (code, expected) in cases {
+            assert_eq!(ConnectReasonCode::from_v5(code), expected, "{code:#x}");
+        }
+        // The v3.1.1 table gives 0x05 another meaning
+        assert_eq!(
+            ConnectReasonCode::from(0x05),
+            ConnectReasonCode::NotAuthorized
+        );
+    }
 }
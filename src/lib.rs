@@ -37,48 +37,57 @@
 //! multiple concerns (Home Assistant, telemetry, OTA, etc.):
 //!
 //! ```ignore
-//! use myrtio_mqtt::runtime::{MqttModule, MqttRuntime, TopicRegistry, Context};
+//! use myrtio_mqtt::runtime::{MqttModule, MqttRuntime, PublishOutbox, TopicCollector};
 //!
 //! struct MyModule;
 //!
-//! impl<'a, T, const MAX_TOPICS: usize, const BUF_SIZE: usize>
-//!     MqttModule<'a, T, MAX_TOPICS, BUF_SIZE> for MyModule
-//! where
-//!     T: MqttTransport,
-//! {
-//!     fn register<'reg>(&'reg self, registry: &mut TopicRegistry<'reg, MAX_TOPICS>) {
-//!         let _ = registry.add("device/cmd");
+//! impl MqttModule for MyModule {
+//!     fn register(&self, collector: &mut dyn TopicCollector) {
+//!         collector.add("device/cmd");
 //!     }
 //!
-//!     fn on_message<'m>(&mut self, msg: &Publish<'m>) {
-//!         // Handle incoming messages
+//!     fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+//!         // Handle incoming messages, queueing any response
 //!     }
 //! }
 //! ```
 //!
-//! ## Topic Registration Lifetime Model
+//! ## Topic Registration
 //!
-//! The `MqttModule::register` method uses a generic lifetime `'reg` tied to the
-//! borrow of `self`. This allows modules to register:
+//! `MqttModule::register` passes topics to a `TopicCollector`, which copies
+//! them. This allows modules to register:
 //!
 //! - **Static topics**: `const CMD_TOPIC: &str = "device/cmd";` (recommended)
 //! - **Dynamic topics**: Topics stored in `heapless::String` fields
 //!
-//! The registry is only used during initial subscription and is dropped before
-//! the main event loop, so topics only need to live as long as the module.
+//! Topics only need to live for the duration of the `register` call.
 //!
 //! See `examples/const_topics_module.rs` and `examples/dynamic_topics_module.rs`
 //! for complete examples.
 
 #![no_std]
+#[cfg(feature = "std")]
+extern crate std;
+#[macro_use]
+mod logging;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod client;
 pub mod error;
+pub mod observer;
 pub mod packet;
+pub mod reader;
 pub mod runtime;
+pub mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod transport;
 pub mod util;
 
 // Re-export key types for easier access at the crate root.
-pub use client::{LastWill, MqttClient, MqttEvent, MqttOptions};
+pub use client::{ConnectionState, LastWill, MqttClient, MqttEvent, MqttOptions, MqttVersion};
+pub use observer::MqttObserver;
 pub use packet::QoS;
-pub use transport::TcpTransport;
+#[cfg(feature = "std")]
+pub use transport::StdTcpTransport;
+pub use transport::{StreamTransport, TcpTransport};
@@ -0,0 +1,45 @@
+//! Internal logging macros.
+//!
+//! Log sites use `log_debug!` and `log_warn!` with `core::fmt` style format
+//! strings. They forward to one backend, picked by feature in this order:
+//!
+//! - `defmt`: `defmt::debug!` / `defmt::warn!`
+//! - `log`: `log::debug!` / `log::warn!`
+//! - `esp32-log`: `esp_println::println!`, without levels
+//!
+//! Without any of them, the arguments are only type-checked inside an
+//! `if false` block, so no code is emitted and values that are only logged
+//! don't trigger unused warnings.
+//!
+//! Format strings must stay within the subset all backends accept: plain `{}`
+//! and `{:?}` placeholders, and `{:#x}` for hex.
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::debug!($($arg)*);
+        #[cfg(all(feature = "log", not(feature = "defmt")))]
+        ::log::debug!($($arg)*);
+        #[cfg(all(feature = "esp32-log", not(any(feature = "defmt", feature = "log"))))]
+        ::esp_println::println!($($arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "log", feature = "esp32-log")))]
+        if false {
+            let _ = ::core::format_args!($($arg)*);
+        }
+    }};
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        ::defmt::warn!($($arg)*);
+        #[cfg(all(feature = "log", not(feature = "defmt")))]
+        ::log::warn!($($arg)*);
+        #[cfg(all(feature = "esp32-log", not(any(feature = "defmt", feature = "log"))))]
+        ::esp_println::println!($($arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "log", feature = "esp32-log")))]
+        if false {
+            let _ = ::core::format_args!($($arg)*);
+        }
+    }};
+}
@@ -0,0 +1,53 @@
+//! # Observer
+//!
+//! Hooks for watching what the client does without tying the crate to a
+//! particular logging backend. Implement `MqttObserver` on top of `defmt`,
+//! `log`, a serial console or a set of counters, and attach it to a client
+//! with `MqttClient::set_observer`.
+//!
+//! ```ignore
+//! struct DefmtObserver;
+//!
+//! impl MqttObserver for DefmtObserver {
+//!     fn on_packet_received(&self, packet: &MqttPacket<'_>) {
+//!         defmt::info!("MQTT RX: {}", packet);
+//!     }
+//!
+//!     fn on_error(&self, error: &MqttError<&dyn core::fmt::Debug>) {
+//!         defmt::warn!("MQTT error: {}", defmt::Debug2Format(error));
+//!     }
+//! }
+//!
+//! static OBSERVER: DefmtObserver = DefmtObserver;
+//! client.set_observer(&OBSERVER);
+//! ```
+
+use core::fmt::Debug;
+
+use crate::error::MqttError;
+use crate::packet::MqttPacket;
+
+/// Receives notifications about the packets and errors of an `MqttClient`.
+///
+/// Every method has an empty default, so implementations only override what
+/// they need. Methods take `&self` so one observer can be shared; use `Cell`
+/// or atomics for state.
+pub trait MqttObserver {
+    /// Called after a packet was written to the transport.
+    fn on_packet_sent(&self, _packet: &MqttPacket<'_>) {}
+
+    /// Called after a packet was received and decoded.
+    fn on_packet_received(&self, _packet: &MqttPacket<'_>) {}
+
+    /// Called when a client operation fails, before the error is returned.
+    ///
+    /// The transport error is passed as `&dyn Debug`, so the observer doesn't
+    /// depend on the transport type.
+    fn on_error(&self, _error: &MqttError<&dyn Debug>) {}
+}
+
+/// An observer that ignores every notification. Used by clients without one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl MqttObserver for NoopObserver {}
@@ -7,7 +7,8 @@
 use crate::client::{LastWill, MqttVersion};
 use crate::error::{MqttError, ProtocolError};
 use crate::transport;
-use crate::util::{self, read_utf8_string, write_utf8_string};
+use crate::util::{self, read_u8, read_u16, read_utf8_string, write_utf8_string};
+#[cfg(not(feature = "v5"))]
 use core::marker::PhantomData;
 use heapless::Vec;
 
@@ -44,13 +45,19 @@ pub trait DecodePacket<'a>: Sized {
 
 /// An enumeration of all possible MQTT control packets.
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MqttPacket<'a> {
     Connect(Connect<'a>),
     ConnAck(ConnAck<'a>),
     Publish(Publish<'a>),
     PubAck(PubAck<'a>),
+    PubRec(PubRec<'a>),
+    PubRel(PubRel<'a>),
+    PubComp(PubComp<'a>),
     Subscribe(Subscribe<'a>),
     SubAck(SubAck<'a>),
+    Unsubscribe(Unsubscribe<'a>),
+    UnsubAck(UnsubAck<'a>),
     PingReq,
     PingResp,
     Disconnect(Disconnect<'a>),
@@ -64,11 +71,11 @@ pub fn decode<'a, T>(
 where
     T: transport::TransportError,
 {
-    if buf.is_empty() {
+    let Some(&first_byte) = buf.first() else {
         return Ok(None);
-    }
+    };
 
-    let packet_type = buf[0] >> 4;
+    let packet_type = first_byte >> 4;
     let packet = match packet_type {
         1 => MqttPacket::Connect(
             Connect::decode(buf, version).map_err(MqttError::cast_transport_error)?,
@@ -82,14 +89,35 @@ where
         4 => MqttPacket::PubAck(
             PubAck::decode(buf, version).map_err(MqttError::cast_transport_error)?,
         ),
+        5 => MqttPacket::PubRec(
+            PubRec::decode(buf, version).map_err(MqttError::cast_transport_error)?,
+        ),
+        6 => MqttPacket::PubRel(
+            PubRel::decode(buf, version).map_err(MqttError::cast_transport_error)?,
+        ),
+        7 => MqttPacket::PubComp(
+            PubComp::decode(buf, version).map_err(MqttError::cast_transport_error)?,
+        ),
         8 => MqttPacket::Subscribe(
             Subscribe::decode(buf, version).map_err(MqttError::cast_transport_error)?,
         ),
         9 => MqttPacket::SubAck(
             SubAck::decode(buf, version).map_err(MqttError::cast_transport_error)?,
         ),
-        12 => MqttPacket::PingReq,
-        13 => MqttPacket::PingResp,
+        10 => MqttPacket::Unsubscribe(
+            Unsubscribe::decode(buf, version).map_err(MqttError::cast_transport_error)?,
+        ),
+        11 => MqttPacket::UnsubAck(
+            UnsubAck::decode(buf, version).map_err(MqttError::cast_transport_error)?,
+        ),
+        12 | 13 => {
+            packet_bounds(buf).map_err(MqttError::cast_transport_error)?;
+            if packet_type == 12 {
+                MqttPacket::PingReq
+            } else {
+                MqttPacket::PingResp
+            }
+        }
         14 => MqttPacket::Disconnect(
             Disconnect::decode(buf, version).map_err(MqttError::cast_transport_error)?,
         ),
@@ -103,15 +131,176 @@ where
     Ok(Some(packet))
 }
 
+/// Reads the fixed header's remaining length and returns the packet cut off
+/// at its declared end, along with the offset of the variable header.
+///
+/// Decoders read from the returned slice only, so a packet whose declared
+/// length exceeds the bytes actually available fails with `MalformedPacket`
+/// instead of indexing out of bounds.
+fn packet_bounds(buf: &[u8]) -> Result<(&[u8], usize), MqttError<transport::ErrorPlaceHolder>> {
+    let mut cursor = 1;
+    let remaining_len = util::read_variable_byte_integer(&mut cursor, buf)?;
+    let packet = buf
+        .get(..cursor + remaining_len)
+        .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+    Ok((packet, cursor))
+}
+
+/// Maximum number of v5 properties a packet can carry.
+///
+/// Decoding a packet with more properties fails with `TooManyProperties`.
 #[cfg(feature = "v5")]
-#[derive(Debug)]
+pub const MAX_PROPERTIES: usize = 16;
+
+/// The properties of an MQTT v5 packet.
+#[cfg(feature = "v5")]
+pub type Properties<'a> = Vec<Property<'a>, MAX_PROPERTIES>;
+
+/// Maximum number of v5 Will Properties a CONNECT can carry. Kept below
+/// `MAX_PROPERTIES` since a will rarely needs more than its delay, expiry and
+/// content type.
+#[cfg(feature = "v5")]
+pub const MAX_WILL_PROPERTIES: usize = 8;
+
+/// An MQTT v5 property.
+///
+/// `data` holds the raw encoded value exactly as it appears on the wire,
+/// including length prefixes for strings and binary data.
+#[cfg(feature = "v5")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Property<'a> {
     pub id: u8,
     pub data: &'a [u8],
 }
 
+/// An MQTT v5 string or binary property whose `data` lacks the two-byte
+/// length prefix, which the encoder writes itself.
+///
+/// This lets the client encode a caller's string or binary value straight
+/// into its TX buffer instead of first copying it into a scratch buffer.
+#[cfg_attr(not(feature = "v5"), allow(dead_code))]
+pub(crate) struct PrefixedProperty<'a> {
+    pub id: u8,
+    pub data: &'a [u8],
+}
+
+#[cfg(feature = "v5")]
+impl Property<'_> {
+    pub const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+    pub const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    pub const CONTENT_TYPE: u8 = 0x03;
+    pub const RESPONSE_TOPIC: u8 = 0x08;
+    pub const CORRELATION_DATA: u8 = 0x09;
+    pub const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+    pub const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+    pub const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+    pub const SERVER_KEEP_ALIVE: u8 = 0x13;
+    pub const WILL_DELAY_INTERVAL: u8 = 0x18;
+    pub const REASON_STRING: u8 = 0x1F;
+    pub const RECEIVE_MAXIMUM: u8 = 0x21;
+    pub const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+    pub const TOPIC_ALIAS: u8 = 0x23;
+    pub const MAXIMUM_QOS: u8 = 0x24;
+    pub const RETAIN_AVAILABLE: u8 = 0x25;
+    pub const USER_PROPERTY: u8 = 0x26;
+    pub const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+    pub const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+    pub const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+    pub const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+}
+
+/// Typed views of the property value. Each returns `None` if `data` isn't a
+/// well-formed value of the requested wire type.
+#[cfg(feature = "v5")]
+impl<'a> Property<'a> {
+    /// Decodes a Byte value, e.g. Maximum QoS.
+    pub fn as_u8(&self) -> Option<u8> {
+        match self.data {
+            [value] => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Decodes a Two Byte Integer, e.g. Topic Alias Maximum.
+    pub fn as_u16(&self) -> Option<u16> {
+        self.data.try_into().ok().map(u16::from_be_bytes)
+    }
+
+    /// Decodes a Four Byte Integer, e.g. Session Expiry Interval.
+    pub fn as_u32(&self) -> Option<u32> {
+        self.data.try_into().ok().map(u32::from_be_bytes)
+    }
+
+    /// Decodes a Variable Byte Integer, e.g. Subscription Identifier.
+    pub fn as_variable_int(&self) -> Option<usize> {
+        util::read_variable_byte_integer(&mut 0, self.data).ok()
+    }
+
+    /// Decodes a UTF-8 Encoded String, e.g. Response Topic.
+    pub fn as_str(&self) -> Option<&'a str> {
+        read_utf8_string(&mut 0, self.data).ok()
+    }
+
+    /// Decodes Binary Data, e.g. Correlation Data.
+    pub fn as_binary(&self) -> Option<&'a [u8]> {
+        read_binary_data(&mut 0, self.data).ok()
+    }
+
+    /// Decodes a UTF-8 String Pair, used by User Properties.
+    pub fn as_str_pair(&self) -> Option<(&'a str, &'a str)> {
+        let mut cursor = 0;
+        let key = read_utf8_string(&mut cursor, self.data).ok()?;
+        let value = read_utf8_string(&mut cursor, self.data).ok()?;
+        Some((key, value))
+    }
+}
+
+/// Lookup of v5 properties by identifier, for the `properties` of any packet.
+///
+/// ```
+/// use myrtio_mqtt::packet::{PropertiesExt, Property};
+///
+/// let props = [
+///     Property { id: Property::SESSION_EXPIRY_INTERVAL, data: &[0, 0, 1, 44] },
+///     Property { id: Property::RESPONSE_TOPIC, data: b"\x00\x05reply" },
+/// ];
+/// let expiry = props.find_property(Property::SESSION_EXPIRY_INTERVAL);
+/// assert_eq!(expiry.and_then(Property::as_u32), Some(300));
+/// let topic = props.find_property(Property::RESPONSE_TOPIC);
+/// assert_eq!(topic.and_then(Property::as_str), Some("reply"));
+/// // The wrong wire type doesn't decode
+/// assert_eq!(topic.and_then(Property::as_u32), None);
+/// ```
+#[cfg(feature = "v5")]
+pub trait PropertiesExt<'a> {
+    /// Returns the first property with identifier `id`.
+    fn find_property(&self, id: u8) -> Option<&Property<'a>>;
+
+    /// Returns every property with identifier `id`, for properties that may
+    /// appear more than once, such as User Properties.
+    fn filter_properties<'s>(&'s self, id: u8) -> impl Iterator<Item = &'s Property<'a>>
+    where
+        'a: 's;
+}
+
+#[cfg(feature = "v5")]
+impl<'a> PropertiesExt<'a> for [Property<'a>] {
+    fn find_property(&self, id: u8) -> Option<&Property<'a>> {
+        self.iter().find(|prop| prop.id == id)
+    }
+
+    fn filter_properties<'s>(&'s self, id: u8) -> impl Iterator<Item = &'s Property<'a>>
+    where
+        'a: 's,
+    {
+        self.iter().filter(move |prop| prop.id == id)
+    }
+}
+
 // --- CONNECT Packet ---
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Connect<'a> {
     pub clean_session: bool,
     pub keep_alive: u16,
@@ -120,10 +309,31 @@ pub struct Connect<'a> {
     pub password: Option<&'a [u8]>,
     pub will: Option<LastWill<'a>>,
     #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
+    pub properties: Properties<'a>,
+    /// Will Properties, sent ahead of the will topic when `will` is set.
+    #[cfg(feature = "v5")]
+    pub will_properties: Vec<Property<'a>, MAX_WILL_PROPERTIES>,
 }
 
 impl<'a> Connect<'a> {
+    /// Creates a new Connect packet.
+    ///
+    /// An empty `client_id` asks the broker to assign one. That needs
+    /// `clean_session`, otherwise encoding fails with `EmptyClientId`.
+    ///
+    /// ```
+    /// use myrtio_mqtt::client::MqttVersion;
+    /// use myrtio_mqtt::error::{MqttError, ProtocolError};
+    /// use myrtio_mqtt::packet::{Connect, EncodePacket};
+    ///
+    /// let mut buf = [0u8; 32];
+    /// let len = Connect::new("", 60, true).encode(&mut buf, MqttVersion::V3)?;
+    /// assert_eq!(&buf[len - 2..len], &[0, 0]);
+    ///
+    /// let err = Connect::new("", 60, false).encode(&mut buf, MqttVersion::V3);
+    /// assert!(matches!(err, Err(MqttError::Protocol(ProtocolError::EmptyClientId))));
+    /// # Ok::<(), MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+    /// ```
     pub fn new(client_id: &'a str, keep_alive: u16, clean_session: bool) -> Self {
         Self {
             client_id,
@@ -134,6 +344,8 @@ impl<'a> Connect<'a> {
             will: None,
             #[cfg(feature = "v5")]
             properties: Vec::new(),
+            #[cfg(feature = "v5")]
+            will_properties: Vec::new(),
         }
     }
 
@@ -155,7 +367,91 @@ impl<'a> Connect<'a> {
             will,
             #[cfg(feature = "v5")]
             properties: Vec::new(),
+            #[cfg(feature = "v5")]
+            will_properties: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "v5")]
+impl<'a> Connect<'a> {
+    /// Sets the MQTT v5 Will Delay Interval: after an unexpected disconnect
+    /// the broker waits `seconds` before publishing the will, and skips it if
+    /// the client reconnects in time.
+    ///
+    /// The value is stored in `scratch`, which needs at least four bytes.
+    ///
+    /// ```
+    /// use myrtio_mqtt::client::{LastWill, MqttVersion};
+    /// use myrtio_mqtt::packet::{Connect, EncodePacket};
+    /// use myrtio_mqtt::QoS;
+    ///
+    /// let mut scratch = [0u8; 4];
+    /// let will = LastWill { topic: "t", payload: b"x", qos: QoS::AtMostOnce, retain: false };
+    /// let mut connect = Connect::with_credentials("c", 60, true, None, None, Some(will));
+    /// connect.set_will_delay(&mut scratch, 30)?;
+    ///
+    /// let mut buf = [0u8; 64];
+    /// let len = connect.encode(&mut buf, MqttVersion::V5)?;
+    /// // Client id, then the will properties ahead of the will topic and payload
+    /// assert_eq!(
+    ///     &buf[len - 15..len],
+    ///     &[0, 1, b'c', 5, 0x18, 0, 0, 0, 30, 0, 1, b't', 0, 1, b'x'],
+    /// );
+    /// # Ok::<(), myrtio_mqtt::error::MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+    /// ```
+    pub fn set_will_delay(
+        &mut self,
+        scratch: &'a mut [u8],
+        seconds: u32,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let value = scratch.get_mut(..4).ok_or(MqttError::BufferTooSmall)?;
+        value.copy_from_slice(&seconds.to_be_bytes());
+        let value: &'a [u8] = value;
+        self.will_properties
+            .retain(|prop| prop.id != Property::WILL_DELAY_INTERVAL);
+        self.will_properties
+            .push(Property {
+                id: Property::WILL_DELAY_INTERVAL,
+                data: value,
+            })
+            .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))
+    }
+
+    /// Returns the Will Delay Interval in seconds.
+    pub fn will_delay(&self) -> Option<u32> {
+        self.will_properties
+            .find_property(Property::WILL_DELAY_INTERVAL)
+            .and_then(Property::as_u32)
+    }
+}
+
+impl Connect<'_> {
+    /// Returns the number of bytes `encode` writes for this packet.
+    pub fn encoded_len(&self, version: MqttVersion) -> usize {
+        // Protocol name, level, connect flags and keep-alive
+        let mut len = 6 + 1 + 1 + 2;
+        #[cfg(feature = "v5")]
+        if version == MqttVersion::V5 {
+            len += util::properties_len(&self.properties);
+        }
+        len += 2 + self.client_id.len();
+        if let Some(will) = self.will {
+            #[cfg(feature = "v5")]
+            if version == MqttVersion::V5 {
+                len += util::properties_len(&self.will_properties);
+            }
+            len += 2 + will.topic.len() + 2 + will.payload.len();
+        }
+        if let Some(username) = self.username {
+            len += 2 + username.len();
+        }
+        if let Some(password) = self.password {
+            len += 2 + password.len();
         }
+        #[cfg(not(feature = "v5"))]
+        let _ = version;
+        1 + util::variable_byte_integer_len(len) + len
     }
 }
 
@@ -165,6 +461,11 @@ impl<'a> EncodePacket for Connect<'a> {
         buf: &mut [u8],
         version: MqttVersion,
     ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        // The broker assigns an id to an empty one, which only works for a new session
+        if self.client_id.is_empty() && !self.clean_session {
+            return Err(MqttError::Protocol(ProtocolError::EmptyClientId));
+        }
+
         let mut cursor = 0;
         buf[cursor] = 0x10;
         cursor += 1;
@@ -210,8 +511,12 @@ impl<'a> EncodePacket for Connect<'a> {
         // Payload: Client ID
         cursor += write_utf8_string(&mut buf[cursor..], self.client_id)?;
 
-        // Payload: Will topic and payload (if present)
+        // Payload: Will properties (v5), topic and payload (if present)
         if let Some(will) = self.will {
+            #[cfg(feature = "v5")]
+            if version == MqttVersion::V5 {
+                write_properties(&mut cursor, buf, &self.will_properties)?;
+            }
             cursor += write_utf8_string(&mut buf[cursor..], will.topic)?;
             cursor += write_binary_data(&mut buf[cursor..], will.payload)?;
         }
@@ -239,17 +544,17 @@ impl<'a> DecodePacket<'a> for Connect<'a> {
         buf: &'a [u8],
         _version: MqttVersion,
     ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
-        let mut cursor = 2;
-        cursor += 6;
-        let connect_flags = buf[cursor];
+        let (buf, mut cursor) = packet_bounds(buf)?;
+        // Protocol name and level
+        read_utf8_string(&mut cursor, buf)?;
+        read_u8(&mut cursor, buf)?;
+        let connect_flags = read_u8(&mut cursor, buf)?;
         let clean_session = (connect_flags & 0x02) != 0;
         let has_will = (connect_flags & 0x04) != 0;
         let will_retain = (connect_flags & 0x20) != 0;
         let has_username = (connect_flags & 0x80) != 0;
         let has_password = (connect_flags & 0x40) != 0;
-        cursor += 1;
-        let keep_alive = u16::from_be_bytes([buf[cursor], buf[cursor + 1]]);
-        cursor += 2;
+        let keep_alive = read_u16(&mut cursor, buf)?;
         #[cfg(feature = "v5")]
         let properties = if _version == MqttVersion::V5 {
             read_properties(&mut cursor, buf)?
@@ -257,6 +562,12 @@ impl<'a> DecodePacket<'a> for Connect<'a> {
             Vec::new()
         };
         let client_id = read_utf8_string(&mut cursor, buf)?;
+        #[cfg(feature = "v5")]
+        let will_properties = if has_will && _version == MqttVersion::V5 {
+            util::read_properties_with_capacity(&mut cursor, buf)?
+        } else {
+            Vec::new()
+        };
         let will = if has_will {
             let will_qos = match (connect_flags >> 3) & 0x03 {
                 0 => QoS::AtMostOnce,
@@ -265,16 +576,7 @@ impl<'a> DecodePacket<'a> for Connect<'a> {
                 _ => return Err(MqttError::Protocol(ProtocolError::MalformedPacket)),
             };
             let will_topic = read_utf8_string(&mut cursor, buf)?;
-            if cursor + 2 > buf.len() {
-                return Err(MqttError::Protocol(ProtocolError::MalformedPacket));
-            }
-            let len = u16::from_be_bytes([buf[cursor], buf[cursor + 1]]) as usize;
-            cursor += 2;
-            if cursor + len > buf.len() {
-                return Err(MqttError::Protocol(ProtocolError::MalformedPacket));
-            }
-            let will_payload = &buf[cursor..cursor + len];
-            cursor += len;
+            let will_payload = read_binary_data(&mut cursor, buf)?;
 
             Some(LastWill {
                 topic: will_topic,
@@ -291,10 +593,7 @@ impl<'a> DecodePacket<'a> for Connect<'a> {
             None
         };
         let password = if has_password {
-            let len = u16::from_be_bytes([buf[cursor], buf[cursor + 1]]) as usize;
-            cursor += 2;
-            let pwd = &buf[cursor..cursor + len];
-            Some(pwd)
+            Some(read_binary_data(&mut cursor, buf)?)
         } else {
             None
         };
@@ -307,10 +606,24 @@ impl<'a> DecodePacket<'a> for Connect<'a> {
             will,
             #[cfg(feature = "v5")]
             properties,
+            #[cfg(feature = "v5")]
+            will_properties,
         })
     }
 }
 
+fn read_binary_data<'a>(
+    cursor: &mut usize,
+    buf: &'a [u8],
+) -> Result<&'a [u8], MqttError<transport::ErrorPlaceHolder>> {
+    let len = read_u16(cursor, buf)? as usize;
+    let data = buf
+        .get(*cursor..*cursor + len)
+        .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+    *cursor += len;
+    Ok(data)
+}
+
 fn write_binary_data(
     buf: &mut [u8],
     data: &[u8],
@@ -328,13 +641,30 @@ fn write_binary_data(
     Ok(2 + len)
 }
 
+/// Checks that a packet with `remaining_len` bytes after its fixed header
+/// fits `buf`.
+fn check_encode_len(
+    buf: &[u8],
+    remaining_len: usize,
+) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+    let needed = 1 + util::variable_byte_integer_len(remaining_len) + remaining_len;
+    if needed > buf.len() {
+        return Err(MqttError::EncodeBufferTooSmall {
+            needed,
+            available: buf.len(),
+        });
+    }
+    Ok(())
+}
+
 // --- CONNACK Packet ---
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ConnAck<'a> {
     pub session_present: bool,
     pub reason_code: u8,
     #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
+    pub properties: Properties<'a>,
     #[cfg(not(feature = "v5"))]
     _phantom: PhantomData<&'a ()>,
 }
@@ -343,13 +673,13 @@ impl<'a> DecodePacket<'a> for ConnAck<'a> {
         buf: &'a [u8],
         _version: MqttVersion,
     ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
-        let mut cursor = 2;
-        let session_present = (buf[cursor] & 0x01) != 0;
-        cursor += 1;
-        let reason_code = buf[cursor];
+        let (buf, mut cursor) = packet_bounds(buf)?;
+        let session_present = (read_u8(&mut cursor, buf)? & 0x01) != 0;
+        let reason_code = read_u8(&mut cursor, buf)?;
+        // A v3.1.1-only broker refuses a v5 CONNECT with a v3 CONNACK, which
+        // has no properties
         #[cfg(feature = "v5")]
-        let properties = if version == MqttVersion::V5 {
-            cursor += 1;
+        let properties = if _version == MqttVersion::V5 && cursor < buf.len() {
             read_properties(&mut cursor, buf)?
         } else {
             Vec::new()
@@ -365,27 +695,148 @@ impl<'a> DecodePacket<'a> for ConnAck<'a> {
     }
 }
 
+#[cfg(feature = "v5")]
+impl<'a> ConnAck<'a> {
+    /// Returns the client identifier assigned by the broker, sent when the
+    /// client connected with an empty client id.
+    pub fn assigned_client_id(&self) -> Option<&'a str> {
+        self.properties
+            .find_property(Property::ASSIGNED_CLIENT_IDENTIFIER)
+            .and_then(Property::as_str)
+    }
+
+    /// Returns the highest topic alias the broker accepts from the client.
+    ///
+    /// `0`, the default when the property is absent, means topic aliases must
+    /// not be used.
+    pub fn topic_alias_maximum(&self) -> u16 {
+        self.properties
+            .find_property(Property::TOPIC_ALIAS_MAXIMUM)
+            .and_then(Property::as_u16)
+            .unwrap_or(0)
+    }
+
+    /// Returns how many QoS 1 and QoS 2 publishes the broker accepts before
+    /// it has acknowledged earlier ones.
+    ///
+    /// Defaults to `65535` when the property is absent.
+    pub fn receive_maximum(&self) -> u16 {
+        self.properties
+            .find_property(Property::RECEIVE_MAXIMUM)
+            .and_then(Property::as_u16)
+            .unwrap_or(u16::MAX)
+    }
+
+    /// Returns the largest packet in bytes the broker accepts, or `None` if
+    /// it has no limit beyond the protocol maximum.
+    pub fn maximum_packet_size(&self) -> Option<u32> {
+        self.properties
+            .find_property(Property::MAXIMUM_PACKET_SIZE)
+            .and_then(Property::as_u32)
+    }
+
+    /// Returns the keep-alive interval in seconds the broker requires, if it
+    /// overrides the one the client requested.
+    pub fn server_keep_alive(&self) -> Option<u16> {
+        self.properties
+            .find_property(Property::SERVER_KEEP_ALIVE)
+            .and_then(Property::as_u16)
+    }
+
+    /// Returns the Session Expiry Interval in seconds the broker granted, if
+    /// it differs from the one the client requested.
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.properties
+            .find_property(Property::SESSION_EXPIRY_INTERVAL)
+            .and_then(Property::as_u32)
+    }
+
+    /// Returns the optional features the broker announced support for.
+    pub fn capabilities(&self) -> ServerCapabilities {
+        // Each feature is available unless the property is present and 0
+        let available = |id| {
+            self.properties
+                .find_property(id)
+                .and_then(Property::as_u8)
+                .is_none_or(|value| value != 0)
+        };
+        ServerCapabilities {
+            retain_available: available(Property::RETAIN_AVAILABLE),
+            wildcard_subscription_available: available(Property::WILDCARD_SUBSCRIPTION_AVAILABLE),
+            subscription_identifiers_available: available(
+                Property::SUBSCRIPTION_IDENTIFIER_AVAILABLE,
+            ),
+            shared_subscription_available: available(Property::SHARED_SUBSCRIPTION_AVAILABLE),
+        }
+    }
+}
+
+/// Optional features a v5 broker announces in its CONNACK.
+///
+/// Each is available unless the broker says otherwise, so a v3.1.1 broker
+/// is assumed to support all of them.
+#[cfg(feature = "v5")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ServerCapabilities {
+    /// Retained messages are supported (Retain Available).
+    pub retain_available: bool,
+    /// Filters may contain wildcards (Wildcard Subscription Available).
+    pub wildcard_subscription_available: bool,
+    /// Subscriptions may carry an identifier (Subscription Identifiers
+    /// Available).
+    pub subscription_identifiers_available: bool,
+    /// `$share/` filters are supported (Shared Subscription Available).
+    pub shared_subscription_available: bool,
+}
+
+#[cfg(feature = "v5")]
+impl Default for ServerCapabilities {
+    fn default() -> Self {
+        Self {
+            retain_available: true,
+            wildcard_subscription_available: true,
+            subscription_identifiers_available: true,
+            shared_subscription_available: true,
+        }
+    }
+}
+
 // --- PUBLISH Packet ---
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Publish<'a> {
     pub topic: &'a str,
     pub qos: QoS,
+    /// MQTT DUP flag. Set when the packet is a re-delivery of an earlier attempt.
+    ///
+    /// Only meaningful for QoS > 0; receivers can use it to deduplicate.
+    pub dup: bool,
     /// MQTT retain flag. When set, the broker stores the last message on this topic.
     ///
     /// Home Assistant MQTT discovery expects config publishes to be retained.
     pub retain: bool,
+    /// The message body.
+    ///
+    /// Decoding never copies the payload: this always borrows from the buffer
+    /// the packet was decoded from, which for received messages is the client's
+    /// receive buffer. Use `payload_reader` to consume a large payload in chunks.
+    ///
+    /// May be empty; a retained publish with an empty payload deletes the
+    /// message retained on the topic.
     pub payload: &'a [u8],
     pub packet_id: Option<u16>,
     #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
+    pub properties: Properties<'a>,
 }
 impl<'a> DecodePacket<'a> for Publish<'a> {
     fn decode(
         buf: &'a [u8],
         _version: MqttVersion,
     ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
-        let flags = buf[0] & 0x0F;
+        let flags = read_u8(&mut 0, buf)? & 0x0F;
         let retain = (flags & 0x01) != 0;
+        let dup = ((flags >> 3) & 0x01) != 0;
         let qos = match (flags >> 1) & 0x03 {
             0 => QoS::AtMostOnce,
             1 => QoS::AtLeastOnce,
@@ -393,15 +844,12 @@ impl<'a> DecodePacket<'a> for Publish<'a> {
             _ => return Err(MqttError::Protocol(ProtocolError::MalformedPacket)),
         };
 
-        let mut cursor = 1;
-        let _remaining_len = util::read_variable_byte_integer(&mut cursor, buf)?;
+        let (buf, mut cursor) = packet_bounds(buf)?;
 
         let topic = read_utf8_string(&mut cursor, buf)?;
 
         let packet_id = if qos != QoS::AtMostOnce {
-            let id = u16::from_be_bytes([buf[cursor], buf[cursor + 1]]);
-            cursor += 2;
-            Some(id)
+            Some(read_u16(&mut cursor, buf)?)
         } else {
             None
         };
@@ -413,11 +861,14 @@ impl<'a> DecodePacket<'a> for Publish<'a> {
             Vec::new()
         };
 
-        let payload = &buf[cursor..];
+        let payload = buf
+            .get(cursor..)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
 
         Ok(Publish {
             topic,
             qos,
+            dup,
             retain,
             payload,
             packet_id,
@@ -426,36 +877,63 @@ impl<'a> DecodePacket<'a> for Publish<'a> {
         })
     }
 }
-impl<'a> EncodePacket for Publish<'a> {
-    fn encode(
+impl Publish<'_> {
+    /// Returns the number of bytes `encode` writes for this packet.
+    pub fn encoded_len(&self, version: MqttVersion) -> usize {
+        let len = self.remaining_len(version, &[]);
+        1 + util::variable_byte_integer_len(len) + len
+    }
+
+    fn remaining_len(&self, _version: MqttVersion, _prefixed: &[PrefixedProperty]) -> usize {
+        let mut len = 2 + self.topic.len() + self.payload.len();
+        if self.qos != QoS::AtMostOnce {
+            len += 2;
+        }
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            len += util::properties_len_with(&self.properties, _prefixed);
+        }
+        len
+    }
+
+    /// Encodes the packet like `encode`, with `prefixed` written after the
+    /// packet's own properties. They are left out for MQTT v3.1.1.
+    pub(crate) fn encode_with(
         &self,
         buf: &mut [u8],
         _version: MqttVersion,
+        _prefixed: &[PrefixedProperty],
     ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
-        let mut cursor = 0;
+        // QoS 1 and 2 messages can't go on the wire without their packet ID
+        let packet_id = match (self.qos, self.packet_id) {
+            (QoS::AtMostOnce, _) => None,
+            (_, Some(id)) => Some(id),
+            (_, None) => return Err(MqttError::Protocol(ProtocolError::MissingPacketId)),
+        };
+        let remaining_len = self.remaining_len(_version, _prefixed);
+        check_encode_len(buf, remaining_len)?;
 
-        // Fixed header: PUBLISH packet type (3) with QoS + retain flags
+        // Fixed header: PUBLISH packet type (3) with DUP, QoS and retain flags
+        let dup_flag = u8::from(self.dup) << 3;
         let retain_flag = u8::from(self.retain);
-        let flags = ((self.qos as u8) << 1) | retain_flag;
-        buf[cursor] = 0x30 | flags;
-        cursor += 1;
-
-        // Reserve space for remaining length (max 4 bytes)
-        let remaining_len_pos = cursor;
-        cursor += 4;
-        let content_start = cursor;
+        let flags = dup_flag | ((self.qos as u8) << 1) | retain_flag;
+        buf[0] = 0x30 | flags;
+        let mut cursor = 1 + util::write_variable_byte_integer_len(&mut buf[1..], remaining_len)?;
 
         // Topic name
         cursor += write_utf8_string(&mut buf[cursor..], self.topic)?;
 
         // Packet ID (only for QoS > 0)
-        if self.qos != QoS::AtMostOnce
-            && let Some(id) = self.packet_id
-        {
+        if let Some(id) = packet_id {
             buf[cursor..cursor + 2].copy_from_slice(&id.to_be_bytes());
             cursor += 2;
         }
 
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            util::write_properties_with(&mut cursor, buf, &self.properties, _prefixed)?;
+        }
+
         // Payload
         if cursor + self.payload.len() > buf.len() {
             return Err(MqttError::BufferTooSmall);
@@ -463,123 +941,1050 @@ impl<'a> EncodePacket for Publish<'a> {
         buf[cursor..cursor + self.payload.len()].copy_from_slice(self.payload);
         cursor += self.payload.len();
 
-        // Write remaining length and compact
-        let remaining_len = cursor - content_start;
-        let len_bytes =
-            util::write_variable_byte_integer_len(&mut buf[remaining_len_pos..], remaining_len)?;
-        let header_len = 1 + len_bytes;
-        buf.copy_within(content_start..cursor, header_len);
-
-        Ok(header_len + remaining_len)
+        Ok(cursor)
     }
 }
 
-// --- PUBACK Packet ---
-#[derive(Debug)]
-pub struct PubAck<'a> {
-    pub packet_id: u16,
-    #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
-    #[cfg(not(feature = "v5"))]
-    _phantom: PhantomData<&'a ()>,
-}
-impl<'a> DecodePacket<'a> for PubAck<'a> {
-    fn decode(
-        _buf: &'a [u8],
-        _version: MqttVersion,
-    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
-        Ok(PubAck {
-            packet_id: 0,
-            #[cfg(feature = "v5")]
-            properties: Vec::new(),
-            #[cfg(not(feature = "v5"))]
-            _phantom: PhantomData,
-        })
+impl<'a> EncodePacket for Publish<'a> {
+    /// Fails with `EncodeBufferTooSmall` before writing anything if the
+    /// packet doesn't fit `buf`, and with `MissingPacketId` for a QoS 1 or
+    /// QoS 2 message without a packet ID.
+    fn encode(
+        &self,
+        buf: &mut [u8],
+        version: MqttVersion,
+    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        self.encode_with(buf, version, &[])
     }
 }
 
-// --- SUBSCRIBE Packet ---
-#[derive(Debug)]
-pub struct Subscribe<'a> {
-    pub packet_id: u16,
-    pub topics: Vec<(&'a str, QoS), 8>,
-    #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
-}
-
-impl<'a> Subscribe<'a> {
-    /// Creates a new Subscribe packet with a single topic.
-    pub fn new(packet_id: u16, topic: &'a str, qos: QoS) -> Self {
-        let mut topics = Vec::new();
-        let _ = topics.push((topic, qos));
+impl<'a> Publish<'a> {
+    /// Creates a new, non-retained Publish packet.
+    ///
+    /// The packet ID is left unset; the client assigns one when sending a
+    /// QoS 1 or QoS 2 message. Encoding such a message without one fails with
+    /// `MissingPacketId`.
+    ///
+    /// ```
+    /// use myrtio_mqtt::QoS;
+    /// use myrtio_mqtt::client::MqttVersion;
+    /// use myrtio_mqtt::packet::{EncodePacket, Publish};
+    ///
+    /// let publish = Publish::new("t", b"ON", QoS::AtLeastOnce)
+    ///     .retain(true)
+    ///     .with_packet_id(7);
+    ///
+    /// let mut buf = [0u8; 16];
+    /// let len = publish.encode(&mut buf, MqttVersion::V3)?;
+    /// assert_eq!(&buf[..len], &[0x33, 7, 0, 1, b't', 0, 7, b'O', b'N']);
+    /// # Ok::<(), myrtio_mqtt::error::MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+    /// ```
+    pub fn new(topic: &'a str, payload: &'a [u8], qos: QoS) -> Self {
         Self {
-            packet_id,
-            topics,
+            topic,
+            qos,
+            dup: false,
+            retain: false,
+            payload,
+            packet_id: None,
             #[cfg(feature = "v5")]
             properties: Vec::new(),
         }
     }
-}
 
-impl<'a> DecodePacket<'a> for Subscribe<'a> {
-    fn decode(
-        _buf: &'a [u8],
-        _version: MqttVersion,
-    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
-        Ok(Subscribe {
-            packet_id: 0,
-            topics: Vec::new(),
-            #[cfg(feature = "v5")]
-            properties: Vec::new(),
-        })
+    /// Sets the retain flag.
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
     }
-}
-impl<'a> EncodePacket for Subscribe<'a> {
-    fn encode(
-        &self,
-        buf: &mut [u8],
-        _version: MqttVersion,
-    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
-        let mut cursor = 0;
-
-        // Fixed header: SUBSCRIBE packet type (8) with reserved bits (0x02)
-        buf[cursor] = 0x82;
-        cursor += 1;
 
-        // Reserve space for remaining length
-        let remaining_len_pos = cursor;
-        cursor += 4;
-        let content_start = cursor;
+    /// Sets the packet ID, which QoS 1 and QoS 2 messages need on the wire.
+    pub fn with_packet_id(mut self, packet_id: u16) -> Self {
+        self.packet_id = Some(packet_id);
+        self
+    }
 
-        // Packet ID
-        buf[cursor..cursor + 2].copy_from_slice(&self.packet_id.to_be_bytes());
-        cursor += 2;
+    /// Returns the payload length in bytes.
+    ///
+    /// The payload ends where the fixed header's remaining length says, so
+    /// bytes of a following packet in the same buffer are not included.
+    ///
+    /// ```
+    /// use myrtio_mqtt::client::MqttVersion;
+    /// use myrtio_mqtt::packet::{DecodePacket, Publish};
+    ///
+    /// // QoS 0 PUBLISH on topic "t", followed by a PINGRESP
+    /// let msg = Publish::decode(&[0x30, 5, 0, 1, b't', b'O', b'N', 0xD0, 0], MqttVersion::V3)?;
+    /// assert_eq!(msg.payload, b"ON");
+    /// assert_eq!(msg.payload_len(), 2);
+    /// # Ok::<(), myrtio_mqtt::error::MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+    /// ```
+    pub fn payload_len(&self) -> usize {
+        self.payload.len()
+    }
 
-        // Topic filters with QoS
-        for (topic, qos) in &self.topics {
-            cursor += write_utf8_string(&mut buf[cursor..], topic)?;
-            buf[cursor] = *qos as u8;
-            cursor += 1;
+    /// Returns a reader that yields the payload incrementally, without copying
+    /// it out of the receive buffer.
+    pub fn payload_reader(&self) -> PayloadReader<'a> {
+        PayloadReader {
+            remaining: self.payload,
         }
+    }
 
-        // Write remaining length and compact
-        let remaining_len = cursor - content_start;
-        let len_bytes =
-            util::write_variable_byte_integer_len(&mut buf[remaining_len_pos..], remaining_len)?;
-        let header_len = 1 + len_bytes;
-        buf.copy_within(content_start..cursor, header_len);
+    /// Returns the payload as a string slice, or `InvalidUtf8String` if it
+    /// isn't valid UTF-8.
+    ///
+    /// ```
+    /// use myrtio_mqtt::client::MqttVersion;
+    /// use myrtio_mqtt::error::ProtocolError;
+    /// use myrtio_mqtt::packet::{DecodePacket, Publish};
+    ///
+    /// // QoS 0 PUBLISH on topic "t"
+    /// let msg = Publish::decode(&[0x30, 5, 0, 1, b't', b'O', b'N'], MqttVersion::V3)?;
+    /// assert_eq!(msg.payload_str(), Ok("ON"));
+    /// assert!(msg.is_valid_utf8());
+    ///
+    /// let msg = Publish::decode(&[0x30, 5, 0, 1, b't', 0xC3, 0x28], MqttVersion::V3)?;
+    /// assert_eq!(msg.payload_str(), Err(ProtocolError::InvalidUtf8String));
+    /// assert!(!msg.is_valid_utf8());
+    /// # Ok::<(), myrtio_mqtt::error::MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+    /// ```
+    pub fn payload_str(&self) -> Result<&'a str, ProtocolError> {
+        core::str::from_utf8(self.payload).map_err(|_| ProtocolError::InvalidUtf8String)
+    }
+
+    /// Returns `true` if the payload is valid UTF-8.
+    pub fn is_valid_utf8(&self) -> bool {
+        self.payload_str().is_ok()
+    }
+}
+
+/// Reads a `Publish` payload in chunks, e.g. to stream a firmware image into
+/// flash one page at a time.
+///
+/// `next_chunk` hands out sub-slices of the receive buffer directly; the
+/// `embedded_io_async::Read` implementation copies into a caller buffer for
+/// APIs that expect a reader.
+#[derive(Debug, Clone)]
+pub struct PayloadReader<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    /// Returns the next chunk of at most `max_len` bytes, or `None` once the
+    /// payload has been consumed.
+    pub fn next_chunk(&mut self, max_len: usize) -> Option<&'a [u8]> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        let (chunk, rest) = self.remaining.split_at(max_len.min(self.remaining.len()));
+        self.remaining = rest;
+        Some(chunk)
+    }
+
+    /// Returns the number of bytes not yet read.
+    pub fn remaining(&self) -> usize {
+        self.remaining.len()
+    }
+}
+
+impl embedded_io_async::ErrorType for PayloadReader<'_> {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_async::Read for PayloadReader<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let chunk = self.next_chunk(buf.len()).unwrap_or_default();
+        buf[..chunk.len()].copy_from_slice(chunk);
+        Ok(chunk.len())
+    }
+}
+
+#[cfg(feature = "v5")]
+impl<'a> Publish<'a> {
+    /// Adds an MQTT v5 User Property (key/value pair) to the publish.
+    ///
+    /// The pair is encoded into `scratch`, which must outlive the packet.
+    /// Returns `BufferTooSmall` if `scratch` can't hold both strings.
+    pub fn add_user_property(
+        &mut self,
+        scratch: &'a mut [u8],
+        key: &str,
+        value: &str,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let key_len = write_utf8_string(scratch, key)?;
+        let value_len = write_utf8_string(&mut scratch[key_len..], value)?;
+        let scratch: &'a [u8] = scratch;
+        self.properties
+            .push(Property {
+                id: Property::USER_PROPERTY,
+                data: &scratch[..key_len + value_len],
+            })
+            .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))
+    }
+
+    /// Sets the MQTT v5 Response Topic, on which the receiver should publish
+    /// its reply to this message.
+    ///
+    /// The topic is encoded into `scratch`, which must outlive the packet.
+    /// Returns `BufferTooSmall` if `scratch` can't hold it.
+    pub fn set_response_topic(
+        &mut self,
+        scratch: &'a mut [u8],
+        topic: &str,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let len = write_utf8_string(scratch, topic)?;
+        let scratch: &'a [u8] = scratch;
+        self.push_property(Property::RESPONSE_TOPIC, &scratch[..len])
+    }
+
+    /// Sets the MQTT v5 Correlation Data, which a reply carries back so the
+    /// requester can match it to its request.
+    ///
+    /// The data is encoded into `scratch`, which must outlive the packet.
+    /// Returns `BufferTooSmall` if `scratch` can't hold it.
+    pub fn set_correlation_data(
+        &mut self,
+        scratch: &'a mut [u8],
+        data: &[u8],
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let len = write_binary_data(scratch, data)?;
+        let scratch: &'a [u8] = scratch;
+        self.push_property(Property::CORRELATION_DATA, &scratch[..len])
+    }
+
+    /// Sets the MQTT v5 Message Expiry Interval: the broker discards the
+    /// message if it can't be delivered within `seconds`.
+    ///
+    /// The value is stored in `scratch`, which needs at least four bytes.
+    pub fn set_message_expiry(
+        &mut self,
+        scratch: &'a mut [u8],
+        seconds: u32,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let value = scratch.get_mut(..4).ok_or(MqttError::BufferTooSmall)?;
+        value.copy_from_slice(&seconds.to_be_bytes());
+        let value: &'a [u8] = value;
+        self.push_property(Property::MESSAGE_EXPIRY_INTERVAL, value)
+    }
+
+    /// Sets the MQTT v5 Payload Format Indicator, telling receivers whether
+    /// the payload is UTF-8 text (`true`) or unspecified bytes (`false`).
+    ///
+    /// With the indicator set, the broker may check that the payload really
+    /// is valid UTF-8.
+    pub fn set_payload_format_indicator(
+        &mut self,
+        is_utf8: bool,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let value: &'static [u8] = if is_utf8 { &[1] } else { &[0] };
+        self.push_property(Property::PAYLOAD_FORMAT_INDICATOR, value)
+    }
+
+    /// Sets the MQTT v5 Content Type of the payload, e.g. `application/json`.
+    ///
+    /// The string is encoded into `scratch`, which must outlive the packet.
+    /// Returns `BufferTooSmall` if `scratch` can't hold it.
+    ///
+    /// ```
+    /// use myrtio_mqtt::client::MqttVersion;
+    /// use myrtio_mqtt::packet::{DecodePacket, EncodePacket, Publish};
+    ///
+    /// let mut scratch = [0u8; 32];
+    /// let mut out = [0u8; 64];
+    /// // v5 QoS 0 PUBLISH on topic "t" without properties
+    /// let raw = [0x30, 6, 0, 1, b't', 0, b'{', b'}'];
+    /// let mut msg = Publish::decode(&raw, MqttVersion::V5)?;
+    /// assert!(!msg.is_utf8_payload());
+    /// msg.set_payload_format_indicator(true)?;
+    /// msg.set_content_type(&mut scratch, "application/json")?;
+    ///
+    /// let len = msg.encode(&mut out, MqttVersion::V5)?;
+    /// // Properties: 0x01 = 1, then 0x03 with the length-prefixed string
+    /// assert_eq!(&out[5..10], &[21, 0x01, 1, 0x03, 0]);
+    ///
+    /// let received = Publish::decode(&out[..len], MqttVersion::V5)?;
+    /// assert!(received.is_utf8_payload());
+    /// assert_eq!(received.content_type(), Some("application/json"));
+    /// assert_eq!(received.payload, b"{}");
+    /// # Ok::<(), myrtio_mqtt::error::MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+    /// ```
+    pub fn set_content_type(
+        &mut self,
+        scratch: &'a mut [u8],
+        content_type: &str,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let len = write_utf8_string(scratch, content_type)?;
+        let scratch: &'a [u8] = scratch;
+        self.push_property(Property::CONTENT_TYPE, &scratch[..len])
+    }
+
+    /// Returns `true` if the Payload Format Indicator marks the payload as
+    /// UTF-8 text.
+    ///
+    /// This only reports what the sender declared; `payload_str` checks the
+    /// payload itself.
+    pub fn is_utf8_payload(&self) -> bool {
+        self.properties
+            .find_property(Property::PAYLOAD_FORMAT_INDICATOR)
+            .and_then(Property::as_u8)
+            == Some(1)
+    }
+
+    /// Returns the Content Type the sender declared for the payload.
+    pub fn content_type(&self) -> Option<&'a str> {
+        self.properties
+            .find_property(Property::CONTENT_TYPE)
+            .and_then(Property::as_str)
+    }
+
+    /// Returns the Message Expiry Interval in seconds. For a received message
+    /// the broker has already subtracted the time it spent waiting.
+    pub fn message_expiry(&self) -> Option<u32> {
+        self.properties
+            .find_property(Property::MESSAGE_EXPIRY_INTERVAL)
+            .and_then(Property::as_u32)
+    }
+
+    /// Returns the Topic Alias the message carries, if any.
+    pub fn topic_alias(&self) -> Option<u16> {
+        self.properties
+            .find_property(Property::TOPIC_ALIAS)
+            .and_then(Property::as_u16)
+    }
+
+    /// Returns the Subscription Identifier of the subscription this message
+    /// was delivered through, if it was subscribed with one.
+    ///
+    /// If several subscriptions with identifiers match, the first one is
+    /// returned.
+    pub fn subscription_id(&self) -> Option<usize> {
+        self.properties
+            .find_property(Property::SUBSCRIPTION_IDENTIFIER)
+            .and_then(Property::as_variable_int)
+    }
+
+    /// Returns the Response Topic the sender expects a reply on.
+    pub fn response_topic(&self) -> Option<&'a str> {
+        self.properties
+            .find_property(Property::RESPONSE_TOPIC)
+            .and_then(Property::as_str)
+    }
+
+    /// Returns the Correlation Data to include in a reply.
+    pub fn correlation_data(&self) -> Option<&'a [u8]> {
+        self.properties
+            .find_property(Property::CORRELATION_DATA)
+            .and_then(Property::as_binary)
+    }
+
+    /// Replaces the property `id` with `data`, or adds it.
+    fn push_property(
+        &mut self,
+        id: u8,
+        data: &'a [u8],
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        self.properties.retain(|prop| prop.id != id);
+        self.properties
+            .push(Property { id, data })
+            .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))
+    }
+
+    /// Returns an iterator over the User Properties carried by the publish.
+    ///
+    /// Malformed pairs are skipped.
+    pub fn user_properties(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        self.properties
+            .filter_properties(Property::USER_PROPERTY)
+            .filter_map(Property::as_str_pair)
+    }
+}
+
+// --- PUBACK Packet ---
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PubAck<'a> {
+    pub packet_id: u16,
+    /// MQTT v5 reason code; `0` (success) when omitted by the sender.
+    #[cfg(feature = "v5")]
+    pub reason_code: u8,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+    #[cfg(not(feature = "v5"))]
+    _phantom: PhantomData<&'a ()>,
+}
+impl<'a> PubAck<'a> {
+    /// Creates a successful PUBACK for the given packet id.
+    pub fn new(packet_id: u16) -> Self {
+        Self {
+            packet_id,
+            #[cfg(feature = "v5")]
+            reason_code: 0,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a> DecodePacket<'a> for PubAck<'a> {
+    fn decode(
+        buf: &'a [u8],
+        _version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        let (buf, mut cursor) = packet_bounds(buf)?;
+
+        // Packet ID
+        let packet_id = read_u16(&mut cursor, buf)?;
+
+        // v5 reason code and properties are omitted when the ack is a plain success
+        #[cfg(feature = "v5")]
+        let (reason_code, properties) = {
+            if _version == MqttVersion::V5 && cursor < buf.len() {
+                let reason_code = read_u8(&mut cursor, buf)?;
+                let properties = if cursor < buf.len() {
+                    read_properties(&mut cursor, buf)?
+                } else {
+                    Vec::new()
+                };
+                (reason_code, properties)
+            } else {
+                (0, Vec::new())
+            }
+        };
+
+        Ok(PubAck {
+            packet_id,
+            #[cfg(feature = "v5")]
+            reason_code,
+            #[cfg(feature = "v5")]
+            properties,
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        })
+    }
+}
+impl<'a> EncodePacket for PubAck<'a> {
+    fn encode(
+        &self,
+        buf: &mut [u8],
+        _version: MqttVersion,
+    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 && (self.reason_code != 0 || !self.properties.is_empty()) {
+            return encode_ack_v5(
+                buf,
+                0x40,
+                self.packet_id,
+                self.reason_code,
+                &self.properties,
+            );
+        }
+        encode_ack(buf, 0x40, self.packet_id)
+    }
+}
+
+// --- PUBREC Packet ---
+/// First reply to a QoS 2 PUBLISH: the message was received.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PubRec<'a> {
+    pub packet_id: u16,
+    /// MQTT v5 reason code; `0` (success) when omitted by the sender.
+    #[cfg(feature = "v5")]
+    pub reason_code: u8,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+    #[cfg(not(feature = "v5"))]
+    _phantom: PhantomData<&'a ()>,
+}
+impl<'a> PubRec<'a> {
+    /// Creates a successful PUBREC for the given packet id.
+    pub fn new(packet_id: u16) -> Self {
+        Self::from_ack(PubAck::new(packet_id))
+    }
+
+    fn from_ack(ack: PubAck<'a>) -> Self {
+        Self {
+            packet_id: ack.packet_id,
+            #[cfg(feature = "v5")]
+            reason_code: ack.reason_code,
+            #[cfg(feature = "v5")]
+            properties: ack.properties,
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a> DecodePacket<'a> for PubRec<'a> {
+    fn decode(
+        buf: &'a [u8],
+        version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        // Same layout as PUBACK
+        PubAck::decode(buf, version).map(Self::from_ack)
+    }
+}
+impl<'a> EncodePacket for PubRec<'a> {
+    fn encode(
+        &self,
+        buf: &mut [u8],
+        _version: MqttVersion,
+    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 && (self.reason_code != 0 || !self.properties.is_empty()) {
+            return encode_ack_v5(
+                buf,
+                0x50,
+                self.packet_id,
+                self.reason_code,
+                &self.properties,
+            );
+        }
+        encode_ack(buf, 0x50, self.packet_id)
+    }
+}
+
+// --- PUBREL Packet ---
+/// Second step of the QoS 2 handshake: the sender releases the packet id.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PubRel<'a> {
+    pub packet_id: u16,
+    /// MQTT v5 reason code; `0` (success) when omitted by the sender.
+    #[cfg(feature = "v5")]
+    pub reason_code: u8,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+    #[cfg(not(feature = "v5"))]
+    _phantom: PhantomData<&'a ()>,
+}
+impl<'a> PubRel<'a> {
+    fn from_ack(ack: PubAck<'a>) -> Self {
+        Self {
+            packet_id: ack.packet_id,
+            #[cfg(feature = "v5")]
+            reason_code: ack.reason_code,
+            #[cfg(feature = "v5")]
+            properties: ack.properties,
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a> DecodePacket<'a> for PubRel<'a> {
+    fn decode(
+        buf: &'a [u8],
+        version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        // Same layout as PUBACK
+        PubAck::decode(buf, version).map(Self::from_ack)
+    }
+}
+
+// --- PUBCOMP Packet ---
+/// Final reply of the QoS 2 handshake: the packet id may be reused.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PubComp<'a> {
+    pub packet_id: u16,
+    /// MQTT v5 reason code; `0` (success) when omitted by the sender.
+    #[cfg(feature = "v5")]
+    pub reason_code: u8,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+    #[cfg(not(feature = "v5"))]
+    _phantom: PhantomData<&'a ()>,
+}
+impl<'a> PubComp<'a> {
+    /// Creates a successful PUBCOMP for the given packet id.
+    pub fn new(packet_id: u16) -> Self {
+        Self::from_ack(PubAck::new(packet_id))
+    }
+
+    fn from_ack(ack: PubAck<'a>) -> Self {
+        Self {
+            packet_id: ack.packet_id,
+            #[cfg(feature = "v5")]
+            reason_code: ack.reason_code,
+            #[cfg(feature = "v5")]
+            properties: ack.properties,
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a> DecodePacket<'a> for PubComp<'a> {
+    fn decode(
+        buf: &'a [u8],
+        version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        // Same layout as PUBACK
+        PubAck::decode(buf, version).map(Self::from_ack)
+    }
+}
+impl<'a> EncodePacket for PubComp<'a> {
+    fn encode(
+        &self,
+        buf: &mut [u8],
+        _version: MqttVersion,
+    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 && (self.reason_code != 0 || !self.properties.is_empty()) {
+            return encode_ack_v5(
+                buf,
+                0x70,
+                self.packet_id,
+                self.reason_code,
+                &self.properties,
+            );
+        }
+        encode_ack(buf, 0x70, self.packet_id)
+    }
+}
+
+/// Encodes a PUBACK, PUBREC or PUBCOMP that carries only the packet id, which
+/// is how both versions send a plain success.
+fn encode_ack(
+    buf: &mut [u8],
+    header: u8,
+    packet_id: u16,
+) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+    let [id_hi, id_lo] = packet_id.to_be_bytes();
+    buf.get_mut(..4)
+        .ok_or(MqttError::BufferTooSmall)?
+        .copy_from_slice(&[header, 2, id_hi, id_lo]);
+    Ok(4)
+}
+
+/// Encodes a v5 acknowledgement with its reason code and properties.
+#[cfg(feature = "v5")]
+fn encode_ack_v5(
+    buf: &mut [u8],
+    header: u8,
+    packet_id: u16,
+    reason_code: u8,
+    properties: &Properties<'_>,
+) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+    let mut cursor = 0;
+    *buf.get_mut(cursor).ok_or(MqttError::BufferTooSmall)? = header;
+    cursor += 1;
+
+    // Reserve space for remaining length
+    let remaining_len_pos = cursor;
+    cursor += 4;
+    let content_start = cursor;
+
+    buf.get_mut(cursor..cursor + 2)
+        .ok_or(MqttError::BufferTooSmall)?
+        .copy_from_slice(&packet_id.to_be_bytes());
+    cursor += 2;
+    *buf.get_mut(cursor).ok_or(MqttError::BufferTooSmall)? = reason_code;
+    cursor += 1;
+    write_properties(&mut cursor, buf, properties)?;
+
+    // Write remaining length and compact
+    let remaining_len = cursor - content_start;
+    let len_bytes =
+        util::write_variable_byte_integer_len(&mut buf[remaining_len_pos..], remaining_len)?;
+    let header_len = 1 + len_bytes;
+    buf.copy_within(content_start..cursor, header_len);
+
+    Ok(header_len + remaining_len)
+}
+
+/// Maximum number of topic filters carried by a single SUBSCRIBE packet.
+pub const MAX_SUBSCRIBE_TOPICS: usize = 8;
+
+/// How a v5 broker sends retained messages when a subscription is made.
+#[cfg(feature = "v5")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum RetainHandling {
+    /// Send retained messages whenever the subscription is made.
+    #[default]
+    SendAtSubscribe = 0,
+    /// Send retained messages only if the subscription didn't exist yet.
+    SendIfNew = 1,
+    /// Don't send retained messages.
+    DoNotSend = 2,
+}
+
+/// The options byte following each topic filter in a SUBSCRIBE.
+///
+/// MQTT v3.1.1 only carries the maximum QoS. The other options are MQTT v5
+/// and aren't encoded for a v3.1.1 broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubscribeOptions {
+    /// Maximum QoS the broker may deliver messages with.
+    pub qos: QoS,
+    /// Don't deliver messages published by this client (No Local).
+    #[cfg(feature = "v5")]
+    pub no_local: bool,
+    /// Keep the retain flag of delivered messages instead of clearing it
+    /// (Retain As Published).
+    #[cfg(feature = "v5")]
+    pub retain_as_published: bool,
+    /// When retained messages are sent for this subscription.
+    #[cfg(feature = "v5")]
+    pub retain_handling: RetainHandling,
+}
+
+impl SubscribeOptions {
+    /// Creates options with the given maximum QoS and the spec defaults for
+    /// everything else.
+    pub const fn new(qos: QoS) -> Self {
+        Self {
+            qos,
+            #[cfg(feature = "v5")]
+            no_local: false,
+            #[cfg(feature = "v5")]
+            retain_as_published: false,
+            #[cfg(feature = "v5")]
+            retain_handling: RetainHandling::SendAtSubscribe,
+        }
+    }
+
+    /// Sets the No Local option.
+    #[cfg(feature = "v5")]
+    pub const fn with_no_local(mut self, no_local: bool) -> Self {
+        self.no_local = no_local;
+        self
+    }
+
+    /// Sets the Retain As Published option.
+    #[cfg(feature = "v5")]
+    pub const fn with_retain_as_published(mut self, retain_as_published: bool) -> Self {
+        self.retain_as_published = retain_as_published;
+        self
+    }
+
+    /// Sets the Retain Handling option.
+    #[cfg(feature = "v5")]
+    pub const fn with_retain_handling(mut self, retain_handling: RetainHandling) -> Self {
+        self.retain_handling = retain_handling;
+        self
+    }
+
+    /// Encodes the options byte for the given protocol version.
+    ///
+    /// ```
+    /// # #[cfg(feature = "v5")] {
+    /// use myrtio_mqtt::client::MqttVersion;
+    /// use myrtio_mqtt::packet::{QoS, RetainHandling, SubscribeOptions};
+    ///
+    /// let options = SubscribeOptions::new(QoS::AtLeastOnce)
+    ///     .with_no_local(true)
+    ///     .with_retain_as_published(true)
+    ///     .with_retain_handling(RetainHandling::DoNotSend);
+    /// assert_eq!(options.to_byte(MqttVersion::V5), 0b0010_1101);
+    /// assert_eq!(options.to_byte(MqttVersion::V3), 0b0000_0001);
+    /// # }
+    /// ```
+    pub fn to_byte(self, _version: MqttVersion) -> u8 {
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            return self.qos as u8
+                | u8::from(self.no_local) << 2
+                | u8::from(self.retain_as_published) << 3
+                | (self.retain_handling as u8) << 4;
+        }
+        self.qos as u8
+    }
+
+    /// Decodes an options byte, rejecting reserved bits and values.
+    pub fn from_byte(
+        byte: u8,
+        _version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        let malformed = MqttError::Protocol(ProtocolError::MalformedPacket);
+        let qos = match byte & 0x03 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => return Err(malformed),
+        };
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            let retain_handling = match (byte >> 4) & 0x03 {
+                0 => RetainHandling::SendAtSubscribe,
+                1 => RetainHandling::SendIfNew,
+                2 => RetainHandling::DoNotSend,
+                _ => return Err(malformed),
+            };
+            if byte & 0xC0 != 0 {
+                return Err(malformed);
+            }
+            return Ok(Self {
+                qos,
+                no_local: byte & 0x04 != 0,
+                retain_as_published: byte & 0x08 != 0,
+                retain_handling,
+            });
+        }
+        if byte & 0xFC != 0 {
+            return Err(malformed);
+        }
+        Ok(Self::new(qos))
+    }
+}
+
+impl From<QoS> for SubscribeOptions {
+    fn from(qos: QoS) -> Self {
+        Self::new(qos)
+    }
+}
+
+/// Returns the largest PUBLISH `MqttClient` sends for a `topic_len`-byte topic
+/// and a `payload_len`-byte payload.
+///
+/// For MQTT v5 this leaves room for the properties the client adds itself, a
+/// Topic Alias and a Message Expiry Interval. `publish_with_response_len`
+/// covers `MqttClient::publish_with_response`.
+pub const fn publish_len(
+    topic_len: usize,
+    payload_len: usize,
+    qos: QoS,
+    version: MqttVersion,
+) -> usize {
+    publish_with_response_len(topic_len, payload_len, None, None, qos, version)
+}
+
+/// Returns the largest PUBLISH `MqttClient::publish_with_response` sends,
+/// like `publish_len`, with a Response Topic of `response_topic_len` bytes and
+/// Correlation Data of `correlation_len` bytes. `None` leaves the property
+/// out.
+pub const fn publish_with_response_len(
+    topic_len: usize,
+    payload_len: usize,
+    response_topic_len: Option<usize>,
+    correlation_len: Option<usize>,
+    qos: QoS,
+    version: MqttVersion,
+) -> usize {
+    let mut len = 2 + topic_len + payload_len;
+    if !matches!(qos, QoS::AtMostOnce) {
+        len += 2;
+    }
+    if matches!(version, MqttVersion::V5) {
+        // Topic Alias (3) and Message Expiry Interval (5)
+        let mut properties = 3 + 5;
+        // Identifier and two-byte length before each value
+        if let Some(response_topic_len) = response_topic_len {
+            properties += 3 + response_topic_len;
+        }
+        if let Some(correlation_len) = correlation_len {
+            properties += 3 + correlation_len;
+        }
+        len += util::variable_byte_integer_len(properties) + properties;
+    }
+    1 + util::variable_byte_integer_len(len) + len
+}
+
+/// Returns the largest SUBSCRIBE `MqttClient` sends for a single
+/// `filter_len`-byte topic filter.
+///
+/// For MQTT v5 this leaves room for a Subscription Identifier.
+pub const fn subscribe_len(filter_len: usize, version: MqttVersion) -> usize {
+    let mut len = 2 + 2 + filter_len + 1;
+    if matches!(version, MqttVersion::V5) {
+        // Property length and a four-byte Subscription Identifier
+        len += 1 + 5;
+    }
+    1 + util::variable_byte_integer_len(len) + len
+}
+
+// --- SUBSCRIBE Packet ---
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Subscribe<'a> {
+    pub packet_id: u16,
+    pub topics: Vec<(&'a str, SubscribeOptions), MAX_SUBSCRIBE_TOPICS>,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+}
+
+impl<'a> Subscribe<'a> {
+    /// Creates a new Subscribe packet with a single topic.
+    pub fn new(packet_id: u16, topic: &'a str, qos: QoS) -> Self {
+        let mut topics = Vec::new();
+        let _ = topics.push((topic, SubscribeOptions::new(qos)));
+        Self {
+            packet_id,
+            topics,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+        }
+    }
+
+    /// Creates a new Subscribe packet carrying several topic filters.
+    ///
+    /// Returns `None` if more than `MAX_SUBSCRIBE_TOPICS` filters are given.
+    pub fn with_topics(packet_id: u16, filters: &[(&'a str, QoS)]) -> Option<Self> {
+        if filters.len() > MAX_SUBSCRIBE_TOPICS {
+            return None;
+        }
+        let topics = filters
+            .iter()
+            .map(|&(topic, qos)| (topic, SubscribeOptions::new(qos)))
+            .collect();
+        Some(Self {
+            packet_id,
+            topics,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+        })
+    }
+
+    /// Creates a new Subscribe packet carrying several topic filters, each
+    /// with its own subscription options.
+    ///
+    /// Returns `None` if more than `MAX_SUBSCRIBE_TOPICS` filters are given.
+    pub fn with_options(packet_id: u16, filters: &[(&'a str, SubscribeOptions)]) -> Option<Self> {
+        let topics = Vec::from_slice(filters).ok()?;
+        Some(Self {
+            packet_id,
+            topics,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+        })
+    }
+}
+
+/// Largest value of a v5 Subscription Identifier, the maximum of a Variable
+/// Byte Integer.
+#[cfg(feature = "v5")]
+pub const MAX_SUBSCRIPTION_ID: usize = 268_435_455;
+
+#[cfg(feature = "v5")]
+impl<'a> Subscribe<'a> {
+    /// Sets the MQTT v5 Subscription Identifier, which the broker attaches to
+    /// every PUBLISH delivered through this subscription.
+    ///
+    /// The value is encoded into `scratch`, which needs up to four bytes.
+    /// Fails with `InvalidSubscriptionId` unless `1 <= id <= MAX_SUBSCRIPTION_ID`.
+    pub fn set_subscription_id(
+        &mut self,
+        scratch: &'a mut [u8],
+        id: usize,
+    ) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        if !(1..=MAX_SUBSCRIPTION_ID).contains(&id) {
+            return Err(MqttError::Protocol(ProtocolError::InvalidSubscriptionId));
+        }
+        let mut len = 0;
+        util::write_variable_byte_integer(&mut len, scratch, id)?;
+        let scratch: &'a [u8] = scratch;
+        self.properties
+            .retain(|prop| prop.id != Property::SUBSCRIPTION_IDENTIFIER);
+        self.properties
+            .push(Property {
+                id: Property::SUBSCRIPTION_IDENTIFIER,
+                data: &scratch[..len],
+            })
+            .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))
+    }
+}
+
+impl<'a> DecodePacket<'a> for Subscribe<'a> {
+    fn decode(
+        buf: &'a [u8],
+        _version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        let (buf, mut cursor) = packet_bounds(buf)?;
+
+        // Packet ID
+        let packet_id = read_u16(&mut cursor, buf)?;
+
+        #[cfg(feature = "v5")]
+        let properties = if _version == MqttVersion::V5 {
+            read_properties(&mut cursor, buf)?
+        } else {
+            Vec::new()
+        };
+
+        // Topic filters, each followed by its QoS / subscription options byte
+        let mut topics = Vec::new();
+        while cursor < buf.len() {
+            let topic = read_utf8_string(&mut cursor, buf)?;
+            let options = SubscribeOptions::from_byte(read_u8(&mut cursor, buf)?, _version)?;
+            topics
+                .push((topic, options))
+                .map_err(|_| MqttError::BufferTooSmall)?;
+        }
+
+        Ok(Subscribe {
+            packet_id,
+            topics,
+            #[cfg(feature = "v5")]
+            properties,
+        })
+    }
+}
+impl Subscribe<'_> {
+    /// Returns the number of bytes `encode` writes for this packet.
+    pub fn encoded_len(&self, version: MqttVersion) -> usize {
+        let len = self.remaining_len(version);
+        1 + util::variable_byte_integer_len(len) + len
+    }
+
+    fn remaining_len(&self, _version: MqttVersion) -> usize {
+        let mut len = 2;
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            len += util::properties_len(&self.properties);
+        }
+        for (topic, _) in &self.topics {
+            len += 2 + topic.len() + 1;
+        }
+        len
+    }
+}
+
+impl<'a> EncodePacket for Subscribe<'a> {
+    /// Fails with `EncodeBufferTooSmall` before writing anything if the
+    /// packet doesn't fit `buf`.
+    fn encode(
+        &self,
+        buf: &mut [u8],
+        _version: MqttVersion,
+    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        let remaining_len = self.remaining_len(_version);
+        check_encode_len(buf, remaining_len)?;
+
+        // Fixed header: SUBSCRIBE packet type (8) with reserved bits (0x02)
+        buf[0] = 0x82;
+        let mut cursor = 1 + util::write_variable_byte_integer_len(&mut buf[1..], remaining_len)?;
+
+        // Packet ID
+        buf[cursor..cursor + 2].copy_from_slice(&self.packet_id.to_be_bytes());
+        cursor += 2;
+
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            write_properties(&mut cursor, buf, &self.properties)?;
+        }
 
-        Ok(header_len + remaining_len)
+        // Topic filters with their options
+        for (topic, options) in &self.topics {
+            cursor += write_utf8_string(&mut buf[cursor..], topic)?;
+            buf[cursor] = options.to_byte(_version);
+            cursor += 1;
+        }
+
+        Ok(cursor)
     }
 }
 
 // --- SUBACK Packet ---
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct SubAck<'a> {
     pub packet_id: u16,
     pub reason_codes: Vec<u8, 8>,
     #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
+    pub properties: Properties<'a>,
     #[cfg(not(feature = "v5"))]
     _phantom: PhantomData<&'a ()>,
 }
@@ -588,13 +1993,10 @@ impl<'a> DecodePacket<'a> for SubAck<'a> {
         buf: &'a [u8],
         _version: MqttVersion,
     ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
-        let mut cursor = 1;
-        let remaining_len = util::read_variable_byte_integer(&mut cursor, buf)?;
-        let packet_end = cursor + remaining_len;
+        let (buf, mut cursor) = packet_bounds(buf)?;
 
         // Packet ID
-        let packet_id = u16::from_be_bytes([buf[cursor], buf[cursor + 1]]);
-        cursor += 2;
+        let packet_id = read_u16(&mut cursor, buf)?;
 
         #[cfg(feature = "v5")]
         let properties = if _version == MqttVersion::V5 {
@@ -605,9 +2007,11 @@ impl<'a> DecodePacket<'a> for SubAck<'a> {
 
         // Reason codes
         let mut reason_codes = Vec::new();
-        while cursor < packet_end {
-            let _ = reason_codes.push(buf[cursor]);
-            cursor += 1;
+        let codes = buf
+            .get(cursor..)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+        for &code in codes {
+            let _ = reason_codes.push(code);
         }
 
         Ok(SubAck {
@@ -621,8 +2025,158 @@ impl<'a> DecodePacket<'a> for SubAck<'a> {
     }
 }
 
+// --- UNSUBSCRIBE Packet ---
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Unsubscribe<'a> {
+    pub packet_id: u16,
+    pub topics: Vec<&'a str, MAX_SUBSCRIBE_TOPICS>,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+}
+
+impl<'a> Unsubscribe<'a> {
+    /// Creates a new Unsubscribe packet with a single topic filter.
+    pub fn new(packet_id: u16, topic: &'a str) -> Self {
+        let mut topics = Vec::new();
+        let _ = topics.push(topic);
+        Self {
+            packet_id,
+            topics,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+        }
+    }
+}
+
+impl<'a> DecodePacket<'a> for Unsubscribe<'a> {
+    fn decode(
+        buf: &'a [u8],
+        _version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        let (buf, mut cursor) = packet_bounds(buf)?;
+
+        // Packet ID
+        let packet_id = read_u16(&mut cursor, buf)?;
+
+        #[cfg(feature = "v5")]
+        let properties = if _version == MqttVersion::V5 {
+            read_properties(&mut cursor, buf)?
+        } else {
+            Vec::new()
+        };
+
+        // Topic filters
+        let mut topics = Vec::new();
+        while cursor < buf.len() {
+            let topic = read_utf8_string(&mut cursor, buf)?;
+            topics.push(topic).map_err(|_| MqttError::BufferTooSmall)?;
+        }
+
+        Ok(Unsubscribe {
+            packet_id,
+            topics,
+            #[cfg(feature = "v5")]
+            properties,
+        })
+    }
+}
+
+impl<'a> EncodePacket for Unsubscribe<'a> {
+    fn encode(
+        &self,
+        buf: &mut [u8],
+        _version: MqttVersion,
+    ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        let mut cursor = 0;
+
+        // Fixed header: UNSUBSCRIBE packet type (10) with reserved bits (0x02)
+        buf[cursor] = 0xA2;
+        cursor += 1;
+
+        // Reserve space for remaining length
+        let remaining_len_pos = cursor;
+        cursor += 4;
+        let content_start = cursor;
+
+        // Packet ID
+        buf[cursor..cursor + 2].copy_from_slice(&self.packet_id.to_be_bytes());
+        cursor += 2;
+
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 {
+            write_properties(&mut cursor, buf, &self.properties)?;
+        }
+
+        // Topic filters
+        for topic in &self.topics {
+            cursor += write_utf8_string(&mut buf[cursor..], topic)?;
+        }
+
+        // Write remaining length and compact
+        let remaining_len = cursor - content_start;
+        let len_bytes =
+            util::write_variable_byte_integer_len(&mut buf[remaining_len_pos..], remaining_len)?;
+        let header_len = 1 + len_bytes;
+        buf.copy_within(content_start..cursor, header_len);
+
+        Ok(header_len + remaining_len)
+    }
+}
+
+// --- UNSUBACK Packet ---
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UnsubAck<'a> {
+    pub packet_id: u16,
+    /// One reason code per topic filter in MQTT v5; empty in v3.1.1, where
+    /// an UNSUBACK carries no payload.
+    pub reason_codes: Vec<u8, MAX_SUBSCRIBE_TOPICS>,
+    #[cfg(feature = "v5")]
+    pub properties: Properties<'a>,
+    #[cfg(not(feature = "v5"))]
+    _phantom: PhantomData<&'a ()>,
+}
+impl<'a> DecodePacket<'a> for UnsubAck<'a> {
+    fn decode(
+        buf: &'a [u8],
+        _version: MqttVersion,
+    ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        let (buf, mut cursor) = packet_bounds(buf)?;
+
+        // Packet ID
+        let packet_id = read_u16(&mut cursor, buf)?;
+
+        #[cfg(feature = "v5")]
+        let properties = if _version == MqttVersion::V5 {
+            read_properties(&mut cursor, buf)?
+        } else {
+            Vec::new()
+        };
+
+        // Reason codes (v5 only)
+        let mut reason_codes = Vec::new();
+        let codes = buf
+            .get(cursor..)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+        for &code in codes {
+            let _ = reason_codes.push(code);
+        }
+
+        Ok(UnsubAck {
+            packet_id,
+            reason_codes,
+            #[cfg(feature = "v5")]
+            properties,
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        })
+    }
+}
+
 // --- PINGREQ Packet ---
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct PingReq;
 impl EncodePacket for PingReq {
     fn encode(
@@ -641,15 +2195,17 @@ impl EncodePacket for PingReq {
 
 // --- PINGRESP Packet ---
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct PingResp;
 
 // --- DISCONNECT Packet ---
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Disconnect<'a> {
     #[cfg(feature = "v5")]
     pub reason_code: u8,
     #[cfg(feature = "v5")]
-    pub properties: Vec<Property<'a>, 8>,
+    pub properties: Properties<'a>,
     #[cfg(not(feature = "v5"))]
     pub _phantom: PhantomData<&'a ()>,
 }
@@ -658,22 +2214,107 @@ impl<'a> DecodePacket<'a> for Disconnect<'a> {
         _buf: &'a [u8],
         _version: MqttVersion,
     ) -> Result<Self, MqttError<transport::ErrorPlaceHolder>> {
+        #[cfg(not(feature = "v5"))]
+        packet_bounds(_buf)?;
+        // A normal disconnection may omit the reason code and properties
+        #[cfg(feature = "v5")]
+        let (reason_code, properties) = {
+            let (buf, mut cursor) = packet_bounds(_buf)?;
+            if _version == MqttVersion::V5 && cursor < buf.len() {
+                let reason_code = read_u8(&mut cursor, buf)?;
+                let properties = if cursor < buf.len() {
+                    read_properties(&mut cursor, buf)?
+                } else {
+                    Vec::new()
+                };
+                (reason_code, properties)
+            } else {
+                (0, Vec::new())
+            }
+        };
+
         Ok(Disconnect {
+            #[cfg(feature = "v5")]
+            reason_code,
+            #[cfg(feature = "v5")]
+            properties,
+            #[cfg(not(feature = "v5"))]
+            _phantom: PhantomData,
+        })
+    }
+}
+impl<'a> Disconnect<'a> {
+    /// Creates a DISCONNECT packet for a normal disconnection.
+    pub fn new() -> Self {
+        Self {
             #[cfg(feature = "v5")]
             reason_code: 0,
             #[cfg(feature = "v5")]
             properties: Vec::new(),
             #[cfg(not(feature = "v5"))]
             _phantom: PhantomData,
-        })
+        }
+    }
+
+    /// Creates a DISCONNECT packet carrying an MQTT v5 reason code.
+    #[cfg(feature = "v5")]
+    pub fn with_reason_code(reason_code: u8) -> Self {
+        Self {
+            reason_code,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Returns the human-readable Reason String a v5 broker may add to
+    /// explain the disconnection.
+    #[cfg(feature = "v5")]
+    pub fn reason_string(&self) -> Option<&'a str> {
+        self.properties
+            .find_property(Property::REASON_STRING)
+            .and_then(Property::as_str)
+    }
+}
+
+impl<'a> Default for Disconnect<'a> {
+    fn default() -> Self {
+        Self::new()
     }
 }
+
 impl<'a> EncodePacket for Disconnect<'a> {
     fn encode(
         &self,
         buf: &mut [u8],
         _version: MqttVersion,
     ) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        // v5 may omit the variable header entirely for a normal disconnection
+        #[cfg(feature = "v5")]
+        if _version == MqttVersion::V5 && (self.reason_code != 0 || !self.properties.is_empty()) {
+            let mut cursor = 0;
+            *buf.get_mut(cursor).ok_or(MqttError::BufferTooSmall)? = 0xE0;
+            cursor += 1;
+
+            // Reserve space for remaining length
+            let remaining_len_pos = cursor;
+            cursor += 4;
+            let content_start = cursor;
+
+            *buf.get_mut(cursor).ok_or(MqttError::BufferTooSmall)? = self.reason_code;
+            cursor += 1;
+            write_properties(&mut cursor, buf, &self.properties)?;
+
+            // Write remaining length and compact
+            let remaining_len = cursor - content_start;
+            let len_bytes = util::write_variable_byte_integer_len(
+                &mut buf[remaining_len_pos..],
+                remaining_len,
+            )?;
+            let header_len = 1 + len_bytes;
+            buf.copy_within(content_start..cursor, header_len);
+
+            return Ok(header_len + remaining_len);
+        }
+
         if buf.len() < 2 {
             return Err(MqttError::BufferTooSmall);
         }
@@ -682,3 +2323,490 @@ impl<'a> EncodePacket for Disconnect<'a> {
         Ok(2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ErrorPlaceHolder;
+
+    #[test]
+    fn publish_decodes_dup_flag() {
+        // QoS 1 with DUP on "t", packet id 5, payload "x"
+        let packet = [0x3A, 6, 0, 1, b't', 0, 5, b'x'];
+        let publish = Publish::decode(&packet, MqttVersion::V3).unwrap();
+        assert!(publish.dup);
+        assert_eq!(publish.qos, QoS::AtLeastOnce);
+        assert_eq!(publish.packet_id, Some(5));
+        assert_eq!(publish.payload, b"x");
+
+        let packet = [0x32, 6, 0, 1, b't', 0, 5, b'x'];
+        assert!(!Publish::decode(&packet, MqttVersion::V3).unwrap().dup);
+    }
+
+    /// One well-formed v3.1.1 packet of each type.
+    const PACKETS: [&[u8]; 13] = [
+        &[
+            0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 2, 0, 60, 0, 1, b'c',
+        ],
+        &[0x20, 2, 0, 0],
+        &[0x32, 6, 0, 1, b't', 0, 5, b'x'],
+        &[0x40, 2, 0, 5],
+        &[0x50, 2, 0, 5],
+        &[0x62, 2, 0, 5],
+        &[0x70, 2, 0, 5],
+        &[0x82, 6, 0, 1, 0, 1, b't', 0],
+        &[0x90, 3, 0, 1, 0],
+        &[0xA2, 5, 0, 1, 0, 1, b't'],
+        &[0xB0, 2, 0, 1],
+        &[0xD0, 0],
+        &[0xE0, 0],
+    ];
+
+    fn decode_v3(buf: &[u8]) -> Result<Option<MqttPacket<'_>>, MqttError<ErrorPlaceHolder>> {
+        decode(buf, MqttVersion::V3)
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        for packet in PACKETS {
+            assert!(decode_v3(packet).unwrap().is_some(), "{packet:?}");
+            for len in 1..packet.len() {
+                let result = decode_v3(&packet[..len]);
+                assert!(
+                    matches!(
+                        result,
+                        Err(MqttError::Protocol(ProtocolError::MalformedPacket))
+                    ),
+                    "{:?} -> {result:?}",
+                    &packet[..len]
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn connack_capabilities_default_to_available() {
+        // Retain Available 0
+        let packet = [0x20, 5, 0, 0, 2, 0x25, 0];
+        let connack = ConnAck::decode(&packet, MqttVersion::V5).unwrap();
+        let capabilities = connack.capabilities();
+        assert!(!capabilities.retain_available);
+        assert!(capabilities.wildcard_subscription_available);
+        assert!(capabilities.subscription_identifiers_available);
+        assert!(capabilities.shared_subscription_available);
+    }
+
+    #[test]
+    fn remaining_length_past_the_buffer_is_rejected() {
+        // Declares 100 bytes but carries 6
+        let packet = [0x32, 100, 0, 1, b't', 0, 5, b'x'];
+        assert!(matches!(
+            decode_v3(&packet),
+            Err(MqttError::Protocol(ProtocolError::MalformedPacket))
+        ));
+    }
+
+    #[test]
+    fn decoders_reject_a_one_byte_buffer() {
+        let version = MqttVersion::V3;
+        fn malformed<P: core::fmt::Debug>(result: Result<P, MqttError<ErrorPlaceHolder>>) {
+            assert!(
+                matches!(
+                    result,
+                    Err(MqttError::Protocol(ProtocolError::MalformedPacket))
+                ),
+                "{result:?}"
+            );
+        }
+        malformed(Connect::decode(&[0x10], version));
+        malformed(ConnAck::decode(&[0x20], version));
+        malformed(Publish::decode(&[0x30], version));
+        malformed(PubAck::decode(&[0x40], version));
+        malformed(PubRec::decode(&[0x50], version));
+        malformed(PubRel::decode(&[0x62], version));
+        malformed(PubComp::decode(&[0x70], version));
+        malformed(Subscribe::decode(&[0x82], version));
+        malformed(SubAck::decode(&[0x90], version));
+        malformed(Unsubscribe::decode(&[0xA2], version));
+        malformed(UnsubAck::decode(&[0xB0], version));
+        malformed(Disconnect::decode(&[0xE0], version));
+    }
+
+    #[test]
+    fn encoders_report_the_needed_length_before_writing() {
+        let version = MqttVersion::V3;
+        let subscribe =
+            Subscribe::with_topics(1, &[("a/b", QoS::AtMostOnce), ("c/d/e", QoS::AtLeastOnce)])
+                .unwrap();
+        // Fixed header, packet id, then two length-prefixed filters with options
+        let needed = 2 + 2 + (2 + 3 + 1) + (2 + 5 + 1);
+        assert_eq!(subscribe.encoded_len(version), needed);
+        let mut buf = [0xAA; 32];
+        let result = subscribe.encode(&mut buf[..needed - 1], version);
+        assert!(matches!(
+            result,
+            Err(MqttError::EncodeBufferTooSmall {
+                needed: 18,
+                available: 17
+            })
+        ));
+        // Nothing was written
+        assert!(buf.iter().all(|&b| b == 0xAA));
+        assert_eq!(subscribe.encode(&mut buf, version).unwrap(), needed);
+
+        let publish = Publish::new("t", b"payload", QoS::AtMostOnce);
+        let result = publish.encode(&mut buf[..4], version);
+        assert!(matches!(
+            result,
+            Err(MqttError::EncodeBufferTooSmall {
+                needed: 12,
+                available: 4
+            })
+        ));
+        assert_eq!(publish.encoded_len(version), 12);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn user_properties_round_trip() {
+        let (mut first, mut second) = ([0u8; 16], [0u8; 16]);
+        let mut publish = Publish::new("t", b"x", QoS::AtMostOnce);
+        publish.add_user_property(&mut first, "unit", "C").unwrap();
+        publish
+            .add_user_property(&mut second, "src", "probe-2")
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = publish.encode(&mut buf, MqttVersion::V5).unwrap();
+        let decoded = Publish::decode(&buf[..len], MqttVersion::V5).unwrap();
+        let mut pairs = decoded.user_properties();
+        assert_eq!(pairs.next(), Some(("unit", "C")));
+        assert_eq!(pairs.next(), Some(("src", "probe-2")));
+        assert_eq!(pairs.next(), None);
+        assert_eq!(decoded.payload, b"x");
+    }
+
+    /// The protocol versions this build can encode.
+    fn versions() -> &'static [MqttVersion] {
+        if cfg!(feature = "v5") {
+            &[MqttVersion::V3, MqttVersion::V5]
+        } else {
+            &[MqttVersion::V3]
+        }
+    }
+
+    #[test]
+    fn subscribe_round_trips_every_filter() {
+        let filters = [
+            ("a/+", QoS::AtMostOnce),
+            ("b/#", QoS::AtLeastOnce),
+            ("c", QoS::ExactlyOnce),
+        ];
+        let subscribe = Subscribe::with_topics(42, &filters).unwrap();
+        for &version in versions() {
+            let mut buf = [0u8; 64];
+            let len = subscribe.encode(&mut buf, version).unwrap();
+            let decoded = Subscribe::decode(&buf[..len], version).unwrap();
+            assert_eq!(decoded.packet_id, 42);
+            assert_eq!(decoded.topics.len(), filters.len());
+            for ((topic, options), &(expected, qos)) in decoded.topics.iter().zip(&filters) {
+                assert_eq!(*topic, expected);
+                assert_eq!(*options, SubscribeOptions::new(qos));
+            }
+        }
+    }
+
+    #[test]
+    fn subscribe_encodes_every_filter_with_its_options() {
+        let subscribe = Subscribe::with_topics(
+            7,
+            &[
+                ("a/1", QoS::AtMostOnce),
+                ("b/+", QoS::AtLeastOnce),
+                ("c/#", QoS::ExactlyOnce),
+            ],
+        )
+        .unwrap();
+        let mut buf = [0u8; 64];
+        let len = subscribe.encode(&mut buf, MqttVersion::V3).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0x82, 20, 0, 7,
+            0, 3, b'a', b'/', b'1', 0,
+            0, 3, b'b', b'/', b'+', 1,
+            0, 3, b'c', b'/', b'#', 2,
+        ];
+        assert_eq!(buf[..len], expected);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn response_topic_and_correlation_data_round_trip() {
+        let (mut topic, mut data) = ([0u8; 16], [0u8; 8]);
+        let mut request = Publish::new("rpc/req", b"ping", QoS::AtMostOnce);
+        request.set_response_topic(&mut topic, "rpc/resp").unwrap();
+        request.set_correlation_data(&mut data, &[1, 2, 3]).unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = request.encode(&mut buf, MqttVersion::V5).unwrap();
+        let decoded = Publish::decode(&buf[..len], MqttVersion::V5).unwrap();
+        assert_eq!(decoded.response_topic(), Some("rpc/resp"));
+        assert_eq!(decoded.correlation_data(), Some(&[1, 2, 3][..]));
+        assert_eq!(decoded.payload, b"ping");
+
+        // A reply built from the request carries the same Correlation Data
+        let mut data = [0u8; 8];
+        let mut reply = Publish::new(decoded.response_topic().unwrap(), b"pong", QoS::AtMostOnce);
+        reply
+            .set_correlation_data(&mut data, decoded.correlation_data().unwrap())
+            .unwrap();
+        let mut reply_buf = [0u8; 64];
+        let len = reply.encode(&mut reply_buf, MqttVersion::V5).unwrap();
+        let decoded = Publish::decode(&reply_buf[..len], MqttVersion::V5).unwrap();
+        assert_eq!(decoded.topic, "rpc/resp");
+        assert_eq!(decoded.response_topic(), None);
+        assert_eq!(decoded.correlation_data(), Some(&[1, 2, 3][..]));
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn message_expiry_encodes_as_a_four_byte_property() {
+        let mut scratch = [0u8; 4];
+        let mut publish = Publish::new("t", b"x", QoS::AtMostOnce);
+        publish.set_message_expiry(&mut scratch, 3600).unwrap();
+
+        let mut buf = [0u8; 32];
+        let len = publish.encode(&mut buf, MqttVersion::V5).unwrap();
+        // Property block of 5 bytes: id 0x02, then 3600 big-endian
+        assert_eq!(
+            &buf[..len],
+            [0x30, 10, 0, 1, b't', 5, 0x02, 0x00, 0x00, 0x0E, 0x10, b'x']
+        );
+        let decoded = Publish::decode(&buf[..len], MqttVersion::V5).unwrap();
+        assert_eq!(decoded.message_expiry(), Some(3600));
+    }
+
+    #[test]
+    fn empty_retained_publish_encodes_the_topic_only() {
+        let publish = Publish::new("a/b", b"", QoS::AtMostOnce).retain(true);
+        for &version in versions() {
+            let mut buf = [0u8; 16];
+            let len = publish.encode(&mut buf, version).unwrap();
+            // No payload follows the topic, or the empty v5 property block
+            let expected: &[u8] = match version {
+                MqttVersion::V3 => &[0x31, 5, 0, 3, b'a', b'/', b'b'],
+                _ => &[0x31, 6, 0, 3, b'a', b'/', b'b', 0],
+            };
+            assert_eq!(&buf[..len], expected);
+
+            let decoded = Publish::decode(&buf[..len], version).unwrap();
+            assert!(decoded.retain);
+            assert_eq!(decoded.topic, "a/b");
+            assert!(decoded.payload.is_empty());
+        }
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn malformed_property_values_do_not_decode() {
+        let prop = |data| Property { id: 0, data };
+        assert_eq!(prop(&[1, 2]).as_u8(), None);
+        assert_eq!(prop(&[1]).as_u16(), None);
+        assert_eq!(prop(&[0, 0, 1]).as_u32(), None);
+        assert_eq!(prop(&[0, 0, 0, 1, 0]).as_u32(), None);
+        // A continuation bit with nothing following
+        assert_eq!(prop(&[0x80]).as_variable_int(), None);
+        // Length prefixes longer than the data
+        assert_eq!(prop(&[0, 4, b'a', b'b']).as_str(), None);
+        assert_eq!(prop(&[0, 3, 1, 2]).as_binary(), None);
+        // Invalid UTF-8
+        assert_eq!(prop(&[0, 2, 0xC3, 0x28]).as_str(), None);
+        // A key without its value
+        assert_eq!(prop(&[0, 1, b'k']).as_str_pair(), None);
+        assert_eq!(prop(&[]).as_str(), None);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn absent_and_repeated_properties_are_looked_up() {
+        let props = [
+            Property {
+                id: Property::USER_PROPERTY,
+                data: &[0, 1, b'a', 0, 1, b'1'],
+            },
+            Property {
+                id: Property::TOPIC_ALIAS,
+                data: &[0, 7],
+            },
+            Property {
+                id: Property::USER_PROPERTY,
+                data: &[0, 1, b'b', 0, 1, b'2'],
+            },
+        ];
+        assert!(props.find_property(Property::CONTENT_TYPE).is_none());
+        assert_eq!(
+            props
+                .find_property(Property::USER_PROPERTY)
+                .and_then(Property::as_str_pair),
+            Some(("a", "1"))
+        );
+        let pairs: Vec<_, 4> = props
+            .filter_properties(Property::USER_PROPERTY)
+            .filter_map(Property::as_str_pair)
+            .collect();
+        assert_eq!(pairs, [("a", "1"), ("b", "2")]);
+        assert_eq!(props.filter_properties(Property::CONTENT_TYPE).count(), 0);
+    }
+
+    #[test]
+    fn qos1_publish_without_a_packet_id_is_not_encoded() {
+        let publish = Publish::new("t", b"x", QoS::AtLeastOnce);
+        for &version in versions() {
+            let mut buf = [0xAA; 16];
+            assert!(matches!(
+                publish.encode(&mut buf, version),
+                Err(MqttError::Protocol(ProtocolError::MissingPacketId))
+            ));
+            assert_eq!(buf, [0xAA; 16]);
+        }
+        // The computed length includes the identifier it needs
+        assert_eq!(
+            publish.encoded_len(MqttVersion::V3),
+            publish.with_packet_id(1).encoded_len(MqttVersion::V3)
+        );
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn subscription_id_round_trips() {
+        // 300 takes two bytes as a Variable Byte Integer
+        let mut scratch = [0u8; 4];
+        let mut subscribe = Subscribe::new(1, "a/+", QoS::AtMostOnce);
+        subscribe.set_subscription_id(&mut scratch, 300).unwrap();
+        let mut buf = [0u8; 32];
+        let len = subscribe.encode(&mut buf, MqttVersion::V5).unwrap();
+        assert_eq!(
+            &buf[4..8],
+            [3, Property::SUBSCRIPTION_IDENTIFIER, 0xAC, 0x02]
+        );
+        let decoded = Subscribe::decode(&buf[..len], MqttVersion::V5).unwrap();
+        let id = decoded
+            .properties
+            .find_property(Property::SUBSCRIPTION_IDENTIFIER)
+            .and_then(Property::as_variable_int);
+        assert_eq!(id, Some(300));
+
+        // The broker tags matching messages with it
+        let mut publish = Publish::new("a/b", b"1", QoS::AtMostOnce);
+        publish
+            .properties
+            .push(Property {
+                id: Property::SUBSCRIPTION_IDENTIFIER,
+                data: &[0xAC, 0x02],
+            })
+            .unwrap();
+        let mut buf = [0u8; 32];
+        let len = publish.encode(&mut buf, MqttVersion::V5).unwrap();
+        let decoded = Publish::decode(&buf[..len], MqttVersion::V5).unwrap();
+        assert_eq!(decoded.subscription_id(), Some(300));
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn subscription_id_out_of_range_is_rejected() {
+        for id in [0, MAX_SUBSCRIPTION_ID + 1] {
+            let mut scratch = [0u8; 4];
+            let mut subscribe = Subscribe::new(1, "a/+", QoS::AtMostOnce);
+            assert!(matches!(
+                subscribe.set_subscription_id(&mut scratch, id),
+                Err(MqttError::Protocol(ProtocolError::InvalidSubscriptionId))
+            ));
+            assert!(subscribe.properties.is_empty());
+        }
+    }
+
+    #[cfg(feature = "v5")]
+    fn connect_with_will(will: LastWill<'_>) -> Connect<'_> {
+        Connect::with_credentials("c", 60, true, None, None, Some(will))
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn will_delay_needs_four_bytes_of_scratch() {
+        let will = LastWill {
+            topic: "t",
+            payload: b"x",
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+        let mut scratch = [0u8; 3];
+        let mut connect = connect_with_will(will);
+        assert!(matches!(
+            connect.set_will_delay(&mut scratch, 30),
+            Err(MqttError::BufferTooSmall)
+        ));
+        assert!(connect.will_properties.is_empty());
+        assert_eq!(connect.will_delay(), None);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn will_delay_is_replaced_and_round_trips() {
+        let will = LastWill {
+            topic: "t",
+            payload: b"x",
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+        let (mut first, mut second) = ([0u8; 4], [0u8; 4]);
+        let mut connect = connect_with_will(will);
+        connect.set_will_delay(&mut first, 30).unwrap();
+        connect.set_will_delay(&mut second, u32::MAX).unwrap();
+        assert_eq!(connect.will_properties.len(), 1);
+        assert_eq!(connect.will_delay(), Some(u32::MAX));
+
+        let mut buf = [0u8; 64];
+        let len = connect.encode(&mut buf, MqttVersion::V5).unwrap();
+        let decoded = Connect::decode(&buf[..len], MqttVersion::V5).unwrap();
+        assert_eq!(decoded.will_delay(), Some(u32::MAX));
+        assert_eq!(decoded.will.unwrap().topic, "t");
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn will_properties_are_not_sent_in_v3() {
+        let will = LastWill {
+            topic: "t",
+            payload: b"x",
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+        let mut scratch = [0u8; 4];
+        let mut connect = connect_with_will(will);
+        connect.set_will_delay(&mut scratch, 30).unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = connect.encode(&mut buf, MqttVersion::V3).unwrap();
+        // Client id, will topic and will payload, with no property block
+        assert_eq!(&buf[len - 9..len], &[0, 1, b'c', 0, 1, b't', 0, 1, b'x']);
+    }
+
+    #[test]
+    fn payload_str_handles_empty_and_truncated_payloads() {
+        let msg = Publish::decode(&[0x30, 3, 0, 1, b't'], MqttVersion::V3).unwrap();
+        assert_eq!(msg.payload_str(), Ok(""));
+        assert!(msg.is_valid_utf8());
+
+        // "°C" is fine, but the payload cut in the middle of "°" is not
+        let msg =
+            Publish::decode(&[0x30, 6, 0, 1, b't', 0xC2, 0xB0, b'C'], MqttVersion::V3).unwrap();
+        assert_eq!(msg.payload_str(), Ok("°C"));
+        let msg = Publish::decode(&[0x30, 4, 0, 1, b't', 0xC2], MqttVersion::V3).unwrap();
+        assert_eq!(msg.payload_str(), Err(ProtocolError::InvalidUtf8String));
+
+        // An overlong encoding of "/" is rejected too
+        let msg = Publish::decode(&[0x30, 5, 0, 1, b't', 0xC0, 0xAF], MqttVersion::V3).unwrap();
+        assert!(!msg.is_valid_utf8());
+    }
+}
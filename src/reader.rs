@@ -0,0 +1,281 @@
+//! # Packet Reader
+//!
+//! Stream transports deliver bytes without message boundaries: a single `recv`
+//! may return half a packet, or the end of one packet followed by the start of
+//! the next. This module provides `PacketReader`, which accumulates received
+//! bytes and yields one complete MQTT packet at a time using the length encoded
+//! in the fixed header.
+
+use crate::error::{MqttError, ProtocolError};
+use crate::transport::{self, MqttTransport};
+
+/// Assembles complete MQTT packets from arbitrary chunks of a byte stream.
+///
+/// Bytes are read into an internal buffer of `N` bytes, which also bounds the
+/// largest packet that can be received. A packet returned by `packet` stays
+/// valid until the next call to `next_packet` or `fill`, which discard it.
+///
+/// A packet can be kept for later with `hold` instead, e.g. a message that
+/// arrives while the client waits for an acknowledgment. Held packets stay at
+/// the front of the buffer; after `release_held` they are yielded again, in
+/// order, before any packet read after them, and `is_replayed` tells them
+/// apart.
+pub struct PacketReader<const N: usize> {
+    buf: [u8; N],
+    /// Number of buffered bytes.
+    len: usize,
+    /// Length of the packet yielded last, discarded on the next read.
+    current: usize,
+    /// Length of the held packets at the front of the buffer.
+    held: usize,
+    /// Length of the released packets following the held ones, which are
+    /// yielded again.
+    replay: usize,
+}
+
+impl<const N: usize> PacketReader<N> {
+    /// Creates an empty reader.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            current: 0,
+            held: 0,
+            replay: 0,
+        }
+    }
+
+    /// Drops all buffered bytes, e.g. after the connection was reset.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.current = 0;
+        self.held = 0;
+        self.replay = 0;
+    }
+
+    /// Discards the previously yielded packet and checks whether the next one
+    /// has been fully received.
+    ///
+    /// Returns the packet length if it is complete, after which it can be
+    /// accessed with `packet`. Returns `None` if more bytes are needed.
+    pub fn next_packet(&mut self) -> Result<Option<usize>, MqttError<transport::ErrorPlaceHolder>> {
+        self.discard_current();
+
+        let Some(total_len) = Self::frame_len(&self.buf[self.held..self.len])? else {
+            return Ok(None);
+        };
+        if total_len > N - self.held {
+            return Err(MqttError::BufferTooSmall);
+        }
+        if total_len > self.len - self.held {
+            return Ok(None);
+        }
+
+        self.current = total_len;
+        Ok(Some(total_len))
+    }
+
+    /// Returns the packet yielded by the last successful `next_packet`.
+    pub fn packet(&self) -> &[u8] {
+        &self.buf[self.held..self.held + self.current]
+    }
+
+    /// Returns whether the packet yielded last was held and released before.
+    pub fn is_replayed(&self) -> bool {
+        self.replay > 0
+    }
+
+    /// Returns whether the packet yielded last can be held while leaving at
+    /// least half of the buffer for reading.
+    pub fn can_hold(&self) -> bool {
+        self.held + self.current <= N / 2
+    }
+
+    /// Keeps the packet yielded last instead of discarding it on the next
+    /// read.
+    pub fn hold(&mut self) {
+        self.held += self.current;
+        self.replay = self.replay.saturating_sub(self.current);
+        self.current = 0;
+    }
+
+    /// Lets the held packets be yielded again, ahead of the rest.
+    pub fn release_held(&mut self) {
+        self.discard_current();
+        self.replay += self.held;
+        self.held = 0;
+    }
+
+    /// Reads more bytes from the transport into the buffer.
+    ///
+    /// Returns the number of bytes read.
+    pub async fn fill<T: MqttTransport>(&mut self, transport: &mut T) -> Result<usize, T::Error> {
+        self.discard_current();
+        let n = transport.recv(&mut self.buf[self.len..]).await?;
+        self.len += n;
+        Ok(n)
+    }
+
+    /// Like `fill`, but only reads data the transport already has, returning
+    /// 0 if there is none.
+    pub async fn try_fill<T: MqttTransport>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<usize, T::Error> {
+        self.discard_current();
+        let n = transport.try_recv(&mut self.buf[self.len..]).await?;
+        self.len += n;
+        Ok(n)
+    }
+
+    fn discard_current(&mut self) {
+        if self.current > 0 {
+            self.buf
+                .copy_within(self.held + self.current..self.len, self.held);
+            self.len -= self.current;
+            self.replay = self.replay.saturating_sub(self.current);
+            self.current = 0;
+        }
+    }
+
+    /// Parses the fixed header and returns the total packet length, or `None`
+    /// if the header itself is incomplete.
+    fn frame_len(buf: &[u8]) -> Result<Option<usize>, MqttError<transport::ErrorPlaceHolder>> {
+        let mut remaining_len = 0usize;
+        for i in 0..4 {
+            let Some(&byte) = buf.get(1 + i) else {
+                return Ok(None);
+            };
+            remaining_len |= usize::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(Some(2 + i + remaining_len));
+            }
+        }
+        Err(MqttError::Protocol(ProtocolError::MalformedPacket))
+    }
+}
+
+impl<const N: usize> Default for PacketReader<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MqttVersion;
+    use crate::packet::{DecodePacket, Publish};
+    use crate::test_util::{MockError, MockTransport};
+
+    /// Hands out the scripted bytes one at a time.
+    struct OneByte(MockTransport<32>);
+
+    impl MqttTransport for OneByte {
+        type Error = MockError;
+
+        async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            self.0.send(buf).await
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.0.recv(&mut buf[..1]).await
+        }
+    }
+
+    #[test]
+    fn packet_fed_one_byte_at_a_time_is_assembled() {
+        let packet = [0x30, 5, 0, 1, b't', b'h', b'i'];
+        let mut transport = OneByte(MockTransport::new(MqttVersion::V3));
+        assert!(transport.0.push_rx(&packet));
+        let mut reader = PacketReader::<32>::new();
+
+        for _ in 1..packet.len() {
+            embassy_futures::block_on(reader.fill(&mut transport)).unwrap();
+            assert_eq!(reader.next_packet().unwrap(), None);
+        }
+        embassy_futures::block_on(reader.fill(&mut transport)).unwrap();
+        assert_eq!(reader.next_packet().unwrap(), Some(packet.len()));
+        assert_eq!(reader.packet(), packet);
+    }
+
+    #[test]
+    fn two_packets_in_one_read_are_yielded_in_order() {
+        let mut transport = MockTransport::<32>::new(MqttVersion::V3);
+        assert!(transport.push_rx(&[0x40, 2, 0, 1, 0xD0, 0]));
+        let mut reader = PacketReader::<32>::new();
+        assert_eq!(
+            embassy_futures::block_on(reader.fill(&mut transport)),
+            Ok(6)
+        );
+
+        assert_eq!(reader.next_packet().unwrap(), Some(4));
+        assert_eq!(reader.packet(), &[0x40, 2, 0, 1]);
+        assert_eq!(reader.next_packet().unwrap(), Some(2));
+        assert_eq!(reader.packet(), &[0xD0, 0]);
+        assert_eq!(reader.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn held_packets_are_replayed_first() {
+        let mut transport = MockTransport::<32>::new(MqttVersion::V3);
+        assert!(transport.push_rx(&[0x30, 2, 0, 0, 0xD0, 0, 0x40, 2, 0, 1]));
+        let mut reader = PacketReader::<32>::new();
+        embassy_futures::block_on(reader.fill(&mut transport)).unwrap();
+
+        assert_eq!(reader.next_packet().unwrap(), Some(4));
+        assert!(reader.can_hold());
+        reader.hold();
+        assert_eq!(reader.next_packet().unwrap(), Some(2));
+        assert_eq!(reader.packet(), &[0xD0, 0]);
+        assert!(!reader.is_replayed());
+
+        reader.release_held();
+        assert_eq!(reader.next_packet().unwrap(), Some(4));
+        assert_eq!(reader.packet(), &[0x30, 2, 0, 0]);
+        assert!(reader.is_replayed());
+        assert_eq!(reader.next_packet().unwrap(), Some(4));
+        assert_eq!(reader.packet(), &[0x40, 2, 0, 1]);
+        assert!(!reader.is_replayed());
+        assert_eq!(reader.next_packet().unwrap(), None);
+    }
+
+    /// Returns a QoS 0 PUBLISH on topic `t` that is `len` bytes long.
+    fn publish_of_len(len: usize) -> heapless::Vec<u8, 64> {
+        let mut packet = heapless::Vec::new();
+        packet
+            .extend_from_slice(&[0x30, (len - 2) as u8, 0, 1, b't'])
+            .unwrap();
+        packet.resize(len, b'x').unwrap();
+        packet
+    }
+
+    #[test]
+    fn publish_filling_the_whole_buffer_is_read_in_place() {
+        let packet = publish_of_len(32);
+        let mut transport = MockTransport::<64>::new(MqttVersion::V3);
+        assert!(transport.push_rx(&packet));
+        let mut reader = PacketReader::<32>::new();
+        embassy_futures::block_on(reader.fill(&mut transport)).unwrap();
+
+        assert_eq!(reader.next_packet().unwrap(), Some(32));
+        let msg = Publish::decode(reader.packet(), MqttVersion::V3).unwrap();
+        assert_eq!(msg.payload_len(), 27);
+        // The payload is the tail of the receive buffer, not a copy
+        assert!(core::ptr::eq(msg.payload, &reader.buf[5..]));
+    }
+
+    #[test]
+    fn publish_one_byte_larger_than_the_buffer_is_rejected() {
+        let packet = publish_of_len(33);
+        let mut transport = MockTransport::<64>::new(MqttVersion::V3);
+        assert!(transport.push_rx(&packet));
+        let mut reader = PacketReader::<32>::new();
+        embassy_futures::block_on(reader.fill(&mut transport)).unwrap();
+
+        assert!(matches!(
+            reader.next_packet(),
+            Err(MqttError::BufferTooSmall)
+        ));
+    }
+}
@@ -1,22 +1,28 @@
 //! MQTT Runtime - drives modules and handles the event loop.
 
 use embassy_futures::select::{Either, select};
+use embassy_futures::yield_now;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Receiver;
 use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
 
-use super::publisher::{BufferedOutbox, PublishRequest};
-use super::registry::TopicRegistry;
+use super::inbound::{InboundQueue, InboundSink};
+use super::offline::{OfflineQueue, OfflineSink, is_connection_lost};
+use super::pause::PauseControl;
+use super::publisher::{BufferedOutbox, PublishDecision, PublishMiddleware, PublishRequest};
+use super::registry::{MAX_TOPIC_LEN, TopicRegistry};
 use super::traits::MqttModule;
 use crate::client::MqttClient;
 use crate::error::MqttError;
+use crate::packet::MAX_SUBSCRIBE_TOPICS;
 use crate::transport::{MqttTransport, TransportError};
 use crate::{MqttEvent, QoS};
 
 /// The MQTT runtime that drives modules and handles the event loop.
 ///
 /// The runtime owns the `MqttClient` and multiplexes between:
-/// - Incoming MQTT messages (dispatched to modules)
+/// - Incoming MQTT messages (dispatched to modules by their registered topics)
 /// - Outgoing publish requests from controllers (via channel)
 /// - Periodic ticks for module housekeeping
 ///
@@ -33,35 +39,60 @@ use crate::{MqttEvent, QoS};
 /// During startup, the runtime calls `module.register()` with a `TopicCollector`
 /// to collect all topics the module wants to subscribe to. Topics are copied
 /// into the registry, so they only need to live for the duration of the call.
+/// The registry is kept until the next `run`, and incoming messages are
+/// dispatched through `MqttModule::on_dispatch` by the filters it holds.
 ///
 /// # Publishing Pattern
 ///
-/// Modules use a `BufferedOutbox` to queue publish requests during `on_tick`
-/// and `on_start`. The runtime then drains the outbox and performs the actual
+/// Modules use a `BufferedOutbox` to queue publish requests during `on_tick`,
+/// `on_start` and `on_message`. The runtime then drains the outbox and performs the actual
 /// async publishing.
+///
+/// `TX_BUF` and `RX_BUF` are the client's buffer sizes; `RX_BUF` comes last
+/// so that existing single-size declarations keep compiling.
+///
+/// `TOPIC_LEN` is the longest topic the runtime handles, 128 bytes by
+/// default. It sizes the topic registry and the outbox passed to module
+/// callbacks, so longer filters aren't subscribed and longer outbox topics
+/// are rejected.
 pub struct MqttRuntime<
     'a,
     T,
     M,
     const MAX_TOPICS: usize,
-    const BUF_SIZE: usize,
+    const TX_BUF: usize,
     const OUTBOX_DEPTH: usize,
+    const RX_BUF: usize = TX_BUF,
+    const TOPIC_LEN: usize = MAX_TOPIC_LEN,
 > where
     T: MqttTransport,
     M: MqttModule,
 {
-    client: MqttClient<'a, T, MAX_TOPICS, BUF_SIZE>,
+    client: MqttClient<'a, T, MAX_TOPICS, TX_BUF, RX_BUF>,
     module: M,
+    registry: TopicRegistry<MAX_TOPICS, TOPIC_LEN>,
     publisher_rx: Receiver<'a, CriticalSectionRawMutex, PublishRequest<'a>, OUTBOX_DEPTH>,
+    inbound: Option<&'a dyn InboundSink>,
+    offline: Option<&'a dyn OfflineSink>,
+    pause: Option<&'a PauseControl>,
+    middleware: Option<&'a mut dyn PublishMiddleware<TOPIC_LEN, OUTBOX_PAYLOAD_SIZE>>,
+    yield_every: usize,
 }
 
 /// Constants for the internal publish outbox used during module callbacks.
 const OUTBOX_CAPACITY: usize = 8;
-const OUTBOX_TOPIC_SIZE: usize = 128;
 const OUTBOX_PAYLOAD_SIZE: usize = 1024;
 
-impl<'a, T, M, const MAX_TOPICS: usize, const BUF_SIZE: usize, const OUTBOX_DEPTH: usize>
-    MqttRuntime<'a, T, M, MAX_TOPICS, BUF_SIZE, OUTBOX_DEPTH>
+impl<
+    'a,
+    T,
+    M,
+    const MAX_TOPICS: usize,
+    const TX_BUF: usize,
+    const OUTBOX_DEPTH: usize,
+    const RX_BUF: usize,
+    const TOPIC_LEN: usize,
+> MqttRuntime<'a, T, M, MAX_TOPICS, TX_BUF, OUTBOX_DEPTH, RX_BUF, TOPIC_LEN>
 where
     T: MqttTransport,
     T::Error: TransportError,
@@ -75,67 +106,204 @@ where
     /// - `module`: The module (or composed modules) to drive
     /// - `publisher_rx`: Receiver end of the publish request channel
     pub fn new(
-        client: MqttClient<'a, T, MAX_TOPICS, BUF_SIZE>,
+        client: MqttClient<'a, T, MAX_TOPICS, TX_BUF, RX_BUF>,
         module: M,
         publisher_rx: Receiver<'a, CriticalSectionRawMutex, PublishRequest<'a>, OUTBOX_DEPTH>,
     ) -> Self {
         Self {
             client,
             module,
+            registry: TopicRegistry::new(),
             publisher_rx,
+            inbound: None,
+            offline: None,
+            pause: None,
+            middleware: None,
+            yield_every: 1,
         }
     }
 
+    /// Queue incoming messages instead of handling them in the event loop.
+    ///
+    /// Messages the module subscribed to are copied into `queue` rather than
+    /// passed to `on_message`, and a separate task drains it. Reading the
+    /// network then never waits for message processing, which keeps the loop
+    /// responsive when a burst of messages arrives.
+    pub fn with_inbound_queue<
+        const DEPTH: usize,
+        const TOPIC_SIZE: usize,
+        const PAYLOAD_SIZE: usize,
+    >(
+        mut self,
+        queue: &'a InboundQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>,
+    ) -> Self {
+        self.inbound = Some(queue);
+        self
+    }
+
+    /// Keep publishes that fail because the connection dropped.
+    ///
+    /// Such requests, from the publish channel or the module's outbox, are
+    /// moved into `queue` instead of being lost, and the next `run` sends
+    /// them right after connecting, before `on_start`. Requests kept this way
+    /// are not reported to `on_publish_failed`.
+    pub fn with_offline_queue<
+        const DEPTH: usize,
+        const TOPIC_SIZE: usize,
+        const PAYLOAD_SIZE: usize,
+    >(
+        mut self,
+        queue: &'a OfflineQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>,
+    ) -> Self {
+        self.offline = Some(queue);
+        self
+    }
+
+    /// Let another task pause and resume the event loop through `control`.
+    ///
+    /// See `PauseControl` for what happens while paused. A pause requested
+    /// before `on_start` takes effect once the loop is entered.
+    pub fn with_pause_control(mut self, control: &'a PauseControl) -> Self {
+        self.pause = Some(control);
+        self
+    }
+
+    /// Pass every request from the module's outbox through `middleware`
+    /// before it is sent.
+    ///
+    /// Deferred requests stay in the outbox until a later drain sends or drops
+    /// them; they are discarded if the connection is lost first.
+    pub fn with_middleware(
+        mut self,
+        middleware: &'a mut dyn PublishMiddleware<TOPIC_LEN, OUTBOX_PAYLOAD_SIZE>,
+    ) -> Self {
+        self.middleware = Some(middleware);
+        self
+    }
+
+    /// Let other tasks run after every `packets` received messages or
+    /// requests published from the channel (default 1).
+    ///
+    /// While the transport has data buffered, e.g. a burst of retained
+    /// messages after subscribing, reading never waits, and neither does
+    /// draining a full publish channel, so without a yield point the loop
+    /// would keep a single-priority executor to itself. `0` disables yielding.
+    pub fn with_yield_every(mut self, packets: usize) -> Self {
+        self.yield_every = packets;
+        self
+    }
+
     /// Run the MQTT runtime event loop.
     ///
     /// This method:
-    /// 1. Connects to the MQTT broker
-    /// 2. Subscribes to all topics registered by the module
-    /// 3. Calls `on_start` for initial setup
-    /// 4. Enters the main loop handling messages, publishes, and ticks
+    /// 1. Connects to the MQTT broker and passes the session's client id to `on_connect`
+    /// 2. Subscribes to all topics registered by the module, unless the broker
+    ///    resumed a session that still holds the subscriptions
+    /// 3. Publishes the messages kept in the offline queue, if one is set
+    /// 4. Calls `on_start` for initial setup
+    /// 5. Enters the main loop handling messages, publishes, and ticks
+    ///
+    /// Keep-alive pings are sent from the loop based on the client's
+    /// `keep_alive` option. A missing PINGRESP ends the loop with
+    /// `MqttError::Timeout`, and a DISCONNECT from an MQTT v5 broker with
+    /// `MqttError::ServerDisconnect` carrying the broker's reason code. Call
+    /// `run` again to reconnect.
+    ///
+    /// When the loop ends after `on_start` was called, the module is notified
+    /// with `on_disconnect` before the error is returned.
     ///
     /// This method runs forever unless an error occurs.
     pub async fn run(&mut self) -> Result<(), MqttError<T::Error>> {
         if let Some(last_will) = self.module.last_will()
             && !self.client.set_last_will(last_will)
         {
+            self.client
+                .observer()
+                .on_error(&MqttError::<&dyn core::fmt::Debug>::BufferTooSmall);
             return Err(MqttError::BufferTooSmall);
         }
 
         // Connect to the broker
         self.client.connect().await?;
+        self.module.on_connect(self.client.client_id());
 
-        // Collect and subscribe to topics
-        {
-            let mut registry = TopicRegistry::<MAX_TOPICS>::new();
-            self.module.register(&mut registry);
+        // Deliver QoS 1 publishes left unacknowledged by a previous connection
+        self.client.resend_inflight().await?;
 
-            // Subscribe to all registered topics
-            for topic in registry.iter() {
-                self.client.subscribe(topic, QoS::AtMostOnce).await?;
+        // Collect topics for dispatch, and subscribe to them unless a resumed
+        // session kept the subscriptions
+        self.registry.clear();
+        self.module.register(&mut self.registry);
+        let session_present = self.client.session_present();
+        if !session_present {
+            // Subscribe to all registered topics, packing as many filters
+            // as fit into each SUBSCRIBE packet
+            let topics: Vec<(&str, QoS), MAX_TOPICS> = self
+                .registry
+                .iter()
+                .map(|topic| (topic, QoS::AtMostOnce))
+                .collect();
+            for chunk in topics.chunks(MAX_SUBSCRIBE_TOPICS) {
+                self.client.subscribe_many(chunk).await?;
             }
         }
 
         // Create a reusable outbox for module callbacks
-        let mut outbox: BufferedOutbox<OUTBOX_CAPACITY, OUTBOX_TOPIC_SIZE, OUTBOX_PAYLOAD_SIZE> =
+        let mut outbox: BufferedOutbox<OUTBOX_CAPACITY, TOPIC_LEN, OUTBOX_PAYLOAD_SIZE> =
             BufferedOutbox::new();
 
+        self.flush_offline(&mut outbox).await?;
+
         // Call on_start for initial setup
-        self.module.on_start(&mut outbox);
-        self.drain_outbox(&mut outbox).await?;
+        self.module.on_start(session_present, &mut outbox);
+
+        // The loop only returns once the connection is lost
+        let result = self.event_loop(&mut outbox).await;
+        #[cfg(feature = "v5")]
+        if let Err(MqttError::ServerDisconnect { reason_code }) = &result {
+            log_warn!(
+                "mqtt-runtime: disconnected by broker, reason_code={:#x}",
+                reason_code
+            );
+        }
+        self.module.on_disconnect();
+        result
+    }
+
+    /// Publish the `on_start` requests, then handle messages, publish requests
+    /// and ticks until an error occurs.
+    async fn event_loop(
+        &mut self,
+        outbox: &mut BufferedOutbox<OUTBOX_CAPACITY, TOPIC_LEN, OUTBOX_PAYLOAD_SIZE>,
+    ) -> Result<(), MqttError<T::Error>> {
+        self.drain_outbox(outbox).await?;
 
         // Initial tick and set deadline for next tick
-        let tick_interval = self.module.on_tick(&mut outbox);
-        self.drain_outbox(&mut outbox).await?;
+        let tick_interval = self.module.on_tick(outbox);
+        self.drain_outbox(outbox).await?;
         let mut tick_deadline = Instant::now() + tick_interval;
+        let mut handled = 0;
 
         // Main event loop
         loop {
+            // Buffered messages and queued requests are handled without
+            // waiting, so give other tasks a turn
+            if self.yield_every != 0 && handled >= self.yield_every {
+                handled = 0;
+                yield_now().await;
+            }
+
             // First, check for incoming publish requests (non-blocking)
             if let Ok(req) = self.publisher_rx.try_receive() {
-                self.client
+                if let Err(err) = self
+                    .client
                     .publish_with_retain(req.topic, req.payload, req.qos, req.retain)
-                    .await?;
+                    .await
+                {
+                    self.keep_offline(req.topic, req.payload, req.qos, req.retain, &err);
+                    return Err(err);
+                }
+                handled += 1;
                 continue;
             }
 
@@ -147,58 +315,211 @@ where
                 tick_deadline - now
             };
 
-            // Select between poll and tick timer
-            let timer_fut = Timer::after(remaining);
-            let poll_fut = self.client.poll();
-
-            match select(poll_fut, timer_fut).await {
-                Either::First(result) => {
-                    // Incoming MQTT message or keep-alive handled
-                    match result {
-                        Ok(Some(MqttEvent::Publish(msg))) => {
-                            self.module.on_message(&msg);
-                            // If module needs immediate state publish after command
-                            if self.module.needs_immediate_publish() {
-                                self.module.on_publish(&mut outbox);
-                                self.drain_outbox(&mut outbox).await?;
-                            }
-                        }
-                        Ok(None) => {
-                            // No message, keep-alive was sent, continue
+            // Wait for a message, the tick timer or a pause request. Only the
+            // wait for data is raced, so no acknowledgment is cut off halfway
+            let pause = self.pause;
+            let wake_fut = async {
+                let pause_fut = async {
+                    match pause {
+                        Some(control) => control.wait_for(true).await,
+                        None => core::future::pending().await,
+                    }
+                };
+                select(Timer::after(remaining), pause_fut).await;
+            };
+
+            enum LoopDecision {
+                Message,
+                Idle,
+                Tick,
+                Pause,
+            }
+
+            // Incoming messages borrow the client's receive buffer, so they are
+            // handled inside this statement and only the decision escapes it.
+            let decision = match self.client.poll_until(wake_fut).await {
+                // Incoming MQTT message
+                Ok(Some(MqttEvent::Publish(msg))) => {
+                    handled += 1;
+                    if let Some(queue) = self.inbound {
+                        if self.module.wants_all_messages() || self.registry.matches(msg.topic) {
+                            // Dropped messages are counted by the queue
+                            let _ = queue.push(&msg);
                         }
-                        Err(e) => return Err(e),
+                        LoopDecision::Idle
+                    } else {
+                        self.module.on_dispatch(&msg, outbox, &self.registry, 0);
+                        LoopDecision::Message
+                    }
+                }
+                // Nothing received, a keep-alive was handled or the wait woke up
+                Ok(None) => {
+                    if Instant::now() >= tick_deadline {
+                        LoopDecision::Tick
+                    } else if pause.is_some_and(PauseControl::is_paused) {
+                        LoopDecision::Pause
+                    } else {
+                        LoopDecision::Idle
+                    }
+                }
+                // `poll` reports a lost connection as an error instead
+                Ok(Some(MqttEvent::Disconnected(_))) => LoopDecision::Idle,
+                Err(e) => return Err(e),
+            };
+
+            match decision {
+                LoopDecision::Message => {
+                    // If module needs immediate state publish after command
+                    if self.module.needs_immediate_publish() {
+                        self.module.on_publish(outbox);
+                    }
+                    // Publish responses queued in on_message, now that the
+                    // message no longer borrows the receive buffer
+                    if !outbox.is_empty() {
+                        self.drain_outbox(outbox).await?;
                     }
                 }
-                Either::Second(()) => {
+                LoopDecision::Idle => {}
+                LoopDecision::Tick => {
                     // Tick timer expired - periodic tick for discovery
-                    let interval = self.module.on_tick(&mut outbox);
-                    self.drain_outbox(&mut outbox).await?;
+                    let interval = self.module.on_tick(outbox);
+                    self.drain_outbox(outbox).await?;
                     // Set next tick deadline
                     tick_deadline = Instant::now() + interval;
                 }
+                LoopDecision::Pause => {
+                    if let Some(control) = self.pause {
+                        self.wait_paused(control).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keep the connection alive until `control` is resumed.
+    ///
+    /// Sends a PINGREQ every half keep-alive interval, so the broker never
+    /// sees more than one interval without traffic. Nothing is read: the
+    /// PINGRESPs and any messages are handled by `poll` after resuming.
+    async fn wait_paused(&mut self, control: &PauseControl) -> Result<(), MqttError<T::Error>> {
+        let interval = self.client.keep_alive() / 2;
+        // With keep-alive disabled the broker doesn't expect any traffic
+        if interval == Duration::from_ticks(0) {
+            control.wait_for(false).await;
+            return Ok(());
+        }
+        loop {
+            match select(control.wait_for(false), Timer::after(interval)).await {
+                Either::First(()) => return Ok(()),
+                Either::Second(()) => self.client.send_ping().await?,
             }
         }
     }
 
     /// Drain the outbox and publish all buffered messages.
+    ///
+    /// If a publish fails, the remaining requests are not sent. The module is
+    /// notified with `on_publish_failed` for the failed request and for each
+    /// one after it, unless the offline queue keeps them, then the error is
+    /// returned. Requests the middleware drops are not reported, and the ones
+    /// it defers are left in the outbox.
     async fn drain_outbox(
         &mut self,
-        outbox: &mut BufferedOutbox<OUTBOX_CAPACITY, OUTBOX_TOPIC_SIZE, OUTBOX_PAYLOAD_SIZE>,
+        outbox: &mut BufferedOutbox<OUTBOX_CAPACITY, TOPIC_LEN, OUTBOX_PAYLOAD_SIZE>,
     ) -> Result<(), MqttError<T::Error>> {
-        for req in outbox.drain() {
-            self.client
+        let mut failure = None;
+        let mut deferred: Vec<bool, OUTBOX_CAPACITY> = Vec::new();
+        for mut req in outbox.drain() {
+            let decision = match self.middleware.as_deref_mut() {
+                Some(middleware) => middleware.before_publish(&mut req),
+                None => PublishDecision::Send,
+            };
+            // Can't fail, the outbox holds at most `OUTBOX_CAPACITY` requests
+            let _ = deferred.push(decision == PublishDecision::Defer);
+            if decision != PublishDecision::Send {
+                continue;
+            }
+            if let Some(err) = &failure {
+                if !self.keep_offline(&req.topic, &req.payload, req.qos, req.retain, err) {
+                    self.module
+                        .on_publish_failed(req.topic.as_str(), &MqttError::as_dyn(err));
+                }
+                continue;
+            }
+            if let Err(err) = self
+                .client
                 .publish_with_retain(
                     req.topic.as_str(),
                     req.payload.as_slice(),
                     req.qos,
                     req.retain,
                 )
-                .await?;
+                .await
+            {
+                if !self.keep_offline(&req.topic, &req.payload, req.qos, req.retain, &err) {
+                    self.module
+                        .on_publish_failed(req.topic.as_str(), &err.as_dyn());
+                }
+                failure = Some(err);
+            }
+        }
+        if failure.is_some() {
+            outbox.clear();
+        } else {
+            let mut deferred = deferred.into_iter();
+            outbox.retain(|_| deferred.next().unwrap_or(false));
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Publish the messages kept in the offline queue, oldest first.
+    ///
+    /// A message is only removed once it was published, so the one that fails
+    /// stays queued for the next connection.
+    async fn flush_offline(
+        &mut self,
+        outbox: &mut BufferedOutbox<OUTBOX_CAPACITY, TOPIC_LEN, OUTBOX_PAYLOAD_SIZE>,
+    ) -> Result<(), MqttError<T::Error>> {
+        let Some(queue) = self.offline else {
+            return Ok(());
+        };
+        while let Some(seq) = queue.front_into(outbox) {
+            let result = match outbox.drain().next() {
+                Some(req) => {
+                    self.client
+                        .publish_with_retain(
+                            req.topic.as_str(),
+                            req.payload.as_slice(),
+                            req.qos,
+                            req.retain,
+                        )
+                        .await
+                }
+                None => Ok(()),
+            };
+            outbox.clear();
+            result?;
+            queue.remove(seq);
         }
-        outbox.clear();
         Ok(())
     }
 
+    /// Moves a failed publish into the offline queue if the connection is
+    /// gone. Returns whether it was kept.
+    fn keep_offline(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        err: &MqttError<T::Error>,
+    ) -> bool {
+        match self.offline {
+            Some(queue) if is_connection_lost(err) => queue.push(topic, payload, qos, retain),
+            _ => false,
+        }
+    }
+
     /// Get a reference to the underlying module.
     pub fn module(&self) -> &M {
         &self.module
@@ -209,3 +530,614 @@ where
         &mut self.module
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use heapless::String;
+    use std::boxed::Box;
+
+    use super::*;
+    use crate::client::{MqttOptions, MqttVersion};
+    use crate::packet::{EncodePacket, Publish};
+    use crate::runtime::publisher::PublishRequestChannel;
+    use crate::runtime::traits::{ModulePair, NoopModule, PublishOutbox, TopicCollector};
+    use crate::test_util::{CountingObserver, LoopbackBroker, MockError, MockTransport};
+
+    fn version() -> MqttVersion {
+        if cfg!(feature = "v5") {
+            MqttVersion::V5
+        } else {
+            MqttVersion::V3
+        }
+    }
+
+    fn options() -> MqttOptions<'static> {
+        let options = MqttOptions::new("cid");
+        #[cfg(feature = "v5")]
+        let options = options.with_version(MqttVersion::V5);
+        options
+    }
+
+    /// Records the topics of the messages it receives on `filter`.
+    #[derive(Default)]
+    struct Recorder {
+        filter: &'static str,
+        seen: Vec<String<64>, 8>,
+    }
+
+    impl Recorder {
+        fn new(filter: &'static str) -> Self {
+            Self {
+                filter,
+                ..Self::default()
+            }
+        }
+    }
+
+    impl MqttModule for Recorder {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add(self.filter);
+        }
+
+        fn on_message(&mut self, msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {
+            let _ = self.seen.push(String::try_from(msg.topic).unwrap());
+        }
+    }
+
+    /// Runs `runtime` until its transport's script is exhausted.
+    fn run_until_exhausted<'a, M: MqttModule, const TOPIC_LEN: usize>(
+        runtime: &mut MqttRuntime<'a, MockTransport<1024>, M, 4, 256, 4, 256, TOPIC_LEN>,
+    ) {
+        let result = embassy_futures::block_on(runtime.run());
+        assert!(
+            matches!(result, Err(MqttError::Transport(MockError::Exhausted))),
+            "{result:?}"
+        );
+    }
+
+    /// Returns a transport replaying `script` after the CONNACK.
+    fn transport(script: impl FnOnce(&mut MockTransport<1024>)) -> MockTransport<1024> {
+        let mut transport = MockTransport::new(version());
+        assert!(transport.push_connack(false, 0));
+        script(&mut transport);
+        transport
+    }
+
+    #[test]
+    fn messages_are_dispatched_to_the_module_with_a_matching_filter() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let transport = transport(|t| {
+            assert!(t.push_suback(1, &[0, 0]));
+            assert!(t.push_publish("a/1", b"x", QoS::AtMostOnce, None));
+            assert!(t.push_publish("b/1", b"y", QoS::AtMostOnce, None));
+            assert!(t.push_publish("a/2", b"z", QoS::AtMostOnce, None));
+        });
+        let module = ModulePair::new(Recorder::new("a/+"), Recorder::new("b/#"));
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(
+            MqttClient::new(transport, options()),
+            module,
+            CHANNEL.receiver(),
+        );
+        run_until_exhausted(&mut runtime);
+        assert_eq!(runtime.module().first.seen, ["a/1", "a/2"]);
+        assert_eq!(runtime.module().second.seen, ["b/1"]);
+    }
+
+    #[test]
+    fn filters_rejected_by_the_registry_do_not_match() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let transport = transport(|t| {
+            // Only the short filter is subscribed
+            assert!(t.push_suback(1, &[0]));
+            assert!(t.push_publish("a/1", b"x", QoS::AtMostOnce, None));
+            assert!(t.push_publish("long/topic", b"y", QoS::AtMostOnce, None));
+        });
+        let module = ModulePair::new(Recorder::new("a/1"), Recorder::new("long/topic"));
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4, 256, 8>::new(
+            MqttClient::new(transport, options()),
+            module,
+            CHANNEL.receiver(),
+        );
+        run_until_exhausted(&mut runtime);
+        assert_eq!(runtime.module().first.seen, ["a/1"]);
+        assert!(runtime.module().second.seen.is_empty());
+    }
+
+    /// Records the `session_present` flag of every `on_start`, and counts the
+    /// messages it receives on `a/+`.
+    #[derive(Default)]
+    struct Resumable {
+        starts: Vec<bool, 2>,
+        received: usize,
+    }
+
+    impl MqttModule for Resumable {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add("a/+");
+        }
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {
+            self.received += 1;
+        }
+
+        fn on_start(&mut self, session_present: bool, _outbox: &mut dyn PublishOutbox) {
+            let _ = self.starts.push(session_present);
+        }
+    }
+
+    #[test]
+    fn resumed_session_is_not_subscribed_again() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let mut transport = MockTransport::new(version());
+        assert!(transport.push_connack(true, 0));
+        assert!(transport.push_publish("a/1", b"x", QoS::AtMostOnce, None));
+        let builder = MqttOptions::builder().client_id("cid").clean_session(false);
+        #[cfg(feature = "v5")]
+        let builder = builder.version(MqttVersion::V5);
+        let client = MqttClient::new(transport, builder.build().unwrap());
+        let mut runtime =
+            MqttRuntime::<_, _, 4, 256, 4>::new(client, Resumable::default(), CHANNEL.receiver());
+        run_until_exhausted(&mut runtime);
+
+        assert_eq!(runtime.module().starts, [true]);
+        // Only the CONNECT was sent, no SUBSCRIBE
+        let sent = runtime.client.transport().sent();
+        assert_eq!(sent[0], 0x10);
+        assert_eq!(sent.len(), 2 + usize::from(sent[1]));
+        // The stored subscription still delivers to the module
+        assert_eq!(runtime.module().received, 1);
+    }
+
+    /// Counts its ticks, asking to be ticked again every millisecond.
+    struct Ticker {
+        ticks: &'static AtomicUsize,
+    }
+
+    impl MqttModule for Ticker {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add("t");
+        }
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {}
+
+        fn on_tick(&mut self, _outbox: &mut dyn PublishOutbox) -> Duration {
+            self.ticks.fetch_add(1, Ordering::Relaxed);
+            Duration::from_millis(1)
+        }
+    }
+
+    /// Runs `runtime` alongside `script` until the script completes.
+    fn run_with<M: MqttModule>(
+        runtime: &mut MqttRuntime<'static, impl MqttTransport<Error = MockError>, M, 4, 256, 4>,
+        script: impl Future<Output = ()>,
+    ) {
+        match embassy_futures::block_on(select(runtime.run(), script)) {
+            Either::First(result) => panic!("runtime stopped: {result:?}"),
+            Either::Second(()) => {}
+        }
+    }
+
+    #[test]
+    fn module_is_not_ticked_while_paused() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        static PAUSE: PauseControl = PauseControl::new();
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+        let broker: &'static _ = Box::leak(Box::new(LoopbackBroker::<1024>::new(version())));
+        let client = MqttClient::new(broker.transport(), options());
+        let mut runtime = MqttRuntime::new(client, Ticker { ticks: &TICKS }, CHANNEL.receiver())
+            .with_pause_control(&PAUSE);
+
+        PAUSE.pause();
+        run_with(&mut runtime, async {
+            Timer::after_millis(20).await;
+            // The initial tick runs before the loop sees the pause
+            assert_eq!(TICKS.load(Ordering::Relaxed), 1);
+            PAUSE.resume();
+            Timer::after_millis(20).await;
+            assert!(TICKS.load(Ordering::Relaxed) > 1);
+        });
+    }
+
+    #[test]
+    fn paused_runtime_does_not_ping_without_keep_alive() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        static PAUSE: PauseControl = PauseControl::new();
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+        let broker: &'static _ = Box::leak(Box::new(LoopbackBroker::<1024>::new(version())));
+        let options = options().with_keep_alive(Duration::from_secs(0));
+        let mut client = MqttClient::new(broker.transport(), options);
+        let observer: &'static _ = Box::leak(Box::new(CountingObserver::new()));
+        client.set_observer(observer);
+        let mut runtime = MqttRuntime::new(client, Ticker { ticks: &TICKS }, CHANNEL.receiver())
+            .with_pause_control(&PAUSE);
+
+        PAUSE.pause();
+        run_with(&mut runtime, async {
+            Timer::after_millis(20).await;
+            assert!(broker.is_subscribed("t"));
+            let sent = observer.sent();
+            Timer::after_millis(20).await;
+            assert_eq!(observer.sent(), sent);
+        });
+    }
+
+    /// Counts the messages it receives.
+    struct Counter {
+        messages: &'static AtomicUsize,
+    }
+
+    impl MqttModule for Counter {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add("t/#");
+        }
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {
+            self.messages.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs `runtime` until its script is exhausted, alongside a task that
+    /// samples `count` each time it gets a turn, and returns the non-zero
+    /// samples with repeats removed.
+    fn sample_turns<M: MqttModule>(
+        runtime: &mut MqttRuntime<'static, MockTransport<1024>, M, 4, 256, 4>,
+        count: impl Fn() -> usize,
+    ) -> Vec<usize, 64> {
+        let mut samples = Vec::new();
+        let probe = async {
+            loop {
+                let sample = count();
+                if sample > 0 && samples.last() != Some(&sample) {
+                    let _ = samples.push(sample);
+                }
+                yield_now().await;
+            }
+        };
+        match embassy_futures::block_on(select(runtime.run(), probe)) {
+            Either::First(Err(MqttError::Transport(MockError::Exhausted))) => {}
+            Either::First(result) => panic!("{result:?}"),
+        }
+        samples
+    }
+
+    #[test]
+    fn loop_yields_between_received_messages() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        static MESSAGES: AtomicUsize = AtomicUsize::new(0);
+        let transport = transport(|t| {
+            assert!(t.push_suback(1, &[0]));
+            for _ in 0..3 {
+                assert!(t.push_publish("t/1", b"x", QoS::AtMostOnce, None));
+            }
+        });
+        let client = MqttClient::new(transport, options());
+        let module = Counter {
+            messages: &MESSAGES,
+        };
+        let mut runtime = MqttRuntime::new(client, module, CHANNEL.receiver());
+
+        let samples = sample_turns(&mut runtime, || MESSAGES.load(Ordering::Relaxed));
+        // The other task ran after each message
+        assert_eq!(samples, [1, 2, 3]);
+    }
+
+    #[test]
+    fn loop_yields_between_channel_publishes() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        static MESSAGES: AtomicUsize = AtomicUsize::new(0);
+        let transport = transport(|t| assert!(t.push_suback(1, &[0])));
+        let mut client = MqttClient::new(transport, options());
+        let observer: &'static _ = Box::leak(Box::new(CountingObserver::new()));
+        client.set_observer(observer);
+        let module = Counter {
+            messages: &MESSAGES,
+        };
+        let mut runtime = MqttRuntime::new(client, module, CHANNEL.receiver());
+        for _ in 0..3 {
+            let request = PublishRequest {
+                topic: "s",
+                payload: b"x",
+                qos: QoS::AtMostOnce,
+                retain: false,
+            };
+            assert!(CHANNEL.try_send(request).is_ok());
+        }
+
+        // Not counting the CONNECT and SUBSCRIBE
+        let samples = sample_turns(&mut runtime, || observer.sent().saturating_sub(2));
+        // The other task ran after each publish
+        assert_eq!(samples, [1, 2, 3]);
+    }
+
+    #[test]
+    fn idle_runtime_pings_every_keep_alive_interval() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        static MESSAGES: AtomicUsize = AtomicUsize::new(0);
+        let broker: &'static _ = Box::leak(Box::new(LoopbackBroker::<1024>::new(version())));
+        let observer: &'static _ = Box::leak(Box::new(CountingObserver::new()));
+        let options = options().with_keep_alive(Duration::from_millis(20));
+        let mut client = MqttClient::new(broker.transport(), options);
+        client.set_observer(observer);
+        let module = Counter {
+            messages: &MESSAGES,
+        };
+        let mut runtime = MqttRuntime::new(client, module, CHANNEL.receiver());
+
+        run_with(&mut runtime, async {
+            Timer::after_millis(5).await;
+            assert!(broker.is_subscribed("t/1"));
+            // Only PINGREQs are sent on an idle connection
+            let sent = observer.sent();
+            Timer::after_millis(110).await;
+            let pings = observer.sent() - sent;
+            assert!((3..=6).contains(&pings), "{pings} pings");
+            // Each was answered, or the runtime would have stopped
+            assert!(broker.is_connected());
+        });
+    }
+
+    /// Answers every command on `cmd` with its payload on `state`.
+    struct Echo;
+
+    impl MqttModule for Echo {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add("cmd");
+        }
+
+        fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+            outbox.publish("state", msg.payload, QoS::AtMostOnce);
+        }
+    }
+
+    #[test]
+    fn response_queued_in_on_message_is_published() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let transport = transport(|t| {
+            assert!(t.push_suback(1, &[0]));
+            assert!(t.push_publish("cmd", b"on", QoS::AtMostOnce, None));
+        });
+        let client = MqttClient::new(transport, options());
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(client, Echo, CHANNEL.receiver());
+        run_until_exhausted(&mut runtime);
+
+        let mut expected = MockTransport::<64>::new(version());
+        assert!(expected.push_publish("state", b"on", QoS::AtMostOnce, None));
+        let mut response = [0; 64];
+        let len = embassy_futures::block_on(expected.recv(&mut response)).unwrap();
+        assert!(
+            runtime
+                .client
+                .transport()
+                .sent()
+                .ends_with(&response[..len])
+        );
+    }
+
+    #[test]
+    fn publishes_queued_while_offline_are_sent_after_connecting() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        static OFFLINE: OfflineQueue<4, 16, 16> = OfflineQueue::coalescing();
+        // The broker doesn't answer the first CONNECT
+        let client = MqttClient::new(MockTransport::new(version()), options());
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(client, Echo, CHANNEL.receiver())
+            .with_offline_queue(&OFFLINE);
+        run_until_exhausted(&mut runtime);
+
+        // Queued while the runtime waits to reconnect; only the latest is kept
+        assert!(OFFLINE.push("state", b"1", QoS::AtMostOnce, false));
+        assert!(OFFLINE.push("state", b"2", QoS::AtMostOnce, false));
+        assert_eq!(OFFLINE.len(), 1);
+
+        let transport = runtime.client.transport_mut();
+        transport.clear_sent();
+        assert!(transport.push_connack(false, 0));
+        assert!(transport.push_suback(1, &[0]));
+        run_until_exhausted(&mut runtime);
+
+        let mut expected = MockTransport::<64>::new(version());
+        assert!(expected.push_publish("state", b"2", QoS::AtMostOnce, None));
+        let mut publish = [0; 64];
+        let len = embassy_futures::block_on(expected.recv(&mut publish)).unwrap();
+        let sent = runtime.client.transport().sent();
+        assert_eq!(
+            sent.windows(len).filter(|w| *w == &publish[..len]).count(),
+            1,
+            "{sent:?}"
+        );
+        assert!(OFFLINE.is_empty());
+    }
+
+    /// Prefixes every topic with `dev/` and drops empty payloads.
+    struct Prefix;
+
+    impl<const T: usize, const P: usize> PublishMiddleware<T, P> for Prefix {
+        fn before_publish(
+            &mut self,
+            req: &mut crate::runtime::OwnedPublishRequest<T, P>,
+        ) -> PublishDecision {
+            let mut topic = String::new();
+            if req.payload.is_empty()
+                || topic.push_str("dev/").is_err()
+                || topic.push_str(&req.topic).is_err()
+            {
+                return PublishDecision::Drop;
+            }
+            req.topic = topic;
+            PublishDecision::Send
+        }
+    }
+
+    #[test]
+    fn middleware_rewrites_outgoing_topics() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let transport = transport(|t| {
+            assert!(t.push_suback(1, &[0]));
+            assert!(t.push_publish("cmd", b"on", QoS::AtMostOnce, None));
+            assert!(t.push_publish("cmd", b"", QoS::AtMostOnce, None));
+        });
+        let client = MqttClient::new(transport, options());
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(client, Echo, CHANNEL.receiver())
+            .with_middleware(Box::leak(Box::new(Prefix)));
+        run_until_exhausted(&mut runtime);
+
+        let mut expected = MockTransport::<64>::new(version());
+        assert!(expected.push_publish("dev/state", b"on", QoS::AtMostOnce, None));
+        let mut publish = [0; 64];
+        let len = embassy_futures::block_on(expected.recv(&mut publish)).unwrap();
+        // The empty response was dropped
+        assert!(runtime.client.transport().sent().ends_with(&publish[..len]));
+    }
+
+    /// Records command payloads, apart from the retained ones.
+    #[derive(Default)]
+    struct Commands {
+        live: Vec<Vec<u8, 8>, 4>,
+        retained: Vec<Vec<u8, 8>, 4>,
+    }
+
+    impl MqttModule for Commands {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add("cmd");
+        }
+
+        fn on_message(&mut self, msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {
+            let _ = self.live.push(Vec::from_slice(msg.payload).unwrap());
+        }
+
+        fn on_retained_message(&mut self, msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {
+            let _ = self.retained.push(Vec::from_slice(msg.payload).unwrap());
+        }
+    }
+
+    #[test]
+    fn retained_commands_go_to_on_retained_message() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let retained = Publish::new("cmd", b"old", QoS::AtMostOnce).retain(true);
+        let mut packet = [0; 32];
+        let len = retained.encode(&mut packet, version()).unwrap();
+        let transport = transport(|t| {
+            assert!(t.push_suback(1, &[0]));
+            assert!(t.push_rx(&packet[..len]));
+            assert!(t.push_publish("cmd", b"new", QoS::AtMostOnce, None));
+        });
+        let client = MqttClient::new(transport, options());
+        let module = ModulePair::new(Commands::default(), NoopModule);
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(client, module, CHANNEL.receiver());
+        run_until_exhausted(&mut runtime);
+        assert_eq!(runtime.module().first.retained, [b"old"]);
+        assert_eq!(runtime.module().first.live, [b"new"]);
+    }
+
+    /// Counts its connects and disconnects.
+    struct Lifecycle {
+        connects: usize,
+        disconnects: usize,
+    }
+
+    impl MqttModule for Lifecycle {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add("t");
+        }
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {}
+
+        fn on_connect(&mut self, _client_id: &str) {
+            self.connects += 1;
+        }
+
+        fn on_disconnect(&mut self) {
+            self.disconnects += 1;
+        }
+    }
+
+    #[test]
+    fn module_is_notified_when_the_connection_drops() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let broker: &'static _ = Box::leak(Box::new(LoopbackBroker::<1024>::new(version())));
+        let client = MqttClient::new(broker.transport(), options());
+        let module = Lifecycle {
+            connects: 0,
+            disconnects: 0,
+        };
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(client, module, CHANNEL.receiver());
+
+        let drop_connection = async {
+            Timer::after_millis(5).await;
+            assert!(broker.is_subscribed("t"));
+            broker.drop_connection();
+            core::future::pending::<()>().await
+        };
+        match embassy_futures::block_on(select(runtime.run(), drop_connection)) {
+            Either::First(result) => assert!(
+                matches!(result, Err(MqttError::Transport(MockError::Closed))),
+                "{result:?}"
+            ),
+            Either::Second(()) => unreachable!(),
+        }
+        assert_eq!(runtime.module().connects, 1);
+        assert_eq!(runtime.module().disconnects, 1);
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn broker_disconnect_ends_run_with_its_reason_code() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let transport = transport(|t| {
+            assert!(t.push_suback(1, &[0]));
+            // Keep Alive Timeout
+            assert!(t.push_rx(&[0xE0, 1, 0x8D]));
+        });
+        let module = Lifecycle {
+            connects: 0,
+            disconnects: 0,
+        };
+        let client = MqttClient::new(transport, options());
+        let mut runtime = MqttRuntime::<_, _, 4, 256, 4>::new(client, module, CHANNEL.receiver());
+        let result = embassy_futures::block_on(runtime.run());
+        assert!(
+            matches!(
+                result,
+                Err(MqttError::ServerDisconnect { reason_code: 0x8D })
+            ),
+            "{result:?}"
+        );
+        assert_eq!(runtime.module().disconnects, 1);
+    }
+
+    /// Queues an invalid publish ahead of a valid one on start, recording the
+    /// topics reported as failed.
+    #[derive(Default)]
+    struct Failing {
+        failed: Vec<String<16>, 4>,
+    }
+
+    impl MqttModule for Failing {
+        fn register(&self, _collector: &mut dyn TopicCollector) {}
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {}
+
+        fn on_start(&mut self, _session_present: bool, outbox: &mut dyn PublishOutbox) {
+            outbox.publish("state/#", b"x", QoS::AtMostOnce);
+            outbox.publish("state", b"y", QoS::AtMostOnce);
+        }
+
+        fn on_publish_failed(&mut self, topic: &str, _err: &MqttError<&dyn core::fmt::Debug>) {
+            let _ = self.failed.push(String::try_from(topic).unwrap());
+        }
+    }
+
+    #[test]
+    fn failed_publish_reports_its_topic_and_the_ones_after_it() {
+        static CHANNEL: PublishRequestChannel<'static, 4> = PublishRequestChannel::new();
+        let client = MqttClient::new(transport(|_| {}), options());
+        let mut runtime =
+            MqttRuntime::<_, _, 4, 256, 4>::new(client, Failing::default(), CHANNEL.receiver());
+        let result = embassy_futures::block_on(runtime.run());
+        assert!(matches!(result, Err(MqttError::Protocol(_))), "{result:?}");
+        assert_eq!(runtime.module().failed, ["state/#", "state"]);
+    }
+}
@@ -0,0 +1,221 @@
+//! Bounded queue of received messages.
+//!
+//! `InboundQueue` decouples network reads from message processing: the
+//! runtime copies each message into inline storage and moves on, while another
+//! task drains the queue at its own pace. This trades RAM for smoother
+//! behavior under bursts, e.g. many retained messages arriving right after
+//! subscribing.
+
+use core::cell::RefCell;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::{Deque, String, Vec};
+
+use crate::QoS;
+use crate::packet::Publish;
+
+/// A received message with inline storage for topic and payload.
+#[derive(Debug, Clone)]
+pub struct InboundMessage<const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    /// The topic (stored inline)
+    pub topic: String<TOPIC_SIZE>,
+    /// The payload (stored inline)
+    pub payload: Vec<u8, PAYLOAD_SIZE>,
+    /// Quality of Service level the message was delivered with
+    pub qos: QoS,
+    /// MQTT retain flag
+    pub retain: bool,
+}
+
+struct QueueState<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    messages: Deque<InboundMessage<TOPIC_SIZE, PAYLOAD_SIZE>, DEPTH>,
+    dropped: usize,
+    waker: WakerRegistration,
+}
+
+/// A bounded queue of received messages, shared between the runtime and a
+/// consumer task.
+///
+/// When the queue is full, the oldest message is dropped to make room for the
+/// new one, since newer messages carry the most recent state. Messages whose
+/// topic or payload don't fit the inline storage are dropped as well. Both
+/// are counted by `dropped`.
+///
+/// ```ignore
+/// static INBOUND: InboundQueue<16, 64, 256> = InboundQueue::new();
+///
+/// let mut runtime = MqttRuntime::new(client, module, rx).with_inbound_queue(&INBOUND);
+///
+/// #[embassy_executor::task]
+/// async fn consumer() {
+///     loop {
+///         let msg = INBOUND.receive().await;
+///         handle(&msg.topic, &msg.payload);
+///     }
+/// }
+/// ```
+///
+/// # Type Parameters
+///
+/// - `DEPTH`: Maximum number of queued messages
+/// - `TOPIC_SIZE`: Maximum topic string length
+/// - `PAYLOAD_SIZE`: Maximum payload size
+pub struct InboundQueue<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    state: Mutex<CriticalSectionRawMutex, RefCell<QueueState<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>>>,
+}
+
+impl<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
+    InboundQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    /// Create a new empty queue.
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(QueueState {
+                messages: Deque::new(),
+                dropped: 0,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Copies a message into the queue, dropping the oldest one if it is full.
+    ///
+    /// Returns `false` if the topic or payload doesn't fit the inline storage;
+    /// the message is dropped then.
+    pub fn push(&self, msg: &Publish<'_>) -> bool {
+        let (Ok(topic), Ok(payload)) = (String::try_from(msg.topic), Vec::from_slice(msg.payload))
+        else {
+            log_warn!(
+                "inbound: message too large! topic_len={}, payload_len={}",
+                msg.topic.len(),
+                msg.payload.len()
+            );
+            self.with_state(|state| state.dropped += 1);
+            return false;
+        };
+        let message = InboundMessage {
+            topic,
+            payload,
+            qos: msg.qos,
+            retain: msg.retain,
+        };
+
+        self.with_state(|state| {
+            if state.messages.is_full() {
+                log_warn!("inbound: queue full, dropping oldest! depth={}", DEPTH);
+                state.messages.pop_front();
+                state.dropped += 1;
+            }
+            // Can't fail, there is room now
+            let _ = state.messages.push_back(message);
+            state.waker.wake();
+        });
+        true
+    }
+
+    /// Takes the oldest message, if any.
+    pub fn try_receive(&self) -> Option<InboundMessage<TOPIC_SIZE, PAYLOAD_SIZE>> {
+        self.with_state(|state| state.messages.pop_front())
+    }
+
+    /// Waits for a message and takes it.
+    ///
+    /// Only one task should wait at a time; a second one replaces the first
+    /// in the wake-up registration.
+    pub async fn receive(&self) -> InboundMessage<TOPIC_SIZE, PAYLOAD_SIZE> {
+        core::future::poll_fn(|cx| {
+            self.with_state(|state| match state.messages.pop_front() {
+                Some(message) => Poll::Ready(message),
+                None => {
+                    state.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Get the number of queued messages.
+    pub fn len(&self) -> usize {
+        self.with_state(|state| state.messages.len())
+    }
+
+    /// Check if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns how many messages were dropped, because the queue was full or
+    /// they didn't fit the inline storage.
+    pub fn dropped(&self) -> usize {
+        self.with_state(|state| state.dropped)
+    }
+
+    fn with_state<R>(
+        &self,
+        f: impl FnOnce(&mut QueueState<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>) -> R,
+    ) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+impl<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> Default
+    for InboundQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Type-erased access to an `InboundQueue`, so the runtime doesn't need its
+/// size parameters.
+pub(crate) trait InboundSink {
+    fn push(&self, msg: &Publish<'_>) -> bool;
+}
+
+impl<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> InboundSink
+    for InboundQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    fn push(&self, msg: &Publish<'_>) -> bool {
+        InboundQueue::push(self, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_the_oldest_message() {
+        let queue = InboundQueue::<2, 8, 4>::new();
+        for payload in [b"1", b"2", b"3", b"4"] {
+            assert!(queue.push(&Publish::new("t", payload, QoS::AtMostOnce)));
+        }
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 2);
+        // The newest messages are kept, in order
+        assert_eq!(queue.try_receive().unwrap().payload, b"3");
+        let message = embassy_futures::block_on(queue.receive());
+        assert_eq!(message.payload, b"4");
+        assert!(queue.try_receive().is_none());
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let queue = InboundQueue::<2, 8, 4>::new();
+        assert!(!queue.push(&Publish::new("too/long/", b"1", QoS::AtMostOnce)));
+        assert!(!queue.push(&Publish::new("t", b"12345", QoS::AtMostOnce)));
+        assert!(queue.is_empty());
+        assert_eq!(queue.dropped(), 2);
+
+        let retained = Publish::new("t", b"1234", QoS::AtLeastOnce).retain(true);
+        assert!(queue.push(&retained));
+        let message = queue.try_receive().unwrap();
+        assert_eq!(message.topic, "t");
+        assert_eq!(message.qos, QoS::AtLeastOnce);
+        assert!(message.retain);
+    }
+}
@@ -25,23 +25,57 @@
 //! trait to queue publish requests. The runtime then performs the actual async
 //! publishing after the module method returns.
 //!
+//! # Inbound Queue
+//!
+//! By default, messages are handled by `on_message` inside the receive loop.
+//! `MqttRuntime::with_inbound_queue` copies them into an `InboundQueue`
+//! instead, which another task drains, so bursts don't stall network reads.
+//!
+//! # Offline Queue
+//!
+//! `MqttRuntime::with_offline_queue` keeps publishes that fail because the
+//! connection dropped in an `OfflineQueue` and sends them after the next
+//! connect, optionally keeping only the latest message per topic.
+//!
+//! # Pausing
+//!
+//! `MqttRuntime::with_pause_control` lets another task pause the event loop
+//! with a `PauseControl`; the connection is kept alive meanwhile.
+//!
+//! # Publish Middleware
+//!
+//! `MqttRuntime::with_middleware` installs a `PublishMiddleware` that sees
+//! each outbox request before it is sent and can rewrite, drop or defer it.
+//!
 //! # Example
 //!
 //! See `examples/const_topics_module.rs` for a complete example of building
 //! a module with constant topics.
 
 pub(crate) mod event_loop;
+pub(crate) mod inbound;
+pub(crate) mod offline;
+pub(crate) mod ota;
+pub(crate) mod pause;
 pub(crate) mod publisher;
 pub(crate) mod registry;
 pub(crate) mod traits;
 
 pub use event_loop::MqttRuntime;
+pub use inbound::{InboundMessage, InboundQueue};
+pub use offline::OfflineQueue;
+pub use ota::{FlashWriter, OtaModule};
+pub use pause::PauseControl;
 pub use publisher::{
-    BufferedOutbox, OwnedPublishRequest, PublishRequest, PublishRequestChannel,
-    PublishRequestReceiver, PublishRequestSender, PublisherHandle,
+    BufferedOutbox, OwnedPublishRequest, PublishDecision, PublishMiddleware, PublishRequest,
+    PublishRequestChannel, PublishRequestReceiver, PublishRequestSender, PublishTimeout,
+    PublisherHandle,
 };
 pub use registry::TopicRegistry;
-pub use traits::{ModulePair, MqttModule, NoopModule, PublishOutbox, TopicCollector};
+pub use traits::{
+    ModuleFilters, ModulePair, MqttModule, MqttModuleList, NoopModule, OutboxError, PublishOutbox,
+    TopicCollector,
+};
 
 // Re-export Publish for convenient use in modules
 pub use crate::packet::Publish;
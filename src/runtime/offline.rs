@@ -0,0 +1,232 @@
+//! Bounded queue of publishes made while the connection is down.
+//!
+//! `OfflineQueue` keeps data that would otherwise be lost to `NotConnected`:
+//! the runtime moves publishes that fail because the connection dropped into
+//! the queue and sends them right after the next connect, before `on_start`.
+//! Other tasks can queue messages directly while the runtime is reconnecting.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use heapless::{String, Vec};
+
+use super::publisher::OwnedPublishRequest;
+use super::traits::PublishOutbox;
+use crate::QoS;
+use crate::error::{MqttError, ProtocolError};
+
+struct QueuedPublish<const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    /// Identifies the entry while it is being flushed, as it may be replaced
+    /// by a newer one in the meantime.
+    seq: u32,
+    request: OwnedPublishRequest<TOPIC_SIZE, PAYLOAD_SIZE>,
+}
+
+struct QueueState<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    messages: Vec<QueuedPublish<TOPIC_SIZE, PAYLOAD_SIZE>, DEPTH>,
+    next_seq: u32,
+    dropped: usize,
+    coalesce: bool,
+}
+
+/// A bounded queue of publishes held until the client is connected again.
+///
+/// When the queue is full, the oldest message is dropped to make room for the
+/// new one. A coalescing queue, created with `coalescing`, holds at most one
+/// message per topic: a newer publish replaces the queued one, which suits
+/// state topics where only the latest value matters. Messages whose topic or
+/// payload don't fit the inline storage are dropped. Both drops are counted by
+/// `dropped`.
+///
+/// ```ignore
+/// static OFFLINE: OfflineQueue<8, 64, 128> = OfflineQueue::coalescing();
+///
+/// let mut runtime = MqttRuntime::new(client, module, rx).with_offline_queue(&OFFLINE);
+/// loop {
+///     // Publishes that fail with the connection are kept and flushed here
+///     let _ = runtime.run().await;
+///     Timer::after_secs(5).await;
+/// }
+/// ```
+///
+/// Flushing goes through the runtime's outbox, so topics and payloads longer
+/// than the outbox storage (the runtime's `TOPIC_LEN`, and 1024 bytes) are
+/// dropped at that point.
+/// A QoS 1 message already awaiting its PUBACK when the connection dropped is
+/// also resent from the client's inflight store, so it may arrive twice.
+///
+/// # Type Parameters
+///
+/// - `DEPTH`: Maximum number of queued messages
+/// - `TOPIC_SIZE`: Maximum topic string length
+/// - `PAYLOAD_SIZE`: Maximum payload size
+pub struct OfflineQueue<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    state: Mutex<CriticalSectionRawMutex, RefCell<QueueState<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>>>,
+}
+
+impl<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
+    OfflineQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    /// Create a new empty queue that keeps every publish.
+    pub const fn new() -> Self {
+        Self::with_coalescing(false)
+    }
+
+    /// Create a new empty queue that keeps only the latest publish per topic.
+    pub const fn coalescing() -> Self {
+        Self::with_coalescing(true)
+    }
+
+    const fn with_coalescing(coalesce: bool) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(QueueState {
+                messages: Vec::new(),
+                next_seq: 0,
+                dropped: 0,
+                coalesce,
+            })),
+        }
+    }
+
+    /// Copies a publish into the queue, dropping the oldest one if it is full.
+    ///
+    /// Returns `false` if the topic or payload doesn't fit the inline storage;
+    /// the message is dropped then.
+    pub fn push(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> bool {
+        let (Ok(topic), Ok(payload)) = (String::try_from(topic), Vec::from_slice(payload)) else {
+            log_warn!(
+                "offline: message too large! topic_len={}, payload_len={}",
+                topic.len(),
+                payload.len()
+            );
+            self.with_state(|state| state.dropped += 1);
+            return false;
+        };
+        let request = OwnedPublishRequest {
+            topic,
+            payload,
+            qos,
+            retain,
+        };
+
+        self.with_state(|state| {
+            if state.coalesce {
+                state
+                    .messages
+                    .retain(|queued| queued.request.topic != request.topic);
+            }
+            if state.messages.is_full() {
+                log_warn!("offline: queue full, dropping oldest! depth={}", DEPTH);
+                state.messages.remove(0);
+                state.dropped += 1;
+            }
+            let seq = state.next_seq;
+            state.next_seq = state.next_seq.wrapping_add(1);
+            // Can't fail, there is room now
+            let _ = state.messages.push(QueuedPublish { seq, request });
+        });
+        true
+    }
+
+    /// Get the number of queued messages.
+    pub fn len(&self) -> usize {
+        self.with_state(|state| state.messages.len())
+    }
+
+    /// Check if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns how many messages were dropped, because the queue was full or
+    /// they didn't fit the inline storage.
+    pub fn dropped(&self) -> usize {
+        self.with_state(|state| state.dropped)
+    }
+
+    /// Discards all queued messages.
+    pub fn clear(&self) {
+        self.with_state(|state| state.messages.clear());
+    }
+
+    fn with_state<R>(
+        &self,
+        f: impl FnOnce(&mut QueueState<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>) -> R,
+    ) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+impl<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> Default
+    for OfflineQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether a publish failed because the connection is gone, so it
+/// can be sent again once the client reconnects.
+pub(crate) fn is_connection_lost<E>(err: &MqttError<E>) -> bool {
+    if matches!(
+        err,
+        MqttError::NotConnected
+            | MqttError::Transport(_)
+            | MqttError::Protocol(ProtocolError::ConnectionClosed)
+    ) {
+        return true;
+    }
+    #[cfg(feature = "v5")]
+    if let MqttError::ServerDisconnect { .. } = err {
+        return true;
+    }
+    false
+}
+
+/// Type-erased access to an `OfflineQueue`, so the runtime doesn't need its
+/// size parameters.
+pub(crate) trait OfflineSink {
+    fn push(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> bool;
+
+    /// Copies the oldest message into `outbox` and returns its sequence
+    /// number, leaving it queued. Messages the outbox rejects are dropped.
+    fn front_into(&self, outbox: &mut dyn PublishOutbox) -> Option<u32>;
+
+    /// Removes the message with the given sequence number, if still queued.
+    fn remove(&self, seq: u32);
+}
+
+impl<const DEPTH: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> OfflineSink
+    for OfflineQueue<DEPTH, TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    fn push(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> bool {
+        OfflineQueue::push(self, topic, payload, qos, retain)
+    }
+
+    fn front_into(&self, outbox: &mut dyn PublishOutbox) -> Option<u32> {
+        self.with_state(|state| {
+            while let Some(queued) = state.messages.first() {
+                let request = &queued.request;
+                if outbox
+                    .try_publish_with_retain(
+                        request.topic.as_str(),
+                        request.payload.as_slice(),
+                        request.qos,
+                        request.retain,
+                    )
+                    .is_ok()
+                {
+                    return Some(queued.seq);
+                }
+                state.messages.remove(0);
+                state.dropped += 1;
+            }
+            None
+        })
+    }
+
+    fn remove(&self, seq: u32) {
+        self.with_state(|state| state.messages.retain(|queued| queued.seq != seq));
+    }
+}
@@ -0,0 +1,407 @@
+//! Over-the-air firmware update module.
+//!
+//! `OtaModule` receives a firmware image in chunks on one topic and streams
+//! each chunk to a `FlashWriter` as it arrives, so the image is never held in
+//! RAM. Progress and retransmission requests are published on a status topic.
+//!
+//! # Protocol
+//!
+//! Every message on the firmware topic starts with a big-endian `u32` offset:
+//!
+//! - `offset == 0xFFFF_FFFF` is a header starting a new transfer, followed by
+//!   the image size and its CRC-32 (IEEE), both big-endian `u32`.
+//! - Any other offset is a chunk of image data at that offset.
+//!
+//! Chunks must be written in order. A chunk past the expected offset means one
+//! was lost, so the module publishes `{"state":"resend","offset":N}` and drops
+//! chunks until the one at `N` arrives; the request is repeated on each tick
+//! while the transfer makes no progress. Chunks before the expected offset are
+//! duplicates and ignored. Once the whole image is received, the CRC is checked
+//! and `{"state":"complete"}` or `{"state":"failed","reason":"crc"}` is
+//! published.
+
+use embassy_time::Duration;
+
+use super::traits::{MqttModule, PublishOutbox, TopicCollector};
+use crate::error::MqttError;
+use crate::packet::{Publish, QoS};
+use crate::transport::ErrorPlaceHolder;
+use crate::util::JsonWriter;
+
+/// Offset marking a transfer header on the firmware topic.
+const HEADER_OFFSET: u32 = 0xFFFF_FFFF;
+
+/// How often a stalled transfer re-requests its missing chunk.
+const RESEND_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Destination of a firmware image received by an `OtaModule`, usually the
+/// inactive partition of the device's flash.
+pub trait FlashWriter {
+    /// Prepares for an image of `size` bytes, e.g. by erasing the partition.
+    /// Images larger than 2 GiB are rejected before this is called.
+    ///
+    /// Returning `false` rejects the transfer.
+    fn begin(&mut self, size: u32) -> bool;
+
+    /// Writes `data` at `offset` from the start of the image.
+    ///
+    /// Chunks arrive in order without gaps. Returning `false` aborts the
+    /// transfer.
+    fn write(&mut self, offset: u32, data: &[u8]) -> bool;
+
+    /// Called once the complete image was written and its CRC matches, e.g.
+    /// to mark the partition bootable.
+    fn finish(&mut self);
+
+    /// Called when a started transfer fails or is replaced by a new one.
+    /// The default implementation does nothing.
+    fn abort(&mut self) {}
+}
+
+/// State of the transfer in progress.
+struct Transfer {
+    size: u32,
+    crc: u32,
+    /// Number of bytes written so far, which is also the next expected offset.
+    received: u32,
+    /// Running CRC-32 over the received bytes, before the final inversion.
+    running_crc: u32,
+    /// Set when a chunk was written since the last tick.
+    progressed: bool,
+    /// Set while chunks are dropped until the expected offset arrives.
+    awaiting_resend: bool,
+}
+
+/// Module that receives chunked firmware images. See the module docs for the
+/// wire format.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut ota = OtaModule::new("device/ota/firmware", "device/ota/status", flash);
+/// let modules = MqttModuleList::new([&mut light, &mut ota]);
+/// ```
+pub struct OtaModule<'a, W: FlashWriter> {
+    firmware_topic: &'a str,
+    status_topic: &'a str,
+    writer: W,
+    transfer: Option<Transfer>,
+}
+
+impl<'a, W: FlashWriter> OtaModule<'a, W> {
+    /// Creates a module receiving images on `firmware_topic` and reporting
+    /// on `status_topic`.
+    pub fn new(firmware_topic: &'a str, status_topic: &'a str, writer: W) -> Self {
+        Self {
+            firmware_topic,
+            status_topic,
+            writer,
+            transfer: None,
+        }
+    }
+
+    /// Returns `true` while a transfer is in progress.
+    pub fn is_receiving(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    /// Returns the flash writer.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Returns the flash writer mutably.
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn start(&mut self, header: &[u8], outbox: &mut dyn PublishOutbox) {
+        if self.transfer.take().is_some() {
+            self.writer.abort();
+        }
+        let (Some(size), Some(crc)) = (read_u32(header, 0), read_u32(header, 4)) else {
+            self.publish_failed("header", outbox);
+            return;
+        };
+        if size > i32::MAX as u32 || !self.writer.begin(size) {
+            self.publish_failed("rejected", outbox);
+            return;
+        }
+        self.transfer = Some(Transfer {
+            size,
+            crc,
+            received: 0,
+            running_crc: !0,
+            progressed: true,
+            awaiting_resend: false,
+        });
+        if size == 0 {
+            self.complete(outbox);
+        }
+    }
+
+    fn chunk(&mut self, offset: u32, data: &[u8], outbox: &mut dyn PublishOutbox) {
+        let Some(transfer) = self.transfer.as_mut() else {
+            return;
+        };
+        if offset < transfer.received {
+            return;
+        }
+        if offset > transfer.received {
+            if !transfer.awaiting_resend {
+                transfer.awaiting_resend = true;
+                let received = transfer.received;
+                self.publish_resend(received, outbox);
+            }
+            return;
+        }
+        if data.len() as u64 > u64::from(transfer.size - transfer.received) {
+            self.fail("size", outbox);
+            return;
+        }
+        if !self.writer.write(offset, data) {
+            self.fail("write", outbox);
+            return;
+        }
+        transfer.received += data.len() as u32;
+        transfer.running_crc = crc32_update(transfer.running_crc, data);
+        transfer.progressed = true;
+        transfer.awaiting_resend = false;
+        if transfer.received == transfer.size {
+            self.complete(outbox);
+        }
+    }
+
+    fn complete(&mut self, outbox: &mut dyn PublishOutbox) {
+        let Some(transfer) = self.transfer.take() else {
+            return;
+        };
+        if !transfer.running_crc != transfer.crc {
+            self.writer.abort();
+            self.publish_failed("crc", outbox);
+            return;
+        }
+        self.writer.finish();
+        self.publish_status(outbox, |json| {
+            json.field_str("state", "complete").map(|_| ())
+        });
+    }
+
+    fn fail(&mut self, reason: &str, outbox: &mut dyn PublishOutbox) {
+        self.transfer = None;
+        self.writer.abort();
+        self.publish_failed(reason, outbox);
+    }
+
+    fn publish_failed(&self, reason: &str, outbox: &mut dyn PublishOutbox) {
+        self.publish_status(outbox, |json| {
+            json.field_str("state", "failed")?
+                .field_str("reason", reason)
+                .map(|_| ())
+        });
+    }
+
+    fn publish_resend(&self, offset: u32, outbox: &mut dyn PublishOutbox) {
+        self.publish_status(outbox, |json| {
+            json.field_str("state", "resend")?
+                .field_i32("offset", offset as i32)
+                .map(|_| ())
+        });
+    }
+
+    fn publish_status<F>(&self, outbox: &mut dyn PublishOutbox, fields: F)
+    where
+        F: FnOnce(&mut JsonWriter<'_>) -> Result<(), MqttError<ErrorPlaceHolder>>,
+    {
+        let mut buf = [0u8; 48];
+        let mut json = JsonWriter::new(&mut buf);
+        let written = json
+            .begin_object()
+            .and_then(fields)
+            .and_then(|()| json.end_object());
+        if let Ok(len) = written {
+            outbox.publish(self.status_topic, &buf[..len], QoS::AtLeastOnce);
+        }
+    }
+}
+
+impl<W: FlashWriter> MqttModule for OtaModule<'_, W> {
+    fn register(&self, collector: &mut dyn TopicCollector) {
+        collector.add(self.firmware_topic);
+    }
+
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        let Some(offset) = read_u32(msg.payload, 0) else {
+            return;
+        };
+        let data = &msg.payload[4..];
+        if offset == HEADER_OFFSET {
+            self.start(data, outbox);
+        } else {
+            self.chunk(offset, data, outbox);
+        }
+    }
+
+    fn on_tick(&mut self, outbox: &mut dyn PublishOutbox) -> Duration {
+        let Some(transfer) = self.transfer.as_mut() else {
+            return Duration::from_secs(60);
+        };
+        // Without progress since the last tick, the next chunk was lost
+        if !core::mem::take(&mut transfer.progressed) {
+            transfer.awaiting_resend = true;
+            let received = transfer.received;
+            self.publish_resend(received, outbox);
+        }
+        RESEND_INTERVAL
+    }
+
+    fn on_disconnect(&mut self) {
+        // Publishes sent while offline are lost; the sender restarts the transfer
+        if self.transfer.take().is_some() {
+            self.writer.abort();
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    let bytes = buf.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Feeds `data` into a CRC-32 (IEEE 802.3) computation.
+///
+/// Start with `!0` and invert the result after the last chunk.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::{String, Vec};
+
+    #[derive(Default)]
+    struct Flash {
+        image: Vec<u8, 64>,
+        finished: bool,
+        aborted: usize,
+    }
+
+    impl FlashWriter for Flash {
+        fn begin(&mut self, _size: u32) -> bool {
+            self.image.clear();
+            true
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> bool {
+            assert_eq!(offset as usize, self.image.len());
+            self.image.extend_from_slice(data).is_ok()
+        }
+
+        fn finish(&mut self) {
+            self.finished = true;
+        }
+
+        fn abort(&mut self) {
+            self.aborted += 1;
+        }
+    }
+
+    /// Records the status payloads published by the module.
+    #[derive(Default)]
+    struct Statuses(Vec<String<48>, 4>);
+
+    impl PublishOutbox for Statuses {
+        fn publish(&mut self, topic: &str, payload: &[u8], _qos: QoS) {
+            assert_eq!(topic, "st");
+            let status = core::str::from_utf8(payload).unwrap();
+            self.0.push(status.try_into().unwrap()).unwrap();
+        }
+    }
+
+    fn send(ota: &mut OtaModule<'_, Flash>, outbox: &mut Statuses, offset: u32, data: &[u8]) {
+        let mut payload: Vec<u8, 64> = Vec::new();
+        payload.extend_from_slice(&offset.to_be_bytes()).unwrap();
+        payload.extend_from_slice(data).unwrap();
+        let msg = Publish {
+            topic: "fw",
+            qos: QoS::AtMostOnce,
+            dup: false,
+            retain: false,
+            payload: &payload,
+            packet_id: None,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+        };
+        ota.on_message(&msg, outbox);
+    }
+
+    fn header(ota: &mut OtaModule<'_, Flash>, outbox: &mut Statuses, image: &[u8], crc: u32) {
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&(image.len() as u32).to_be_bytes());
+        header[4..].copy_from_slice(&crc.to_be_bytes());
+        send(ota, outbox, HEADER_OFFSET, &header);
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        !crc32_update(!0, data)
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn three_chunks_are_written_through() {
+        let image = b"hello, firmware image!";
+        let mut ota = OtaModule::new("fw", "st", Flash::default());
+        let mut outbox = Statuses::default();
+        header(&mut ota, &mut outbox, image, crc32(image));
+        send(&mut ota, &mut outbox, 0, &image[..8]);
+        send(&mut ota, &mut outbox, 8, &image[8..16]);
+        assert!(ota.is_receiving());
+        send(&mut ota, &mut outbox, 16, &image[16..]);
+
+        assert_eq!(ota.writer().image, image);
+        assert!(ota.writer().finished);
+        assert!(!ota.is_receiving());
+        assert_eq!(outbox.0, [r#"{"state":"complete"}"#]);
+    }
+
+    #[test]
+    fn missing_chunk_is_requested_again() {
+        let image = b"0123456789";
+        let mut ota = OtaModule::new("fw", "st", Flash::default());
+        let mut outbox = Statuses::default();
+        header(&mut ota, &mut outbox, image, crc32(image) ^ 1);
+        send(&mut ota, &mut outbox, 0, &image[..4]);
+        send(&mut ota, &mut outbox, 8, &image[8..]);
+        // Only the first gap is reported
+        send(&mut ota, &mut outbox, 8, &image[8..]);
+        assert_eq!(outbox.0, [r#"{"state":"resend","offset":4}"#]);
+        // The request is repeated once a tick passes without progress
+        ota.on_tick(&mut outbox);
+        assert_eq!(outbox.0.len(), 1);
+        ota.on_tick(&mut outbox);
+        assert_eq!(outbox.0.len(), 2);
+
+        send(&mut ota, &mut outbox, 0, &image[..4]);
+        send(&mut ota, &mut outbox, 4, &image[4..8]);
+        send(&mut ota, &mut outbox, 8, &image[8..]);
+        assert_eq!(ota.writer().image, image);
+        assert!(!ota.writer().finished);
+        assert_eq!(ota.writer().aborted, 1);
+        assert_eq!(
+            outbox.0.last().unwrap(),
+            r#"{"state":"failed","reason":"crc"}"#
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Pausing the runtime from another task.
+//!
+//! `PauseControl` lets firmware suspend MQTT processing temporarily, e.g.
+//! around a flash write or to save power, without dropping the connection.
+
+use core::cell::RefCell;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+struct PauseState {
+    paused: bool,
+    waker: WakerRegistration,
+}
+
+/// A switch, shared with the runtime, that pauses and resumes its event loop.
+///
+/// While paused, the runtime neither reads from the connection nor calls the
+/// module, and requests on the publish channel wait. It only sends a PINGREQ
+/// every half keep-alive interval, so the broker keeps the session, and
+/// nothing at all when keep-alive is disabled. Messages
+/// sent by the broker meanwhile wait in the transport and are handled after
+/// `resume`, so a long pause needs a transport with room to buffer them.
+///
+/// ```ignore
+/// static PAUSE: PauseControl = PauseControl::new();
+///
+/// let mut runtime = MqttRuntime::new(client, module, rx).with_pause_control(&PAUSE);
+///
+/// // In another task
+/// PAUSE.pause();
+/// write_flash().await;
+/// PAUSE.resume();
+/// ```
+pub struct PauseControl {
+    state: Mutex<CriticalSectionRawMutex, RefCell<PauseState>>,
+}
+
+impl PauseControl {
+    /// Create a new control, initially not paused.
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(PauseState {
+                paused: false,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Pause the runtime once it finishes the current step.
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    /// Resume a paused runtime.
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    /// Check if the runtime is asked to pause.
+    pub fn is_paused(&self) -> bool {
+        self.with_state(|state| state.paused)
+    }
+
+    /// Waits until the control is switched to `paused`.
+    ///
+    /// Only the runtime waits, so a single waker registration is enough.
+    pub(crate) async fn wait_for(&self, paused: bool) {
+        core::future::poll_fn(|cx| {
+            self.with_state(|state| {
+                if state.paused == paused {
+                    Poll::Ready(())
+                } else {
+                    state.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.with_state(|state| {
+            state.paused = paused;
+            state.waker.wake();
+        });
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut PauseState) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+impl Default for PauseControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
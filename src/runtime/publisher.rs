@@ -5,10 +5,14 @@
 
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::{Channel, Receiver, Sender};
-use heapless::Vec;
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
 
-use super::traits::PublishOutbox;
+use super::traits::{OutboxError, PublishOutbox};
 use crate::QoS;
+use crate::client::MqttClient;
+use crate::error::MqttError;
+use crate::transport::{MqttTransport, TransportError};
 
 /// A request to publish a message, sent via channel from controllers to the runtime.
 ///
@@ -35,6 +39,18 @@ pub type PublishRequestSender<'a, const OUTBOX_DEPTH: usize> =
 pub type PublishRequestReceiver<'a, const OUTBOX_DEPTH: usize> =
     Receiver<'a, CriticalSectionRawMutex, PublishRequest<'a>, OUTBOX_DEPTH>;
 
+/// Returned by `PublisherHandle::publish_timeout` when the channel stayed
+/// full for the whole timeout. The request was not queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PublishTimeout;
+
+impl core::fmt::Display for PublishTimeout {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("publish channel stayed full until the timeout")
+    }
+}
+
 /// A handle that allows controllers to publish MQTT messages without direct
 /// access to the `MqttClient`.
 ///
@@ -82,6 +98,32 @@ impl<'a, const OUTBOX_DEPTH: usize> PublisherHandle<'a, OUTBOX_DEPTH> {
         self.tx.send(req).await;
     }
 
+    /// Publish a message, waiting at most `timeout` for room in the channel.
+    ///
+    /// A middle ground between `publish`, which waits for as long as the
+    /// runtime is busy, and `try_publish`, which gives up at once. Returns
+    /// `PublishTimeout` if the channel is still full when `timeout` elapses.
+    pub async fn publish_timeout(
+        &self,
+        topic: &'a str,
+        payload: &'a [u8],
+        qos: QoS,
+        timeout: Duration,
+    ) -> Result<(), PublishTimeout> {
+        let req = PublishRequest {
+            topic,
+            payload,
+            qos,
+            retain: false,
+        };
+        let send_fut = self.tx.send(req);
+        let timer_fut = Timer::after(timeout);
+        match futures::future::select(core::pin::pin!(send_fut), core::pin::pin!(timer_fut)).await {
+            futures::future::Either::Left(((), _)) => Ok(()),
+            futures::future::Either::Right(((), _)) => Err(PublishTimeout),
+        }
+    }
+
     /// Try to publish a message without waiting.
     ///
     /// Returns `false` if the channel is full.
@@ -146,6 +188,60 @@ pub struct OwnedPublishRequest<const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usiz
     pub retain: bool,
 }
 
+/// What the runtime does with an outgoing request after `before_publish`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PublishDecision {
+    /// Publish the request, including any changes made to it.
+    Send,
+    /// Discard the request.
+    Drop,
+    /// Keep the request, unchanged, in the outbox and offer it again the next
+    /// time the outbox is drained.
+    Defer,
+}
+
+/// Hook that sees every request from the module's outbox before it is sent.
+///
+/// Set with `MqttRuntime::with_middleware` to apply cross-cutting rules, such
+/// as a topic prefix or rate limiting, without changing each module. The
+/// runtime passes requests with its outbox sizes, so implementations are
+/// usually generic over them:
+///
+/// ```ignore
+/// struct Prefix(&'static str);
+///
+/// impl<const T: usize, const P: usize> PublishMiddleware<T, P> for Prefix {
+///     fn before_publish(&mut self, req: &mut OwnedPublishRequest<T, P>) -> PublishDecision {
+///         let mut topic = heapless::String::new();
+///         if topic.push_str(self.0).is_err() || topic.push_str(&req.topic).is_err() {
+///             return PublishDecision::Drop;
+///         }
+///         req.topic = topic;
+///         PublishDecision::Send
+///     }
+/// }
+/// ```
+///
+/// Requests from the publish channel and the offline queue are sent as they
+/// are.
+pub trait PublishMiddleware<const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize> {
+    /// Inspects or rewrites `req` and decides whether it is sent.
+    fn before_publish(
+        &mut self,
+        req: &mut OwnedPublishRequest<TOPIC_SIZE, PAYLOAD_SIZE>,
+    ) -> PublishDecision;
+}
+
+impl<const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
+    OwnedPublishRequest<TOPIC_SIZE, PAYLOAD_SIZE>
+{
+    /// Check whether a payload of `payload_len` bytes fits the inline storage.
+    pub const fn would_fit(payload_len: usize) -> bool {
+        payload_len <= PAYLOAD_SIZE
+    }
+}
+
 impl<const CAPACITY: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
     BufferedOutbox<CAPACITY, TOPIC_SIZE, PAYLOAD_SIZE>
 {
@@ -163,11 +259,55 @@ impl<const CAPACITY: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
         self.requests.iter().cloned()
     }
 
+    /// Publish all buffered requests through `client`, in order, and return
+    /// each request's topic with the result of its publish.
+    ///
+    /// Every request is attempted, even after an earlier one failed, and the
+    /// outbox is empty afterwards. Unlike the runtime, this doesn't call
+    /// `on_publish_failed`, so callers can decide what to do with failures.
+    pub async fn drain_to_client<
+        T,
+        const MAX_TOPICS: usize,
+        const TX_BUF: usize,
+        const RX_BUF: usize,
+    >(
+        &mut self,
+        client: &mut MqttClient<'_, T, MAX_TOPICS, TX_BUF, RX_BUF>,
+    ) -> Vec<(String<TOPIC_SIZE>, Result<(), MqttError<T::Error>>), CAPACITY>
+    where
+        T: MqttTransport,
+        T::Error: TransportError,
+    {
+        let mut results = Vec::new();
+        for req in self.requests.iter() {
+            let result = client
+                .publish_with_retain(
+                    req.topic.as_str(),
+                    req.payload.as_slice(),
+                    req.qos,
+                    req.retain,
+                )
+                .await;
+            // Can't fail, there is one result per buffered request
+            let _ = results.push((req.topic.clone(), result));
+        }
+        self.requests.clear();
+        results
+    }
+
     /// Clear all buffered requests.
     pub fn clear(&mut self) {
         self.requests.clear();
     }
 
+    /// Keeps the buffered requests for which `keep` returns `true`, in order.
+    pub(crate) fn retain(
+        &mut self,
+        keep: impl FnMut(&OwnedPublishRequest<TOPIC_SIZE, PAYLOAD_SIZE>) -> bool,
+    ) {
+        self.requests.retain(keep);
+    }
+
     /// Check if the outbox is empty.
     pub fn is_empty(&self) -> bool {
         self.requests.is_empty()
@@ -195,27 +335,35 @@ impl<const CAPACITY: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
     }
 
     fn publish_with_retain(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) {
-        // Try to store the request; silently drop if full or data too large
+        // Rejected requests are dropped; use `try_publish_with_retain` to detect them
+        let _ = self.try_publish_with_retain(topic, payload, qos, retain);
+    }
+
+    fn try_publish_with_retain(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), OutboxError> {
         let mut topic_str = heapless::String::new();
         if topic_str.push_str(topic).is_err() {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!(
+            log_warn!(
                 "outbox: topic too long! topic_len={}, max={}",
                 topic.len(),
                 TOPIC_SIZE
             );
-            return;
+            return Err(OutboxError::TopicTooLong);
         }
 
         let mut payload_vec = heapless::Vec::new();
         if payload_vec.extend_from_slice(payload).is_err() {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!(
+            log_warn!(
                 "outbox: payload too large! payload_len={}, max={}",
                 payload.len(),
                 PAYLOAD_SIZE
             );
-            return;
+            return Err(OutboxError::PayloadTooLong);
         }
 
         let req = OwnedPublishRequest {
@@ -226,17 +374,126 @@ impl<const CAPACITY: usize, const TOPIC_SIZE: usize, const PAYLOAD_SIZE: usize>
         };
 
         if self.requests.push(req).is_err() {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!("outbox: queue full! capacity={}", CAPACITY);
-        } else {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!(
-                "outbox: added message, topic='{}', retain={}, payload_len={}, queue_size={}",
-                topic,
-                retain,
-                payload.len(),
-                self.requests.len()
-            );
+            log_warn!("outbox: queue full! capacity={}", CAPACITY);
+            return Err(OutboxError::Full);
         }
+
+        log_debug!(
+            "outbox: added message, topic='{}', retain={}, payload_len={}, queue_size={}",
+            topic,
+            retain,
+            payload.len(),
+            self.requests.len()
+        );
+        Ok(())
+    }
+
+    fn max_payload(&self) -> usize {
+        PAYLOAD_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProtocolError;
+    use crate::test_util::MockTransport;
+    use crate::{MqttOptions, MqttVersion};
+
+    #[test]
+    fn outbox_reports_why_a_request_was_rejected() {
+        let mut outbox = BufferedOutbox::<2, 8, 4>::new();
+        assert_eq!(
+            outbox.try_publish("t", b"12345", QoS::AtMostOnce),
+            Err(OutboxError::PayloadTooLong)
+        );
+        assert_eq!(
+            outbox.try_publish("too/long/", b"1", QoS::AtMostOnce),
+            Err(OutboxError::TopicTooLong)
+        );
+        assert_eq!(outbox.try_publish("t", b"1234", QoS::AtMostOnce), Ok(()));
+        assert_eq!(outbox.try_publish("t", b"", QoS::AtMostOnce), Ok(()));
+        assert_eq!(
+            outbox.try_publish("t", b"", QoS::AtMostOnce),
+            Err(OutboxError::Full)
+        );
+        // `publish` drops the request instead
+        outbox.publish("t", b"", QoS::AtMostOnce);
+        assert_eq!(outbox.len(), 2);
+    }
+
+    #[test]
+    fn outbox_reports_its_payload_limit() {
+        let outbox = BufferedOutbox::<1, 8, 16>::new();
+        assert_eq!(outbox.max_payload(), 16);
+        assert!(OwnedPublishRequest::<8, 16>::would_fit(16));
+        assert!(!OwnedPublishRequest::<8, 16>::would_fit(17));
+    }
+
+    #[test]
+    fn drain_to_client_publishes_every_request() {
+        let mut transport = MockTransport::<256>::new(MqttVersion::V3);
+        assert!(transport.push_connack(false, 0));
+        let mut client: MqttClient<'_, _, 1, 64> =
+            MqttClient::new(transport, MqttOptions::new("cid"));
+        embassy_futures::block_on(client.connect()).unwrap();
+        client.transport_mut().clear_sent();
+
+        let mut outbox = BufferedOutbox::<3, 8, 4>::new();
+        outbox.publish("a/1", b"1", QoS::AtMostOnce);
+        // Wildcards aren't valid in a topic name
+        outbox.publish("a/#", b"2", QoS::AtMostOnce);
+        outbox.publish("a/3", b"3", QoS::AtMostOnce);
+
+        let results = embassy_futures::block_on(outbox.drain_to_client(&mut client));
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, "a/1");
+        assert!(results[0].1.is_ok());
+        assert_eq!(results[1].0, "a/#");
+        assert!(
+            matches!(
+                results[1].1,
+                Err(MqttError::Protocol(ProtocolError::InvalidTopic))
+            ),
+            "{:?}",
+            results[1].1
+        );
+        assert_eq!(results[2].0, "a/3");
+        assert!(results[2].1.is_ok());
+        assert!(outbox.is_empty());
+        // Only the valid requests were sent
+        assert_eq!(
+            client.transport().sent(),
+            [
+                0x30, 6, 0, 3, b'a', b'/', b'1', b'1', 0x30, 6, 0, 3, b'a', b'/', b'3', b'3'
+            ]
+        );
+    }
+
+    #[test]
+    fn publish_timeout_gives_up_on_a_full_channel() {
+        static CHANNEL: PublishRequestChannel<'static, 1> = PublishRequestChannel::new();
+        let publisher = PublisherHandle::new(CHANNEL.sender());
+        assert!(publisher.try_publish("t", b"1", QoS::AtMostOnce));
+
+        let result = embassy_futures::block_on(publisher.publish_timeout(
+            "t",
+            b"2",
+            QoS::AtMostOnce,
+            Duration::from_millis(5),
+        ));
+        assert_eq!(result, Err(PublishTimeout));
+        // Only the first request was queued
+        assert_eq!(CHANNEL.try_receive().unwrap().payload, b"1");
+        assert!(CHANNEL.try_receive().is_err());
+
+        // With room again, it is queued right away
+        let result = embassy_futures::block_on(publisher.publish_timeout(
+            "t",
+            b"3",
+            QoS::AtMostOnce,
+            Duration::from_millis(5),
+        ));
+        assert_eq!(result, Ok(()));
     }
 }
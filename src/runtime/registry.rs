@@ -2,9 +2,10 @@
 
 use heapless::{String, Vec};
 
-use super::traits::TopicCollector;
+use super::traits::{ModuleFilters, TopicCollector};
+use crate::util::topic_matches;
 
-/// Maximum length for a single topic string.
+/// Default maximum length for a single topic string.
 pub const MAX_TOPIC_LEN: usize = 128;
 
 /// A registry for topics that modules want to subscribe to.
@@ -12,6 +13,16 @@ pub const MAX_TOPIC_LEN: usize = 128;
 /// This registry owns the topic strings (copies them on add), making it
 /// suitable for use with the object-safe `TopicCollector` trait.
 ///
+/// Each topic is recorded with the index of the module that added it, as
+/// reported by composite modules through `TopicCollector::set_module_index`.
+/// `iter_with_module` lists them, which helps to find out which module of a
+/// `ModulePair` or `MqttModuleList` subscribed to what.
+///
+/// Topics are stored inline, `TOPIC_LEN` bytes each, so the registry takes
+/// about `MAX_TOPICS * TOPIC_LEN` bytes. Lower the limit on devices with short
+/// topics, or raise it for deep hierarchies like Home Assistant discovery
+/// topics.
+///
 /// # Example
 ///
 /// ```ignore
@@ -24,13 +35,24 @@ pub const MAX_TOPIC_LEN: usize = 128;
 /// for topic in registry.iter() {
 ///     client.subscribe(topic, QoS::AtMostOnce).await?;
 /// }
+///
+/// // Which module registered what
+/// for (module, topic) in registry.iter_with_module() {
+///     println!("module {module}: {topic}");
+/// }
+///
+/// // Topics up to 32 bytes
+/// let mut registry = TopicRegistry::<8, 32>::new();
 /// ```
 #[derive(Default)]
-pub struct TopicRegistry<const MAX_TOPICS: usize> {
-    topics: Vec<String<MAX_TOPIC_LEN>, MAX_TOPICS>,
+pub struct TopicRegistry<const MAX_TOPICS: usize, const TOPIC_LEN: usize = MAX_TOPIC_LEN> {
+    /// Registered topics with the index of the module that added them
+    topics: Vec<(usize, String<TOPIC_LEN>), MAX_TOPICS>,
+    /// Module index attributed to topics added next
+    module_index: usize,
 }
 
-impl<const MAX_TOPICS: usize> TopicRegistry<MAX_TOPICS> {
+impl<const MAX_TOPICS: usize, const TOPIC_LEN: usize> TopicRegistry<MAX_TOPICS, TOPIC_LEN> {
     /// Create a new empty topic registry.
     pub fn new() -> Self {
         Self::default()
@@ -41,7 +63,7 @@ impl<const MAX_TOPICS: usize> TopicRegistry<MAX_TOPICS> {
     /// Returns `true` if successful, `false` if the registry is full
     /// or the topic is too long.
     pub fn add_topic(&mut self, topic: &str) -> bool {
-        if topic.len() > MAX_TOPIC_LEN {
+        if topic.len() > TOPIC_LEN {
             return false;
         }
 
@@ -50,12 +72,27 @@ impl<const MAX_TOPICS: usize> TopicRegistry<MAX_TOPICS> {
             return false;
         }
 
-        self.topics.push(owned).is_ok()
+        self.topics.push((self.module_index, owned)).is_ok()
     }
 
     /// Get an iterator over the registered topics.
     pub fn iter(&self) -> impl Iterator<Item = &str> {
-        self.topics.iter().map(|s| s.as_str())
+        self.topics.iter().map(|(_, s)| s.as_str())
+    }
+
+    /// Get an iterator over the registered topics, each with the index of
+    /// the module that added it.
+    ///
+    /// Indices count leaf modules in registration order, across nested
+    /// compositions. A single module registers all its topics with index `0`.
+    pub fn iter_with_module(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.topics.iter().map(|(module, s)| (*module, s.as_str()))
+    }
+
+    /// Check whether `topic` matches any registered filter, honoring the
+    /// `+` and `#` wildcards.
+    pub fn matches(&self, topic: &str) -> bool {
+        self.iter().any(|filter| topic_matches(filter, topic))
     }
 
     /// Get the number of registered topics.
@@ -71,11 +108,88 @@ impl<const MAX_TOPICS: usize> TopicRegistry<MAX_TOPICS> {
     /// Clear all registered topics.
     pub fn clear(&mut self) {
         self.topics.clear();
+        self.module_index = 0;
     }
 }
 
-impl<const MAX_TOPICS: usize> TopicCollector for TopicRegistry<MAX_TOPICS> {
+impl<const MAX_TOPICS: usize, const TOPIC_LEN: usize> TopicCollector
+    for TopicRegistry<MAX_TOPICS, TOPIC_LEN>
+{
     fn add(&mut self, topic: &str) -> bool {
         self.add_topic(topic)
     }
+
+    fn module_index(&self) -> usize {
+        self.module_index
+    }
+
+    fn set_module_index(&mut self, index: usize) {
+        self.module_index = index;
+    }
+}
+
+impl<const MAX_TOPICS: usize, const TOPIC_LEN: usize> ModuleFilters
+    for TopicRegistry<MAX_TOPICS, TOPIC_LEN>
+{
+    fn matches_module(&self, module_index: usize, topic: &str) -> bool {
+        self.iter_with_module()
+            .any(|(module, filter)| module == module_index && topic_matches(filter, topic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Publish;
+    use crate::runtime::traits::{ModulePair, MqttModule, PublishOutbox};
+
+    /// Registers a fixed set of topics.
+    struct Topics(&'static [&'static str]);
+
+    impl MqttModule for Topics {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            for topic in self.0 {
+                collector.add(topic);
+            }
+        }
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {}
+    }
+
+    #[test]
+    fn add_rejects_topics_longer_than_topic_len() {
+        let mut registry = TopicRegistry::<4, 32>::new();
+        let collector: &mut dyn TopicCollector = &mut registry;
+        assert!(collector.add("a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p1"));
+        assert!(!collector.add("a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p12"));
+        assert_eq!(registry.len(), 1);
+        assert!(registry.matches("a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p1"));
+    }
+
+    #[test]
+    fn topics_are_attributed_to_the_module_that_added_them() {
+        let mut registry = TopicRegistry::<8, 32>::new();
+        let pair = ModulePair::new(Topics(&["a"]), Topics(&["b", "b/+"]));
+        pair.register(&mut registry);
+        assert!(
+            registry
+                .iter_with_module()
+                .eq([(0, "a"), (1, "b"), (1, "b/+")])
+        );
+
+        // A nested pair continues the numbering of its leaves
+        registry.clear();
+        let nested = ModulePair::new(
+            ModulePair::new(Topics(&["a"]), Topics(&["b"])),
+            ModulePair::new(Topics(&[]), Topics(&["d/#"])),
+        );
+        nested.register(&mut registry);
+        assert!(
+            registry
+                .iter_with_module()
+                .eq([(0, "a"), (1, "b"), (3, "d/#")])
+        );
+        assert!(registry.matches_module(3, "d/e"));
+        assert!(!registry.matches_module(2, "d/e"));
+    }
 }
@@ -21,11 +21,13 @@
 //!
 //! This separation keeps the trait object-safe while maintaining good performance.
 
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant};
 
 use crate::client::LastWill;
+use crate::error::MqttError;
 use crate::packet::Publish;
 use crate::packet::QoS;
+use crate::util::topic_matches;
 
 /// Object-safe trait for queuing MQTT publish requests.
 ///
@@ -62,6 +64,51 @@ pub trait PublishOutbox {
     fn publish_with_retain(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) {
         self.publish(topic, payload, qos);
     }
+
+    /// Queue a message for publishing, reporting whether it was accepted.
+    ///
+    /// Unlike [`PublishOutbox::publish`], which drops requests that don't fit,
+    /// this lets a module detect a lost update and retry on a later tick.
+    fn try_publish(&mut self, topic: &str, payload: &[u8], qos: QoS) -> Result<(), OutboxError> {
+        self.try_publish_with_retain(topic, payload, qos, false)
+    }
+
+    /// Queue a message for publishing with the MQTT retain flag, reporting
+    /// whether it was accepted.
+    ///
+    /// Default implementation calls [`PublishOutbox::publish_with_retain`] and
+    /// always succeeds. Implementations that can run out of space should override this.
+    fn try_publish_with_retain(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), OutboxError> {
+        self.publish_with_retain(topic, payload, qos, retain);
+        Ok(())
+    }
+
+    /// Returns the largest payload, in bytes, this outbox can queue.
+    ///
+    /// Modules sending large data can split it into chunks of this size
+    /// instead of having requests rejected with `OutboxError::PayloadTooLong`.
+    /// The default reports no limit.
+    fn max_payload(&self) -> usize {
+        usize::MAX
+    }
+}
+
+/// Reason a publish request was rejected by a `PublishOutbox`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OutboxError {
+    /// The outbox has no room for another request.
+    Full,
+    /// The topic exceeds the outbox's inline topic storage.
+    TopicTooLong,
+    /// The payload exceeds the outbox's inline payload storage.
+    PayloadTooLong,
 }
 
 /// Object-safe trait for collecting topics during registration.
@@ -76,6 +123,34 @@ pub trait TopicCollector {
     ///
     /// Returns `true` if the topic was added, `false` if the collector is full.
     fn add(&mut self, topic: &str) -> bool;
+
+    /// Returns the index of the module whose topics are being added.
+    ///
+    /// Composite modules read this before registering their members. The
+    /// default implementation always returns `0`.
+    fn module_index(&self) -> usize {
+        0
+    }
+
+    /// Attributes the following topics to the module at `index`.
+    ///
+    /// Composite modules call this before registering each member, with the
+    /// member's position among all leaf modules, so a collector like
+    /// `TopicRegistry` can tell which module added which topic. The default
+    /// implementation ignores it.
+    fn set_module_index(&mut self, _index: usize) {}
+}
+
+/// Object-safe view of the topic filters each module registered.
+///
+/// The runtime keeps the `TopicRegistry` it subscribed from and passes it to
+/// `MqttModule::on_dispatch`, so incoming messages are matched against the
+/// stored filters instead of calling `register` again. Filters the registry
+/// rejected were never subscribed and don't match.
+pub trait ModuleFilters {
+    /// Returns `true` if the module at `module_index` registered a filter
+    /// matching `topic`.
+    fn matches_module(&self, module_index: usize, topic: &str) -> bool;
 }
 
 /// Object-safe trait for MQTT modules that handle incoming messages and periodic tasks.
@@ -102,19 +177,17 @@ pub trait TopicCollector {
 /// const CMD_TOPIC: &str = "device/cmd";
 /// const STATE_TOPIC: &str = "device/state";
 ///
-/// struct MyModule {
-///     pending_state_update: bool,
-/// }
+/// struct MyModule;
 ///
 /// impl MqttModule for MyModule {
 ///     fn register(&self, collector: &mut dyn TopicCollector) {
 ///         collector.add(CMD_TOPIC);
 ///     }
 ///
-///     fn on_message(&mut self, msg: &Publish<'_>) {
+///     fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
 ///         if msg.topic == CMD_TOPIC {
-///             // Process command, set flag for response
-///             self.pending_state_update = true;
+///             // Process command and answer right away
+///             outbox.publish(STATE_TOPIC, msg.payload, QoS::AtMostOnce);
 ///         }
 ///     }
 ///
@@ -122,10 +195,6 @@ pub trait TopicCollector {
 ///         outbox.publish(STATE_TOPIC, b"online", QoS::AtMostOnce);
 ///         Duration::from_secs(30)
 ///     }
-///
-///     fn needs_immediate_publish(&self) -> bool {
-///         self.pending_state_update
-///     }
 /// }
 /// ```
 pub trait MqttModule {
@@ -140,15 +209,28 @@ pub trait MqttModule {
 
     /// Handle an incoming MQTT message (synchronous processing only).
     ///
-    /// Called for every incoming publish. The module should check if `msg.topic`
-    /// matches one of its registered topics and handle accordingly.
+    /// Called for incoming publishes whose topic matches one of the filters added
+    /// in `register` (or for every publish if `wants_all_messages` returns `true`),
+    /// through `on_dispatch`.
+    /// Modules registering several topics still check `msg.topic` to tell them
+    /// apart; `util::topic_matches` handles wildcard filters.
     ///
-    /// **Important**: This method cannot publish responses directly because the
-    /// message data borrows from the client's buffer. Instead:
-    /// - Process the message and update internal state
-    /// - Set a flag indicating a response is needed
-    /// - Publish the response in `on_tick` (triggered by `needs_immediate_publish`)
-    fn on_message(&mut self, msg: &Publish<'_>);
+    /// The message borrows from the client's receive buffer, so responses can't
+    /// be sent while it is alive. Queue them in `outbox` instead; the runtime
+    /// publishes them once this method returns and the message is released.
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox);
+
+    /// Handle a retained message the broker replays on subscribing.
+    ///
+    /// Called instead of `on_message` for incoming publishes with the retain
+    /// flag set, which brokers only set on messages sent because of a new
+    /// subscription, e.g. after every reconnect. Modules treating messages as
+    /// commands can override this to ignore stale ones, so a device isn't
+    /// actuated again by the last command on each reconnect.
+    /// The default implementation calls `on_message`.
+    fn on_retained_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        self.on_message(msg, outbox);
+    }
 
     /// Perform periodic tasks and return the desired interval until the next tick.
     ///
@@ -163,11 +245,32 @@ pub trait MqttModule {
         Duration::from_secs(60)
     }
 
+    /// Called after each successful connect, before topics are registered.
+    ///
+    /// `client_id` is the identifier of the session, which an MQTT v5 broker
+    /// may have assigned when the client connected with an empty one. Modules
+    /// that put the client id in their topics store it here, so that the
+    /// following `register` call can use it.
+    /// The default implementation does nothing.
+    fn on_connect(&mut self, _client_id: &str) {}
+
     /// Called once after connection is established and subscriptions are done.
     ///
+    /// `session_present` is `true` when the broker resumed the session of a
+    /// previous connection. Its subscriptions are still active, so the
+    /// runtime didn't subscribe again.
+    ///
     /// Use this for initial announces, state publishing, etc.
     /// The default implementation does nothing.
-    fn on_start(&mut self, _outbox: &mut dyn PublishOutbox) {}
+    fn on_start(&mut self, _session_present: bool, _outbox: &mut dyn PublishOutbox) {}
+
+    /// Called when the connection started by `on_start` is lost.
+    ///
+    /// The runtime calls this after a transport error, keep-alive timeout or
+    /// disconnect ends its event loop. Use it to reset state that depends on
+    /// the broker, e.g. mark a device as unavailable until the next `on_start`.
+    /// The default implementation does nothing.
+    fn on_disconnect(&mut self) {}
 
     /// Returns MQTT Last Will and Testament settings used for broker-side offline detection.
     ///
@@ -178,9 +281,55 @@ pub trait MqttModule {
         None
     }
 
+    /// Dispatch an incoming publish to this module, or for composite modules to
+    /// each member whose filters match its topic.
+    ///
+    /// The runtime calls this for every incoming publish with the filters it
+    /// collected from `register` at subscribe time; `module_index` is this
+    /// module's index among them. The default implementation calls
+    /// `on_retained_message` for retained messages and `on_message` otherwise,
+    /// if `filters` has a matching filter for `module_index` or
+    /// `wants_all_messages` returns `true`. Composite modules forward the call
+    /// to their members with each member's index.
+    fn on_dispatch(
+        &mut self,
+        msg: &Publish<'_>,
+        outbox: &mut dyn PublishOutbox,
+        filters: &dyn ModuleFilters,
+        module_index: usize,
+    ) {
+        if !self.wants_all_messages() && !filters.matches_module(module_index, msg.topic) {
+            return;
+        }
+        if msg.retain {
+            self.on_retained_message(msg, outbox);
+        } else {
+            self.on_message(msg, outbox);
+        }
+    }
+
+    /// Opt out of topic-based dispatch and receive every incoming publish.
+    ///
+    /// The default implementation returns `false`, so `on_message` is only
+    /// called for topics matching the filters added in `register`.
+    fn wants_all_messages(&self) -> bool {
+        false
+    }
+
+    /// Returns how many modules this one is composed of.
+    ///
+    /// Composite modules return the sum over their members, so module indices
+    /// passed to `TopicCollector::set_module_index` stay unique when
+    /// compositions are nested. The default implementation returns `1`.
+    fn module_count(&self) -> usize {
+        1
+    }
+
     /// Check if the module needs to publish immediately after processing a message.
     ///
     /// If this returns `true`, `on_publish` will be called immediately after `on_message`.
+    /// Responses queued in `on_message` are published regardless, so this is only
+    /// needed by modules that defer building the response to `on_publish`.
     /// The default implementation returns `false`.
     fn needs_immediate_publish(&self) -> bool {
         false
@@ -192,6 +341,64 @@ pub trait MqttModule {
     /// Unlike `on_tick`, this should NOT re-announce discovery configs.
     /// The default implementation does nothing.
     fn on_publish(&mut self, _outbox: &mut dyn PublishOutbox) {}
+
+    /// Called when the runtime fails to publish a request queued in the outbox.
+    ///
+    /// `topic` is the topic of the request that was not published. After a
+    /// failed publish the runtime stops draining, so this is also called for
+    /// every request queued after it, with the same error. Use it to mark the
+    /// state as dirty so it is published again after the next `on_start`.
+    /// The default implementation does nothing.
+    fn on_publish_failed(&mut self, _topic: &str, _err: &MqttError<&dyn core::fmt::Debug>) {}
+}
+
+/// Returns `true` if `module` should receive a publish on `topic`.
+///
+/// Only used when a composite module's `on_message` is called directly rather
+/// than through `on_dispatch`: without the runtime's stored filters, they are
+/// collected from `register` on each call.
+fn wants_message<M: MqttModule + ?Sized>(module: &M, topic: &str) -> bool {
+    if module.wants_all_messages() {
+        return true;
+    }
+
+    let mut matcher = TopicMatcher {
+        topic,
+        matched: false,
+    };
+    module.register(&mut matcher);
+    matcher.matched
+}
+
+/// A `TopicCollector` that checks registered filters against a single topic.
+struct TopicMatcher<'t> {
+    topic: &'t str,
+    matched: bool,
+}
+
+impl TopicCollector for TopicMatcher<'_> {
+    fn add(&mut self, filter: &str) -> bool {
+        self.matched |= topic_matches(filter, self.topic);
+        true
+    }
+}
+
+/// Calls `on_tick` on `module` if it has never been ticked or its `deadline`
+/// has passed, and returns the instant it is due next.
+fn tick_if_due<M: MqttModule + ?Sized>(
+    module: &mut M,
+    deadline: &mut Option<Instant>,
+    now: Instant,
+    outbox: &mut dyn PublishOutbox,
+) -> Instant {
+    match *deadline {
+        Some(due) if due > now => due,
+        _ => {
+            let due = now.saturating_add(module.on_tick(outbox));
+            *deadline = Some(due);
+            due
+        }
+    }
 }
 
 /// A no-op module that does nothing.
@@ -202,13 +409,15 @@ pub struct NoopModule;
 impl MqttModule for NoopModule {
     fn register(&self, _collector: &mut dyn TopicCollector) {}
 
-    fn on_message(&mut self, _msg: &Publish<'_>) {}
+    fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {}
 }
 
 /// A composite module that combines two modules into one.
 ///
-/// Both modules receive all messages and ticks. Use this to compose
-/// multiple independent modules into a single runtime.
+/// Each module keeps its own tick schedule: `on_tick` is only forwarded to a
+/// module once the interval it returned last time has elapsed. Each incoming
+/// message is only passed to the modules whose registered filters match its
+/// topic. Use this to compose multiple independent modules into a single runtime.
 ///
 /// # Example
 ///
@@ -222,12 +431,19 @@ pub struct ModulePair<M1, M2> {
     pub first: M1,
     /// Second module
     pub second: M2,
+    first_deadline: Option<Instant>,
+    second_deadline: Option<Instant>,
 }
 
 impl<M1, M2> ModulePair<M1, M2> {
     /// Create a new combined module from two modules.
     pub fn new(first: M1, second: M2) -> Self {
-        Self { first, second }
+        Self {
+            first,
+            second,
+            first_deadline: None,
+            second_deadline: None,
+        }
     }
 }
 
@@ -237,31 +453,80 @@ where
     M2: MqttModule,
 {
     fn register(&self, collector: &mut dyn TopicCollector) {
+        let base = collector.module_index();
         self.first.register(collector);
+        collector.set_module_index(base + self.first.module_count());
         self.second.register(collector);
     }
 
-    fn on_message(&mut self, msg: &Publish<'_>) {
-        self.first.on_message(msg);
-        self.second.on_message(msg);
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        if wants_message(&self.first, msg.topic) {
+            self.first.on_message(msg, outbox);
+        }
+        if wants_message(&self.second, msg.topic) {
+            self.second.on_message(msg, outbox);
+        }
+    }
+
+    fn on_retained_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        if wants_message(&self.first, msg.topic) {
+            self.first.on_retained_message(msg, outbox);
+        }
+        if wants_message(&self.second, msg.topic) {
+            self.second.on_retained_message(msg, outbox);
+        }
+    }
+
+    fn on_dispatch(
+        &mut self,
+        msg: &Publish<'_>,
+        outbox: &mut dyn PublishOutbox,
+        filters: &dyn ModuleFilters,
+        module_index: usize,
+    ) {
+        self.first.on_dispatch(msg, outbox, filters, module_index);
+        let second_index = module_index + self.first.module_count();
+        self.second.on_dispatch(msg, outbox, filters, second_index);
     }
 
     fn on_tick(&mut self, outbox: &mut dyn PublishOutbox) -> Duration {
-        let d1 = self.first.on_tick(outbox);
-        let d2 = self.second.on_tick(outbox);
-        // Return the smaller interval so both modules get ticked appropriately
-        if d1 < d2 { d1 } else { d2 }
+        let now = Instant::now();
+        let d1 = tick_if_due(&mut self.first, &mut self.first_deadline, now, outbox);
+        let d2 = tick_if_due(&mut self.second, &mut self.second_deadline, now, outbox);
+        // Wake up for whichever module is due first
+        d1.min(d2).saturating_duration_since(now)
+    }
+
+    fn on_connect(&mut self, client_id: &str) {
+        self.first.on_connect(client_id);
+        self.second.on_connect(client_id);
+    }
+
+    fn on_start(&mut self, session_present: bool, outbox: &mut dyn PublishOutbox) {
+        // Both modules get their initial tick on the new connection
+        self.first_deadline = None;
+        self.second_deadline = None;
+        self.first.on_start(session_present, outbox);
+        self.second.on_start(session_present, outbox);
     }
 
-    fn on_start(&mut self, outbox: &mut dyn PublishOutbox) {
-        self.first.on_start(outbox);
-        self.second.on_start(outbox);
+    fn on_disconnect(&mut self) {
+        self.first.on_disconnect();
+        self.second.on_disconnect();
     }
 
     fn last_will(&self) -> Option<LastWill<'_>> {
         self.first.last_will().or_else(|| self.second.last_will())
     }
 
+    fn wants_all_messages(&self) -> bool {
+        self.first.wants_all_messages() || self.second.wants_all_messages()
+    }
+
+    fn module_count(&self) -> usize {
+        self.first.module_count() + self.second.module_count()
+    }
+
     fn needs_immediate_publish(&self) -> bool {
         self.first.needs_immediate_publish() || self.second.needs_immediate_publish()
     }
@@ -270,6 +535,149 @@ where
         self.first.on_publish(outbox);
         self.second.on_publish(outbox);
     }
+
+    fn on_publish_failed(&mut self, topic: &str, err: &MqttError<&dyn core::fmt::Debug>) {
+        self.first.on_publish_failed(topic, err);
+        self.second.on_publish_failed(topic, err);
+    }
+}
+
+/// A composite module that fans out to a fixed array of module references.
+///
+/// This avoids nesting `ModulePair`s when composing many modules, and since
+/// it only holds `&mut dyn MqttModule`, the modules themselves can live in
+/// `StaticCell`s. Like `ModulePair`, messages are only passed to modules whose
+/// registered filters match, and each module is ticked on its own schedule.
+///
+/// # Example
+///
+/// ```ignore
+/// let modules = MqttModuleList::new([&mut ha_module, &mut telemetry_module, &mut ota_module]);
+/// let mut runtime = MqttRuntime::new(client, modules, publisher_rx);
+/// ```
+pub struct MqttModuleList<'a, const N: usize> {
+    /// The composed modules, in dispatch order
+    pub modules: [&'a mut dyn MqttModule; N],
+    deadlines: [Option<Instant>; N],
+}
+
+impl<'a, const N: usize> MqttModuleList<'a, N> {
+    /// Create a new combined module from an array of modules.
+    pub fn new(modules: [&'a mut dyn MqttModule; N]) -> Self {
+        Self {
+            modules,
+            deadlines: [None; N],
+        }
+    }
+}
+
+impl<const N: usize> MqttModule for MqttModuleList<'_, N> {
+    fn register(&self, collector: &mut dyn TopicCollector) {
+        let mut index = collector.module_index();
+        for module in &self.modules {
+            collector.set_module_index(index);
+            module.register(collector);
+            index += module.module_count();
+        }
+    }
+
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        for module in &mut self.modules {
+            if wants_message(&**module, msg.topic) {
+                module.on_message(msg, outbox);
+            }
+        }
+    }
+
+    fn on_retained_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        for module in &mut self.modules {
+            if wants_message(&**module, msg.topic) {
+                module.on_retained_message(msg, outbox);
+            }
+        }
+    }
+
+    fn on_dispatch(
+        &mut self,
+        msg: &Publish<'_>,
+        outbox: &mut dyn PublishOutbox,
+        filters: &dyn ModuleFilters,
+        module_index: usize,
+    ) {
+        let mut index = module_index;
+        for module in &mut self.modules {
+            module.on_dispatch(msg, outbox, filters, index);
+            index += module.module_count();
+        }
+    }
+
+    fn on_tick(&mut self, outbox: &mut dyn PublishOutbox) -> Duration {
+        let now = Instant::now();
+        // Wake up for whichever module is due first
+        self.modules
+            .iter_mut()
+            .zip(self.deadlines.iter_mut())
+            .map(|(module, deadline)| tick_if_due(&mut **module, deadline, now, outbox))
+            .min()
+            .map_or(Duration::from_secs(60), |next| {
+                next.saturating_duration_since(now)
+            })
+    }
+
+    fn on_connect(&mut self, client_id: &str) {
+        for module in &mut self.modules {
+            module.on_connect(client_id);
+        }
+    }
+
+    fn on_start(&mut self, session_present: bool, outbox: &mut dyn PublishOutbox) {
+        // Every module gets its initial tick on the new connection
+        self.deadlines = [None; N];
+        for module in &mut self.modules {
+            module.on_start(session_present, outbox);
+        }
+    }
+
+    fn on_disconnect(&mut self) {
+        for module in &mut self.modules {
+            module.on_disconnect();
+        }
+    }
+
+    fn last_will(&self) -> Option<LastWill<'_>> {
+        self.modules.iter().find_map(|module| module.last_will())
+    }
+
+    fn wants_all_messages(&self) -> bool {
+        self.modules
+            .iter()
+            .any(|module| module.wants_all_messages())
+    }
+
+    fn module_count(&self) -> usize {
+        self.modules
+            .iter()
+            .map(|module| module.module_count())
+            .sum()
+    }
+
+    fn needs_immediate_publish(&self) -> bool {
+        self.modules
+            .iter()
+            .any(|module| module.needs_immediate_publish())
+    }
+
+    fn on_publish(&mut self, outbox: &mut dyn PublishOutbox) {
+        for module in &mut self.modules {
+            module.on_publish(outbox);
+        }
+    }
+
+    fn on_publish_failed(&mut self, topic: &str, err: &MqttError<&dyn core::fmt::Debug>) {
+        for module in &mut self.modules {
+            module.on_publish_failed(topic, err);
+        }
+    }
 }
 
 /// Blanket implementation for mutable references to trait objects.
@@ -280,22 +688,52 @@ impl<M: MqttModule + ?Sized> MqttModule for &mut M {
         (**self).register(collector)
     }
 
-    fn on_message(&mut self, msg: &Publish<'_>) {
-        (**self).on_message(msg)
+    fn on_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        (**self).on_message(msg, outbox)
+    }
+
+    fn on_retained_message(&mut self, msg: &Publish<'_>, outbox: &mut dyn PublishOutbox) {
+        (**self).on_retained_message(msg, outbox)
+    }
+
+    fn on_dispatch(
+        &mut self,
+        msg: &Publish<'_>,
+        outbox: &mut dyn PublishOutbox,
+        filters: &dyn ModuleFilters,
+        module_index: usize,
+    ) {
+        (**self).on_dispatch(msg, outbox, filters, module_index)
     }
 
     fn on_tick(&mut self, outbox: &mut dyn PublishOutbox) -> Duration {
         (**self).on_tick(outbox)
     }
 
-    fn on_start(&mut self, outbox: &mut dyn PublishOutbox) {
-        (**self).on_start(outbox)
+    fn on_connect(&mut self, client_id: &str) {
+        (**self).on_connect(client_id)
+    }
+
+    fn on_start(&mut self, session_present: bool, outbox: &mut dyn PublishOutbox) {
+        (**self).on_start(session_present, outbox)
+    }
+
+    fn on_disconnect(&mut self) {
+        (**self).on_disconnect()
     }
 
     fn last_will(&self) -> Option<LastWill<'_>> {
         (**self).last_will()
     }
 
+    fn wants_all_messages(&self) -> bool {
+        (**self).wants_all_messages()
+    }
+
+    fn module_count(&self) -> usize {
+        (**self).module_count()
+    }
+
     fn needs_immediate_publish(&self) -> bool {
         (**self).needs_immediate_publish()
     }
@@ -303,4 +741,124 @@ impl<M: MqttModule + ?Sized> MqttModule for &mut M {
     fn on_publish(&mut self, outbox: &mut dyn PublishOutbox) {
         (**self).on_publish(outbox)
     }
+
+    fn on_publish_failed(&mut self, topic: &str, err: &MqttError<&dyn core::fmt::Debug>) {
+        (**self).on_publish_failed(topic, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::publisher::BufferedOutbox;
+    use crate::test_util::MockClock;
+
+    /// Counts its ticks and asks to be ticked again after `interval`.
+    struct Periodic {
+        interval: Duration,
+        ticks: usize,
+    }
+
+    impl Periodic {
+        fn new(millis: u64) -> Self {
+            Self {
+                interval: Duration::from_millis(millis),
+                ticks: 0,
+            }
+        }
+    }
+
+    impl MqttModule for Periodic {
+        fn register(&self, _collector: &mut dyn TopicCollector) {}
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {}
+
+        fn on_tick(&mut self, _outbox: &mut dyn PublishOutbox) -> Duration {
+            self.ticks += 1;
+            self.interval
+        }
+    }
+
+    #[test]
+    fn composed_modules_are_ticked_on_their_own_schedule() {
+        let clock = MockClock::start();
+        let mut pair = ModulePair::new(Periodic::new(1000), Periodic::new(10_000));
+        let mut outbox = BufferedOutbox::<1, 8, 8>::new();
+
+        // Both modules get their first tick right away
+        assert_eq!(pair.on_tick(&mut outbox), Duration::from_secs(1));
+        assert_eq!((pair.first.ticks, pair.second.ticks), (1, 1));
+
+        // Before the fast module is due, nothing is ticked
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(pair.on_tick(&mut outbox), Duration::from_millis(600));
+        assert_eq!((pair.first.ticks, pair.second.ticks), (1, 1));
+        clock.advance(Duration::from_millis(600));
+
+        for _ in 0..100 {
+            assert_eq!(pair.on_tick(&mut outbox), Duration::from_secs(1));
+            clock.advance(Duration::from_secs(1));
+        }
+        // 100 more seconds: a tick a second, and one every ten
+        assert_eq!((pair.first.ticks, pair.second.ticks), (101, 11));
+    }
+
+    /// Counts the messages it receives on `filter`.
+    struct Listener {
+        filter: &'static str,
+        received: usize,
+    }
+
+    impl Listener {
+        fn new(filter: &'static str) -> Self {
+            Self {
+                filter,
+                received: 0,
+            }
+        }
+    }
+
+    impl MqttModule for Listener {
+        fn register(&self, collector: &mut dyn TopicCollector) {
+            collector.add(self.filter);
+        }
+
+        fn on_message(&mut self, _msg: &Publish<'_>, _outbox: &mut dyn PublishOutbox) {
+            self.received += 1;
+        }
+    }
+
+    #[test]
+    fn every_module_of_a_nested_pair_receives_a_matching_message() {
+        let mut modules = ModulePair::new(
+            Listener::new("dev/+"),
+            ModulePair::new(Listener::new("dev/#"), Listener::new("dev/light")),
+        );
+        let mut outbox = BufferedOutbox::<1, 8, 8>::new();
+        modules.on_message(
+            &Publish::new("dev/light", b"on", QoS::AtMostOnce),
+            &mut outbox,
+        );
+        modules.on_message(&Publish::new("other", b"on", QoS::AtMostOnce), &mut outbox);
+
+        assert_eq!(modules.first.received, 1);
+        assert_eq!(modules.second.first.received, 1);
+        assert_eq!(modules.second.second.received, 1);
+    }
+
+    #[test]
+    fn every_module_of_a_list_receives_a_matching_message() {
+        let mut a = Listener::new("dev/+");
+        let mut b = Listener::new("dev/#");
+        let mut c = Listener::new("dev/light");
+        let mut modules = MqttModuleList::new([&mut a, &mut b, &mut c]);
+        let mut outbox = BufferedOutbox::<1, 8, 8>::new();
+        modules.on_message(
+            &Publish::new("dev/light", b"on", QoS::AtMostOnce),
+            &mut outbox,
+        );
+        modules.on_message(&Publish::new("other", b"on", QoS::AtMostOnce), &mut outbox);
+
+        assert_eq!((a.received, b.received, c.received), (1, 1, 1));
+    }
 }
@@ -0,0 +1,90 @@
+//! # Connection Statistics
+//!
+//! Lightweight counters for field debugging, e.g. to tell whether a silent
+//! device fails to send or the broker drops its messages. Counting is enabled
+//! with the `stats` feature; without it the counters compile to nothing and
+//! `MqttClient::stats` is unavailable.
+
+use crate::packet::MqttPacket;
+
+/// A snapshot of the counters accumulated by an `MqttClient`.
+///
+/// Counters wrap on overflow rather than panic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MqttStats {
+    /// Bytes written to the transport.
+    pub bytes_sent: u64,
+    /// Bytes read from the transport.
+    pub bytes_received: u64,
+    /// PUBLISH packets sent, including resends after a reconnect.
+    pub publishes_sent: u32,
+    /// PUBLISH packets received from the broker.
+    pub messages_received: u32,
+    /// PINGREQ packets sent.
+    pub pings_sent: u32,
+    /// Successful connections after the first one.
+    pub reconnects: u32,
+}
+
+/// Accumulates `MqttStats` inside the client; a no-op without the `stats` feature.
+#[derive(Debug, Default)]
+pub(crate) struct StatsRecorder {
+    #[cfg(feature = "stats")]
+    stats: MqttStats,
+    #[cfg(feature = "stats")]
+    connected_before: bool,
+}
+
+#[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+impl StatsRecorder {
+    #[cfg(feature = "stats")]
+    pub(crate) fn snapshot(&self) -> MqttStats {
+        self.stats
+    }
+
+    pub(crate) fn bytes_sent(&mut self, len: usize) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.bytes_sent = self.stats.bytes_sent.wrapping_add(len as u64);
+        }
+    }
+
+    pub(crate) fn bytes_received(&mut self, len: usize) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.bytes_received = self.stats.bytes_received.wrapping_add(len as u64);
+        }
+    }
+
+    pub(crate) fn publish_sent(&mut self) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.publishes_sent = self.stats.publishes_sent.wrapping_add(1);
+        }
+    }
+
+    pub(crate) fn packet_received(&mut self, packet: &MqttPacket<'_>) {
+        #[cfg(feature = "stats")]
+        if let MqttPacket::Publish(_) = packet {
+            self.stats.messages_received = self.stats.messages_received.wrapping_add(1);
+        }
+    }
+
+    pub(crate) fn ping_sent(&mut self) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.pings_sent = self.stats.pings_sent.wrapping_add(1);
+        }
+    }
+
+    pub(crate) fn connected(&mut self) {
+        #[cfg(feature = "stats")]
+        {
+            if self.connected_before {
+                self.stats.reconnects = self.stats.reconnects.wrapping_add(1);
+            }
+            self.connected_before = true;
+        }
+    }
+}
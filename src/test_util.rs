@@ -0,0 +1,788 @@
+//! # Test Utilities
+//!
+//! Helpers for testing code built on the client without a broker or network.
+//! Enabled with the `test-util` feature.
+//!
+//! `MockTransport` plays the broker side of a connection from a script: bytes
+//! queued with `push_rx` (or the `push_*` packet helpers) are handed to the
+//! client on `recv`, and everything the client sends is captured for inspection.
+//!
+//! `LoopbackBroker` goes further and answers like a tiny broker, so a whole
+//! `MqttRuntime` with its modules can be driven end to end.
+//!
+//! `CountingObserver` counts the packets and errors reported to an
+//! `MqttObserver`.
+//!
+//! ```ignore
+//! use myrtio_mqtt::client::MqttVersion;
+//! use myrtio_mqtt::test_util::MockTransport;
+//!
+//! let mut transport = MockTransport::<256>::new(MqttVersion::V3);
+//! transport.push_connack(false, 0);
+//! transport.push_suback(1, &[0x00]);
+//!
+//! let mut client = MqttClient::<_, 4, 256>::new(transport, options);
+//! client.connect().await?;
+//! client.subscribe("device/cmd", QoS::AtMostOnce).await?;
+//!
+//! // The first byte the client sent is the CONNECT packet type
+//! assert_eq!(client.transport().sent()[0], 0x10);
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Debug;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::{Deque, String, Vec};
+
+use crate::client::MqttVersion;
+use crate::error::MqttError;
+use crate::observer::MqttObserver;
+use crate::packet::{DecodePacket, EncodePacket, MqttPacket, Publish, QoS, Subscribe, Unsubscribe};
+use crate::runtime::registry::MAX_TOPIC_LEN;
+use crate::transport::{MqttTransport, TransportError};
+use crate::util::{self, topic_matches};
+
+/// Errors returned by `MockTransport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MockError {
+    /// The client tried to receive, but no scripted bytes are left.
+    Exhausted,
+    /// The client sent more bytes than the capture buffer can hold.
+    SendOverflow,
+    /// The connection to the `LoopbackBroker` was closed.
+    Closed,
+}
+
+impl TransportError for MockError {}
+
+/// An in-memory `MqttTransport` that replays scripted broker responses and
+/// records what the client sends.
+///
+/// Both the receive queue and the send capture hold up to `N` bytes.
+pub struct MockTransport<const N: usize> {
+    rx: Deque<u8, N>,
+    tx: Vec<u8, N>,
+    flushes: usize,
+    version: MqttVersion,
+}
+
+impl<const N: usize> MockTransport<N> {
+    /// Creates an empty transport that encodes helper packets for `version`.
+    pub fn new(version: MqttVersion) -> Self {
+        Self {
+            rx: Deque::new(),
+            tx: Vec::new(),
+            flushes: 0,
+            version,
+        }
+    }
+
+    /// Queues raw bytes for the client to receive.
+    ///
+    /// Returns `false` (queueing nothing) if they don't fit.
+    pub fn push_rx(&mut self, bytes: &[u8]) -> bool {
+        if self.rx.capacity() - self.rx.len() < bytes.len() {
+            return false;
+        }
+        for &byte in bytes {
+            let _ = self.rx.push_back(byte);
+        }
+        true
+    }
+
+    /// Queues a CONNACK with the given session-present flag and reason code.
+    pub fn push_connack(&mut self, session_present: bool, reason_code: u8) -> bool {
+        let flags = u8::from(session_present);
+        match self.version {
+            MqttVersion::V3 => self.push_rx(&[0x20, 2, flags, reason_code]),
+            // No properties
+            MqttVersion::V5 => self.push_rx(&[0x20, 3, flags, reason_code, 0]),
+        }
+    }
+
+    /// Queues a SUBACK carrying one reason code per requested topic.
+    pub fn push_suback(&mut self, packet_id: u16, reason_codes: &[u8]) -> bool {
+        let v5 = self.version == MqttVersion::V5;
+        let remaining_len = 2 + usize::from(v5) + reason_codes.len();
+        // Keep the remaining length to a single byte
+        if remaining_len > 127 {
+            return false;
+        }
+
+        let mut packet: Vec<u8, 130> = Vec::new();
+        let [id_hi, id_lo] = packet_id.to_be_bytes();
+        let _ = packet.extend_from_slice(&[0x90, remaining_len as u8, id_hi, id_lo]);
+        if v5 {
+            // No properties
+            let _ = packet.push(0);
+        }
+        let _ = packet.extend_from_slice(reason_codes);
+        self.push_rx(&packet)
+    }
+
+    /// Queues an UNSUBACK. The reason codes are only sent in MQTT v5 mode,
+    /// since a v3.1.1 UNSUBACK has no payload.
+    pub fn push_unsuback(&mut self, packet_id: u16, reason_codes: &[u8]) -> bool {
+        let v5 = self.version == MqttVersion::V5;
+        let reason_codes = if v5 { reason_codes } else { &[] };
+        let remaining_len = 2 + usize::from(v5) + reason_codes.len();
+        // Keep the remaining length to a single byte
+        if remaining_len > 127 {
+            return false;
+        }
+
+        let mut packet: Vec<u8, 130> = Vec::new();
+        let [id_hi, id_lo] = packet_id.to_be_bytes();
+        let _ = packet.extend_from_slice(&[0xB0, remaining_len as u8, id_hi, id_lo]);
+        if v5 {
+            // No properties
+            let _ = packet.push(0);
+        }
+        let _ = packet.extend_from_slice(reason_codes);
+        self.push_rx(&packet)
+    }
+
+    /// Queues a successful PUBACK for `packet_id`.
+    pub fn push_puback(&mut self, packet_id: u16) -> bool {
+        let [id_hi, id_lo] = packet_id.to_be_bytes();
+        self.push_rx(&[0x40, 2, id_hi, id_lo])
+    }
+
+    /// Queues a PUBLISH without properties. `packet_id` is only sent for
+    /// QoS 1 and 2.
+    pub fn push_publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        packet_id: Option<u16>,
+    ) -> bool {
+        let publish = Publish {
+            packet_id,
+            ..Publish::new(topic, payload, qos)
+        };
+        let mut packet = [0u8; N];
+        match publish.encode(&mut packet, self.version) {
+            Ok(len) => self.push_rx(&packet[..len]),
+            Err(_) => false,
+        }
+    }
+
+    /// Queues a PINGRESP.
+    pub fn push_pingresp(&mut self) -> bool {
+        self.push_rx(&[0xD0, 0])
+    }
+
+    /// Returns the number of queued bytes the client hasn't received yet.
+    pub fn pending_rx(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Returns every byte the client has sent so far.
+    pub fn sent(&self) -> &[u8] {
+        &self.tx
+    }
+
+    /// Forgets the bytes captured so far.
+    pub fn clear_sent(&mut self) {
+        self.tx.clear();
+    }
+
+    /// Returns how many times the client ended a write batch with `flush`.
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+}
+
+impl<const N: usize> MqttTransport for MockTransport<N> {
+    type Error = MockError;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.tx
+            .extend_from_slice(buf)
+            .map_err(|_| MockError::SendOverflow)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.rx.is_empty() {
+            return Err(MockError::Exhausted);
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(byte) = self.rx.pop_front() else {
+                break;
+            };
+            buf[n] = byte;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.rx.is_empty() {
+            return Ok(0);
+        }
+        self.recv(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+/// Maximum number of filters a `LoopbackBroker` keeps subscribed.
+pub const MAX_LOOPBACK_SUBSCRIPTIONS: usize = 16;
+/// Maximum number of client publishes a `LoopbackBroker` records.
+pub const MAX_LOOPBACK_RECORDED: usize = 8;
+/// Maximum payload size of a publish recorded by a `LoopbackBroker`.
+pub const MAX_LOOPBACK_PAYLOAD_LEN: usize = 256;
+
+/// A publish the client sent to a `LoopbackBroker`.
+#[derive(Debug, Clone)]
+pub struct RecordedPublish {
+    pub topic: String<MAX_TOPIC_LEN>,
+    pub payload: Vec<u8, MAX_LOOPBACK_PAYLOAD_LEN>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// A minimal in-memory broker for end-to-end tests of a single client.
+///
+/// Connect the client through `transport()`. The broker answers:
+///
+/// - CONNECT with a successful CONNACK
+/// - SUBSCRIBE with a SUBACK granting the requested QoS (capped at 1)
+/// - UNSUBSCRIBE with an UNSUBACK, dropping the filters
+/// - PUBLISH with a PUBACK for QoS 1, then routes it back to the client if
+///   one of its subscriptions matches
+/// - PINGREQ with a PINGRESP
+///
+/// Tests keep a shared reference to the broker to inject publishes from
+/// "other clients" with `publish`, inspect what the client published with
+/// `take_published`, or simulate a lost link with `drop_connection`.
+///
+/// Receiving waits until the broker has something to deliver, so the client
+/// idles like it would against a real broker.
+///
+/// ```ignore
+/// let broker = LoopbackBroker::<1024>::new(MqttVersion::V3);
+/// let client = MqttClient::<_, 4, 512>::new(broker.transport(), options);
+/// let mut runtime = MqttRuntime::new(client, light_module, publisher_rx);
+///
+/// let script = async {
+///     // Let the runtime connect and subscribe, then send a command
+///     Timer::after_millis(10).await;
+///     broker.publish("device/light/cmd", b"ON");
+///     Timer::after_millis(10).await;
+///     assert_eq!(broker.take_published()[0].topic, "device/light/state");
+/// };
+/// select(runtime.run(), script).await;
+/// ```
+pub struct LoopbackBroker<const N: usize> {
+    state: Mutex<NoopRawMutex, RefCell<BrokerState<N>>>,
+}
+
+struct BrokerState<const N: usize> {
+    version: MqttVersion,
+    connected: bool,
+    /// Bytes sent by the client that don't form a complete packet yet.
+    inbound: Vec<u8, N>,
+    /// Bytes waiting to be received by the client.
+    outbound: Deque<u8, N>,
+    subscriptions: Vec<(String<MAX_TOPIC_LEN>, QoS), MAX_LOOPBACK_SUBSCRIPTIONS>,
+    published: Vec<RecordedPublish, MAX_LOOPBACK_RECORDED>,
+    next_packet_id: u16,
+    waker: WakerRegistration,
+}
+
+impl<const N: usize> LoopbackBroker<N> {
+    /// Creates a broker speaking the given protocol version.
+    pub fn new(version: MqttVersion) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(BrokerState {
+                version,
+                connected: false,
+                inbound: Vec::new(),
+                outbound: Deque::new(),
+                subscriptions: Vec::new(),
+                published: Vec::new(),
+                next_packet_id: 0,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Returns a transport connecting a client to this broker.
+    pub fn transport(&self) -> LoopbackTransport<'_, N> {
+        LoopbackTransport { broker: self }
+    }
+
+    /// Publishes a message as if it came from another client, delivering it
+    /// to the connected client if one of its subscriptions matches.
+    ///
+    /// Returns `true` if the message was delivered.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> bool {
+        self.with_state(|state| state.connected && state.route(topic, payload, QoS::AtMostOnce))
+    }
+
+    /// Returns `true` if a CONNECT was received and the connection is open.
+    pub fn is_connected(&self) -> bool {
+        self.with_state(|state| state.connected)
+    }
+
+    /// Returns `true` if one of the client's subscriptions matches `topic`.
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.with_state(|state| {
+            state
+                .subscriptions
+                .iter()
+                .any(|(filter, _)| topic_matches(filter, topic))
+        })
+    }
+
+    /// Returns the publishes received from the client since the last call.
+    pub fn take_published(&self) -> Vec<RecordedPublish, MAX_LOOPBACK_RECORDED> {
+        self.with_state(|state| core::mem::take(&mut state.published))
+    }
+
+    /// Closes the connection; the client's next `recv` or `send` fails with
+    /// `MockError::Closed`. Subscriptions are forgotten, as for a clean session.
+    pub fn drop_connection(&self) {
+        self.with_state(|state| {
+            state.connected = false;
+            state.inbound.clear();
+            state.outbound.clear();
+            state.subscriptions.clear();
+            state.waker.wake();
+        });
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut BrokerState<N>) -> R) -> R {
+        self.state.lock(|state| f(&mut state.borrow_mut()))
+    }
+}
+
+impl<const N: usize> BrokerState<N> {
+    /// Queues bytes for the client, dropping them if they don't fit.
+    fn deliver(&mut self, bytes: &[u8]) -> bool {
+        if self.outbound.capacity() - self.outbound.len() < bytes.len() {
+            return false;
+        }
+        for &byte in bytes {
+            let _ = self.outbound.push_back(byte);
+        }
+        self.waker.wake();
+        true
+    }
+
+    /// Delivers a publish to the client if a subscription matches `topic`.
+    fn route(&mut self, topic: &str, payload: &[u8], qos: QoS) -> bool {
+        let Some(granted) = self
+            .subscriptions
+            .iter()
+            .filter(|(filter, _)| topic_matches(filter, topic))
+            .map(|&(_, granted)| granted)
+            .reduce(|a, b| if a < b { b } else { a })
+        else {
+            return false;
+        };
+
+        let qos = if qos < granted { qos } else { granted };
+        let packet_id = (qos != QoS::AtMostOnce).then(|| {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            self.next_packet_id
+        });
+        let publish = Publish {
+            topic,
+            qos,
+            dup: false,
+            retain: false,
+            payload,
+            packet_id,
+            #[cfg(feature = "v5")]
+            properties: Vec::new(),
+        };
+
+        let mut buf = [0u8; N];
+        match publish.encode(&mut buf, self.version) {
+            Ok(len) => self.deliver(&buf[..len]),
+            Err(_) => false,
+        }
+    }
+
+    /// Answers every complete packet buffered in `inbound`.
+    fn process_inbound(&mut self) {
+        loop {
+            let mut cursor = 1;
+            let Ok(remaining_len) = util::read_variable_byte_integer(&mut cursor, &self.inbound)
+            else {
+                // Incomplete header
+                return;
+            };
+            let total_len = cursor + remaining_len;
+            if self.inbound.len() < total_len {
+                return;
+            }
+
+            let mut packet: Vec<u8, N> = Vec::new();
+            let _ = packet.extend_from_slice(&self.inbound[..total_len]);
+            self.inbound.copy_within(total_len.., 0);
+            self.inbound.truncate(self.inbound.len() - total_len);
+            self.handle_packet(&packet);
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) {
+        let v5 = self.version == MqttVersion::V5;
+        match packet[0] >> 4 {
+            // CONNECT
+            1 => {
+                self.connected = true;
+                if v5 {
+                    self.deliver(&[0x20, 3, 0, 0, 0]);
+                } else {
+                    self.deliver(&[0x20, 2, 0, 0]);
+                }
+            }
+            // PUBLISH
+            3 => {
+                let Ok(publish) = Publish::decode(packet, self.version) else {
+                    return;
+                };
+                if let Some([id_hi, id_lo]) = publish.packet_id.map(u16::to_be_bytes) {
+                    self.deliver(&[0x40, 2, id_hi, id_lo]);
+                }
+
+                let mut topic = String::new();
+                let mut payload = Vec::new();
+                if topic.push_str(publish.topic).is_ok()
+                    && payload.extend_from_slice(publish.payload).is_ok()
+                {
+                    let _ = self.published.push(RecordedPublish {
+                        topic,
+                        payload,
+                        qos: publish.qos,
+                        retain: publish.retain,
+                    });
+                }
+                self.route(publish.topic, publish.payload, publish.qos);
+            }
+            // SUBSCRIBE
+            8 => {
+                let Ok(subscribe) = Subscribe::decode(packet, self.version) else {
+                    return;
+                };
+                let mut suback: Vec<u8, 16> = Vec::new();
+                let [id_hi, id_lo] = subscribe.packet_id.to_be_bytes();
+                let remaining_len = 2 + usize::from(v5) + subscribe.topics.len();
+                let _ = suback.extend_from_slice(&[0x90, remaining_len as u8, id_hi, id_lo]);
+                if v5 {
+                    let _ = suback.push(0);
+                }
+                for &(filter, options) in &subscribe.topics {
+                    let qos = options.qos;
+                    let granted = if qos > QoS::AtLeastOnce {
+                        QoS::AtLeastOnce
+                    } else {
+                        qos
+                    };
+                    let mut owned = String::new();
+                    let accepted = owned.push_str(filter).is_ok()
+                        && self.subscriptions.push((owned, granted)).is_ok();
+                    let _ = suback.push(if accepted { granted as u8 } else { 0x80 });
+                }
+                self.deliver(&suback);
+            }
+            // UNSUBSCRIBE
+            10 => {
+                let Ok(unsubscribe) = Unsubscribe::decode(packet, self.version) else {
+                    return;
+                };
+                let mut unsuback: Vec<u8, 16> = Vec::new();
+                let [id_hi, id_lo] = unsubscribe.packet_id.to_be_bytes();
+                let remaining_len = if v5 { 3 + unsubscribe.topics.len() } else { 2 };
+                let _ = unsuback.extend_from_slice(&[0xB0, remaining_len as u8, id_hi, id_lo]);
+                if v5 {
+                    let _ = unsuback.push(0);
+                }
+                for &filter in &unsubscribe.topics {
+                    let before = self.subscriptions.len();
+                    self.subscriptions.retain(|(f, _)| f != filter);
+                    if v5 {
+                        // 0x11: No subscription existed
+                        let existed = self.subscriptions.len() < before;
+                        let _ = unsuback.push(if existed { 0x00 } else { 0x11 });
+                    }
+                }
+                self.deliver(&unsuback);
+            }
+            // PINGREQ
+            12 => {
+                self.deliver(&[0xD0, 0]);
+            }
+            // DISCONNECT
+            14 => {
+                self.connected = false;
+                self.subscriptions.clear();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The client side of a connection to a `LoopbackBroker`.
+pub struct LoopbackTransport<'b, const N: usize> {
+    broker: &'b LoopbackBroker<N>,
+}
+
+impl<const N: usize> MqttTransport for LoopbackTransport<'_, N> {
+    type Error = MockError;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.broker.with_state(|state| {
+            // A new connection starts with CONNECT
+            if !state.connected && buf.first().map(|b| b >> 4) != Some(1) {
+                return Err(MockError::Closed);
+            }
+            state
+                .inbound
+                .extend_from_slice(buf)
+                .map_err(|_| MockError::SendOverflow)?;
+            state.process_inbound();
+            Ok(())
+        })
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::poll_fn(|cx| {
+            self.broker.with_state(|state| {
+                if state.outbound.is_empty() {
+                    if !state.connected {
+                        return Poll::Ready(Err(MockError::Closed));
+                    }
+                    state.waker.register(cx.waker());
+                    return Poll::Pending;
+                }
+
+                let mut n = 0;
+                while n < buf.len() {
+                    let Some(byte) = state.outbound.pop_front() else {
+                        break;
+                    };
+                    buf[n] = byte;
+                    n += 1;
+                }
+                Poll::Ready(Ok(n))
+            })
+        })
+        .await
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let connected = self.broker.with_state(|state| state.connected);
+        if connected && self.broker.with_state(|state| state.outbound.is_empty()) {
+            return Ok(0);
+        }
+        self.recv(buf).await
+    }
+}
+
+/// An `MqttObserver` that counts what it is notified of.
+#[derive(Debug, Default)]
+pub struct CountingObserver {
+    sent: Cell<usize>,
+    received: Cell<usize>,
+    errors: Cell<usize>,
+}
+
+impl CountingObserver {
+    /// Creates an observer with all counts at zero.
+    pub const fn new() -> Self {
+        Self {
+            sent: Cell::new(0),
+            received: Cell::new(0),
+            errors: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of packets the client sent.
+    pub fn sent(&self) -> usize {
+        self.sent.get()
+    }
+
+    /// Returns the number of packets the client received.
+    pub fn received(&self) -> usize {
+        self.received.get()
+    }
+
+    /// Returns the number of failed client operations.
+    pub fn errors(&self) -> usize {
+        self.errors.get()
+    }
+}
+
+impl MqttObserver for CountingObserver {
+    fn on_packet_sent(&self, _packet: &MqttPacket<'_>) {
+        self.sent.set(self.sent.get() + 1);
+    }
+
+    fn on_packet_received(&self, _packet: &MqttPacket<'_>) {
+        self.received.set(self.received.get() + 1);
+    }
+
+    fn on_error(&self, _error: &MqttError<&dyn Debug>) {
+        self.errors.set(self.errors.get() + 1);
+    }
+}
+
+/// `embassy-time` driver for the unit tests, counting microseconds since the
+/// first call, or returning the time of the thread's `MockClock`.
+#[cfg(test)]
+mod time_driver {
+    extern crate std;
+
+    use core::cell::Cell;
+    use core::task::Waker;
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    struct StdDriver;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    std::thread_local! {
+        /// Time of the `MockClock` running on this thread, in microseconds.
+        pub(super) static MOCK_NOW: Cell<Option<u64>> = const { Cell::new(None) };
+    }
+
+    impl embassy_time_driver::Driver for StdDriver {
+        fn now(&self) -> u64 {
+            MOCK_NOW
+                .get()
+                .unwrap_or_else(|| START.get_or_init(Instant::now).elapsed().as_micros() as u64)
+        }
+
+        fn schedule_wake(&self, _at: u64, waker: &Waker) {
+            // Tests run under `block_on`, which keeps polling after a wake
+            waker.wake_by_ref();
+        }
+    }
+
+    embassy_time_driver::time_driver_impl!(static DRIVER: StdDriver = StdDriver);
+}
+
+/// A clock for unit tests that only moves when advanced, so durations can be
+/// checked exactly without sleeping.
+///
+/// While it exists, `Instant::now` on the creating thread returns its time;
+/// dropping it switches the thread back to the wall clock. A timer that
+/// isn't due yet stays pending until the clock is advanced past it.
+#[cfg(test)]
+pub(crate) struct MockClock {
+    /// Ties the clock to the thread whose time it sets.
+    _thread: core::marker::PhantomData<*const ()>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Stops the current thread's clock at the current time.
+    pub(crate) fn start() -> Self {
+        let now = embassy_time::Instant::now().as_micros();
+        time_driver::MOCK_NOW.set(Some(now));
+        Self {
+            _thread: core::marker::PhantomData,
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub(crate) fn advance(&self, duration: embassy_time::Duration) {
+        time_driver::MOCK_NOW.set(
+            time_driver::MOCK_NOW
+                .get()
+                .map(|now| now + duration.as_micros()),
+        );
+    }
+}
+
+#[cfg(test)]
+impl Drop for MockClock {
+    fn drop(&mut self) {
+        time_driver::MOCK_NOW.set(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MqttClient, MqttOptions};
+
+    #[test]
+    fn mock_transport_scripts_broker_replies() {
+        let mut transport = MockTransport::<256>::new(MqttVersion::V3);
+        assert!(transport.push_connack(false, 0));
+        assert!(transport.push_suback(1, &[0x01]));
+        assert!(transport.push_puback(2));
+
+        let mut client = MqttClient::<_, 4, 256>::new(transport, MqttOptions::new("cid"));
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            client.subscribe("a/b", QoS::AtLeastOnce).await.unwrap();
+            client.publish("x", b"1", QoS::AtLeastOnce).await.unwrap();
+        });
+
+        let sent = client.transport().sent();
+        assert_eq!(sent[0], 0x10);
+        // SUBSCRIBE id 1, then PUBLISH QoS 1 id 2, after the CONNECT
+        let connect_len = 2 + usize::from(sent[1]);
+        assert_eq!(
+            &sent[connect_len..connect_len + 10],
+            &[0x82, 8, 0, 1, 0, 3, b'a', b'/', b'b', 1]
+        );
+        assert_eq!(
+            &sent[connect_len + 10..],
+            &[0x32, 6, 0, 1, b'x', 0, 2, b'1']
+        );
+        assert_eq!(client.transport().pending_rx(), 0);
+    }
+
+    #[test]
+    fn mock_transport_reports_exhausted_script() {
+        let transport = MockTransport::<64>::new(MqttVersion::V3);
+        let mut client = MqttClient::<_, 4, 64>::new(transport, MqttOptions::new("cid"));
+        let err = embassy_futures::block_on(client.connect()).unwrap_err();
+        assert!(
+            matches!(err, MqttError::Transport(MockError::Exhausted)),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn counting_observer_counts_packets_and_errors() {
+        let observer = CountingObserver::new();
+        let mut transport = MockTransport::<256>::new(MqttVersion::V3);
+        assert!(transport.push_connack(false, 0));
+        assert!(transport.push_puback(1));
+        let mut client = MqttClient::<_, 4, 256>::new(transport, MqttOptions::new("cid"));
+        client.set_observer(&observer);
+
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            assert_eq!((observer.sent(), observer.received()), (1, 1));
+
+            client.publish("x", b"1", QoS::AtLeastOnce).await.unwrap();
+            assert_eq!((observer.sent(), observer.received()), (2, 2));
+            assert_eq!(observer.errors(), 0);
+
+            // No SUBACK is scripted
+            assert!(client.subscribe("a/b", QoS::AtMostOnce).await.is_err());
+        });
+        assert_eq!(observer.errors(), 1);
+    }
+}
@@ -6,11 +6,31 @@
 //!
 //! With the Rust 2024 Edition, this trait uses native `async fn`, removing the
 //! need for the `#[async_trait]` macro.
+//!
+//! ## TLS
+//!
+//! With the `tls` feature, `TlsTransport` carries MQTT over an
+//! [`embedded-tls`](https://crates.io/crates/embedded-tls) session on an
+//! `embassy-net` TCP socket, for brokers that require TLS (usually on port
+//! 8883). See `examples/tls_client.rs`.
 
 use crate::error::MqttError;
-use embassy_net::tcp::{Error as TcpError, TcpSocket};
+use embassy_net::tcp::TcpSocket;
 use embassy_time::{Duration, Timer};
-use embedded_io_async::Write;
+use embedded_io_async::{Read, Write};
+#[cfg(feature = "tls")]
+use embedded_tls::{
+    Aes128GcmSha256, TlsCipherSuite, TlsConfig, TlsConnection, TlsContext, TlsVerifier,
+};
+#[cfg(feature = "tls")]
+use rand_core::{CryptoRng, RngCore};
+
+/// The `embassy-net` TCP error, returned by `MqttError::as_tcp_error`.
+pub use embassy_net::tcp::Error as TcpError;
+
+/// The `embedded-tls` error, returned by a `TlsTransport`.
+#[cfg(feature = "tls")]
+pub use embedded_tls::TlsError;
 
 /// A placeholder error type used in contexts where the actual transport error is not known,
 /// such as in the `EncodePacket` trait.
@@ -31,92 +51,1073 @@ pub trait MqttTransport {
     ///
     /// Returns the number of bytes read.
     async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Receives data that is already available, without waiting for more.
+    ///
+    /// Returns `Ok(0)` if nothing is buffered. Used by
+    /// `MqttClient::run_until_idle`. The default calls `recv`, so it waits for
+    /// data on transports that don't override it.
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.recv(buf).await
+    }
+
+    /// Starts a write batch: until the next `flush`, `send` may buffer data
+    /// instead of pushing it to the network, so several small packets leave
+    /// in fewer segments.
+    ///
+    /// Transports that batch must still flush pending data before `recv`
+    /// waits for a reply. The default does nothing.
+    fn begin_batch(&mut self) {}
+
+    /// Ends the current write batch and pushes all buffered data out.
+    ///
+    /// The default does nothing, since `send` is expected to flush by itself.
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 // Allow the placeholder to be treated as a transport error for generic contexts.
 impl TransportError for ErrorPlaceHolder {}
 
 /// A marker trait for transport-related errors.
-pub trait TransportError: core::fmt::Debug {}
+pub trait TransportError: core::fmt::Debug {
+    /// Returns `true` if a read gave up waiting for data while the connection
+    /// is still open, such as the read timeout of a `StreamTransport`.
+    ///
+    /// `MqttClient::poll` treats such a read as having received nothing.
+    fn is_timeout(&self) -> bool {
+        false
+    }
+}
 
-// Implement TransportError for MqttError so TcpTransport works with client methods
-impl<T: core::fmt::Debug> TransportError for MqttError<T> {}
+// Implement TransportError for MqttError so transports may report their own
+// timeouts as `MqttError::Timeout`
+impl<T: core::fmt::Debug> TransportError for MqttError<T> {
+    fn is_timeout(&self) -> bool {
+        matches!(self, MqttError::Timeout)
+    }
+}
+
+/// Error of the byte stream transports: `StreamTransport` (and so
+/// `TcpTransport` and `TlsTransport`), `UartTransport` and `StdTcpTransport`.
+///
+/// A client over one of them fails with `MqttError<StreamError<E>>`, where
+/// `E` is the error of the stream itself.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StreamError<E> {
+    /// No data arrived within the transport's read timeout.
+    Timeout,
+    /// The peer closed the connection.
+    Closed,
+    /// The remaining length of a packet read by a `UartTransport` is longer
+    /// than four bytes.
+    Malformed,
+    /// A packet read by a `UartTransport` doesn't fit the receive buffer.
+    BufferTooSmall,
+    /// The stream failed.
+    Io(E),
+}
+
+impl<E: core::fmt::Debug> TransportError for StreamError<E> {
+    fn is_timeout(&self) -> bool {
+        matches!(self, StreamError::Timeout)
+    }
+}
 
 // Implement TransportError for embassy_net tcp error
 impl TransportError for TcpError {}
 
+/// Transport implementation over any reliable `embedded-io-async` byte stream.
+///
+/// Each `recv` performs a single read on the stream, bounded by the configured
+/// timeout if there is one. This works with any network stack exposing `Read + Write` sockets,
+/// as well as with in-memory streams.
+///
+/// Every `send` is flushed unless a batch was started with `begin_batch`; then
+/// the stream is flushed once by `flush`, or before the next read.
+pub struct StreamTransport<S> {
+    stream: S,
+    timeout: Option<Duration>,
+    batching: bool,
+    /// Set when data was written but not flushed yet.
+    unflushed: bool,
+}
+
 /// TCP transport implementation using `embassy-net`.
-pub struct TcpTransport<'a> {
-    socket: TcpSocket<'a>,
-    timeout: Duration,
-}
-
-impl<'a> TcpTransport<'a> {
-    /// Creates a new `TcpTransport` with the given socket and timeout.
-    pub fn new(socket: TcpSocket<'a>, timeout: Duration) -> Self {
-        Self { socket, timeout }
-    }
-
-    /// A helper function to perform a read with a timeout.
-    async fn read_with_timeout(
-        &mut self,
-        buf: &mut [u8],
-    ) -> Result<usize, MqttError<embassy_net::tcp::Error>> {
-        // Use `select` to race the read operation against a timer.
-        let read_fut = self.socket.read(buf);
-        let timer = Timer::after(self.timeout);
-
-        match futures::future::select(core::pin::pin!(read_fut), core::pin::pin!(timer)).await {
-            futures::future::Either::Left((Ok(n), _)) => {
-                #[cfg(feature = "esp32-log")]
-                esp_println::println!("TCP read: {} bytes", n);
-
-                if n == 0 {
-                    // If the peer closes the connection, read returns 0.
-                    #[cfg(feature = "esp32-log")]
-                    esp_println::println!("TCP connection closed by peer!");
-
-                    Err(MqttError::Protocol(
-                        super::error::ProtocolError::ConnectionClosed,
-                    ))
-                } else {
-                    Ok(n)
+///
+/// A client over it fails with `MqttError<StreamError<TcpError>>`. Use
+/// `MqttError::as_tcp_error` to get at the socket error:
+///
+/// ```ignore
+/// match client.publish("state", b"on", QoS::AtLeastOnce).await {
+///     Err(e) if e.as_tcp_error() == Some(&TcpError::ConnectionReset) => reconnect(),
+///     Err(MqttError::Timeout | MqttError::Transport(StreamError::Timeout)) => retry(),
+///     Err(e) => return Err(e),
+///     Ok(()) => {}
+/// }
+/// ```
+pub type TcpTransport<'a> = StreamTransport<TcpSocket<'a>>;
+
+impl<S: Read + Write> StreamTransport<S> {
+    /// Creates a new `StreamTransport` with the given stream and read timeout.
+    ///
+    /// Pass `None` to wait for data indefinitely, e.g. for an idle subscriber
+    /// whose keep-alive is longer than any sensible read timeout. The client's
+    /// keep-alive still detects a dead connection then.
+    pub fn new(stream: S, timeout: impl Into<Option<Duration>>) -> Self {
+        Self {
+            stream,
+            timeout: timeout.into(),
+            batching: false,
+            unflushed: false,
+        }
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Flushes the stream if data was written since the last flush.
+    async fn flush_pending(&mut self) -> Result<(), StreamError<S::Error>> {
+        if self.unflushed {
+            self.stream.flush().await.map_err(StreamError::Io)?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+
+    /// A helper function to perform a read with the configured timeout.
+    async fn read_with_timeout(&mut self, buf: &mut [u8]) -> Result<usize, StreamError<S::Error>> {
+        let result = match self.timeout {
+            Some(timeout) => {
+                // Use `select` to race the read operation against a timer.
+                let read_fut = self.stream.read(buf);
+                let timer = Timer::after(timeout);
+
+                match futures::future::select(core::pin::pin!(read_fut), core::pin::pin!(timer))
+                    .await
+                {
+                    futures::future::Either::Left((result, _)) => result,
+                    futures::future::Either::Right(((), _)) => return Err(StreamError::Timeout),
                 }
             }
-            futures::future::Either::Left((Err(e), _)) => {
-                #[cfg(feature = "esp32-log")]
-                esp_println::println!("TCP read error: {:?}", e);
+            None => self.stream.read(buf).await,
+        };
+
+        match result {
+            // If the peer closes the connection, read returns 0.
+            Ok(0) => Err(StreamError::Closed),
+            Ok(n) => Ok(n),
+            Err(e) => Err(StreamError::Io(e)),
+        }
+    }
+}
+
+impl<S: Read + Write> MqttTransport for StreamTransport<S> {
+    type Error = StreamError<S::Error>;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.stream.write_all(buf).await.map_err(StreamError::Io)?;
+        self.unflushed = true;
+        if self.batching {
+            return Ok(());
+        }
+
+        // Flush to ensure data is actually sent to the network
+        self.flush_pending().await
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        // A reply can't arrive for a request that is still sitting in a buffer
+        self.flush_pending().await?;
+        self.read_with_timeout(buf).await
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.flush_pending().await?;
+
+        // Poll the read once; a read that isn't ready is dropped, as when it
+        // loses against the timeout
+        let mut read_fut = core::pin::pin!(self.stream.read(buf));
+        let result = core::future::poll_fn(|cx| match read_fut.as_mut().poll(cx) {
+            core::task::Poll::Ready(result) => core::task::Poll::Ready(Some(result)),
+            core::task::Poll::Pending => core::task::Poll::Ready(None),
+        })
+        .await;
+
+        match result {
+            None => Ok(0),
+            Some(Ok(0)) => Err(StreamError::Closed),
+            Some(Ok(n)) => Ok(n),
+            Some(Err(e)) => Err(StreamError::Io(e)),
+        }
+    }
+
+    fn begin_batch(&mut self) {
+        self.batching = true;
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.batching = false;
+        self.flush_pending().await
+    }
+}
+
+/// TLS transport over an `embassy-net` TCP socket, using `embedded-tls`.
+///
+/// `connect` performs the TLS 1.3 handshake over a connected socket, with the
+/// server name, CA and client certificate or pre-shared key of an
+/// `embedded-tls` `TlsConfig`, and verifies the server with the verifier `V`,
+/// e.g. `NoVerify` for a PSK setup. Afterwards the transport works like a
+/// `TcpTransport`, encrypting what the client sends.
+///
+/// Each record buffer holds one TLS record. Brokers send records of up to
+/// 16640 bytes unless a smaller Max Fragment Length is negotiated in the
+/// `TlsConfig`, so size the read buffer for that. The write buffer only needs
+/// to fit the largest packet the client sends plus the record overhead.
+///
+/// Handshake failures and errors of the session, such as a record that
+/// doesn't decrypt, are reported as `StreamError::Io(TlsError)`.
+///
+/// ```ignore
+/// let config = TlsConfig::new()
+///     .with_server_name("broker.example.com")
+///     .with_ca(Certificate::X509(CA_DER));
+/// let transport = TlsTransport::connect::<NoVerify, _>(
+///     socket, &mut read_buf, &mut write_buf, &config, &mut rng, Duration::from_secs(30),
+/// )
+/// .await?;
+/// let mut client = MqttClient::<_, 4, 1024>::new(transport, options);
+/// ```
+#[cfg(feature = "tls")]
+pub struct TlsTransport<'a, S = TcpSocket<'a>, C = Aes128GcmSha256>
+where
+    S: Read + Write + 'a,
+    C: TlsCipherSuite + 'static,
+{
+    inner: StreamTransport<TlsConnection<'a, S, C>>,
+}
+
+#[cfg(feature = "tls")]
+impl<'a, S, C> TlsTransport<'a, S, C>
+where
+    S: Read + Write + 'a,
+    C: TlsCipherSuite + 'static,
+{
+    /// Performs the TLS handshake over `socket` and returns the transport,
+    /// with `timeout` as the read timeout of the established session.
+    pub async fn connect<V, R>(
+        socket: S,
+        record_read_buf: &'a mut [u8],
+        record_write_buf: &'a mut [u8],
+        config: &TlsConfig<'_, C>,
+        rng: &mut R,
+        timeout: impl Into<Option<Duration>>,
+    ) -> Result<Self, StreamError<TlsError>>
+    where
+        V: for<'v> TlsVerifier<'v, C>,
+        R: CryptoRng + RngCore,
+    {
+        let mut tls = TlsConnection::new(socket, record_read_buf, record_write_buf);
+        tls.open::<R, V>(TlsContext::new(config, rng))
+            .await
+            .map_err(StreamError::Io)?;
+        Ok(Self {
+            inner: StreamTransport::new(tls, timeout),
+        })
+    }
 
-                Err(MqttError::Transport(e))
+    /// Returns the underlying TLS session, e.g. to close it.
+    pub fn into_inner(self) -> TlsConnection<'a, S, C> {
+        self.inner.into_inner()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<'a, S, C> MqttTransport for TlsTransport<'a, S, C>
+where
+    S: Read + Write + 'a,
+    C: TlsCipherSuite + 'static,
+{
+    type Error = StreamError<TlsError>;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.inner.send(buf).await
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.inner.recv(buf).await
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.inner.try_recv(buf).await
+    }
+
+    fn begin_batch(&mut self) {
+        self.inner.begin_batch();
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+}
+
+/// UART transport implementation over any `embedded-io-async` serial driver.
+///
+/// UART is a raw byte stream without message boundaries, so a single driver
+/// read may return part of a packet or the tail of one and the head of the
+/// next. This transport uses the MQTT fixed header framing (packet type byte
+/// followed by the variable-length remaining length) to read exactly one
+/// complete packet per call.
+///
+/// The timeout only bounds the wait for a packet to start. Once its first
+/// byte arrived, the rest of the packet is read without a timeout: giving up
+/// halfway would drop the bytes read so far and leave the stream in the
+/// middle of a packet.
+pub struct UartTransport<U> {
+    uart: U,
+    timeout: Option<Duration>,
+}
+
+impl<U: Read + Write> UartTransport<U> {
+    /// Creates a new `UartTransport` with the given serial driver and read
+    /// timeout, or `None` to wait for a packet indefinitely.
+    pub fn new(uart: U, timeout: impl Into<Option<Duration>>) -> Self {
+        Self {
+            uart,
+            timeout: timeout.into(),
+        }
+    }
+
+    /// Returns the underlying serial driver.
+    pub fn into_inner(self) -> U {
+        self.uart
+    }
+
+    /// Fills `buf` completely, treating a zero-length read as a closed link.
+    async fn read_exact(uart: &mut U, buf: &mut [u8]) -> Result<(), StreamError<U::Error>> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = uart
+                .read(&mut buf[filled..])
+                .await
+                .map_err(StreamError::Io)?;
+            if n == 0 {
+                return Err(StreamError::Closed);
             }
-            futures::future::Either::Right(((), _)) => {
-                #[cfg(feature = "esp32-log")]
-                esp_println::println!("TCP read timeout!");
+            filled += n;
+        }
+        Ok(())
+    }
 
-                Err(MqttError::Timeout)
+    /// Reads the rest of an MQTT packet whose first byte is in `buf[0]` and
+    /// returns its total length.
+    async fn read_packet(uart: &mut U, buf: &mut [u8]) -> Result<usize, StreamError<U::Error>> {
+        // The remaining length is at most 4 bytes, 7 bits each.
+        let mut remaining_len = 0usize;
+        let mut header_len = 1;
+        loop {
+            if header_len > 4 {
+                return Err(StreamError::Malformed);
+            }
+            let byte = buf.get_mut(header_len).ok_or(StreamError::BufferTooSmall)?;
+            Self::read_exact(uart, core::slice::from_mut(byte)).await?;
+            let byte = *byte;
+            remaining_len |= usize::from(byte & 0x7F) << (7 * (header_len - 1));
+            header_len += 1;
+            if byte & 0x80 == 0 {
+                break;
             }
         }
+
+        let total_len = header_len + remaining_len;
+        let body = buf
+            .get_mut(header_len..total_len)
+            .ok_or(StreamError::BufferTooSmall)?;
+        Self::read_exact(uart, body).await?;
+        Ok(total_len)
     }
 }
 
-impl<'a> MqttTransport for TcpTransport<'a> {
-    type Error = MqttError<embassy_net::tcp::Error>;
+impl<U: Read + Write> MqttTransport for UartTransport<U> {
+    type Error = StreamError<U::Error>;
 
     async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
-        #[cfg(feature = "esp32-log")]
-        esp_println::println!("TCP TX ({} bytes): {:02X?}", buf.len(), buf);
+        self.uart.write_all(buf).await.map_err(StreamError::Io)?;
+        self.uart.flush().await.map_err(StreamError::Io)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.len() < 2 {
+            return Err(StreamError::BufferTooSmall);
+        }
+        let first_fut = Self::read_exact(&mut self.uart, &mut buf[..1]);
+        match self.timeout {
+            Some(timeout) => {
+                let timer = Timer::after(timeout);
+                match futures::future::select(core::pin::pin!(first_fut), core::pin::pin!(timer))
+                    .await
+                {
+                    futures::future::Either::Left((result, _)) => result?,
+                    futures::future::Either::Right(((), _)) => return Err(StreamError::Timeout),
+                }
+            }
+            None => first_fut.await?,
+        }
+        Self::read_packet(&mut self.uart, buf).await
+    }
+}
 
-        self.socket.write_all(buf).await.map_err(|e| {
-            #[cfg(feature = "esp32-log")]
-            esp_println::println!("TCP write error: {:?}", e);
-            MqttError::Transport(e)
-        })?;
+/// Marks the start of a `FramedTransport` frame.
+const FRAME_SYNC: [u8; 2] = [0xA5, 0x5A];
 
-        // Flush to ensure data is actually sent to the network
-        self.socket.flush().await.map_err(MqttError::Transport)
+/// Bytes a `FramedTransport` frame adds around its payload: the sync marker,
+/// the payload length and the CRC.
+pub const FRAME_OVERHEAD: usize = FRAME_SYNC.len() + 2 + 2;
+
+/// Transport adding length and CRC framing for links that may corrupt bytes,
+/// such as UART or RS-485.
+///
+/// Each `send` is wrapped into frames of the form `A5 5A len[2] data crc[2]`,
+/// with a big-endian length and a CRC-16/CCITT-FALSE over the length and data.
+/// Received frames are checked before their data is passed on, and a corrupt
+/// one is dropped whole: the transport searches for the next sync marker and
+/// carries on. The client sees only the data and needs no changes, but the
+/// other end of the link must frame its data the same way.
+///
+/// Dropping a frame only keeps the MQTT packet stream in sync when every
+/// packet fits a single frame, i.e. is at most `BUF - FRAME_OVERHEAD` bytes.
+/// Losing one frame of a packet split across several corrupts the packets
+/// that follow, as on an unframed link.
+///
+/// `BUF` is the size of the receive and the send buffer, and bounds the
+/// frames accepted. Sends are split into frames that fit the same `BUF`, each
+/// passed to the wrapped transport in a single `send`, so both ends should use
+/// equal sizes. The wrapped transport must pass bytes through unchanged, e.g. a
+/// `StreamTransport` over the serial driver; `UartTransport` expects plain
+/// MQTT packets and doesn't fit.
+///
+/// ```ignore
+/// let link = StreamTransport::new(uart, Duration::from_secs(5));
+/// let transport = FramedTransport::<_, 512>::new(link);
+/// let mut client = MqttClient::<_, 4, 512>::new(transport, options);
+/// ```
+pub struct FramedTransport<T, const BUF: usize> {
+    inner: T,
+    rx: [u8; BUF],
+    /// Frame being sent.
+    tx: [u8; BUF],
+    /// Number of bytes buffered in `rx`.
+    len: usize,
+    /// Data of a checked frame at the start of `rx` not passed on yet, as a
+    /// range of `rx`.
+    pending: Option<(usize, usize)>,
+    rejected: usize,
+}
+
+impl<T: MqttTransport, const BUF: usize> FramedTransport<T, BUF> {
+    /// Creates a new `FramedTransport` over the given transport.
+    pub fn new(inner: T) -> Self {
+        const { assert!(BUF > FRAME_OVERHEAD, "BUF can't hold a frame") };
+        Self {
+            inner,
+            rx: [0; BUF],
+            tx: [0; BUF],
+            len: 0,
+            pending: None,
+            rejected: 0,
+        }
+    }
+
+    /// Returns a reference to the wrapped transport.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the wrapped transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns how many received frames were dropped because their length or
+    /// CRC didn't check out.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// Largest data length of a single frame.
+    fn max_frame_data() -> usize {
+        (BUF - FRAME_OVERHEAD).min(usize::from(u16::MAX))
+    }
+
+    /// Copies data of the buffered frame into `buf`, checking the next frame
+    /// once the current one is used up. Returns 0 if no complete frame is
+    /// buffered.
+    fn take_data(&mut self, buf: &mut [u8]) -> usize {
+        if self.pending.is_none() {
+            self.pending = self.next_frame();
+        }
+        let Some((start, end)) = self.pending else {
+            return 0;
+        };
+        let n = buf.len().min(end - start);
+        buf[..n].copy_from_slice(&self.rx[start..start + n]);
+        if start + n < end {
+            self.pending = Some((start + n, end));
+        } else {
+            self.pending = None;
+            self.consume(end + 2);
+        }
+        n
+    }
+
+    /// Finds the first valid frame in the buffered bytes, dropping garbage and
+    /// corrupt frames before it, and returns the range of its data.
+    fn next_frame(&mut self) -> Option<(usize, usize)> {
+        loop {
+            // Resynchronize on the first sync marker
+            let start = self.rx[..self.len]
+                .windows(FRAME_SYNC.len())
+                .position(|window| window == FRAME_SYNC)
+                .unwrap_or(self.len.saturating_sub(FRAME_SYNC.len() - 1));
+            self.consume(start);
+            if self.len < 4 {
+                return None;
+            }
+
+            let data_len = usize::from(u16::from_be_bytes([self.rx[2], self.rx[3]]));
+            if data_len == 0 || data_len > Self::max_frame_data() {
+                self.reject();
+                continue;
+            }
+            let end = 4 + data_len;
+            if self.len < end + 2 {
+                return None;
+            }
+            let crc = u16::from_be_bytes([self.rx[end], self.rx[end + 1]]);
+            if crc16(&self.rx[2..end]) != crc {
+                self.reject();
+                continue;
+            }
+            return Some((4, end));
+        }
+    }
+
+    /// Drops a frame that didn't check out by skipping its sync marker, so
+    /// the search restarts right after it.
+    fn reject(&mut self) {
+        log_warn!("framed: dropping corrupt frame");
+        self.rejected += 1;
+        self.consume(1);
+    }
+
+    /// Removes the first `n` buffered bytes.
+    fn consume(&mut self, n: usize) {
+        self.rx.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+}
+
+impl<T: MqttTransport, const BUF: usize> MqttTransport for FramedTransport<T, BUF> {
+    type Error = T::Error;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        for chunk in buf.chunks(Self::max_frame_data()) {
+            // Can't truncate, `max_frame_data` fits a `u16`
+            let len = (chunk.len() as u16).to_be_bytes();
+            let end = 4 + chunk.len();
+            self.tx[..2].copy_from_slice(&FRAME_SYNC);
+            self.tx[2..4].copy_from_slice(&len);
+            self.tx[4..end].copy_from_slice(chunk);
+            let crc = crc16(&self.tx[2..end]);
+            self.tx[end..end + 2].copy_from_slice(&crc.to_be_bytes());
+            // One send per frame, so a `StreamTransport` flushes it once
+            self.inner.send(&self.tx[..end + 2]).await?;
+        }
+        Ok(())
     }
 
     async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        self.read_with_timeout(buf).await
+        loop {
+            let n = self.take_data(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            let n = self.inner.recv(&mut self.rx[self.len..]).await?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.len += n;
+        }
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            let n = self.take_data(buf);
+            if n > 0 {
+                return Ok(n);
+            }
+            let n = self.inner.try_recv(&mut self.rx[self.len..]).await?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.len += n;
+        }
+    }
+
+    fn begin_batch(&mut self) {
+        self.inner.begin_batch();
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF.
+struct Crc16(u16);
+
+impl Crc16 {
+    fn new() -> Self {
+        Self(0xFFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= u16::from(byte) << 8;
+            for _ in 0..8 {
+                self.0 = if self.0 & 0x8000 != 0 {
+                    (self.0 << 1) ^ 0x1021
+                } else {
+                    self.0 << 1
+                };
+            }
+        }
+    }
+
+    fn finish(self) -> u16 {
+        self.0
+    }
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = Crc16::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Transport over a `std::net::TcpStream`, for running the client on a host,
+/// e.g. integration tests against a local broker.
+///
+/// The stream is switched to non-blocking mode: a read that finds no data
+/// stays pending, so the client can race it against its keep-alive timer.
+/// The pending read wakes itself right away, so the executor keeps polling
+/// it, but any executor works, `embassy_futures::block_on` included.
+///
+/// ```ignore
+/// let transport = StdTcpTransport::connect("localhost:1883")?;
+/// let mut client = MqttClient::<_, 4, 1024>::new(transport, MqttOptions::new("host-test"));
+/// embassy_futures::block_on(client.connect())?;
+/// ```
+#[cfg(feature = "std")]
+pub struct StdTcpTransport {
+    stream: std::net::TcpStream,
+}
+
+#[cfg(feature = "std")]
+impl StdTcpTransport {
+    /// Connects to `addr`, e.g. `"localhost:1883"`.
+    pub fn connect(addr: impl std::net::ToSocketAddrs) -> std::io::Result<Self> {
+        Self::new(std::net::TcpStream::connect(addr)?)
+    }
+
+    /// Wraps an established stream, switching it to non-blocking mode.
+    pub fn new(stream: std::net::TcpStream) -> std::io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        // Packets are small and sent one at a time
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> std::net::TcpStream {
+        self.stream
+    }
+
+    /// Reads once, returning `None` if no data is available yet.
+    fn read_ready(&mut self, buf: &mut [u8]) -> Option<Result<usize, StreamError<std::io::Error>>> {
+        use std::io::{ErrorKind, Read as _};
+
+        match self.stream.read(buf) {
+            // The broker closed the connection
+            Ok(0) if !buf.is_empty() => Some(Err(StreamError::Closed)),
+            Ok(n) => Some(Ok(n)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) if e.kind() == ErrorKind::Interrupted => None,
+            Err(e) => Some(Err(StreamError::Io(e))),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl MqttTransport for StdTcpTransport {
+    type Error = StreamError<std::io::Error>;
+
+    async fn send(&mut self, mut buf: &[u8]) -> Result<(), Self::Error> {
+        use std::io::{ErrorKind, Write as _};
+
+        core::future::poll_fn(|cx| {
+            while !buf.is_empty() {
+                match self.stream.write(buf) {
+                    Ok(0) => {
+                        return core::task::Poll::Ready(Err(StreamError::Closed));
+                    }
+                    Ok(n) => buf = &buf[n..],
+                    Err(e)
+                        if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) =>
+                    {
+                        cx.waker().wake_by_ref();
+                        return core::task::Poll::Pending;
+                    }
+                    Err(e) => return core::task::Poll::Ready(Err(StreamError::Io(e))),
+                }
+            }
+            core::task::Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::poll_fn(|cx| match self.read_ready(buf) {
+            Some(result) => core::task::Poll::Ready(result),
+            None => {
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn try_recv(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.read_ready(buf).unwrap_or(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Deque;
+
+    use super::*;
+
+    /// An in-memory byte stream that hands out at most `chunk` bytes per
+    /// read. Written bytes are queued for reading when `loopback` is set.
+    struct Pipe {
+        rx: Deque<u8, 256>,
+        chunk: usize,
+        loopback: bool,
+        writes: usize,
+    }
+
+    impl Pipe {
+        fn new(chunk: usize, loopback: bool) -> Self {
+            Self {
+                rx: Deque::new(),
+                chunk,
+                loopback,
+                writes: 0,
+            }
+        }
+
+        fn push(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.rx.push_back(byte).unwrap();
+            }
+        }
+    }
+
+    impl embedded_io_async::ErrorType for Pipe {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Pipe {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let mut n = 0;
+            while n < buf.len().min(self.chunk) {
+                let Some(byte) = self.rx.pop_front() else {
+                    break;
+                };
+                buf[n] = byte;
+                n += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.writes += 1;
+            if self.loopback {
+                self.push(buf);
+            }
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn uart_transport_reads_one_packet_per_recv() {
+        // Bytes trickle in one at a time, with no boundaries between packets
+        let mut uart = UartTransport::new(Pipe::new(1, true), None);
+        let publish = [0x30, 5, 0, 1, b't', b'h', b'i'];
+        let puback = [0x40, 2, 0, 7];
+        embassy_futures::block_on(async {
+            uart.send(&publish).await.unwrap();
+            uart.send(&puback).await.unwrap();
+            let mut buf = [0u8; 16];
+            let len = uart.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], publish);
+            let len = uart.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], puback);
+            // The link has nothing more to read
+            assert!(matches!(
+                uart.recv(&mut buf).await,
+                Err(StreamError::Closed)
+            ));
+        });
+    }
+
+    #[test]
+    fn uart_transport_rejects_a_packet_larger_than_the_buffer() {
+        let mut pipe = Pipe::new(8, false);
+        pipe.push(&[0x30, 5, 0, 1, b't', b'h', b'i']);
+        let mut uart = UartTransport::new(pipe, None);
+        let mut buf = [0u8; 4];
+        let result = embassy_futures::block_on(uart.recv(&mut buf));
+        assert!(matches!(result, Err(StreamError::BufferTooSmall)));
+    }
+
+    #[test]
+    fn client_assembles_packets_from_partial_stream_reads() {
+        use crate::client::{MqttClient, MqttEvent, MqttOptions};
+        use crate::packet::QoS;
+
+        let mut pipe = Pipe::new(3, false);
+        pipe.push(&[0x20, 2, 0, 0]);
+        pipe.push(&[0x30, 8, 0, 3, b'a', b'/', b'b', b'o', b'n', b'!']);
+        let transport = StreamTransport::new(pipe, None);
+        // MQTT v3.1.1 by default
+        let options = MqttOptions::new("cid");
+        let mut client = MqttClient::<_, 4, 64>::new(transport, options);
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            // `poll` returns `None` while a packet is incomplete
+            match client.next_event().await.unwrap() {
+                MqttEvent::Publish(publish) => {
+                    assert_eq!(publish.topic, "a/b");
+                    assert_eq!(publish.payload, b"on!");
+                    assert_eq!(publish.qos, QoS::AtMostOnce);
+                }
+                other => panic!("{other:?}"),
+            }
+        });
+    }
+
+    /// A predictable stand-in for a hardware RNG.
+    #[cfg(feature = "tls")]
+    struct CountingRng(u8);
+
+    #[cfg(feature = "tls")]
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                self.0 = self.0.wrapping_add(1);
+                *byte = self.0;
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    impl CryptoRng for CountingRng {}
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_handshake_failure_is_a_transport_error() {
+        use embedded_tls::NoVerify;
+
+        // The broker closes the connection instead of answering the ClientHello
+        let mut read_buf = [0; 4096];
+        let mut write_buf = [0; 4096];
+        let config = TlsConfig::new().with_server_name("broker.local");
+        let result =
+            embassy_futures::block_on(TlsTransport::<_, Aes128GcmSha256>::connect::<NoVerify, _>(
+                Pipe::new(64, false),
+                &mut read_buf,
+                &mut write_buf,
+                &config,
+                &mut CountingRng(0),
+                None,
+            ));
+        assert!(
+            matches!(result, Err(StreamError::Io(_))),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    /// A `Pipe` whose reads only complete after `delay`, like a quiet link.
+    struct Delayed {
+        pipe: Pipe,
+        delay: Duration,
+    }
+
+    impl embedded_io_async::ErrorType for Delayed {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Delayed {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            Timer::after(self.delay).await;
+            self.pipe.read(buf).await
+        }
+    }
+
+    impl Write for Delayed {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.pipe.write(buf).await
+        }
+    }
+
+    #[test]
+    fn delayed_message_is_received_without_a_read_timeout() {
+        use crate::client::{MqttClient, MqttEvent, MqttOptions};
+
+        let mut pipe = Pipe::new(64, false);
+        pipe.push(&[0x20, 2, 0, 0]);
+        pipe.push(&[0x30, 5, 0, 1, b't', b'o', b'n']);
+        let delayed = Delayed {
+            pipe,
+            delay: Duration::from_millis(50),
+        };
+        let transport = StreamTransport::new(delayed, None);
+        let mut client = MqttClient::<_, 4, 64>::new(transport, MqttOptions::new("cid"));
+        embassy_futures::block_on(async {
+            client.connect().await.unwrap();
+            match client.next_event().await.unwrap() {
+                MqttEvent::Publish(publish) => assert_eq!(publish.payload, b"on"),
+                other => panic!("{other:?}"),
+            }
+        });
+    }
+
+    #[test]
+    fn read_timeout_fails_a_read_that_takes_longer() {
+        let mut pipe = Pipe::new(64, false);
+        pipe.push(&[0x20, 2, 0, 0]);
+        let delayed = Delayed {
+            pipe,
+            delay: Duration::from_millis(50),
+        };
+        let mut transport = StreamTransport::new(delayed, Duration::from_millis(5));
+        let mut buf = [0u8; 8];
+        let result = embassy_futures::block_on(transport.recv(&mut buf));
+        assert!(matches!(result, Err(StreamError::Timeout)), "{result:?}");
+    }
+
+    #[test]
+    fn uart_timeout_only_bounds_the_wait_for_a_packet() {
+        // Every byte takes 10 ms to arrive
+        let mut pipe = Pipe::new(1, false);
+        pipe.push(&[0x30, 5, 0, 1, b't', b'o', b'n']);
+        let delayed = Delayed {
+            pipe,
+            delay: Duration::from_millis(10),
+        };
+        let mut uart = UartTransport::new(delayed, Duration::from_millis(5));
+        let mut buf = [0u8; 16];
+        embassy_futures::block_on(async {
+            let result = uart.recv(&mut buf).await;
+            assert!(matches!(result, Err(StreamError::Timeout)), "{result:?}");
+
+            // The packet takes longer than the timeout, but started within it,
+            // and nothing was lost to the timeout before
+            uart.timeout = Some(Duration::from_millis(30));
+            let len = uart.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], [0x30, 5, 0, 1, b't', b'o', b'n']);
+        });
+    }
+
+    /// Frames `data` like `FramedTransport` does.
+    fn frame(data: &[u8]) -> heapless::Vec<u8, 64> {
+        let mut frame = heapless::Vec::new();
+        frame.extend_from_slice(&FRAME_SYNC).unwrap();
+        frame
+            .extend_from_slice(&(data.len() as u16).to_be_bytes())
+            .unwrap();
+        frame.extend_from_slice(data).unwrap();
+        let crc = crc16(&frame[2..]);
+        frame.extend_from_slice(&crc.to_be_bytes()).unwrap();
+        frame
+    }
+
+    #[test]
+    fn framed_transport_sends_each_frame_in_one_write() {
+        // Frames carry at most 10 bytes of data
+        let mut framed =
+            FramedTransport::<_, 16>::new(StreamTransport::new(Pipe::new(64, true), None));
+        let packet = [
+            0x30, 12, 0, 1, b't', b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8',
+        ];
+        embassy_futures::block_on(async {
+            framed.send(&packet).await.unwrap();
+            assert_eq!(framed.inner().stream.writes, 2);
+
+            let mut buf = [0u8; 16];
+            let len = framed.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], packet[..10]);
+            let len = framed.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], packet[10..]);
+        });
+        assert_eq!(framed.rejected(), 0);
+    }
+
+    #[test]
+    fn framed_transport_resyncs_after_junk_and_a_corrupt_frame() {
+        use crate::client::MqttVersion;
+        use crate::test_util::MockTransport;
+
+        let puback = [0x40, 2, 0, 7];
+        let mut inner = MockTransport::<128>::new(MqttVersion::V3);
+        // Junk, including a sync marker with an impossible length
+        assert!(inner.push_rx(&[0x00, 0xA5, 0x13, 0xA5, 0x5A, 0xFF, 0xFF]));
+        let mut corrupt = frame(&[0x30, 3, 0, 1, b't']);
+        corrupt[6] ^= 0x01;
+        assert!(inner.push_rx(&corrupt));
+        assert!(inner.push_rx(&frame(&puback)));
+        assert!(inner.push_rx(&frame(&[0xD0, 0])));
+        let mut framed = FramedTransport::<_, 64>::new(inner);
+
+        let mut buf = [0u8; 16];
+        let len = embassy_futures::block_on(framed.recv(&mut buf)).unwrap();
+        assert_eq!(buf[..len], puback);
+        assert_eq!(framed.rejected(), 2);
+        // The stream stays in sync with the frames that follow
+        let len = embassy_futures::block_on(framed.recv(&mut buf)).unwrap();
+        assert_eq!(buf[..len], [0xD0, 0]);
+        assert_eq!(framed.rejected(), 2);
     }
 }
@@ -0,0 +1,53 @@
+//! # Client Identifiers
+//!
+//! Builds client identifiers for devices without a provisioned one, from a
+//! fixed prefix and a hardware id such as a MAC address or chip id.
+
+use super::encoding::HEX_DIGITS;
+
+/// Writes `prefix` followed by `unique` as lowercase hex into `out` and
+/// returns the written client id.
+///
+/// If `out` is too small, the prefix is shortened first so the id stays
+/// unique per device. If even the hex suffix doesn't fit, its leading digits
+/// are dropped, since the trailing bytes of a MAC address differ the most.
+///
+/// ```
+/// use myrtio_mqtt::util::format_client_id;
+///
+/// let mac = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+/// let mut buf = [0u8; 32];
+/// assert_eq!(format_client_id("light-", &mac, &mut buf), "light-240ac4123456");
+///
+/// // The prefix is cut to make room for the suffix
+/// let mut small = [0u8; 14];
+/// assert_eq!(format_client_id("light-", &mac, &mut small), "li240ac4123456");
+///
+/// // Only the last digits are kept when the suffix alone doesn't fit
+/// let mut tiny = [0u8; 4];
+/// assert_eq!(format_client_id("light-", &mac, &mut tiny), "3456");
+/// ```
+pub fn format_client_id<'a>(prefix: &str, unique: &[u8], out: &'a mut [u8]) -> &'a str {
+    let hex_len = (unique.len() * 2).min(out.len());
+    let mut prefix_len = prefix.len().min(out.len() - hex_len);
+    while !prefix.is_char_boundary(prefix_len) {
+        prefix_len -= 1;
+    }
+    out[..prefix_len].copy_from_slice(&prefix.as_bytes()[..prefix_len]);
+
+    let digits = unique
+        .iter()
+        .flat_map(|byte| {
+            [
+                HEX_DIGITS[usize::from(byte >> 4)],
+                HEX_DIGITS[usize::from(byte & 0x0F)],
+            ]
+        })
+        .skip(unique.len() * 2 - hex_len);
+    for (slot, digit) in out[prefix_len..].iter_mut().zip(digits) {
+        *slot = digit;
+    }
+
+    // A prefix cut at a char boundary followed by ASCII digits is valid UTF-8
+    core::str::from_utf8(&out[..prefix_len + hex_len]).unwrap_or_default()
+}
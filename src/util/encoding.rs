@@ -0,0 +1,248 @@
+//! # Payload Encodings
+//!
+//! Hex and base64 encoders for carrying binary payloads over bridges or
+//! integrations that only handle text. All functions work on caller-provided
+//! buffers.
+
+use crate::error::ProtocolError;
+
+pub(crate) const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Writes `src` as lowercase hex into `dst` and returns the number of bytes
+/// written.
+///
+/// Only as many input bytes as fit are encoded, so a result shorter than
+/// `src.len() * 2` means `dst` was too small.
+///
+/// ```
+/// use myrtio_mqtt::util::{decode_hex, encode_hex};
+///
+/// let mut hex = [0u8; 8];
+/// let len = encode_hex(&[0xde, 0xad, 0xbe, 0xef], &mut hex);
+/// assert_eq!(&hex[..len], b"deadbeef");
+///
+/// let mut bytes = [0u8; 4];
+/// let len = decode_hex(b"DEADbeef", &mut bytes)?;
+/// assert_eq!(&bytes[..len], &[0xde, 0xad, 0xbe, 0xef]);
+///
+/// // Odd lengths and non-hex characters are rejected
+/// assert!(decode_hex(b"abc", &mut bytes).is_err());
+/// assert!(decode_hex(b"zz", &mut bytes).is_err());
+/// # Ok::<(), myrtio_mqtt::error::ProtocolError>(())
+/// ```
+pub fn encode_hex(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut len = 0;
+    for (byte, out) in src.iter().zip(dst.chunks_exact_mut(2)) {
+        out[0] = HEX_DIGITS[usize::from(byte >> 4)];
+        out[1] = HEX_DIGITS[usize::from(byte & 0x0F)];
+        len += 2;
+    }
+    len
+}
+
+/// Decodes hex from `src` into `dst` and returns the number of bytes written.
+///
+/// Accepts upper- and lowercase digits. Fails with `InvalidEncoding` for an
+/// odd length or a non-hex character, and with `PayloadTooLarge` if `dst` is
+/// shorter than half of `src`.
+pub fn decode_hex(src: &[u8], dst: &mut [u8]) -> Result<usize, ProtocolError> {
+    if !src.len().is_multiple_of(2) {
+        return Err(ProtocolError::InvalidEncoding);
+    }
+    let len = src.len() / 2;
+    let out = dst.get_mut(..len).ok_or(ProtocolError::PayloadTooLarge)?;
+    for (pair, byte) in src.chunks_exact(2).zip(out) {
+        *byte = (hex_value(pair[0])? << 4) | hex_value(pair[1])?;
+    }
+    Ok(len)
+}
+
+fn hex_value(digit: u8) -> Result<u8, ProtocolError> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err(ProtocolError::InvalidEncoding),
+    }
+}
+
+/// Writes `src` as padded standard base64 into `dst` and returns the number
+/// of bytes written.
+///
+/// Only as many 3-byte groups as fit are encoded, so a result shorter than
+/// `src.len().div_ceil(3) * 4` means `dst` was too small.
+///
+/// ```
+/// use myrtio_mqtt::util::{decode_base64, encode_base64};
+///
+/// let mut text = [0u8; 8];
+/// let len = encode_base64(b"hi!ok", &mut text);
+/// assert_eq!(&text[..len], b"aGkhb2s=");
+///
+/// let mut bytes = [0u8; 8];
+/// let len = decode_base64(b"aGkhb2s=", &mut bytes)?;
+/// assert_eq!(&bytes[..len], b"hi!ok");
+///
+/// // Missing padding and characters outside the alphabet are rejected
+/// assert!(decode_base64(b"aGkhb2s", &mut bytes).is_err());
+/// assert!(decode_base64(b"aGk*", &mut bytes).is_err());
+/// # Ok::<(), myrtio_mqtt::error::ProtocolError>(())
+/// ```
+pub fn encode_base64(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut len = 0;
+    for (group, out) in src.chunks(3).zip(dst.chunks_exact_mut(4)) {
+        let b = [
+            group[0],
+            group.get(1).copied().unwrap_or(0),
+            group.get(2).copied().unwrap_or(0),
+        ];
+        out[0] = BASE64_ALPHABET[usize::from(b[0] >> 2)];
+        out[1] = BASE64_ALPHABET[usize::from((b[0] & 0x03) << 4 | b[1] >> 4)];
+        out[2] = if group.len() > 1 {
+            BASE64_ALPHABET[usize::from((b[1] & 0x0F) << 2 | b[2] >> 6)]
+        } else {
+            b'='
+        };
+        out[3] = if group.len() > 2 {
+            BASE64_ALPHABET[usize::from(b[2] & 0x3F)]
+        } else {
+            b'='
+        };
+        len += 4;
+    }
+    len
+}
+
+/// Decodes padded standard base64 from `src` into `dst` and returns the
+/// number of bytes written.
+///
+/// Fails with `InvalidEncoding` if the length isn't a multiple of 4, for a
+/// character outside the alphabet or misplaced padding, and with
+/// `PayloadTooLarge` if the decoded bytes don't fit into `dst`.
+pub fn decode_base64(src: &[u8], dst: &mut [u8]) -> Result<usize, ProtocolError> {
+    if !src.len().is_multiple_of(4) {
+        return Err(ProtocolError::InvalidEncoding);
+    }
+    let groups = src.len() / 4;
+    let mut len = 0;
+    for (index, group) in src.chunks_exact(4).enumerate() {
+        // Padding may only end the last group
+        let padding = match group {
+            [_, _, b'=', b'='] => 2,
+            [_, _, _, b'='] => 1,
+            _ => 0,
+        };
+        if padding > 0 && index + 1 != groups {
+            return Err(ProtocolError::InvalidEncoding);
+        }
+        let mut value = 0u32;
+        for &c in &group[..4 - padding] {
+            value = value << 6 | u32::from(base64_value(c)?);
+        }
+        value <<= 6 * padding;
+
+        let bytes = value.to_be_bytes();
+        let decoded = &bytes[1..4 - padding];
+        dst.get_mut(len..len + decoded.len())
+            .ok_or(ProtocolError::PayloadTooLarge)?
+            .copy_from_slice(decoded);
+        len += decoded.len();
+    }
+    Ok(len)
+}
+
+fn base64_value(c: u8) -> Result<u8, ProtocolError> {
+    match c {
+        b'A'..=b'Z' => Ok(c - b'A'),
+        b'a'..=b'z' => Ok(c - b'a' + 26),
+        b'0'..=b'9' => Ok(c - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err(ProtocolError::InvalidEncoding),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every byte value, so each digit and alphabet entry is exercised.
+    fn all_bytes() -> [u8; 256] {
+        core::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn hex_round_trips_every_byte() {
+        let bytes = all_bytes();
+        let mut hex = [0u8; 512];
+        assert_eq!(encode_hex(&bytes, &mut hex), 512);
+        let mut decoded = [0u8; 256];
+        assert_eq!(decode_hex(&hex, &mut decoded), Ok(256));
+        assert_eq!(decoded, bytes);
+    }
+
+    #[test]
+    fn hex_decoder_rejects_odd_lengths_and_invalid_digits() {
+        let mut out = [0u8; 4];
+        assert_eq!(
+            decode_hex(b"a", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        assert_eq!(
+            decode_hex(b"abc", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        assert_eq!(
+            decode_hex(b"0g", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        assert_eq!(
+            decode_hex(b"+1", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        assert_eq!(
+            decode_hex(b"0011223344", &mut out),
+            Err(ProtocolError::PayloadTooLarge)
+        );
+        assert_eq!(decode_hex(b"", &mut out), Ok(0));
+    }
+
+    #[test]
+    fn base64_round_trips_every_length() {
+        let bytes = all_bytes();
+        let mut text = [0u8; 344];
+        let mut decoded = [0u8; 256];
+        // Covers zero, one and two padding characters
+        for len in [0, 1, 2, 3, 4, 5, 255, 256] {
+            let text_len = encode_base64(&bytes[..len], &mut text);
+            assert_eq!(text_len, len.div_ceil(3) * 4);
+            assert_eq!(decode_base64(&text[..text_len], &mut decoded), Ok(len));
+            assert_eq!(decoded[..len], bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn base64_decoder_rejects_malformed_input() {
+        let mut out = [0u8; 8];
+        assert_eq!(
+            decode_base64(b"aGk", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        assert_eq!(
+            decode_base64(b"aG-h", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        // Padding in the middle
+        assert_eq!(
+            decode_base64(b"aG==aGkh", &mut out),
+            Err(ProtocolError::InvalidEncoding)
+        );
+        assert_eq!(
+            decode_base64(b"aGkhaGkhaGkh", &mut out),
+            Err(ProtocolError::PayloadTooLarge)
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! # JSON Writer
+//!
+//! A minimal JSON object writer for state payloads, e.g. Home Assistant
+//! `{"state":"ON","brightness":255}` messages, without `serde` or allocation.
+
+use crate::error::MqttError;
+use crate::transport;
+
+/// Writes a flat JSON object into a byte buffer.
+///
+/// Keys and string values are escaped. Every method fails with
+/// `BufferTooSmall` once the buffer is full; the buffer contents are then
+/// unspecified.
+///
+/// ```
+/// use myrtio_mqtt::util::JsonWriter;
+///
+/// let mut buf = [0u8; 64];
+/// let mut json = JsonWriter::new(&mut buf);
+/// json.begin_object()?
+///     .field_str("state", "ON")?
+///     .field_i32("brightness", 255)?
+///     .field_bool("online", true)?;
+/// let len = json.end_object()?;
+/// assert_eq!(&buf[..len], br#"{"state":"ON","brightness":255,"online":true}"#);
+///
+/// // Quotes, backslashes and control characters are escaped
+/// let mut json = JsonWriter::new(&mut buf);
+/// json.begin_object()?.field_str("name", "say \"hi\"\n")?;
+/// let len = json.end_object()?;
+/// assert_eq!(&buf[..len], br#"{"name":"say \"hi\"\n"}"#);
+///
+/// // A full buffer is reported instead of truncating the object
+/// let mut small = [0u8; 8];
+/// let mut json = JsonWriter::new(&mut small);
+/// assert!(json.begin_object()?.field_str("state", "OFF").is_err());
+/// # Ok::<(), myrtio_mqtt::error::MqttError<myrtio_mqtt::transport::ErrorPlaceHolder>>(())
+/// ```
+pub struct JsonWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    /// Set once the current object has a field, so the next one needs a comma.
+    has_fields: bool,
+}
+
+impl<'a> JsonWriter<'a> {
+    /// Creates a writer that starts at the beginning of `buf`.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            has_fields: false,
+        }
+    }
+
+    /// Writes the opening brace of an object.
+    pub fn begin_object(&mut self) -> Result<&mut Self, MqttError<transport::ErrorPlaceHolder>> {
+        self.write_raw(b"{")?;
+        self.has_fields = false;
+        Ok(self)
+    }
+
+    /// Writes a field with a string value.
+    pub fn field_str(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> Result<&mut Self, MqttError<transport::ErrorPlaceHolder>> {
+        self.write_key(key)?;
+        self.write_string(value)?;
+        Ok(self)
+    }
+
+    /// Writes a field with an integer value.
+    pub fn field_i32(
+        &mut self,
+        key: &str,
+        value: i32,
+    ) -> Result<&mut Self, MqttError<transport::ErrorPlaceHolder>> {
+        self.write_key(key)?;
+        // i32::MIN is 11 characters long
+        let mut digits = [0u8; 11];
+        let mut start = digits.len();
+        let mut rest = value.unsigned_abs();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+            if rest == 0 {
+                break;
+            }
+        }
+        if value < 0 {
+            start -= 1;
+            digits[start] = b'-';
+        }
+        self.write_raw(&digits[start..])?;
+        Ok(self)
+    }
+
+    /// Writes a field with a boolean value.
+    pub fn field_bool(
+        &mut self,
+        key: &str,
+        value: bool,
+    ) -> Result<&mut Self, MqttError<transport::ErrorPlaceHolder>> {
+        self.write_key(key)?;
+        self.write_raw(if value { b"true" } else { b"false" })?;
+        Ok(self)
+    }
+
+    /// Writes the closing brace and returns the number of bytes written.
+    pub fn end_object(&mut self) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        self.write_raw(b"}")?;
+        Ok(self.pos)
+    }
+
+    fn write_key(&mut self, key: &str) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        if self.has_fields {
+            self.write_raw(b",")?;
+        }
+        self.has_fields = true;
+        self.write_string(key)?;
+        self.write_raw(b":")
+    }
+
+    /// Writes `value` as a quoted, escaped JSON string.
+    fn write_string(&mut self, value: &str) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        self.write_raw(b"\"")?;
+        for &byte in value.as_bytes() {
+            match byte {
+                b'"' => self.write_raw(b"\\\"")?,
+                b'\\' => self.write_raw(b"\\\\")?,
+                b'\n' => self.write_raw(b"\\n")?,
+                b'\r' => self.write_raw(b"\\r")?,
+                b'\t' => self.write_raw(b"\\t")?,
+                0..0x20 => {
+                    const HEX: &[u8; 16] = b"0123456789abcdef";
+                    let escape = [
+                        b'\\',
+                        b'u',
+                        b'0',
+                        b'0',
+                        HEX[usize::from(byte >> 4)],
+                        HEX[usize::from(byte & 0x0F)],
+                    ];
+                    self.write_raw(&escape)?;
+                }
+                // Multi-byte UTF-8 sequences are valid JSON as they are
+                _ => self.write_raw(&[byte])?,
+            }
+        }
+        self.write_raw(b"\"")
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(MqttError::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_and_control_characters_are_escaped() {
+        let mut buf = [0u8; 64];
+        let mut json = JsonWriter::new(&mut buf);
+        json.begin_object()
+            .unwrap()
+            .field_str("a\"b", "\"q\" \\ \u{1}")
+            .unwrap()
+            .field_i32("n", i32::MIN)
+            .unwrap();
+        let len = json.end_object().unwrap();
+        assert_eq!(
+            &buf[..len],
+            br#"{"a\"b":"\"q\" \\ \u0001","n":-2147483648}"#
+        );
+    }
+
+    #[test]
+    fn full_buffer_is_reported() {
+        let object = br#"{"state":"ON"}"#;
+        for size in 0..object.len() {
+            let mut buf = [0u8; 16];
+            let mut json = JsonWriter::new(&mut buf[..size]);
+            let result = json
+                .begin_object()
+                .and_then(|json| json.field_str("state", "ON"))
+                .and_then(|json| json.end_object());
+            assert!(matches!(result, Err(MqttError::BufferTooSmall)), "{size}");
+        }
+
+        let mut buf = [0u8; 14];
+        let mut json = JsonWriter::new(&mut buf);
+        json.begin_object()
+            .unwrap()
+            .field_str("state", "ON")
+            .unwrap();
+        assert_eq!(json.end_object().unwrap(), object.len());
+        assert_eq!(&buf, object);
+    }
+}
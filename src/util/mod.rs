@@ -0,0 +1,475 @@
+//! # MQTT Serialization Utilities
+//!
+//! This module provides helper functions for reading and writing MQTT-specific data types
+//! from and to byte buffers, such as variable-byte integers and length-prefixed strings.
+
+mod client_id;
+mod encoding;
+mod json;
+mod topic;
+
+pub use client_id::format_client_id;
+pub use encoding::{decode_base64, decode_hex, encode_base64, encode_hex};
+pub use json::JsonWriter;
+pub use topic::{
+    shared_filter, strip_shared, topic_matches, validate_publish_topic, validate_subscribe_filter,
+};
+
+use crate::error::{MqttError, ProtocolError};
+#[cfg(feature = "v5")]
+use crate::packet;
+use crate::transport;
+#[cfg(feature = "v5")]
+use heapless::Vec;
+
+/// Reads a variable-byte integer from the buffer, advancing the cursor.
+///
+/// This is a common encoding scheme in MQTT for packet lengths.
+pub fn read_variable_byte_integer(
+    cursor: &mut usize,
+    buf: &[u8],
+) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+    let mut multiplier = 1;
+    let mut value = 0;
+    let mut i = 0;
+    loop {
+        let encoded_byte = buf
+            .get(*cursor + i)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+        value += (encoded_byte & 127) as usize * multiplier;
+        if (encoded_byte & 128) == 0 {
+            break;
+        }
+        multiplier *= 128;
+        i += 1;
+        if i >= 4 {
+            return Err(MqttError::Protocol(ProtocolError::MalformedPacket));
+        }
+    }
+    *cursor += i + 1;
+    Ok(value)
+}
+
+/// Writes a variable-byte integer to the buffer, advancing the cursor.
+pub fn write_variable_byte_integer(
+    cursor: &mut usize,
+    buf: &mut [u8],
+    mut val: usize,
+) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+    loop {
+        let mut encoded_byte = (val % 128) as u8;
+        val /= 128;
+        if val > 0 {
+            encoded_byte |= 128;
+        }
+        // CORRECTED: Dereference the `&mut u8` to assign the value directly.
+        *buf.get_mut(*cursor).ok_or(MqttError::BufferTooSmall)? = encoded_byte;
+        *cursor += 1;
+        if val == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A simplified version of `write_variable_byte_integer` for external use that returns the byte count.
+pub fn write_variable_byte_integer_len(
+    buf: &mut [u8],
+    mut val: usize,
+) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+    let mut i = 0;
+    loop {
+        let mut encoded_byte = (val % 128) as u8;
+        val /= 128;
+        if val > 0 {
+            encoded_byte |= 128;
+        }
+        // CORRECTED: Dereference the `&mut u8` to assign the value directly.
+        *buf.get_mut(i).ok_or(MqttError::BufferTooSmall)? = encoded_byte;
+        i += 1;
+        if val == 0 {
+            break;
+        }
+    }
+    Ok(i)
+}
+
+/// Returns the number of bytes `val` takes as a Variable Byte Integer.
+pub const fn variable_byte_integer_len(val: usize) -> usize {
+    match val {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+/// Reads a UTF-8 encoded string (prefixed with a 2-byte length) from the buffer.
+pub fn read_utf8_string<'a>(
+    cursor: &mut usize,
+    buf: &'a [u8],
+) -> Result<&'a str, MqttError<transport::ErrorPlaceHolder>> {
+    let len = u16::from_be_bytes(
+        buf.get(*cursor..*cursor + 2)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    *cursor += 2;
+    let s = core::str::from_utf8(
+        buf.get(*cursor..*cursor + len)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?,
+    )
+    .map_err(|_| MqttError::Protocol(ProtocolError::InvalidUtf8String))?;
+    *cursor += len;
+    Ok(s)
+}
+
+/// Reads a single byte from the buffer, advancing the cursor.
+pub fn read_u8(
+    cursor: &mut usize,
+    buf: &[u8],
+) -> Result<u8, MqttError<transport::ErrorPlaceHolder>> {
+    let byte = *buf
+        .get(*cursor)
+        .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+    *cursor += 1;
+    Ok(byte)
+}
+
+/// Reads a big-endian two-byte integer from the buffer, advancing the cursor.
+pub fn read_u16(
+    cursor: &mut usize,
+    buf: &[u8],
+) -> Result<u16, MqttError<transport::ErrorPlaceHolder>> {
+    let bytes = buf
+        .get(*cursor..*cursor + 2)
+        .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+    *cursor += 2;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a big-endian four-byte integer from the buffer, advancing the cursor.
+pub fn read_u32(
+    cursor: &mut usize,
+    buf: &[u8],
+) -> Result<u32, MqttError<transport::ErrorPlaceHolder>> {
+    let bytes = buf
+        .get(*cursor..*cursor + 4)
+        .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+    *cursor += 4;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Checks that `s` only holds characters an MQTT UTF-8 string may carry.
+///
+/// Rust strings are always well-formed UTF-8, but MQTT additionally forbids
+/// the null character and allows a receiver to treat the control characters
+/// U+0001..U+001F and U+007F..U+009F and the Unicode non-characters (such as
+/// U+FFFF) as a malformed packet. Some brokers close the connection over
+/// them. Fails with `InvalidUtf8String` if `s` contains any of them.
+///
+/// `write_utf8_string` doesn't check this, so strings from untrusted input
+/// should be validated first; `validate_publish_topic` and
+/// `validate_subscribe_filter` do so for topics.
+///
+/// ```
+/// use myrtio_mqtt::util::validate_utf8_string;
+///
+/// assert!(validate_utf8_string("küche/licht/温度").is_ok());
+/// assert!(validate_utf8_string("a\0b").is_err());
+/// assert!(validate_utf8_string("a\u{1b}b").is_err());
+/// assert!(validate_utf8_string("a\u{fdd0}").is_err());
+/// assert!(validate_utf8_string("a\u{10ffff}").is_err());
+/// ```
+pub fn validate_utf8_string(s: &str) -> Result<(), ProtocolError> {
+    let disallowed = |c: char| {
+        let code = u32::from(c);
+        c.is_control() || (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE
+    };
+    if s.contains(disallowed) {
+        return Err(ProtocolError::InvalidUtf8String);
+    }
+    Ok(())
+}
+
+/// Writes a UTF-8 encoded string (prefixed with a 2-byte length) to the buffer.
+pub fn write_utf8_string(
+    buf: &mut [u8],
+    s: &str,
+) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+    let len = s.len();
+    if len > u16::MAX as usize {
+        return Err(MqttError::Protocol(ProtocolError::PayloadTooLarge));
+    }
+    let len_bytes = (len as u16).to_be_bytes();
+
+    let required_space = 2 + len;
+    let slice = buf
+        .get_mut(0..required_space)
+        .ok_or(MqttError::BufferTooSmall)?;
+
+    slice[0..2].copy_from_slice(&len_bytes);
+    slice[2..].copy_from_slice(s.as_bytes());
+    Ok(required_space)
+}
+
+/// Returns the number of bytes occupied by the value of the property `id`
+/// starting at `cursor`.
+///
+/// The length is derived from the property's wire type as defined by the
+/// MQTT v5 specification (byte, two-byte integer, four-byte integer,
+/// variable-byte integer, UTF-8 string, binary data or UTF-8 string pair).
+#[cfg(feature = "v5")]
+fn property_value_len(
+    id: u8,
+    cursor: usize,
+    buf: &[u8],
+) -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+    // Reads a 2-byte length prefix at `at`, as used by strings and binary data.
+    let prefixed_len = |at: usize| -> Result<usize, MqttError<transport::ErrorPlaceHolder>> {
+        let bytes = buf
+            .get(at..at + 2)
+            .ok_or(MqttError::Protocol(ProtocolError::MalformedPacket))?;
+        Ok(2 + u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    };
+
+    match id {
+        // Byte
+        0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => Ok(1),
+        // Two-byte integer
+        0x13 | 0x21 | 0x22 | 0x23 => Ok(2),
+        // Four-byte integer
+        0x02 | 0x11 | 0x18 | 0x27 => Ok(4),
+        // Variable-byte integer
+        0x0B => {
+            let mut end = cursor;
+            read_variable_byte_integer(&mut end, buf)?;
+            Ok(end - cursor)
+        }
+        // UTF-8 string or binary data
+        0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1A | 0x1C | 0x1F => prefixed_len(cursor),
+        // UTF-8 string pair
+        0x26 => {
+            let key_len = prefixed_len(cursor)?;
+            let value_len = prefixed_len(cursor + key_len)?;
+            Ok(key_len + value_len)
+        }
+        _ => Err(MqttError::Protocol(ProtocolError::MalformedPacket)),
+    }
+}
+
+/// Reads MQTT v5 properties from the buffer.
+///
+/// Each returned `Property` holds the identifier and the raw encoded value
+/// (including any length prefixes), so it can be written back unchanged.
+/// More than `packet::MAX_PROPERTIES` properties fail with `TooManyProperties`.
+#[cfg(feature = "v5")]
+pub fn read_properties<'a>(
+    cursor: &mut usize,
+    buf: &'a [u8],
+) -> Result<packet::Properties<'a>, MqttError<transport::ErrorPlaceHolder>> {
+    read_properties_with_capacity(cursor, buf)
+}
+
+/// Reads MQTT v5 properties like `read_properties`, into a vector of
+/// capacity `N`. More than `N` properties fail with `TooManyProperties`.
+#[cfg(feature = "v5")]
+pub fn read_properties_with_capacity<'a, const N: usize>(
+    cursor: &mut usize,
+    buf: &'a [u8],
+) -> Result<Vec<packet::Property<'a>, N>, MqttError<transport::ErrorPlaceHolder>> {
+    let mut properties = Vec::new();
+    let prop_len = read_variable_byte_integer(cursor, buf)?;
+    let prop_end = *cursor + prop_len;
+    if prop_end > buf.len() {
+        return Err(MqttError::Protocol(ProtocolError::MalformedPacket));
+    }
+
+    while *cursor < prop_end {
+        let id = read_u8(cursor, buf)?;
+        let data_start = *cursor;
+        let data_len = property_value_len(id, data_start, &buf[..prop_end])?;
+        let data_end = data_start + data_len;
+        if data_end > prop_end {
+            return Err(MqttError::Protocol(ProtocolError::MalformedPacket));
+        }
+        *cursor = data_end;
+        properties
+            .push(packet::Property {
+                id,
+                data: &buf[data_start..data_end],
+            })
+            .map_err(|_| MqttError::Protocol(ProtocolError::TooManyProperties))?;
+    }
+    Ok(properties)
+}
+
+/// Writes MQTT v5 properties to the buffer.
+///
+/// The total property length is computed first, so the variable-byte length
+/// prefix gets exactly as many bytes as it needs (blocks longer than 127 bytes
+/// use a multi-byte prefix).
+#[cfg(feature = "v5")]
+pub fn write_properties(
+    cursor: &mut usize,
+    buf: &mut [u8],
+    properties: &[packet::Property],
+) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+    write_properties_with(cursor, buf, properties, &[])
+}
+
+/// Writes MQTT v5 properties like `write_properties`, followed by `prefixed`
+/// with a two-byte length before each value.
+#[cfg(feature = "v5")]
+pub(crate) fn write_properties_with(
+    cursor: &mut usize,
+    buf: &mut [u8],
+    properties: &[packet::Property],
+    prefixed: &[packet::PrefixedProperty],
+) -> Result<(), MqttError<transport::ErrorPlaceHolder>> {
+    if prefixed
+        .iter()
+        .any(|prop| prop.data.len() > u16::MAX as usize)
+    {
+        return Err(MqttError::Protocol(ProtocolError::PayloadTooLarge));
+    }
+    write_variable_byte_integer(cursor, buf, properties_sum(properties, prefixed))?;
+
+    for prop in properties {
+        let end = *cursor + 1 + prop.data.len();
+        let slice = buf.get_mut(*cursor..end).ok_or(MqttError::BufferTooSmall)?;
+        slice[0] = prop.id;
+        slice[1..].copy_from_slice(prop.data);
+        *cursor = end;
+    }
+    for prop in prefixed {
+        let end = *cursor + 3 + prop.data.len();
+        let slice = buf.get_mut(*cursor..end).ok_or(MqttError::BufferTooSmall)?;
+        slice[0] = prop.id;
+        // Checked above
+        slice[1..3].copy_from_slice(&(prop.data.len() as u16).to_be_bytes());
+        slice[3..].copy_from_slice(prop.data);
+        *cursor = end;
+    }
+
+    Ok(())
+}
+
+/// Returns the length of MQTT v5 properties as written by `write_properties`,
+/// length prefix included.
+#[cfg(feature = "v5")]
+pub fn properties_len(properties: &[packet::Property]) -> usize {
+    properties_len_with(properties, &[])
+}
+
+/// Returns the length of MQTT v5 properties as written by
+/// `write_properties_with`, length prefix included.
+#[cfg(feature = "v5")]
+pub(crate) fn properties_len_with(
+    properties: &[packet::Property],
+    prefixed: &[packet::PrefixedProperty],
+) -> usize {
+    let total_prop_len = properties_sum(properties, prefixed);
+    variable_byte_integer_len(total_prop_len) + total_prop_len
+}
+
+/// Returns the length of the properties without their length prefix.
+#[cfg(feature = "v5")]
+fn properties_sum(properties: &[packet::Property], prefixed: &[packet::PrefixedProperty]) -> usize {
+    let own: usize = properties.iter().map(|prop| 1 + prop.data.len()).sum();
+    let prefixed: usize = prefixed.iter().map(|prop| 3 + prop.data.len()).sum();
+    own + prefixed
+}
+
+#[cfg(all(test, feature = "v5"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_properties_advances_past_typed_values() {
+        #[rustfmt::skip]
+        let buf = [
+            // Property length
+            12,
+            // Content Type "ab", a UTF-8 string
+            0x03, 0, 2, b'a', b'b',
+            // Message Expiry Interval 300, a four-byte integer
+            0x02, 0, 0, 0x01, 0x2C,
+            // Payload Format Indicator, a byte
+            0x01, 1,
+            // Payload
+            b'x',
+        ];
+        let mut cursor = 0;
+        let properties = read_properties(&mut cursor, &buf).unwrap();
+        assert_eq!(cursor, 13);
+        assert_eq!(&buf[cursor..], b"x");
+        assert_eq!(properties.len(), 3);
+        assert_eq!(properties[0].id, packet::Property::CONTENT_TYPE);
+        assert_eq!(properties[0].data, [0, 2, b'a', b'b']);
+        assert_eq!(properties[1].id, packet::Property::MESSAGE_EXPIRY_INTERVAL);
+        assert_eq!(properties[1].data, 300u32.to_be_bytes());
+        assert_eq!(properties[2].data, [1]);
+    }
+
+    #[test]
+    fn read_properties_rejects_a_string_past_the_block() {
+        // The string claims 5 bytes, but the block ends after 2
+        let buf = [5, 0x03, 0, 5, b'a', b'b', b'c', b'd', b'e'];
+        assert!(matches!(
+            read_properties(&mut 0, &buf),
+            Err(MqttError::Protocol(ProtocolError::MalformedPacket))
+        ));
+    }
+
+    #[test]
+    fn property_block_over_127_bytes_gets_a_two_byte_prefix() {
+        // Twelve Correlation Data values of 14 bytes, 12 * (1 + 2 + 14) bytes
+        let mut data = [7u8; 16];
+        data[..2].copy_from_slice(&14u16.to_be_bytes());
+        let properties: Vec<packet::Property, 12> = (0..12)
+            .map(|_| packet::Property {
+                id: packet::Property::CORRELATION_DATA,
+                data: &data,
+            })
+            .collect();
+        let len = 12 * 17;
+        assert_eq!(properties_len(&properties), 2 + len);
+
+        let mut buf = [0u8; 256];
+        let mut cursor = 0;
+        write_properties(&mut cursor, &mut buf, &properties).unwrap();
+        assert_eq!(cursor, 2 + len);
+        // 204 as a variable byte integer
+        assert_eq!(buf[..2], [0xCC, 0x01]);
+
+        let mut cursor = 0;
+        let read = read_properties(&mut cursor, &buf).unwrap();
+        assert_eq!(cursor, 2 + len);
+        assert_eq!(read.len(), 12);
+        assert!(
+            read.iter()
+                .all(|prop| prop.id == packet::Property::CORRELATION_DATA && prop.data == data)
+        );
+    }
+
+    #[test]
+    fn more_properties_than_the_capacity_are_rejected() {
+        // Ten Payload Format Indicators, two bytes each
+        let mut buf = [0u8; 21];
+        buf[0] = 20;
+        for prop in buf[1..].chunks_mut(2) {
+            prop.copy_from_slice(&[packet::Property::PAYLOAD_FORMAT_INDICATOR, 1]);
+        }
+
+        let result = read_properties_with_capacity::<4>(&mut 0, &buf);
+        assert!(matches!(
+            result,
+            Err(MqttError::Protocol(ProtocolError::TooManyProperties))
+        ));
+        // A capacity that fits reads all ten
+        let properties = read_properties_with_capacity::<10>(&mut 0, &buf).unwrap();
+        assert_eq!(properties.len(), 10);
+    }
+}
@@ -0,0 +1,228 @@
+//! # Topic Filter Matching
+//!
+//! Implements the MQTT topic filter semantics used to decide whether a topic
+//! name matches a subscription filter containing `+` and `#` wildcards, and
+//! the checks the client applies to topics before sending them.
+//!
+//! MQTT v5 shared subscriptions use filters of the form
+//! `$share/<group>/<filter>`; the broker delivers each message to one member
+//! of the group, with the topic it was published to.
+
+use super::validate_utf8_string;
+use crate::error::ProtocolError;
+
+/// Returns `true` if `topic` matches the subscription `filter`, following
+/// the topic wildcard rules of the MQTT specification (section 4.7).
+///
+/// - `+` matches exactly one topic level, which may be empty.
+/// - `#` matches the parent level and any number of child levels; it is only
+///   valid as the last level of the filter.
+/// - Topics starting with `$` (e.g. `$SYS/...`) are not matched by filters
+///   whose first level is a wildcard.
+/// - A shared subscription filter matches like the filter it wraps.
+///
+/// ```
+/// use myrtio_mqtt::util::topic_matches;
+///
+/// assert!(topic_matches("sport/+/player1", "sport/tennis/player1"));
+/// assert!(topic_matches("sport/#", "sport"));
+/// assert!(!topic_matches("#", "$SYS/uptime"));
+/// assert!(topic_matches("$share/workers/jobs/+", "jobs/42"));
+/// ```
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let filter = strip_shared(filter);
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Checks that `topic` can be published to.
+///
+/// A topic name must not be empty or longer than 65535 bytes, and must not
+/// contain the `+` and `#` wildcards, which are only valid in subscription
+/// filters, or characters `validate_utf8_string` rejects, like the null
+/// character.
+///
+/// ```
+/// use myrtio_mqtt::util::validate_publish_topic;
+///
+/// assert!(validate_publish_topic("home/kitchen/light").is_ok());
+/// assert!(validate_publish_topic("maison/café/température").is_ok());
+/// assert!(validate_publish_topic("a/+/b").is_err());
+/// assert!(validate_publish_topic("a/#").is_err());
+/// assert!(validate_publish_topic("").is_err());
+/// assert!(validate_publish_topic("home/\0/light").is_err());
+/// ```
+pub fn validate_publish_topic(topic: &str) -> Result<(), ProtocolError> {
+    if topic.is_empty()
+        || topic.len() > usize::from(u16::MAX)
+        || topic.contains(['+', '#'])
+        || validate_utf8_string(topic).is_err()
+    {
+        return Err(ProtocolError::InvalidTopic);
+    }
+    Ok(())
+}
+
+/// Checks that `filter` is a valid subscription filter.
+///
+/// A filter must not be empty, longer than 65535 bytes or contain characters
+/// `validate_utf8_string` rejects, like the null character. `+` must occupy a
+/// whole level, and `#` must occupy the whole last level.
+///
+/// ```
+/// use myrtio_mqtt::util::validate_subscribe_filter;
+///
+/// assert!(validate_subscribe_filter("a/+/b").is_ok());
+/// assert!(validate_subscribe_filter("sport/#").is_ok());
+/// assert!(validate_subscribe_filter("#").is_ok());
+/// assert!(validate_subscribe_filter("a/b+").is_err());
+/// assert!(validate_subscribe_filter("a/#/b").is_err());
+/// assert!(validate_subscribe_filter("").is_err());
+/// assert!(validate_subscribe_filter("$share/group/a/#").is_ok());
+/// assert!(validate_subscribe_filter("$share/group").is_err());
+/// assert!(validate_subscribe_filter("дом/+/свет").is_ok());
+/// assert!(validate_subscribe_filter("a/\0/#").is_err());
+/// ```
+pub fn validate_subscribe_filter(filter: &str) -> Result<(), ProtocolError> {
+    if filter.is_empty()
+        || filter.len() > usize::from(u16::MAX)
+        || validate_utf8_string(filter).is_err()
+    {
+        return Err(ProtocolError::InvalidTopic);
+    }
+    if let Some(shared) = filter.strip_prefix(SHARED_PREFIX) {
+        let (group, inner) = shared.split_once('/').ok_or(ProtocolError::InvalidTopic)?;
+        validate_share_group(group)?;
+        return validate_subscribe_filter(inner);
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let valid = match level {
+            "+" => true,
+            "#" => levels.peek().is_none(),
+            _ => !level.contains(['+', '#']),
+        };
+        if !valid {
+            return Err(ProtocolError::InvalidTopic);
+        }
+    }
+    Ok(())
+}
+
+/// Prefix of an MQTT v5 shared subscription filter.
+const SHARED_PREFIX: &str = "$share/";
+
+/// A share name must not be empty or contain wildcards.
+fn validate_share_group(group: &str) -> Result<(), ProtocolError> {
+    if group.is_empty() || group.contains(['+', '#']) {
+        return Err(ProtocolError::InvalidTopic);
+    }
+    Ok(())
+}
+
+/// Formats the shared subscription filter `$share/<group>/<filter>` into
+/// `out` and returns it.
+///
+/// Fails with `InvalidTopic` if the group is empty or contains `/`, `+` or
+/// `#`, or if `filter` isn't a valid filter, and with `PayloadTooLarge` if
+/// `out` is too small.
+///
+/// ```
+/// use myrtio_mqtt::util::shared_filter;
+///
+/// let mut buf = [0u8; 32];
+/// assert_eq!(shared_filter("workers", "jobs/+", &mut buf), Ok("$share/workers/jobs/+"));
+/// assert!(shared_filter("a/b", "jobs/+", &mut buf).is_err());
+/// ```
+pub fn shared_filter<'a>(
+    group: &str,
+    filter: &str,
+    out: &'a mut [u8],
+) -> Result<&'a str, ProtocolError> {
+    if group.contains('/') {
+        return Err(ProtocolError::InvalidTopic);
+    }
+    validate_share_group(group)?;
+    validate_subscribe_filter(filter)?;
+
+    let len = SHARED_PREFIX.len() + group.len() + 1 + filter.len();
+    let out = out.get_mut(..len).ok_or(ProtocolError::PayloadTooLarge)?;
+    let (prefix, rest) = out.split_at_mut(SHARED_PREFIX.len());
+    prefix.copy_from_slice(SHARED_PREFIX.as_bytes());
+    let (name, rest) = rest.split_at_mut(group.len());
+    name.copy_from_slice(group.as_bytes());
+    rest[0] = b'/';
+    rest[1..].copy_from_slice(filter.as_bytes());
+    // Only whole strings were copied
+    core::str::from_utf8(out).map_err(|_| ProtocolError::InvalidUtf8String)
+}
+
+/// Returns the filter wrapped by a shared subscription filter, or `filter`
+/// itself if it isn't one.
+///
+/// ```
+/// use myrtio_mqtt::util::strip_shared;
+///
+/// assert_eq!(strip_shared("$share/workers/jobs/+"), "jobs/+");
+/// assert_eq!(strip_shared("jobs/+"), "jobs/+");
+/// ```
+pub fn strip_shared(filter: &str) -> &str {
+    filter
+        .strip_prefix(SHARED_PREFIX)
+        .and_then(|shared| shared.split_once('/'))
+        .map_or(filter, |(_, inner)| inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_specification_examples() {
+        let cases = [
+            ("sport/tennis/player1/#", "sport/tennis/player1", true),
+            (
+                "sport/tennis/player1/#",
+                "sport/tennis/player1/ranking",
+                true,
+            ),
+            (
+                "sport/tennis/player1/#",
+                "sport/tennis/player1/score/wimbledon",
+                true,
+            ),
+            ("sport/#", "sport", true),
+            ("sport/tennis/+", "sport/tennis/player1", true),
+            ("sport/tennis/+", "sport/tennis/player1/ranking", false),
+            ("sport/+", "sport", false),
+            ("sport/+", "sport/", true),
+            ("+/+", "/finance", true),
+            ("/+", "/finance", true),
+            ("+", "/finance", false),
+            ("#", "$SYS/uptime", false),
+            ("+/monitor/Clients", "$SYS/monitor/Clients", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/monitor/+", "$SYS/monitor/Clients", true),
+        ];
+        for (filter, topic, expected) in cases {
+            assert_eq!(
+                topic_matches(filter, topic),
+                expected,
+                "{filter} vs {topic}"
+            );
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Integration test against a real broker over `StdTcpTransport`.
+//!
+//! Ignored by default. Start a broker on `localhost:1883` (e.g.
+//! `docker run -p 1883:1883 eclipse-mosquitto:2 mosquitto -c /mosquitto-no-auth.conf`),
+//! then run `cargo test --features std --test std_broker -- --ignored`.
+
+use std::sync::OnceLock;
+use std::task::Waker;
+use std::time::Instant;
+
+use myrtio_mqtt::{MqttClient, MqttEvent, MqttOptions, QoS, StdTcpTransport};
+
+/// `embassy-time` driver counting microseconds since the first call.
+struct StdDriver;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+impl embassy_time_driver::Driver for StdDriver {
+    fn now(&self) -> u64 {
+        START.get_or_init(Instant::now).elapsed().as_micros() as u64
+    }
+
+    fn schedule_wake(&self, _at: u64, waker: &Waker) {
+        // The transport busy-polls, so waking right away is enough
+        waker.wake_by_ref();
+    }
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: StdDriver = StdDriver);
+
+#[test]
+#[ignore = "needs an MQTT broker on localhost:1883"]
+fn publish_and_receive_own_message() {
+    let transport = StdTcpTransport::connect("localhost:1883").expect("broker not reachable");
+    let options = MqttOptions::new("myrtio-mqtt-std-test");
+    let mut client = MqttClient::<_, 4, 1024>::new(transport, options);
+
+    embassy_futures::block_on(async {
+        client.connect().await.unwrap();
+        client
+            .subscribe("myrtio-mqtt/std-test", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        client
+            .publish("myrtio-mqtt/std-test", b"hello", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        match client.next_event().await.unwrap() {
+            MqttEvent::Publish(msg) => {
+                assert_eq!(msg.topic, "myrtio-mqtt/std-test");
+                assert_eq!(msg.payload, b"hello");
+            }
+            MqttEvent::Disconnected(_) => panic!("connection lost"),
+        }
+        client.disconnect().await.unwrap();
+    });
+}